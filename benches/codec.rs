@@ -0,0 +1,128 @@
+//! Parsing and building messages, the work done for every query and referral.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use dns_resolver_rs::builder::MessageBuilder;
+use dns_resolver_rs::view::PacketView;
+use dns_resolver_rs::{Class, DNSPacket, DNSRecord, DNSRecordData, RecordType, Soa};
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+
+/// A referral like those from the root: NS records in the authority section
+/// and their addresses in the additional section, names heavily compressed.
+fn referral() -> DNSPacket {
+    let mut builder = MessageBuilder::query().question("www.example.com", RecordType::A, Class::In);
+    for (i, letter) in ('a'..='m').enumerate() {
+        let server = format!("{letter}.gtld-servers.net");
+        builder = builder
+            .authority(DNSRecord::new(
+                "com",
+                RecordType::Ns,
+                Class::In,
+                172800,
+                DNSRecordData::Name(server.clone()),
+            ))
+            .additional(DNSRecord::new(
+                &server,
+                RecordType::A,
+                Class::In,
+                172800,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 5, 6, i as u8)),
+            ));
+    }
+    builder.build().unwrap()
+}
+
+/// An answer with `count` records of mixed types under one zone.
+fn many_records(count: usize) -> DNSPacket {
+    let mut builder =
+        MessageBuilder::query().question("bulk.example.com", RecordType::A, Class::In);
+    for i in 0..count {
+        let name = format!("host{i}.bulk.example.com");
+        let data = match i % 4 {
+            0 => DNSRecordData::Ipv4Addr(Ipv4Addr::new(10, 0, (i / 256) as u8, i as u8)),
+            1 => DNSRecordData::Name(format!("alias{i}.example.com")),
+            2 => DNSRecordData::Txt(vec!["v=spf1 -all".to_string(); 3]),
+            _ => DNSRecordData::Soa(Soa {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: i as u32,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        };
+        let type_ = match data {
+            DNSRecordData::Ipv4Addr(_) => RecordType::A,
+            DNSRecordData::Name(_) => RecordType::Cname,
+            DNSRecordData::Txt(_) => RecordType::Txt,
+            _ => RecordType::Soa,
+        };
+        builder = builder.answer(DNSRecord::new(&name, type_, Class::In, 300, data));
+    }
+    builder.build().unwrap()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    let fixtures = [
+        ("referral", referral()),
+        ("records/10", many_records(10)),
+        ("records/200", many_records(200)),
+    ];
+    for (name, packet) in &fixtures {
+        let bytes = packet.to_bytes();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::new("owned", name), &bytes, |b, bytes| {
+            b.iter(|| DNSPacket::parse(black_box(bytes)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("view", name), &bytes, |b, bytes| {
+            b.iter(|| {
+                let view = PacketView::parse(black_box(bytes)).unwrap();
+                view.answers().chain(view.authorities()).count()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Every NS target in the referral points back into the question or an
+/// earlier record, so this is mostly following compression pointers.
+fn decode_names(c: &mut Criterion) {
+    let bytes = referral().to_bytes();
+    c.bench_function("decode_names/compressed", |b| {
+        b.iter(|| {
+            let view = PacketView::parse(black_box(&bytes)).unwrap();
+            view.authorities()
+                .filter_map(|record| record.target())
+                .map(|name| name.to_string().len())
+                .sum::<usize>()
+        })
+    });
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.bench_function("query", |b| {
+        b.iter(|| {
+            MessageBuilder::query()
+                .question(black_box("www.example.com"), RecordType::A, Class::In)
+                .build()
+                .unwrap()
+                .to_bytes()
+        })
+    });
+    let large = many_records(200);
+    let mut buf = Vec::new();
+    group.bench_function("records/200", |b| {
+        b.iter(|| {
+            buf.clear();
+            black_box(&large).write_to(&mut buf);
+            buf.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, decode_names, build);
+criterion_main!(benches);
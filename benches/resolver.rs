@@ -0,0 +1,44 @@
+//! Resolutions answered from the cache, the common case for a busy resolver.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use dns_resolver_rs::transport::MockTransport;
+use dns_resolver_rs::{Class, DNSRecord, DNSRecordData, DNSResolver, RecordType};
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use tokio::runtime::Runtime;
+
+fn cached_resolution(c: &mut Criterion) {
+    let records = (1..=8)
+        .map(|i| {
+            DNSRecord::new(
+                "cached.example",
+                RecordType::A,
+                Class::In,
+                3600,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, i)),
+            )
+        })
+        .collect();
+    let resolver = DNSResolver::builder()
+        .transport(MockTransport::new().answer("cached.example", RecordType::A, records))
+        .recursion_desired(true)
+        .cache(true)
+        .build()
+        .unwrap();
+    let runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(resolver.resolve_record("cached.example", RecordType::A))
+        .unwrap();
+
+    c.bench_function("resolve_record/cached", |b| {
+        b.to_async(&runtime).iter(|| async {
+            resolver
+                .resolve_record(black_box("cached.example"), RecordType::A)
+                .await
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, cached_resolution);
+criterion_main!(benches);
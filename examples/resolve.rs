@@ -10,7 +10,7 @@ async fn main() {
         .with_max_level(Level::TRACE)
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-    let resolver = DNSResolver::new("198.41.0.4");
+    let resolver = DNSResolver::default();
 
     let args: Vec<String> = env::args().skip(1).collect();
     let domains = if args.is_empty() {
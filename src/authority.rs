@@ -0,0 +1,985 @@
+//! The zones the daemon answers for itself, each read from a master file or
+//! transferred from primary servers, as its configuration lists them.
+//!
+//! Zone files are watched, and read again when they change: the new records
+//! replace the old all at once, so a query never sees half an edit, and the
+//! records added and removed are logged. A file that no longer parses leaves
+//! the zone as it was.
+//!
+//! Secondary zones are transferred whole with AXFR (RFC 5936) over TCP, and
+//! kept up to date as their SOA says: the primaries' serial is checked every
+//! refresh interval, or every retry interval after a failure, and the zone
+//! transferred again when it is newer. A NOTIFY (RFC 1996) from a primary
+//! checks at once. A zone that could not be refreshed for its expire
+//! interval is answered with SERVFAIL until it can. Only the record types
+//! this crate parses can be transferred.
+//!
+//! Names can have regional addresses too, given to clients in some
+//! countries or continents in place of the zone's own, as a
+//! [GeoIP](crate::geoip) database locates them or the subnet they send
+//! (RFC 7871). And the addresses of a name can be weighted, to split
+//! traffic between servers unevenly: each answer then gives one address,
+//! picked with a probability proportional to its weight, or all of them in
+//! an order drawn the same way.
+//!
+//! Those addresses can be health checked too, by connecting to a port or
+//! asking it for a page over HTTP every so often. An address failing its
+//! check is left out of answers until it passes again, though when every
+//! address of a name fails they are all given out, as one may still work.
+
+use crate::builder::MessageBuilder;
+use crate::daemon::{
+    ClientNetwork, HealthCheck, HealthCheckProtocol, RegionalRecords, Selection, WeightedRecords,
+    ZoneConfig,
+};
+use crate::edns::{ClientSubnet, Edns, EdnsOption};
+use crate::error::{DnsError, Result};
+use crate::flags::{DNSFlags, Rcode};
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::zone::{Zone, absolute, is_subdomain};
+use crate::{Class, DNSPacket, DNSRecord, DNSRecordData, RecordType, Soa};
+use notify::{RecursiveMode, Watcher};
+use rand::Rng;
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// How long to wait after a file changes for any further changes, since
+/// editors save in several steps.
+const RELOAD_DELAY: Duration = Duration::from_millis(50);
+
+/// How long a secondary waits to try again when it has no SOA to take the
+/// retry interval from.
+const FIRST_TRANSFER_RETRY: Duration = Duration::from_secs(60);
+
+/// The shortest a secondary waits between refreshes, so an SOA with a
+/// refresh, retry or expire of 0 cannot have it query its primaries
+/// without pause.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// How long checking a primary's serial, or a whole transfer, may take.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many records each message of an outgoing transfer carries.
+const RECORDS_PER_MESSAGE: usize = 100;
+
+/// How much of an HTTP health check's response is read looking for its
+/// status line.
+const MAX_STATUS_LINE: u64 = 1024;
+
+#[derive(Debug)]
+struct ServedZone {
+    /// Lowercase, without the trailing dot.
+    origin: String,
+    source: Source,
+    /// `None` for a secondary until its first transfer, and once expired.
+    zone: Option<Arc<Zone>>,
+    /// How many times the zone has been loaded or transferred.
+    generation: u64,
+    allow_transfer: Vec<ClientNetwork>,
+    /// With absolute, lowercase names, like `weighted`.
+    regional: Vec<RegionalRecords>,
+    weighted: Arc<[WeightedRecords]>,
+    /// With absolute, lowercase names too.
+    health_checks: Vec<HealthCheck>,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// Canonicalized, to match the paths of change events.
+    File(PathBuf),
+    Primaries {
+        servers: Vec<SocketAddrV4>,
+        /// Woken by a NOTIFY from one of the servers.
+        notify: Arc<Notify>,
+    },
+}
+
+/// Where a client is, as far as picking regional addresses goes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The ISO 3166 code of the country, such as `DE`.
+    pub country: Option<String>,
+    /// The code of the continent, such as `EU`.
+    pub continent: Option<String>,
+}
+
+/// The zones of a daemon. Clones share them, so they can be reloaded while
+/// others answer from them.
+#[derive(Debug, Clone, Default)]
+pub struct Zones {
+    zones: Arc<RwLock<Vec<ServedZone>>>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<GeoIp>,
+    /// The names and addresses failing their health checks.
+    down: Arc<RwLock<HashSet<(String, IpAddr)>>>,
+}
+
+impl Zones {
+    /// Reads every zone file, failing on the first that cannot be read or
+    /// parsed. Secondary zones are empty until
+    /// [`spawn_refresh`](Self::spawn_refresh) transfers them.
+    pub fn load(configs: &[ZoneConfig]) -> Result<Self> {
+        let mut zones = Vec::with_capacity(configs.len());
+        for config in configs {
+            let origin = config.origin.trim_end_matches('.').to_ascii_lowercase();
+            let (source, zone) = match (&config.file, config.primaries.is_empty()) {
+                (Some(file), true) => {
+                    let path = file.canonicalize()?;
+                    let zone = read(&origin, &path)?;
+                    info!(zone = %origin, records = zone.records().count(), "Zone loaded");
+                    (Source::File(path), Some(Arc::new(zone)))
+                }
+                (None, false) => {
+                    let source = Source::Primaries {
+                        servers: config.primaries.clone(),
+                        notify: Arc::default(),
+                    };
+                    (source, None)
+                }
+                _ => {
+                    return Err(DnsError::InvalidConfig(format!(
+                        "Zone {} needs either a file or primaries",
+                        config.origin
+                    )));
+                }
+            };
+            let owner = |name: &str| {
+                let name = absolute(&name.to_ascii_lowercase(), &origin);
+                if !is_subdomain(&name, &origin) {
+                    return Err(DnsError::InvalidConfig(format!(
+                        "{name} is outside zone {}",
+                        config.origin
+                    )));
+                }
+                Ok(name)
+            };
+            let mut regional = config.regional.clone();
+            for records in &mut regional {
+                records.name = owner(&records.name)?;
+            }
+            let mut weighted = config.weighted.clone();
+            for records in &mut weighted {
+                records.name = owner(&records.name)?;
+            }
+            let mut health_checks = config.health_checks.clone();
+            for check in &mut health_checks {
+                check.name = owner(&check.name)?;
+            }
+            let mut checked = HashSet::new();
+            if let Some(check) = health_checks
+                .iter()
+                .find(|check| !checked.insert(&check.name))
+            {
+                return Err(DnsError::InvalidConfig(format!(
+                    "{} has more than one health check",
+                    check.name
+                )));
+            }
+            zones.push(ServedZone {
+                origin,
+                source,
+                generation: u64::from(zone.is_some()),
+                zone,
+                allow_transfer: config.allow_transfer.clone(),
+                regional,
+                weighted: weighted.into(),
+                health_checks,
+            });
+        }
+        Ok(Zones {
+            zones: Arc::new(RwLock::new(zones)),
+            #[cfg(feature = "geoip")]
+            geoip: None,
+            down: Arc::default(),
+        })
+    }
+
+    /// Locates clients in `geoip` to pick regional addresses for them.
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// The zone `name` is in, the most specific if several contain it, as
+    /// long as that zone is loaded.
+    pub fn find(&self, name: &str) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        served(&zones, name)?.zone.clone()
+    }
+
+    /// The authoritative response to `query` from `client`, if its question
+    /// is in one of the zones: SERVFAIL while a secondary zone has not been
+    /// transferred. Zone transfers are refused, whatever the zone, as they
+    /// are only served over TCP by [`transfer`](Self::transfer).
+    pub fn answer(&self, query: &DNSPacket, client: IpAddr) -> Option<DNSPacket> {
+        let subnet = query
+            .edns
+            .as_ref()
+            .and_then(Edns::client_subnet)
+            .filter(|subnet| subnet.source_prefix > 0);
+        let location = self.locate(subnet.map_or(client, |subnet| subnet.address));
+        self.answer_at(query, location.as_ref())
+    }
+
+    /// The response to `query` for a client at `location`.
+    pub(crate) fn answer_at(
+        &self,
+        query: &DNSPacket,
+        location: Option<&Location>,
+    ) -> Option<DNSPacket> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        if question.type_ == RecordType::Axfr {
+            return Some(error_response(query, Rcode::Refused));
+        }
+        let (zone, regional, weighted) = {
+            let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+            let served = served(&zones, &question.name)?;
+            let regional = location.and_then(|location| regional_records(served, query, location));
+            (served.zone.clone(), regional, served.weighted.clone())
+        };
+        let mut response = match (zone, regional) {
+            (Some(_), Some(records)) => regional_response(query, records).ok()?,
+            (Some(zone), None) => zone.answer(query).ok()?,
+            (None, _) => return Some(error_response(query, Rcode::ServFail)),
+        };
+        if matches!(question.type_, RecordType::A | RecordType::Aaaa) {
+            let down = self.down.read().unwrap_or_else(|e| e.into_inner());
+            leave_out_down(&mut response.answers, &down);
+            let mut rng = rand::rng();
+            for records in weighted.iter() {
+                weigh(&mut response.answers, records, &mut rng);
+            }
+        }
+        Some(response)
+    }
+
+    fn locate(&self, address: IpAddr) -> Option<Location> {
+        #[cfg(feature = "geoip")]
+        return self.geoip.as_ref()?.locate(address);
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = address;
+            None
+        }
+    }
+
+    /// The messages of a zone transfer `client` asked for with `query`, or
+    /// `None` if it did not ask for one. Clients outside the zone's
+    /// `allow_transfer` networks are refused.
+    pub fn transfer(&self, query: &DNSPacket, client: IpAddr) -> Option<Vec<DNSPacket>> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        if question.type_ != RecordType::Axfr {
+            return None;
+        }
+        let name = question.name.trim_end_matches('.');
+        let (zone, allowed) = {
+            let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+            let Some(served) = zones
+                .iter()
+                .find(|served| served.origin.eq_ignore_ascii_case(name))
+            else {
+                return Some(vec![error_response(query, Rcode::NotAuth)]);
+            };
+            let allowed = served
+                .allow_transfer
+                .iter()
+                .any(|network| network.contains(client));
+            (served.zone.clone(), allowed)
+        };
+        let Some(zone) = zone else {
+            return Some(vec![error_response(query, Rcode::ServFail)]);
+        };
+        if !allowed {
+            debug!(%client, zone = %name, "Refusing a zone transfer");
+            return Some(vec![error_response(query, Rcode::Refused)]);
+        }
+        let flags = DNSFlags {
+            qr: true,
+            aa: true,
+            ..DNSFlags::default()
+        };
+        let messages = zone
+            .transfer()
+            .chunks(RECORDS_PER_MESSAGE)
+            .map(|records| {
+                records
+                    .iter()
+                    .cloned()
+                    .fold(
+                        MessageBuilder::response_to(query).flags(flags),
+                        MessageBuilder::answer,
+                    )
+                    .build()
+            })
+            .collect::<Result<_>>();
+        // A secondary must not take the zone with a message's records missing.
+        match messages {
+            Ok(messages) => Some(messages),
+            Err(e) => {
+                warn!(zone = %name, error = %e, "Zone transfer failed");
+                Some(vec![error_response(query, Rcode::ServFail)])
+            }
+        }
+    }
+
+    /// The response to a NOTIFY `query` from `client`, which has a
+    /// secondary zone checked for changes at once if `client` is one of its
+    /// primaries.
+    pub fn notify(&self, query: &DNSPacket, client: IpAddr) -> DNSPacket {
+        let [question] = query.questions.as_slice() else {
+            return error_response(query, Rcode::FormErr);
+        };
+        let name = question.name.trim_end_matches('.');
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        let Some(served) = zones
+            .iter()
+            .find(|served| served.origin.eq_ignore_ascii_case(name))
+        else {
+            return error_response(query, Rcode::NotAuth);
+        };
+        match &served.source {
+            Source::Primaries { servers, notify }
+                if servers
+                    .iter()
+                    .any(|server| IpAddr::V4(*server.ip()) == client.to_canonical()) =>
+            {
+                debug!(%client, zone = %served.origin, "NOTIFY received");
+                notify.notify_one();
+                error_response(query, Rcode::NoError)
+            }
+            _ => error_response(query, Rcode::Refused),
+        }
+    }
+
+    /// How many times the zone at `origin` has been loaded or transferred,
+    /// or `None` if it is not served.
+    pub fn generation(&self, origin: &str) -> Option<u64> {
+        let origin = origin.trim_end_matches('.');
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        zones
+            .iter()
+            .find(|served| served.origin.eq_ignore_ascii_case(origin))
+            .map(|served| served.generation)
+    }
+
+    /// Reads the zones from the file at `path` again, replacing those whose
+    /// records changed.
+    pub fn reload(&self, path: &Path) {
+        let origins: Vec<String> = {
+            let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+            zones
+                .iter()
+                .filter(|served| matches!(&served.source, Source::File(file) if file == path))
+                .map(|served| served.origin.clone())
+                .collect()
+        };
+        for origin in origins {
+            match read(&origin, path) {
+                Ok(zone) => self.install(&origin, zone, "Zone reloaded"),
+                Err(e) => {
+                    warn!(zone = %origin, path = %path.display(), error = %e, "Zone reload failed, keeping the loaded zone");
+                }
+            }
+        }
+    }
+
+    /// Replaces the zone at `origin` with `zone` if their records differ,
+    /// logging the difference.
+    pub(crate) fn install(&self, origin: &str, zone: Zone, message: &str) {
+        let mut zones = self.zones.write().unwrap_or_else(|e| e.into_inner());
+        let Some(served) = zones.iter_mut().find(|served| served.origin == origin) else {
+            return;
+        };
+        let old: HashSet<String> = served
+            .zone
+            .iter()
+            .flat_map(|zone| zone.records())
+            .map(ToString::to_string)
+            .collect();
+        let new: HashSet<String> = zone.records().map(ToString::to_string).collect();
+        if served.zone.is_some() && old == new {
+            return;
+        }
+        served.generation += 1;
+        served.zone = Some(Arc::new(zone));
+        let added: Vec<&String> = new.difference(&old).collect();
+        let removed: Vec<&String> = old.difference(&new).collect();
+        info!(
+            zone = %origin,
+            generation = served.generation,
+            added = added.len(),
+            removed = removed.len(),
+            "{message}"
+        );
+        added
+            .iter()
+            .for_each(|record| debug!(zone = %origin, "+ {record}"));
+        removed
+            .iter()
+            .for_each(|record| debug!(zone = %origin, "- {record}"));
+    }
+
+    /// Reloads each zone file whenever it changes, in a background task.
+    /// The directories holding the files are watched rather than the files,
+    /// so editors that save by replacing a file are noticed.
+    pub fn watch(&self) -> Result<JoinHandle<()>> {
+        let (sender, mut changes) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event
+                    && !event.kind.is_access()
+                {
+                    let _ = sender.send(event.paths);
+                }
+            })
+            .map_err(watch_error)?;
+        let paths: HashSet<PathBuf> = {
+            let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+            zones
+                .iter()
+                .filter_map(|served| match &served.source {
+                    Source::File(path) => Some(path.clone()),
+                    Source::Primaries { .. } => None,
+                })
+                .collect()
+        };
+        let directories: HashSet<&Path> = paths.iter().filter_map(|path| path.parent()).collect();
+        for directory in directories {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map_err(watch_error)?;
+        }
+        let zones = self.clone();
+        Ok(tokio::spawn(async move {
+            // Dropping the watcher would stop the events.
+            let _watcher = watcher;
+            while let Some(changed) = changes.recv().await {
+                let mut changed: HashSet<PathBuf> = changed.into_iter().collect();
+                tokio::time::sleep(RELOAD_DELAY).await;
+                while let Ok(more) = changes.try_recv() {
+                    changed.extend(more);
+                }
+                for path in changed.into_iter().filter(|path| paths.contains(path)) {
+                    let zones = zones.clone();
+                    let _ = tokio::task::spawn_blocking(move || zones.reload(&path)).await;
+                }
+            }
+        }))
+    }
+
+    /// Transfers each secondary zone now, and keeps it up to date after, in
+    /// a background task per zone. Dropping the set stops them.
+    pub fn spawn_refresh(&self) -> JoinSet<()> {
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        let mut tasks = JoinSet::new();
+        for served in zones.iter() {
+            if let Source::Primaries { servers, notify } = &served.source {
+                tasks.spawn(self.clone().keep_fresh(
+                    served.origin.clone(),
+                    servers.clone(),
+                    notify.clone(),
+                ));
+            }
+        }
+        tasks
+    }
+
+    /// Runs each health check now, and every interval after, in a
+    /// background task per check. Dropping the set stops them.
+    pub fn spawn_health_checks(&self) -> JoinSet<()> {
+        let mut tasks = JoinSet::new();
+        for check in self.health_checks() {
+            let zones = self.clone();
+            tasks.spawn(async move {
+                let period = Duration::from_secs(check.interval_secs.max(1));
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    zones.run_health_check(&check).await;
+                }
+            });
+        }
+        tasks
+    }
+
+    /// Runs every health check once, all at the same time.
+    pub async fn check_health(&self) {
+        let mut checks = JoinSet::new();
+        for check in self.health_checks() {
+            let zones = self.clone();
+            checks.spawn(async move { zones.run_health_check(&check).await });
+        }
+        checks.join_all().await;
+    }
+
+    fn health_checks(&self) -> Vec<HealthCheck> {
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        zones
+            .iter()
+            .flat_map(|served| served.health_checks.iter().cloned())
+            .collect()
+    }
+
+    /// Checks every address of the name `check` is for, logging those that
+    /// go down or come back up.
+    async fn run_health_check(&self, check: &HealthCheck) {
+        let addresses = self.addresses(&check.name);
+        let mut probes = JoinSet::new();
+        for address in addresses.iter().copied() {
+            let check = check.clone();
+            probes.spawn(async move { (address, probe(&check, address).await) });
+        }
+        let results = probes.join_all().await;
+        let mut down = self.down.write().unwrap_or_else(|e| e.into_inner());
+        // Addresses the name no longer has are forgotten.
+        down.retain(|(name, address)| *name != check.name || addresses.contains(address));
+        for (address, result) in results {
+            let key = (check.name.clone(), address);
+            match result {
+                Ok(()) => {
+                    if down.remove(&key) {
+                        info!(name = %check.name, %address, "Health check passed, giving the address out again");
+                    }
+                }
+                Err(e) => {
+                    if down.insert(key) {
+                        warn!(name = %check.name, %address, error = %e, "Health check failed, leaving the address out of answers");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every address `name` has, in its zone or regional.
+    fn addresses(&self, name: &str) -> Vec<IpAddr> {
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        let Some(served) = served(&zones, name) else {
+            return Vec::new();
+        };
+        let mut addresses: Vec<IpAddr> = served
+            .zone
+            .iter()
+            .flat_map(|zone| zone.records())
+            .filter(|record| record.name.trim_end_matches('.').eq_ignore_ascii_case(name))
+            .filter_map(address)
+            .chain(
+                served
+                    .regional
+                    .iter()
+                    .filter(|records| records.name == name)
+                    .flat_map(|records| records.addresses.iter().copied()),
+            )
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    async fn keep_fresh(self, origin: String, primaries: Vec<SocketAddrV4>, notify: Arc<Notify>) {
+        let mut expires_at = None;
+        loop {
+            let wait = match self.refresh(&origin, &primaries).await {
+                Ok(soa) => {
+                    expires_at = Some(Instant::now() + Duration::from_secs(soa.expire.into()));
+                    Duration::from_secs(soa.refresh.into())
+                }
+                Err(e) => {
+                    warn!(zone = %origin, error = %e, "Zone refresh failed");
+                    if expires_at.is_some_and(|expires_at| expires_at <= Instant::now()) {
+                        warn!(zone = %origin, "Zone expired, answering SERVFAIL until refreshed");
+                        self.expire(&origin);
+                        expires_at = None;
+                    }
+                    self.soa(&origin).map_or(FIRST_TRANSFER_RETRY, |soa| {
+                        Duration::from_secs(soa.retry.into())
+                    })
+                }
+            };
+            let wait = expires_at
+                .map_or(wait, |expires_at: Instant| {
+                    wait.min(expires_at.saturating_duration_since(Instant::now()))
+                })
+                .max(MIN_REFRESH);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = notify.notified() => {}
+            }
+        }
+    }
+
+    /// Transfers the zone from the first primary that answers, if its
+    /// serial is newer than the one held, returning the SOA then in use.
+    async fn refresh(&self, origin: &str, primaries: &[SocketAddrV4]) -> Result<Soa> {
+        let mut error = None;
+        for primary in primaries {
+            let refreshed =
+                tokio::time::timeout(TRANSFER_TIMEOUT, self.refresh_from(origin, *primary))
+                    .await
+                    .unwrap_or(Err(DnsError::Timeout(*primary.ip())));
+            match refreshed {
+                Ok(soa) => return Ok(soa),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error
+            .unwrap_or_else(|| DnsError::InvalidConfig(format!("Zone {origin} has no primaries"))))
+    }
+
+    async fn refresh_from(&self, origin: &str, primary: SocketAddrV4) -> Result<Soa> {
+        let serial = primary_serial(primary, origin).await?;
+        if let Some(soa) = self.soa(origin)
+            && !serial_newer(serial, soa.serial)
+        {
+            debug!(zone = %origin, %primary, serial, "Zone is up to date");
+            return Ok(soa);
+        }
+        let zone = transfer(primary, origin).await?;
+        let soa = soa_data(zone.soa());
+        self.install(origin, zone, "Zone transferred");
+        Ok(soa)
+    }
+
+    fn soa(&self, origin: &str) -> Option<Soa> {
+        let zones = self.zones.read().unwrap_or_else(|e| e.into_inner());
+        let served = zones.iter().find(|served| served.origin == origin)?;
+        served.zone.as_ref().map(|zone| soa_data(zone.soa()))
+    }
+
+    fn expire(&self, origin: &str) {
+        let mut zones = self.zones.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(served) = zones.iter_mut().find(|served| served.origin == origin) {
+            served.zone = None;
+        }
+    }
+}
+
+/// The regional addresses of the kind `query` asks for that `served` gives
+/// clients at `location`, if any.
+fn regional_records(
+    served: &ServedZone,
+    query: &DNSPacket,
+    location: &Location,
+) -> Option<Vec<DNSRecord>> {
+    let question = query.questions.first()?;
+    let name = question.name.trim_end_matches('.').to_ascii_lowercase();
+    let in_region = |records: &&RegionalRecords| {
+        let matches = |codes: &[String], code: &Option<String>| {
+            code.as_ref()
+                .is_some_and(|code| codes.iter().any(|c| c.eq_ignore_ascii_case(code)))
+        };
+        matches(&records.countries, &location.country)
+            || matches(&records.continents, &location.continent)
+    };
+    served
+        .regional
+        .iter()
+        .filter(|records| records.name == name)
+        .filter(in_region)
+        .map(|records| {
+            records
+                .addresses
+                .iter()
+                .filter_map(|address| {
+                    let data = match (address, &question.type_) {
+                        (IpAddr::V4(ip), RecordType::A) => DNSRecordData::Ipv4Addr(*ip),
+                        (IpAddr::V6(ip), RecordType::Aaaa) => DNSRecordData::Ipv6Addr(*ip),
+                        _ => return None,
+                    };
+                    let type_ = question.type_.clone();
+                    Some(DNSRecord::new(&name, type_, Class::In, records.ttl, data))
+                })
+                .collect::<Vec<_>>()
+        })
+        .find(|records| !records.is_empty())
+}
+
+/// Leaves out of `answers` the addresses `down` lists, except those of names
+/// with no other address.
+fn leave_out_down(answers: &mut Vec<DNSRecord>, down: &HashSet<(String, IpAddr)>) {
+    if down.is_empty() {
+        return;
+    }
+    let owner = |record: &DNSRecord| record.name.trim_end_matches('.').to_ascii_lowercase();
+    let is_down = |record: &DNSRecord| {
+        address(record).is_some_and(|address| down.contains(&(owner(record), address)))
+    };
+    let up: HashSet<String> = answers
+        .iter()
+        .filter(|record| address(record).is_some() && !is_down(record))
+        .map(owner)
+        .collect();
+    answers.retain(|record| !is_down(record) || !up.contains(&owner(record)));
+}
+
+/// Orders the addresses `records` weighs among `answers` by a weighted
+/// draw (Efraimidis and Spirakis), leaving out those weighing nothing and,
+/// to give one address, all but the first drawn.
+fn weigh(answers: &mut Vec<DNSRecord>, records: &WeightedRecords, rng: &mut impl Rng) {
+    let weighed = |record: &DNSRecord| {
+        matches!(record.type_, RecordType::A | RecordType::Aaaa)
+            && record
+                .name
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(&records.name)
+    };
+    let Some(first) = answers.iter().position(weighed) else {
+        return;
+    };
+    let weight = |record: &DNSRecord| {
+        address(record).map_or(1, |address| {
+            records.weights.get(&address).copied().unwrap_or(1)
+        })
+    };
+    let (addresses, others): (Vec<DNSRecord>, Vec<DNSRecord>) =
+        std::mem::take(answers).into_iter().partition(weighed);
+    let mut drawn: Vec<(f64, DNSRecord)> = addresses
+        .iter()
+        .filter(|record| weight(record) > 0)
+        .map(|record| {
+            let key = rng.random::<f64>().powf(1.0 / f64::from(weight(record)));
+            (key, record.clone())
+        })
+        .collect();
+    drawn.sort_by(|a, b| b.0.total_cmp(&a.0));
+    if records.select == Selection::One {
+        drawn.truncate(1);
+    }
+    // When every address weighs nothing, none is preferred.
+    let addresses = if drawn.is_empty() {
+        addresses
+    } else {
+        drawn.into_iter().map(|(_, record)| record).collect()
+    };
+    *answers = others;
+    answers.splice(first..first, addresses);
+}
+
+/// The address of an A or AAAA record.
+fn address(record: &DNSRecord) -> Option<IpAddr> {
+    match record.data {
+        DNSRecordData::Ipv4Addr(ip) => Some(IpAddr::V4(ip)),
+        DNSRecordData::Ipv6Addr(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    }
+}
+
+/// Whether `address` passes `check`: accepts a connection on its port and,
+/// for HTTP, answers a GET of its path with a 2xx or 3xx status.
+async fn probe(check: &HealthCheck, address: IpAddr) -> io::Result<()> {
+    let timeout = Duration::from_millis(check.timeout_ms);
+    let checked = async {
+        let mut stream = TcpStream::connect((address, check.port)).await?;
+        if check.protocol == HealthCheckProtocol::Tcp {
+            return Ok(());
+        }
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            check.path, check.name
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut status_line = String::new();
+        BufReader::new(stream.take(MAX_STATUS_LINE))
+            .read_line(&mut status_line)
+            .await?;
+        let status = status_line
+            .strip_prefix("HTTP/")
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200..=399) => Ok(()),
+            Some(status) => Err(io::Error::other(format!("HTTP status {status}"))),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an HTTP response",
+            )),
+        }
+    };
+    tokio::time::timeout(timeout, checked)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// The authoritative answer of `records`, telling a client that sent its
+/// subnet the answer is for that whole subnet.
+fn regional_response(query: &DNSPacket, records: Vec<DNSRecord>) -> Result<DNSPacket> {
+    let mut builder = MessageBuilder::response_to(query).flags(DNSFlags {
+        qr: true,
+        aa: true,
+        opcode: query.header.flags.opcode,
+        rd: query.header.flags.rd,
+        ..DNSFlags::default()
+    });
+    if let Some(edns) = &query.edns {
+        let mut response = Edns::default();
+        if let Some(subnet) = edns.client_subnet() {
+            response
+                .options
+                .push(EdnsOption::ClientSubnet(ClientSubnet {
+                    scope_prefix: subnet.source_prefix,
+                    ..subnet.clone()
+                }));
+        }
+        builder = builder.edns(response);
+    }
+    records
+        .into_iter()
+        .fold(builder, MessageBuilder::answer)
+        .build()
+}
+
+/// The most specific zone `name` is in.
+fn served<'a>(zones: &'a [ServedZone], name: &str) -> Option<&'a ServedZone> {
+    zones
+        .iter()
+        .filter(|served| is_subdomain(name, &served.origin))
+        .max_by_key(|served| served.origin.len())
+}
+
+fn soa_data(record: &DNSRecord) -> Soa {
+    match &record.data {
+        DNSRecordData::Soa(soa) => soa.clone(),
+        _ => unreachable!("Zone keeps a SOA record at its apex"),
+    }
+}
+
+/// Whether serial `a` comes after `b` in serial number arithmetic (RFC 1982),
+/// so the serial can wrap around.
+fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// A response to `query` with nothing but `rcode`, or a FORMERR without
+/// the question if that cannot be echoed.
+fn error_response(query: &DNSPacket, rcode: Rcode) -> DNSPacket {
+    let flags = DNSFlags {
+        qr: true,
+        aa: rcode == Rcode::NoError,
+        opcode: query.header.flags.opcode,
+        rd: query.header.flags.rd,
+        rcode,
+        ..DNSFlags::default()
+    };
+    MessageBuilder::response_to(query)
+        .flags(flags)
+        .build()
+        .unwrap_or_else(|_| {
+            MessageBuilder::bare_response(
+                query,
+                DNSFlags {
+                    aa: false,
+                    rcode: Rcode::FormErr,
+                    ..flags
+                },
+            )
+        })
+}
+
+/// The serial of the zone at `origin` on `primary`.
+async fn primary_serial(primary: SocketAddrV4, origin: &str) -> Result<u32> {
+    let query = MessageBuilder::query()
+        .question(origin, RecordType::Soa, Class::In)
+        .build()?;
+    let mut stream = send_tcp(primary, &query).await?;
+    let response = read_response(&mut stream, primary, &query).await?;
+    response
+        .answers
+        .iter()
+        .find_map(|record| match &record.data {
+            DNSRecordData::Soa(soa) if record.name.eq_ignore_ascii_case(origin) => Some(soa.serial),
+            _ => None,
+        })
+        .ok_or_else(|| DnsError::NoRecordsFound(origin.to_string()))
+}
+
+/// Every record of the zone at `origin`, transferred from `primary`.
+async fn transfer(primary: SocketAddrV4, origin: &str) -> Result<Zone> {
+    let query = MessageBuilder::query()
+        .question(origin, RecordType::Axfr, Class::In)
+        .build()?;
+    let mut stream = send_tcp(primary, &query).await?;
+    let mut records: Vec<DNSRecord> = Vec::new();
+    loop {
+        let response = read_response(&mut stream, primary, &query).await?;
+        if response.answers.is_empty() {
+            return Err(DnsError::MalformedPacket(
+                "Zone transfer message without records".to_string(),
+            ));
+        }
+        records.extend(response.answers);
+        if records[0].type_ != RecordType::Soa {
+            return Err(DnsError::MalformedPacket(
+                "Zone transfer does not start with a SOA".to_string(),
+            ));
+        }
+        if records.len() > 1
+            && records
+                .last()
+                .is_some_and(|record| record.type_ == RecordType::Soa)
+        {
+            break;
+        }
+    }
+    Zone::new(origin, records)
+}
+
+async fn send_tcp(primary: SocketAddrV4, query: &DNSPacket) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(primary).await?;
+    stream.write_all(&query.to_tcp_bytes()?).await?;
+    Ok(stream)
+}
+
+/// Reads the next message on `stream`, which must answer `query` without
+/// an error.
+async fn read_response(
+    stream: &mut TcpStream,
+    primary: SocketAddrV4,
+    query: &DNSPacket,
+) -> Result<DNSPacket> {
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let response = DNSPacket::parse(&buf)?;
+    if response.header.id != query.header.id || !response.header.flags.qr {
+        return Err(DnsError::MalformedPacket(
+            "Not a response to the query sent".to_string(),
+        ));
+    }
+    match response.header.flags.rcode {
+        Rcode::NoError => Ok(response),
+        rcode => Err(DnsError::ErrorResponse {
+            domain: query.questions[0].name.clone(),
+            server: *primary.ip(),
+            rcode,
+        }),
+    }
+}
+
+fn read(origin: &str, path: &Path) -> Result<Zone> {
+    let text = std::fs::read_to_string(path)?;
+    Zone::parse(origin, &text).map_err(|e| match e {
+        DnsError::InvalidZone(message) => {
+            DnsError::InvalidZone(format!("{}, {message}", path.display()))
+        }
+        e => e,
+    })
+}
+
+fn watch_error(e: notify::Error) -> DnsError {
+    DnsError::IoError(std::io::Error::other(e))
+}
@@ -0,0 +1,170 @@
+//! Upkeep a long-running resolver can do between lookups: probing upstreams,
+//! re-priming the root servers and prefetching popular cache entries.
+
+use crate::DNSResolver;
+#[cfg(feature = "cache")]
+use crate::cache::RECORD_CACHE;
+#[cfg(feature = "cache")]
+use crate::config::{CachePolicy, QueryOptions};
+use crate::transport::DnsTransport;
+use crate::{DNSRecordData, RecordType};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn};
+
+/// What the background task does, and how often.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackgroundOptions {
+    /// Time between rounds. The first round runs straight away.
+    pub interval: Duration,
+    /// Asks every upstream for the root NS set, keeping the round-trip times
+    /// that order them current and noticing when a failed one recovers.
+    pub probe_upstreams: bool,
+    /// Replaces the upstreams with the root server addresses from a priming
+    /// query (RFC 8109). Only for upstreams that are root servers.
+    pub prime_roots: bool,
+    /// Re-resolves cached record sets that have been served from the cache
+    /// and expire within this long, so popular names never miss it.
+    pub prefetch_within: Option<Duration>,
+}
+
+impl Default for BackgroundOptions {
+    fn default() -> Self {
+        BackgroundOptions {
+            interval: Duration::from_secs(60),
+            probe_upstreams: true,
+            prime_roots: false,
+            prefetch_within: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// Controls a task started by [`DNSResolver::spawn_background`]. Dropping it
+/// stops the task without waiting.
+#[derive(Debug)]
+pub struct BackgroundHandle {
+    task: JoinHandle<()>,
+    stop: DropGuard,
+}
+
+impl BackgroundHandle {
+    /// Stops the task and waits for it to finish, abandoning any round in progress.
+    pub async fn shutdown(self) {
+        drop(self.stop);
+        let _ = self.task.await;
+    }
+}
+
+impl<T: DnsTransport + Clone + 'static> DNSResolver<T> {
+    /// Starts the upkeep described by `options` on `runtime`, for this
+    /// resolver and every clone of it.
+    pub fn spawn_background(
+        &self,
+        runtime: &Handle,
+        options: BackgroundOptions,
+    ) -> BackgroundHandle {
+        let resolver = self.clone();
+        let stop = CancellationToken::new();
+        let stopped = stop.clone();
+        let task = runtime.spawn(async move {
+            let mut rounds = interval(options.interval);
+            rounds.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    () = stopped.cancelled() => break,
+                    _ = rounds.tick() => {}
+                }
+                tokio::select! {
+                    () = stopped.cancelled() => break,
+                    () = resolver.background_round(&options) => {}
+                }
+            }
+            debug!("Background task stopped");
+        });
+        BackgroundHandle {
+            task,
+            stop: stop.drop_guard(),
+        }
+    }
+
+    async fn background_round(&self, options: &BackgroundOptions) {
+        if options.probe_upstreams {
+            for upstream in self.upstreams() {
+                if let Err(e) = self.lookup(".", &upstream, RecordType::Ns).await {
+                    warn!(%upstream, error = %e, "Upstream probe failed");
+                }
+            }
+        }
+        if options.prime_roots {
+            self.prime_roots().await;
+        }
+        #[cfg(feature = "cache")]
+        if let Some(within) = options.prefetch_within
+            && self.use_cache
+        {
+            self.prefetch(within).await;
+        }
+    }
+
+    /// Asks the upstreams, fastest first, for the root NS set and starts from
+    /// the addresses given for those servers.
+    async fn prime_roots(&self) {
+        let mut upstreams = self.upstreams();
+        self.servers.rank(&mut upstreams);
+        for upstream in upstreams {
+            let Ok(response) = self.lookup(".", &upstream, RecordType::Ns).await else {
+                continue;
+            };
+            let roots: Vec<&str> = response
+                .answers
+                .iter()
+                .filter_map(|record| match &record.data {
+                    DNSRecordData::Name(name) => Some(name.as_str()),
+                    _ => None,
+                })
+                .collect();
+            let addresses: Vec<Ipv4Addr> = response
+                .additionals
+                .iter()
+                .filter(|record| {
+                    roots
+                        .iter()
+                        .any(|root| root.eq_ignore_ascii_case(&record.name))
+                })
+                .filter_map(|record| match record.data {
+                    DNSRecordData::Ipv4Addr(ip) => Some(ip),
+                    _ => None,
+                })
+                .collect();
+            if !addresses.is_empty() {
+                info!(%upstream, roots = addresses.len(), "Primed root servers");
+                *self.upstreams.write().unwrap_or_else(|e| e.into_inner()) = addresses;
+                return;
+            }
+        }
+        warn!("Root priming found no root server addresses");
+    }
+
+    #[cfg(feature = "cache")]
+    async fn prefetch(&self, within: Duration) {
+        let due: Vec<_> = RECORD_CACHE
+            .iter()
+            .filter(|(_, cached)| cached.was_used() && cached.expires_in() <= within)
+            .map(|(key, _)| (*key).clone())
+            .collect();
+        let options = QueryOptions {
+            cache: CachePolicy::Refresh,
+            ..QueryOptions::default()
+        };
+        for (name, record_type) in due {
+            debug!(%name, %record_type, "Prefetching");
+            if let Err(e) = self.resolve_detailed(&name, record_type, &options).await {
+                debug!(%name, error = %e, "Prefetch failed");
+            }
+        }
+    }
+}
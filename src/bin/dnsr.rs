@@ -0,0 +1,219 @@
+use clap::Parser;
+use dns_resolver_rs::cli::{
+    Cli, Command, ServeArgs, bench, completions, expand_dig_args, man_page, read_targets, render,
+    render_bench, render_delegation, render_dnssec, render_propagation, render_trace, resolve_bulk,
+    sweep_ptr, watch,
+};
+use dns_resolver_rs::daemon::Daemon;
+use dns_resolver_rs::dnssec::root_trust_anchors;
+use dns_resolver_rs::propagation::PUBLIC_RESOLVERS;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse_from(expand_dig_args(std::env::args_os()));
+    let generated = match &cli.command {
+        Some(Command::Completions(args)) => Some(completions(args.shell, &mut io::stdout())),
+        Some(Command::Man) => Some(man_page(&mut io::stdout())),
+        _ => None,
+    };
+    match generated {
+        Some(Ok(())) => return ExitCode::SUCCESS,
+        Some(Err(e)) => {
+            eprintln!("dnsr: {e}");
+            return ExitCode::FAILURE;
+        }
+        None => {}
+    }
+    if let Some(Command::Serve(args)) = &cli.command {
+        return match serve(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("dnsr: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let resolver = match cli.resolver() {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            eprintln!("dnsr: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match &cli.command {
+        Some(Command::Watch(args)) => {
+            let color = io::stdout().is_terminal();
+            return match watch(&resolver, args, &mut io::stdout(), color).await {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("dnsr: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::Propagation(args)) => {
+            let propagation = resolver
+                .check_propagation(&args.name, args.record_type.clone(), &PUBLIC_RESOLVERS)
+                .await;
+            print!("{}", render_propagation(cli.output, &propagation));
+            return if propagation.agrees() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Some(Command::Dnssec(args)) => {
+            let chain = resolver
+                .check_dnssec(&args.name, args.record_type.clone(), &root_trust_anchors())
+                .await;
+            print!("{}", render_dnssec(cli.output, &chain));
+            return if chain.is_secure() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Some(Command::CheckDelegation(args)) => {
+            let delegation = resolver.check_delegation(&args.zone).await;
+            print!("{}", render_delegation(cli.output, &delegation));
+            return if delegation.is_healthy() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Some(Command::Bench(args)) => {
+            let input = match open_input(&args.targets) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("dnsr: {}: {e}", args.targets.display());
+                    return ExitCode::FAILURE;
+                }
+            };
+            let targets = match read_targets(input) {
+                Ok(targets) if !targets.is_empty() => targets,
+                Ok(_) => {
+                    eprintln!("dnsr: {}: no names to query", args.targets.display());
+                    return ExitCode::FAILURE;
+                }
+                Err(e) => {
+                    eprintln!("dnsr: {}: {e}", args.targets.display());
+                    return ExitCode::FAILURE;
+                }
+            };
+            let servers: Vec<_> = cli.servers.iter().map(|server| *server.ip()).collect();
+            let report = bench(&resolver, &servers, &targets, args).await;
+            print!("{}", render_bench(cli.output, &report));
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Ptr(args)) => {
+            let concurrency = args.concurrency.into();
+            let mut out = io::stdout().lock();
+            return match sweep_ptr(&resolver, args.network, concurrency, cli.output, &mut out).await
+            {
+                Ok(summary) => {
+                    eprintln!(
+                        "dnsr: {} of {} addresses have a PTR record",
+                        summary.resolved,
+                        summary.resolved + summary.failed
+                    );
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("dnsr: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::Completions(_) | Command::Man | Command::Serve(_)) => {
+            unreachable!("handled before")
+        }
+        None => {}
+    }
+    let Some(name) = &cli.name else {
+        let path = cli.file.as_deref().expect("a name or a file is required");
+        let input = match open_input(path) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("dnsr: {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        let concurrency = cli.concurrency.into();
+        let mut out = io::stdout().lock();
+        return match resolve_bulk(&resolver, input, concurrency, cli.output, &mut out).await {
+            Ok(summary) => {
+                eprintln!(
+                    "dnsr: resolved {} of {} names",
+                    summary.resolved,
+                    summary.resolved + summary.failed
+                );
+                if summary.failed == 0 {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                }
+            }
+            Err(e) => {
+                eprintln!("dnsr: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    };
+    if cli.trace {
+        let trace = resolver.resolve_trace(name, cli.record_type.clone()).await;
+        print!(
+            "{}",
+            render_trace(cli.output, name, &cli.record_type, &trace)
+        );
+        return match trace.result {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("dnsr: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    match resolver
+        .resolve_detailed(name, cli.record_type.clone(), &cli.query_options())
+        .await
+    {
+        Ok(result) => {
+            print!("{}", render(cli.output, name, &cli.record_type, &result));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("dnsr: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the daemon `args` configure, which only returns on failure.
+async fn serve(args: &ServeArgs) -> dns_resolver_rs::error::Result<()> {
+    let daemon = Daemon::bind(&args.daemon_config()?).await?;
+    eprintln!("dnsr: listening on {}", daemon.local_addr()?);
+    if let Some(addr) = daemon.tls_addr() {
+        eprintln!("dnsr: listening for DNS over TLS on {}", addr?);
+    }
+    if let Some(addr) = daemon.quic_addr() {
+        eprintln!("dnsr: listening for DNS over QUIC on {}", addr?);
+    }
+    if let Some(addr) = daemon.api_addr() {
+        eprintln!("dnsr: serving the HTTP API on {}", addr?);
+    }
+    Ok(daemon.run().await?)
+}
+
+/// The file at `path`, or stdin for `-`.
+fn open_input(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if path == Path::new("-") {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
@@ -0,0 +1,115 @@
+//! A synchronous wrapper around the async resolver, for programs that do not
+//! run a tokio runtime of their own.
+//!
+//! Each [`DNSResolver`] owns a single-threaded runtime that drives its
+//! lookups. Its methods block the calling thread, and panic if called from
+//! within an async context.
+
+use crate::config::QueryOptions;
+use crate::error::Result;
+use crate::transport::{DnsTransport, UdpTransport};
+use crate::{DNSPacket, DNSRecord, LookupResult, MailExchanger, Mx, RecordType};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Debug)]
+pub struct DNSResolver<T = UdpTransport> {
+    resolver: crate::DNSResolver<T>,
+    runtime: Runtime,
+}
+
+impl DNSResolver {
+    /// A resolver with the default configuration.
+    pub fn new() -> Result<Self> {
+        Self::from_async(crate::DNSResolver::default())
+    }
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// Wraps a resolver configured with
+    /// [`DNSResolverBuilder`](crate::config::DNSResolverBuilder).
+    pub fn from_async(resolver: crate::DNSResolver<T>) -> Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(DNSResolver { resolver, runtime })
+    }
+
+    pub fn resolve(&self, domain_name: &str) -> Result<Ipv4Addr> {
+        self.runtime.block_on(self.resolver.resolve(domain_name))
+    }
+
+    pub fn reverse_resolve(&self, ip_addr: &Ipv4Addr) -> Result<String> {
+        self.runtime
+            .block_on(self.resolver.reverse_resolve(ip_addr))
+    }
+
+    pub fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        self.runtime.block_on(self.resolver.lookup_ip(host))
+    }
+
+    pub fn lookup_mx(&self, domain: &str) -> Result<Vec<Mx>> {
+        self.runtime.block_on(self.resolver.lookup_mx(domain))
+    }
+
+    pub fn lookup_mx_addresses(&self, domain: &str) -> Result<Vec<MailExchanger>> {
+        self.runtime
+            .block_on(self.resolver.lookup_mx_addresses(domain))
+    }
+
+    pub fn resolve_mail_hosts(&self, domain: &str) -> Result<Vec<MailExchanger>> {
+        self.runtime
+            .block_on(self.resolver.resolve_mail_hosts(domain))
+    }
+
+    pub fn resolve_service(
+        &self,
+        service: &str,
+        proto: &str,
+        domain: &str,
+    ) -> Result<Vec<SocketAddr>> {
+        self.runtime
+            .block_on(self.resolver.resolve_service(service, proto, domain))
+    }
+
+    pub fn resolve_record(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+    ) -> Result<Vec<DNSRecord>> {
+        self.runtime
+            .block_on(self.resolver.resolve_record(domain_name, record_type))
+    }
+
+    pub fn resolve_record_with(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<Vec<DNSRecord>> {
+        self.runtime.block_on(
+            self.resolver
+                .resolve_record_with(domain_name, record_type, options),
+        )
+    }
+
+    pub fn resolve_detailed(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<LookupResult> {
+        self.runtime.block_on(
+            self.resolver
+                .resolve_detailed(domain_name, record_type, options),
+        )
+    }
+
+    pub fn lookup(
+        &self,
+        domain_name: &str,
+        ip_addr: &Ipv4Addr,
+        record_type: RecordType,
+    ) -> Result<DNSPacket> {
+        self.runtime
+            .block_on(self.resolver.lookup(domain_name, ip_addr, record_type))
+    }
+}
@@ -0,0 +1,365 @@
+//! Blocking ads and malware by name, as Pi-hole does: the daemon subscribes
+//! to blocklists, downloads them on a schedule and answers NXDOMAIN for
+//! every name they list and every name under those.
+//!
+//! Clients can be put in groups by network, each with blocklists of its own
+//! and names it always or never gets NXDOMAIN for, as [`ClientPolicies`]
+//! describes.
+//!
+//! A blocklist is a hosts file mapping names to a null address, a plain list
+//! of domains one per line, or Adblock Plus rules of the `||domain^` form;
+//! other rules are skipped. `#` and `!` start comments.
+
+use crate::daemon::ClientGroup;
+use crate::idna;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Names hosts files map to themselves rather than block.
+const LOCAL_NAMES: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+/// A set of domains matched with everything under them, looked up by
+/// hashing each suffix of a name in turn.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainSet {
+    domains: HashSet<String>,
+}
+
+impl DomainSet {
+    /// Parses a blocklist in any of the forms the module describes.
+    pub fn parse(text: &str) -> Self {
+        let mut set = DomainSet::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+            if first.parse::<IpAddr>().is_ok() {
+                fields.for_each(|name| set.insert(name));
+            } else if let Some(rule) = first.strip_prefix("||") {
+                if let Some(domain) = rule.strip_suffix('^') {
+                    set.insert(domain);
+                }
+            } else {
+                set.insert(first);
+            }
+        }
+        set
+    }
+
+    /// Adds `domain`, unless it is a local name or not a domain at all.
+    pub fn insert(&mut self, domain: &str) {
+        let domain = normalize(domain);
+        let valid = domain.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+        if valid && !LOCAL_NAMES.contains(&domain.as_str()) && domain.parse::<IpAddr>().is_err() {
+            self.domains.insert(domain);
+        }
+    }
+
+    /// Whether `name` or any domain it is under is in the set.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+        let mut suffix = name.as_str();
+        loop {
+            if self.domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    fn extend(&mut self, other: &DomainSet) {
+        self.domains.extend(other.domains.iter().cloned());
+    }
+}
+
+/// Lowercase ASCII, without a trailing dot.
+fn normalize(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    idna::to_ascii(name)
+        .unwrap_or_else(|_| name.to_string())
+        .to_ascii_lowercase()
+}
+
+/// How the last download of one blocklist went, for `/api/blocklists`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlocklistStatus {
+    /// The group of clients the list applies to, or none for the lists
+    /// applying to every client outside the groups.
+    pub group: Option<String>,
+    /// The URL or local path the list is read from.
+    pub source: String,
+    /// How many domains the list currently in use blocks.
+    pub domains: usize,
+    /// When the list was last downloaded successfully, in seconds since the
+    /// Unix epoch.
+    pub updated_at: Option<u64>,
+    /// Why the last download failed, if it did.
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Blocklist {
+    status: BlocklistStatus,
+    domains: DomainSet,
+}
+
+/// The blocklists a daemon subscribes to, compiled together into one
+/// [`DomainSet`]. Clones share the lists, so one can be refreshed while
+/// others match queries against them.
+#[derive(Debug, Clone)]
+pub struct Blocklists {
+    lists: Arc<RwLock<Vec<Blocklist>>>,
+    matcher: Arc<RwLock<DomainSet>>,
+    client: reqwest::Client,
+}
+
+impl Default for Blocklists {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Blocklists {
+    /// Subscribes to `sources`, each an `http` or `https` URL or a local
+    /// path. Nothing is blocked until the first [`refresh`](Self::refresh).
+    pub fn new(sources: impl IntoIterator<Item = String>) -> Self {
+        Self::for_group(None, sources)
+    }
+
+    fn for_group(group: Option<&str>, sources: impl IntoIterator<Item = String>) -> Self {
+        let lists = sources
+            .into_iter()
+            .map(|source| Blocklist {
+                status: BlocklistStatus {
+                    group: group.map(str::to_string),
+                    source,
+                    domains: 0,
+                    updated_at: None,
+                    last_error: None,
+                },
+                domains: DomainSet::default(),
+            })
+            .collect();
+        Blocklists {
+            lists: Arc::new(RwLock::new(lists)),
+            matcher: Arc::default(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether any blocklist covers `name`.
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.matcher
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(name)
+    }
+
+    /// Downloads every blocklist again and recompiles the matcher. A list
+    /// that fails to download keeps blocking what it did before.
+    pub async fn refresh(&self) {
+        let sources: Vec<String> = self
+            .status()
+            .into_iter()
+            .map(|status| status.source)
+            .collect();
+        let mut downloads = Vec::with_capacity(sources.len());
+        for source in &sources {
+            downloads.push(self.download(source).await);
+        }
+        let mut lists = self.lists.write().unwrap_or_else(|e| e.into_inner());
+        for (list, download) in lists.iter_mut().zip(downloads) {
+            match download {
+                Ok(domains) => {
+                    info!(source = %list.status.source, domains = domains.len(), "Blocklist updated");
+                    list.status.domains = domains.len();
+                    list.status.updated_at = Some(unix_now());
+                    list.status.last_error = None;
+                    list.domains = domains;
+                }
+                Err(e) => {
+                    warn!(source = %list.status.source, error = %e, "Blocklist download failed");
+                    list.status.last_error = Some(e);
+                }
+            }
+        }
+        let mut matcher = DomainSet::default();
+        lists.iter().for_each(|list| matcher.extend(&list.domains));
+        *self.matcher.write().unwrap_or_else(|e| e.into_inner()) = matcher;
+    }
+
+    /// The state of each blocklist, in the order they were subscribed to.
+    pub fn status(&self) -> Vec<BlocklistStatus> {
+        self.lists
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|list| list.status.clone())
+            .collect()
+    }
+
+    async fn download(&self, source: &str) -> Result<DomainSet, String> {
+        let text = if source.starts_with("http://") || source.starts_with("https://") {
+            let response = self
+                .client
+                .get(source)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())?
+        } else {
+            let path = source.to_string();
+            tokio::task::spawn_blocking(move || std::fs::read_to_string(path))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?
+        };
+        Ok(DomainSet::parse(&text))
+    }
+}
+
+/// The blocking of one group of clients, as a `[[groups]]` table of a
+/// [`DaemonConfig`](crate::daemon::DaemonConfig) configures it.
+#[derive(Debug, Clone)]
+pub struct ClientPolicy {
+    group: ClientGroup,
+    blocklists: Blocklists,
+    allow: DomainSet,
+    deny: DomainSet,
+}
+
+impl ClientPolicy {
+    pub fn new(group: ClientGroup) -> Self {
+        let mut allow = DomainSet::default();
+        group.allow.iter().for_each(|name| allow.insert(name));
+        let mut deny = DomainSet::default();
+        group.deny.iter().for_each(|name| deny.insert(name));
+        ClientPolicy {
+            blocklists: Blocklists::for_group(Some(&group.name), group.blocklists.clone()),
+            group,
+            allow,
+            deny,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.group.name
+    }
+
+    /// Whether the group gets NXDOMAIN for `name`, where `default` holds the
+    /// top-level blocklists. The allowlist wins over everything else.
+    fn is_blocked(&self, default: &Blocklists, name: &str) -> bool {
+        !self.allow.contains(name)
+            && (self.deny.contains(name)
+                || self.blocklists.is_blocked(name)
+                || (self.group.default_blocklists && default.is_blocked(name)))
+    }
+}
+
+/// Who gets NXDOMAIN for what: the top-level blocklists apply to every
+/// client outside the groups, and each group's policy to the clients in its
+/// networks. A client in the networks of several groups belongs to the
+/// first. Clones share the blocklists.
+#[derive(Debug, Clone, Default)]
+pub struct ClientPolicies {
+    blocklists: Blocklists,
+    groups: Arc<[ClientPolicy]>,
+}
+
+impl ClientPolicies {
+    pub fn new(blocklists: Blocklists, groups: impl IntoIterator<Item = ClientPolicy>) -> Self {
+        ClientPolicies {
+            blocklists,
+            groups: groups.into_iter().collect(),
+        }
+    }
+
+    /// The policy of the group `client` is in, if any.
+    pub fn group(&self, client: IpAddr) -> Option<&ClientPolicy> {
+        self.groups.iter().find(|policy| {
+            policy
+                .group
+                .clients
+                .iter()
+                .any(|network| network.contains(client))
+        })
+    }
+
+    /// Whether `client` gets NXDOMAIN for `name`.
+    pub fn is_blocked(&self, client: IpAddr, name: &str) -> bool {
+        match self.group(client) {
+            Some(policy) => policy.is_blocked(&self.blocklists, name),
+            None => self.blocklists.is_blocked(name),
+        }
+    }
+
+    /// Downloads every blocklist again, the top-level ones first.
+    pub async fn refresh(&self) {
+        self.blocklists.refresh().await;
+        for policy in self.groups.iter() {
+            policy.blocklists.refresh().await;
+        }
+    }
+
+    /// Refreshes the blocklists now and then every `interval`, in a
+    /// background task.
+    pub fn spawn_refresh(&self, interval: Duration) -> JoinHandle<()> {
+        let policies = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                policies.refresh().await;
+            }
+        })
+    }
+
+    /// The state of every blocklist, the top-level ones first and then
+    /// those of each group in turn.
+    pub fn status(&self) -> Vec<BlocklistStatus> {
+        let mut status = self.blocklists.status();
+        for policy in self.groups.iter() {
+            status.extend(policy.blocklists.status());
+        }
+        status
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
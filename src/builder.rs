@@ -0,0 +1,230 @@
+use crate::edns::Edns;
+use crate::error::{DnsError, Result};
+use crate::flags::{DNSFlags, Rcode};
+use crate::idna;
+use crate::{
+    Class, DNSHeader, DNSPacket, DNSQuestion, DNSRecord, DNSRecordData, RecordType, check_name,
+};
+
+const MAX_RCODE: u8 = 0b1111;
+const MAX_OPCODE: u8 = 0b1111;
+
+/// Assembles a query or response packet section by section, checking it for
+/// consistency before handing back a [`DNSPacket`] ready for `to_bytes`.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    header: DNSHeader,
+    questions: Vec<DNSQuestion>,
+    answers: Vec<DNSRecord>,
+    authorities: Vec<DNSRecord>,
+    additionals: Vec<DNSRecord>,
+    edns: Option<Edns>,
+}
+
+impl MessageBuilder {
+    /// Starts a query with a random ID and all flags cleared.
+    pub fn query() -> Self {
+        MessageBuilder {
+            header: DNSHeader::new(DNSFlags::default(), 0),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        }
+    }
+
+    /// Starts a response to `query`, echoing its ID, opcode, questions and RD flag.
+    pub fn response_to(query: &DNSPacket) -> Self {
+        let mut builder = Self::query().id(query.header.id).flags(DNSFlags {
+            qr: true,
+            opcode: query.header.flags.opcode,
+            rd: query.header.flags.rd,
+            ..DNSFlags::default()
+        });
+        builder.questions = query.questions.clone();
+        builder
+    }
+
+    /// A response to `query` with `flags` and nothing else, not even the
+    /// question, for when that cannot be echoed, as when a label holds a
+    /// dot. Unlike [`build`](Self::build), this cannot fail.
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    pub(crate) fn bare_response(query: &DNSPacket, flags: DNSFlags) -> DNSPacket {
+        DNSPacket {
+            header: DNSHeader {
+                id: query.header.id,
+                ..DNSHeader::new(DNSFlags { qr: true, ..flags }, 0)
+            },
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.header.id = id;
+        self
+    }
+
+    /// Replaces all header flags, including the opcode and RCODE.
+    pub fn flags(mut self, flags: DNSFlags) -> Self {
+        self.header.flags = flags;
+        self
+    }
+
+    pub fn rcode(mut self, rcode: Rcode) -> Self {
+        self.header.flags.rcode = rcode;
+        self
+    }
+
+    pub fn question(mut self, name: &str, type_: RecordType, class: Class) -> Self {
+        self.questions
+            .push(DNSQuestion::new(name.to_string(), type_, class));
+        self
+    }
+
+    pub fn answer(mut self, record: DNSRecord) -> Self {
+        self.answers.push(record);
+        self
+    }
+
+    pub fn authority(mut self, record: DNSRecord) -> Self {
+        self.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: DNSRecord) -> Self {
+        self.additionals.push(record);
+        self
+    }
+
+    /// Attaches an OPT record to the additional section.
+    pub fn edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    /// Validates the message and converts any internationalized names to their
+    /// A-label form. Names breaking the size limits fail with a
+    /// [`NameError`](crate::error::NameError).
+    pub fn build(mut self) -> Result<DNSPacket> {
+        let flags = self.header.flags;
+        if u8::from(flags.rcode) > MAX_RCODE {
+            return Err(DnsError::InvalidMessage(format!(
+                "RCODE {:?} does not fit in the header",
+                flags.rcode
+            )));
+        }
+        if u8::from(flags.opcode) > MAX_OPCODE {
+            return Err(DnsError::InvalidMessage(format!(
+                "Opcode {:?} does not fit in the header",
+                flags.opcode
+            )));
+        }
+        if !flags.qr && self.questions.is_empty() {
+            return Err(DnsError::InvalidMessage(
+                "A query must carry at least one question".to_string(),
+            ));
+        }
+        // The OPT record is counted among the additionals.
+        let num_additionals = self.additionals.len() + usize::from(self.edns.is_some());
+        for len in [self.answers.len(), self.authorities.len(), num_additionals] {
+            if len > u16::MAX as usize {
+                return Err(DnsError::InvalidMessage(format!(
+                    "Too many records in a section: {len}"
+                )));
+            }
+        }
+        if self.questions.len() > u16::MAX as usize {
+            return Err(DnsError::InvalidMessage(format!(
+                "Too many questions: {}",
+                self.questions.len()
+            )));
+        }
+        for question in &mut self.questions {
+            question.name = wire_name(&question.name)?;
+        }
+        for record in self
+            .answers
+            .iter_mut()
+            .chain(&mut self.authorities)
+            .chain(&mut self.additionals)
+        {
+            prepare_record(record)?;
+        }
+        let header = DNSHeader {
+            num_questions: self.questions.len() as u16,
+            num_answers: self.answers.len() as u16,
+            num_authorities: self.authorities.len() as u16,
+            num_additionals: num_additionals as u16,
+            ..self.header
+        };
+        Ok(DNSPacket {
+            header,
+            questions: self.questions,
+            answers: self.answers,
+            authorities: self.authorities,
+            additionals: self.additionals,
+            edns: self.edns,
+        })
+    }
+}
+
+/// Returns the A-label form of `name` after checking its labels are well formed.
+fn wire_name(name: &str) -> Result<String> {
+    let ascii = idna::to_ascii(name)?;
+    check_name(&ascii)?;
+    Ok(ascii)
+}
+
+fn prepare_record(record: &mut DNSRecord) -> Result<()> {
+    record.name = wire_name(&record.name)?;
+    let matches = match &mut record.data {
+        DNSRecordData::Ipv4Addr(_) => record.type_ == RecordType::A,
+        DNSRecordData::Ipv6Addr(_) => record.type_ == RecordType::Aaaa,
+        DNSRecordData::Name(name) => {
+            *name = wire_name(name)?;
+            matches!(
+                record.type_,
+                RecordType::Ns | RecordType::Cname | RecordType::Ptr
+            )
+        }
+        DNSRecordData::Soa(soa) => {
+            soa.mname = wire_name(&soa.mname)?;
+            soa.rname = wire_name(&soa.rname)?;
+            record.type_ == RecordType::Soa
+        }
+        DNSRecordData::Mx(mx) => {
+            mx.exchange = wire_name(&mx.exchange)?;
+            record.type_ == RecordType::Mx
+        }
+        DNSRecordData::Srv(srv) => {
+            srv.target = wire_name(&srv.target)?;
+            record.type_ == RecordType::Srv
+        }
+        DNSRecordData::Naptr(naptr) => {
+            naptr.replacement = wire_name(&naptr.replacement)?;
+            record.type_ == RecordType::Naptr
+                && [&naptr.flags, &naptr.services, &naptr.regexp]
+                    .iter()
+                    .all(|string| string.len() <= 255)
+        }
+        DNSRecordData::Txt(strings) => {
+            record.type_ == RecordType::Txt && strings.iter().all(|string| string.len() <= 255)
+        }
+        // OPT is not a real record; it goes through `edns` instead.
+        DNSRecordData::Data(data) => {
+            record.type_ != RecordType::Opt && data.len() <= u16::MAX as usize
+        }
+    };
+    if !matches {
+        return Err(DnsError::InvalidMessage(format!(
+            "Record data for {} does not match type {:?}",
+            record.name, record.type_
+        )));
+    }
+    Ok(())
+}
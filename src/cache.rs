@@ -1,16 +1,158 @@
+use crate::resolver::NegativeKind;
+use crate::{DNSRecord, NameCompressor, RecordType};
+use moka::Expiry;
 use moka::future::{Cache, CacheBuilder};
 use std::net::Ipv4Addr;
-use std::sync::LazyLock;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+/// Memory budget of each cache, measured in wire-format bytes of the cached records.
+pub const MAX_CACHE_BYTES: u64 = 1024 * 1024;
+
+/// Wire size of a resource record: encoded owner name, the fixed
+/// type/class/ttl/rdlength fields and the rdata itself.
+pub fn record_weight(name: &str, rdata_len: usize) -> u32 {
+    let encoded_name_len = name.len() + 2;
+    (encoded_name_len + 10 + rdata_len)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
 
 pub static DOMAIN_TO_IP_CACHE: LazyLock<Cache<String, Ipv4Addr>> = LazyLock::new(|| {
-    CacheBuilder::new(1000)
+    CacheBuilder::new(MAX_CACHE_BYTES)
+        .weigher(|domain: &String, _ip: &Ipv4Addr| record_weight(domain, 4))
         .time_to_live(Duration::from_secs(60 * 60))
         .build()
 });
 
 pub static IP_TO_DOMAIN_CACHE: LazyLock<Cache<Ipv4Addr, String>> = LazyLock::new(|| {
-    CacheBuilder::new(1000)
+    CacheBuilder::new(MAX_CACHE_BYTES)
+        // Owner names are in-addr.arpa names, weighed at their longest form.
+        .weigher(|_ip: &Ipv4Addr, domain: &String| {
+            record_weight("255.255.255.255.in-addr.arpa", domain.len() + 2)
+        })
         .time_to_live(Duration::from_secs(60 * 60))
         .build()
 });
+
+/// Longest time a record set is cached, whatever its TTL.
+pub const MAX_RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// An answer's records of one type, remembered for their smallest TTL.
+#[derive(Debug, Clone)]
+pub struct CachedRecords {
+    pub records: Vec<DNSRecord>,
+    pub ttl: Duration,
+    stored_at: Instant,
+    /// Set once the records are served from the cache, so that only sets
+    /// someone is asking for get prefetched.
+    used: Arc<AtomicBool>,
+}
+
+impl CachedRecords {
+    pub fn new(records: Vec<DNSRecord>) -> Self {
+        let min_ttl = records.iter().map(|r| r.ttl).min().unwrap_or(0);
+        CachedRecords {
+            records,
+            ttl: Duration::from_secs(min_ttl.into()).min(MAX_RECORD_TTL),
+            stored_at: Instant::now(),
+            used: Arc::default(),
+        }
+    }
+
+    /// Wire size of the records, uncompressed, as the cache weighs them.
+    pub fn weight(&self) -> u32 {
+        self.records
+            .iter()
+            .map(|record| {
+                let mut rdata = Vec::new();
+                record
+                    .data
+                    .write(&mut rdata, &mut NameCompressor::default());
+                record_weight(&record.name, rdata.len())
+            })
+            .fold(0, u32::saturating_add)
+    }
+
+    pub fn mark_used(&self) {
+        self.used.store(true, Ordering::Relaxed);
+    }
+
+    pub fn was_used(&self) -> bool {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// How long until the records expire from the cache.
+    pub fn expires_in(&self) -> Duration {
+        self.ttl.saturating_sub(self.stored_at.elapsed())
+    }
+
+    /// The records with their TTLs counted down by the time spent in the cache.
+    pub fn remaining(self) -> Vec<DNSRecord> {
+        let elapsed = u32::try_from(self.stored_at.elapsed().as_secs()).unwrap_or(u32::MAX);
+        self.records
+            .into_iter()
+            .map(|record| DNSRecord {
+                ttl: record.ttl.saturating_sub(elapsed),
+                ..record
+            })
+            .collect()
+    }
+}
+
+struct RecordExpiry;
+
+impl Expiry<(String, RecordType), CachedRecords> for RecordExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &(String, RecordType),
+        value: &CachedRecords,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// Record sets of any type keyed by queried name and type, as returned by
+/// `resolve_record`.
+pub static RECORD_CACHE: LazyLock<Cache<(String, RecordType), CachedRecords>> =
+    LazyLock::new(|| {
+        CacheBuilder::new(MAX_CACHE_BYTES)
+            .weigher(|_key: &(String, RecordType), value: &CachedRecords| value.weight())
+            .expire_after(RecordExpiry)
+            .build()
+    });
+
+#[derive(Debug, Clone)]
+pub struct NegativeEntry {
+    pub kind: NegativeKind,
+    pub ttl: Duration,
+}
+
+struct NegativeExpiry;
+
+impl Expiry<(String, RecordType), NegativeEntry> for NegativeExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &(String, RecordType),
+        value: &NegativeEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// NXDOMAIN/NODATA answers keyed by queried name and type, kept apart from the
+/// positive caches so a failure never evicts or shadows a good answer.
+pub static NEGATIVE_CACHE: LazyLock<Cache<(String, RecordType), NegativeEntry>> =
+    LazyLock::new(|| {
+        CacheBuilder::new(MAX_CACHE_BYTES)
+            .weigher(
+                |(name, _type): &(String, RecordType), _entry: &NegativeEntry| {
+                    record_weight(name, 0)
+                },
+            )
+            .expire_after(NegativeExpiry)
+            .build()
+    });
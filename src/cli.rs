@@ -0,0 +1,1524 @@
+//! The `dnsr` command line tool: its arguments and how it prints results, kept
+//! in the library so that both can be tested.
+
+use crate::config::{CachePolicy, QueryOptions};
+use crate::daemon::DaemonConfig;
+use crate::delegation::{Delegation, ServerOutcome};
+use crate::dnssec::{ChainLink, DnssecChain, LinkStatus, algorithm_name};
+use crate::error::{DnsError, Result};
+use crate::flags::Rcode;
+use crate::propagation::{PropagatedAnswer, Propagation, ResolverAnswer};
+use crate::transport::{DNS_PORT, DnsTransport};
+use crate::{
+    Class, DNSQuestion, DNSRecord, DNSResolver, HopOutcome, LookupResult, RecordType, Trace,
+    TraceHop,
+};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::io::{self, BufRead};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+
+/// Resolves a name iteratively, starting from the root servers.
+#[derive(Debug, Parser)]
+#[command(
+    name = "dnsr",
+    version,
+    subcommand_negates_reqs = true,
+    override_usage = "dnsr [OPTIONS] [@SERVER[:PORT]] <NAME> [RECORD_TYPE]\n       \
+                      dnsr [OPTIONS] --file <PATH>\n       \
+                      dnsr [OPTIONS] <COMMAND>"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The name to look up.
+    #[arg(required_unless_present = "file")]
+    pub name: Option<String>,
+    /// The record type to ask for, such as AAAA or TYPE28.
+    #[arg(default_value = "A")]
+    pub record_type: RecordType,
+    /// How to print the result.
+    #[arg(short, long, value_enum, default_value_t, global = true)]
+    pub output: OutputFormat,
+    /// A server to start from instead of the root servers, on port 53 unless
+    /// given. May be repeated, and written `@IP[:PORT]` as for dig.
+    #[arg(long = "server", value_name = "IP[:PORT]", value_parser = parse_server, global = true)]
+    pub servers: Vec<SocketAddrV4>,
+    /// Print every server asked on the way from the root, with the referrals
+    /// and glue they gave and how long each took, like `dig +trace`.
+    #[arg(long, conflicts_with = "file")]
+    pub trace: bool,
+    /// Ask the answering server for its identifier (NSID), to tell which
+    /// node behind an anycast address answered.
+    #[arg(long)]
+    pub nsid: bool,
+    /// Set the DO bit and print the RRSIG records over the answer, unvalidated.
+    #[arg(long)]
+    pub dnssec: bool,
+    /// Resolve every name in this file, or in stdin if `-`: one per line,
+    /// optionally followed by a record type. Blank lines and lines starting
+    /// with `#` are skipped.
+    #[arg(long, value_name = "PATH", conflicts_with = "name")]
+    pub file: Option<PathBuf>,
+    /// How many names of `--file` to resolve at once.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Resolve a name again and again, showing when its records or their
+    /// TTLs change, such as while a migration propagates.
+    Watch(WatchArgs),
+    /// Ask the well-known public resolvers for a name at once and show
+    /// whether their answers agree.
+    Propagation(PropagationArgs),
+    /// Walk the DNSSEC chain of trust from the root down to a name, showing
+    /// which links validate, the algorithms used and where the chain breaks.
+    Dnssec(DnssecArgs),
+    /// Check a zone's delegation: that its parent and its own servers list
+    /// the same nameservers, that those have glue, answer authoritatively and
+    /// agree on the SOA serial.
+    CheckDelegation(CheckDelegationArgs),
+    /// Send queries at a steady rate for a while and report latency
+    /// percentiles and error rates. With `--server`, queries go straight to
+    /// the given servers with recursion desired; otherwise each is a full
+    /// resolution by this resolver.
+    Bench(BenchArgs),
+    /// Look up the PTR record of every address in a network, such as
+    /// 192.0.2.0/28, for inventory audits.
+    Ptr(PtrArgs),
+    /// Run a caching recursive resolver answering DNS queries over UDP and
+    /// TCP, until interrupted.
+    Serve(ServeArgs),
+    /// Print the completion script for a shell, to source or to install where
+    /// the shell looks for completions.
+    Completions(CompletionsArgs),
+    /// Print the man page in roff, to view with `man -l -` or install as
+    /// dnsr.1.
+    Man,
+}
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    pub shell: Shell,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// The TOML file to read the configuration from. Every setting has a
+    /// default, so it may be left out.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// The address to listen on, overriding the configuration's.
+    #[arg(long, value_name = "IP:PORT")]
+    pub listen: Option<SocketAddr>,
+}
+
+impl ServeArgs {
+    /// The configuration asked for: that of the file, if any, with
+    /// `--listen` applied.
+    pub fn daemon_config(&self) -> Result<DaemonConfig> {
+        let mut config = match &self.config {
+            Some(path) => DaemonConfig::load(path)?,
+            None => DaemonConfig::default(),
+        };
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PtrArgs {
+    /// The network to sweep, in CIDR notation; a bare address is a /32.
+    #[arg(value_parser = parse_network)]
+    pub network: Ipv4Network,
+    /// How many addresses to look up at once.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+}
+
+/// An IPv4 network: an address with the length of its prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Network {
+    /// The first address, with the host bits clear.
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Network {
+    /// Every address in the network, the network and broadcast addresses
+    /// included.
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.address);
+        let last = first | u32::MAX.checked_shr(self.prefix_len.into()).unwrap_or(0);
+        (first..=last).map(Ipv4Addr::from)
+    }
+}
+
+/// Shorter prefixes would sweep more than 65536 addresses.
+const MIN_SWEEP_PREFIX_LEN: u8 = 16;
+
+/// Parses a network such as `192.0.2.0/24`, clearing any host bits, or a bare
+/// address as a /32. Prefixes shorter than /16 are refused.
+pub fn parse_network(s: &str) -> std::result::Result<Ipv4Network, String> {
+    let (address, prefix_len) = s.split_once('/').unwrap_or((s, "32"));
+    let address: Ipv4Addr = address
+        .parse()
+        .map_err(|_| format!("Invalid IPv4 address {address}"))?;
+    let prefix_len: u8 = prefix_len
+        .parse()
+        .ok()
+        .filter(|len| *len <= 32)
+        .ok_or_else(|| format!("Invalid prefix length {prefix_len}"))?;
+    if prefix_len < MIN_SWEEP_PREFIX_LEN {
+        return Err(format!(
+            "A /{prefix_len} is too large to sweep, the limit is /{MIN_SWEEP_PREFIX_LEN}"
+        ));
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    Ok(Ipv4Network {
+        address: Ipv4Addr::from(u32::from(address) & mask),
+        prefix_len,
+    })
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// The names to query, one per line and optionally followed by a record
+    /// type, as for `--file`, or `-` for stdin. They are used in turn, over
+    /// and over.
+    #[arg(long, value_name = "PATH")]
+    pub targets: PathBuf,
+    /// How many queries to send per second.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    pub qps: u32,
+    /// How long to send queries for, such as 30s or 5m.
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub duration: Duration,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckDelegationArgs {
+    /// The zone to check.
+    pub zone: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DnssecArgs {
+    /// The name to look up.
+    pub name: String,
+    /// The record type to ask for.
+    #[arg(default_value = "A")]
+    pub record_type: RecordType,
+}
+
+#[derive(Debug, Args)]
+pub struct PropagationArgs {
+    /// The name to look up.
+    pub name: String,
+    /// The record type to ask for.
+    #[arg(default_value = "A")]
+    pub record_type: RecordType,
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// The name to watch.
+    pub name: String,
+    /// The record type to ask for.
+    #[arg(default_value = "A")]
+    pub record_type: RecordType,
+    /// How long to wait between lookups, such as 30s, 5m or 1h.
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    pub interval: Duration,
+    /// Stop after this many lookups instead of running until interrupted.
+    #[arg(long)]
+    pub count: Option<u32>,
+}
+
+/// Parses a duration given in whole milliseconds, seconds, minutes or hours,
+/// as in `500ms`, `30s`, `5m` or `1h`. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration {s}"))?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number * 60),
+        "h" => Duration::from_secs(number * 3600),
+        _ => {
+            return Err(format!(
+                "Invalid duration {s}, expected a unit of ms, s, m or h"
+            ));
+        }
+    };
+    if duration.is_zero() {
+        return Err("The duration must not be zero".to_string());
+    }
+    Ok(duration)
+}
+
+/// Parses a `--server` of `IP` or `IP:PORT`.
+pub fn parse_server(s: &str) -> std::result::Result<SocketAddrV4, String> {
+    if let Ok(address) = s.parse() {
+        return Ok(SocketAddrV4::new(address, DNS_PORT));
+    }
+    s.parse()
+        .map_err(|_| format!("Invalid server {s}, expected an IPv4 address and optional port"))
+}
+
+/// Rewrites dig's `@server` and `+option` arguments as `--server` and
+/// `--option`, to be parsed by [`Cli`].
+pub fn expand_dig_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    args.into_iter()
+        .map(|arg| match arg.to_str() {
+            Some(arg) if arg.starts_with('@') => format!("--server={}", &arg[1..]).into(),
+            Some(arg) if arg.len() > 1 && arg.starts_with('+') => format!("--{}", &arg[1..]).into(),
+            _ => arg,
+        })
+        .collect()
+}
+
+impl Cli {
+    /// A resolver starting from the servers asked for, if any.
+    pub fn resolver(&self) -> Result<DNSResolver> {
+        let mut builder = DNSResolver::builder();
+        if !self.servers.is_empty() {
+            builder = builder.upstreams(self.servers.iter().map(|server| *server.ip()));
+        }
+        for server in &self.servers {
+            builder = builder.upstream_port(*server.ip(), server.port());
+        }
+        builder.build()
+    }
+
+    /// The per-lookup options asked for.
+    pub fn query_options(&self) -> QueryOptions {
+        QueryOptions {
+            nsid: self.nsid.then_some(true),
+            dnssec_ok: self.dnssec.then_some(true),
+            ..QueryOptions::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// The question and answer sections as dig prints them, for people and
+    /// tools that read dig's output.
+    #[default]
+    Dig,
+    /// Only the rdata, one record per line, like `dig +short`.
+    Short,
+    /// A JSON object, for scripts and jq.
+    Json,
+    /// The same fields as JSON, in YAML.
+    Yaml,
+    /// One row per record under a header line, for spreadsheets.
+    Csv,
+}
+
+/// The fields printed in JSON and YAML.
+#[derive(Serialize)]
+struct Lookup<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    answers: Vec<Answer<'a>>,
+    /// `None` for answers from the cache.
+    server: Option<Ipv4Addr>,
+    from_cache: bool,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nsid: Option<String>,
+    /// Why the lookup failed, in bulk resolution.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> Lookup<'a> {
+    fn new(
+        name: &'a str,
+        record_type: &'a RecordType,
+        result: &'a Result<LookupResult>,
+        elapsed: Duration,
+    ) -> Self {
+        let (answers, server, from_cache, nsid) = match result {
+            Ok(result) => (
+                Answer::list(answer_section(result)),
+                result.server,
+                result.from_cache,
+                result.nsid.as_deref().map(nsid_text),
+            ),
+            Err(_) => (Vec::new(), None, false, None),
+        };
+        Lookup {
+            name,
+            record_type,
+            answers,
+            server,
+            from_cache,
+            elapsed_ms: elapsed.as_millis() as u64,
+            nsid,
+            error: result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Answer<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    class: &'a Class,
+    ttl: u32,
+    /// The rdata in presentation format.
+    data: String,
+}
+
+impl<'a> Answer<'a> {
+    fn list(records: impl IntoIterator<Item = &'a DNSRecord>) -> Vec<Self> {
+        records
+            .into_iter()
+            .map(|record| Answer {
+                name: record.name(),
+                record_type: record.record_type(),
+                class: record.class(),
+                ttl: record.ttl(),
+                data: record.data().to_string(),
+            })
+            .collect()
+    }
+}
+
+/// The fields printed in JSON and YAML for a trace.
+#[derive(Serialize)]
+struct TraceOutput<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    hops: Vec<Hop<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answers: Option<Vec<Answer<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Hop<'a> {
+    server: Ipv4Addr,
+    /// The name asked for, which changes as CNAMEs are followed.
+    name: &'a str,
+    /// One of answer, cname, referral, nxdomain, nodata or failed.
+    outcome: &'static str,
+    /// The zone of a referral, the target of a CNAME, or why the server failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    nameservers: Vec<&'a str>,
+    /// Addresses of the nameservers that came with a referral.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    glue: Vec<Answer<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rcode: Option<String>,
+    rtt_ms: u64,
+}
+
+impl<'a> Hop<'a> {
+    fn new(hop: &'a TraceHop) -> Self {
+        let (outcome, detail, nameservers) = match &hop.outcome {
+            HopOutcome::Answer => ("answer", None, Vec::new()),
+            HopOutcome::Cname(target) => ("cname", Some(target.as_str()), Vec::new()),
+            HopOutcome::Referral { zone, nameservers } => (
+                "referral",
+                Some(zone.as_str()),
+                nameservers.iter().map(String::as_str).collect(),
+            ),
+            HopOutcome::NxDomain => ("nxdomain", None, Vec::new()),
+            HopOutcome::NoData => ("nodata", None, Vec::new()),
+            HopOutcome::Failed(error) => ("failed", Some(error.as_str()), Vec::new()),
+        };
+        Hop {
+            server: hop.server,
+            name: &hop.question.name,
+            outcome,
+            detail,
+            nameservers,
+            glue: Answer::list(glue(hop)),
+            rcode: hop.rcode.map(|rcode| rcode.to_string()),
+            rtt_ms: hop.rtt.as_millis() as u64,
+        }
+    }
+}
+
+/// The addresses of a referral's nameservers carried in its additional section.
+fn glue(hop: &TraceHop) -> impl Iterator<Item = &DNSRecord> {
+    let nameservers: &[String] = match &hop.outcome {
+        HopOutcome::Referral { nameservers, .. } => nameservers,
+        _ => &[],
+    };
+    hop.response
+        .iter()
+        .flat_map(|response| &response.additionals)
+        .filter(move |record| {
+            matches!(record.type_, RecordType::A | RecordType::Aaaa)
+                && nameservers.contains(&record.name)
+        })
+}
+
+/// `result`, the lookup of `name` and `record_type`, as `format` prints it.
+pub fn render(
+    format: OutputFormat,
+    name: &str,
+    record_type: &RecordType,
+    result: &LookupResult,
+) -> String {
+    match format {
+        OutputFormat::Dig => {
+            let question = DNSQuestion::new(name.to_string(), record_type.clone(), Class::In);
+            let mut out = format!(";; QUESTION SECTION:\n{question}\n\n;; ANSWER SECTION:\n");
+            for record in answer_section(result) {
+                writeln!(out, "{record}").unwrap();
+            }
+            writeln!(out, "\n;; Query time: {} msec", result.elapsed.as_millis()).unwrap();
+            match result.server {
+                Some(server) => writeln!(out, ";; SERVER: {server}#53({server})").unwrap(),
+                None => writeln!(out, ";; SERVER: cache").unwrap(),
+            }
+            if let Some(nsid) = &result.nsid {
+                let hex: Vec<_> = nsid.iter().map(|byte| format!("{byte:02x}")).collect();
+                writeln!(out, ";; NSID: {} (\"{}\")", hex.join(" "), nsid_text(nsid)).unwrap();
+            }
+            out
+        }
+        OutputFormat::Short => answer_section(result)
+            .map(|record| format!("{}\n", record.data()))
+            .collect(),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let answers = Answer::list(answer_section(result));
+            let lookup = Lookup {
+                name,
+                record_type,
+                answers,
+                server: result.server,
+                from_cache: result.from_cache,
+                elapsed_ms: result.elapsed.as_millis() as u64,
+                nsid: result.nsid.as_deref().map(nsid_text),
+                error: None,
+            };
+            serialize(format, &lookup)
+        }
+        OutputFormat::Csv => {
+            let mut out = CSV_HEADER.to_string();
+            out.push_str(&csv_rows(name, record_type, &Ok(result.clone())));
+            out
+        }
+    }
+}
+
+/// The records of a lookup followed by their signatures, as dig prints them.
+fn answer_section(result: &LookupResult) -> impl Iterator<Item = &DNSRecord> {
+    result.records.iter().chain(&result.signatures)
+}
+
+/// An NSID as text, with bytes other than printable ASCII shown as `.`, as
+/// dig does.
+fn nsid_text(nsid: &[u8]) -> String {
+    nsid.iter()
+        .map(|&byte| match byte {
+            b' '..=b'~' => byte as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+const CSV_HEADER: &str = "name,type,status,ttl,data\n";
+
+/// The records of a lookup as CSV rows, or a single row with the error and
+/// no records if it failed.
+fn csv_rows(name: &str, record_type: &RecordType, result: &Result<LookupResult>) -> String {
+    match result {
+        Ok(result) => answer_section(result)
+            .map(|record| {
+                format!(
+                    "{},{},NOERROR,{},{}\n",
+                    csv_field(name),
+                    record.record_type(),
+                    record.ttl(),
+                    csv_field(&record.data().to_string())
+                )
+            })
+            .collect(),
+        Err(e) => format!(
+            "{},{record_type},{},,\n",
+            csv_field(name),
+            csv_field(&e.to_string())
+        ),
+    }
+}
+
+/// Quotes a field holding a comma, quote or line break (RFC 4180).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// How many names [`resolve_bulk`] resolved and how many it could not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkSummary {
+    pub resolved: usize,
+    pub failed: usize,
+}
+
+/// Resolves the names listed in `input`, one per line and optionally followed
+/// by a record type, at most `concurrency` at a time. Results are written to
+/// `out` in the order of `input` as they are ready: JSON as one object per
+/// line, YAML as a sequence, CSV under a single header.
+pub async fn resolve_bulk<T: DnsTransport + Clone + 'static>(
+    resolver: &DNSResolver<T>,
+    input: impl BufRead,
+    concurrency: usize,
+    format: OutputFormat,
+    out: &mut impl io::Write,
+) -> io::Result<BulkSummary> {
+    if format == OutputFormat::Csv {
+        out.write_all(CSV_HEADER.as_bytes())?;
+    }
+    let mut summary = BulkSummary::default();
+    let mut running = VecDeque::new();
+    for line in input.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let Some(name) = fields.next().filter(|name| !name.starts_with('#')) else {
+            continue;
+        };
+        let name = name.to_string();
+        let record_type = fields.next().map_or(Ok(RecordType::A), str::parse);
+        let resolver = resolver.clone();
+        running.push_back(tokio::spawn(async move {
+            let started = Instant::now();
+            let (record_type, result) = match record_type {
+                Ok(record_type) => {
+                    let result = resolver
+                        .resolve_detailed(&name, record_type.clone(), &Default::default())
+                        .await;
+                    (record_type, result)
+                }
+                Err(e) => (RecordType::A, Err(e)),
+            };
+            (name, record_type, result, started.elapsed())
+        }));
+        if running.len() >= concurrency {
+            let next = running.pop_front().expect("lookups are running");
+            write_bulk_entry(next.await?, format, out, &mut summary)?;
+        }
+    }
+    for next in running {
+        write_bulk_entry(next.await?, format, out, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+fn write_bulk_entry(
+    (name, record_type, result, elapsed): (String, RecordType, Result<LookupResult>, Duration),
+    format: OutputFormat,
+    out: &mut impl io::Write,
+    summary: &mut BulkSummary,
+) -> io::Result<()> {
+    match &result {
+        Ok(_) => summary.resolved += 1,
+        Err(_) => summary.failed += 1,
+    }
+    let entry = match (format, &result) {
+        (OutputFormat::Csv, _) => csv_rows(&name, &record_type, &result),
+        (OutputFormat::Json, _) => {
+            let lookup = Lookup::new(&name, &record_type, &result, elapsed);
+            let mut line = serde_json::to_string(&lookup).map_err(io::Error::other)?;
+            line.push('\n');
+            line
+        }
+        (OutputFormat::Yaml, _) => {
+            let lookup = Lookup::new(&name, &record_type, &result, elapsed);
+            serde_yaml_ng::to_string(&[lookup]).map_err(io::Error::other)?
+        }
+        (OutputFormat::Short, Ok(result)) => answer_section(result)
+            .map(|record| format!("{name}\t{}\n", record.data()))
+            .collect(),
+        (OutputFormat::Dig, Ok(result)) => {
+            format!("{}\n", render(format, &name, &record_type, result))
+        }
+        (_, Err(e)) => format!(";; {name} {record_type}: {e}\n"),
+    };
+    out.write_all(entry.as_bytes())
+}
+
+/// `trace`, the traced resolution of `name` and `record_type`, as `format`
+/// prints it. The dig format shows the records each server gave, followed by
+/// who gave them and how quickly, as `dig +trace` does.
+pub fn render_trace(
+    format: OutputFormat,
+    name: &str,
+    record_type: &RecordType,
+    trace: &Trace,
+) -> String {
+    match format {
+        OutputFormat::Dig => {
+            let mut out = String::new();
+            for hop in &trace.hops {
+                let records: Vec<&DNSRecord> = match (&hop.outcome, &hop.response) {
+                    (HopOutcome::Referral { .. }, Some(response)) => response
+                        .authorities
+                        .iter()
+                        .filter(|record| record.type_ == RecordType::Ns)
+                        .chain(glue(hop))
+                        .collect(),
+                    (HopOutcome::NxDomain | HopOutcome::NoData, Some(response)) => {
+                        response.authorities.iter().collect()
+                    }
+                    (_, Some(response)) => response.answers.iter().collect(),
+                    (_, None) => Vec::new(),
+                };
+                for record in records {
+                    writeln!(out, "{record}").unwrap();
+                }
+                let ms = hop.rtt.as_millis();
+                let server = hop.server;
+                match &hop.outcome {
+                    HopOutcome::Failed(error) => {
+                        writeln!(out, ";; No answer from {server} in {ms} ms: {error}").unwrap()
+                    }
+                    outcome => {
+                        let what = match outcome {
+                            HopOutcome::Referral { zone, .. } => {
+                                format!("Referral to {}", crate::fqdn(zone))
+                            }
+                            HopOutcome::Cname(target) => {
+                                format!("CNAME to {}", crate::fqdn(target))
+                            }
+                            HopOutcome::NxDomain => "NXDOMAIN".to_string(),
+                            HopOutcome::NoData => "NODATA".to_string(),
+                            _ => "Answer".to_string(),
+                        };
+                        writeln!(out, ";; {what} from {server}#53({server}) in {ms} ms\n").unwrap()
+                    }
+                }
+            }
+            out
+        }
+        OutputFormat::Short => trace
+            .hops
+            .iter()
+            .map(|hop| {
+                let hop = Hop::new(hop);
+                let detail = hop
+                    .detail
+                    .map(|detail| format!(" {detail}"))
+                    .unwrap_or_default();
+                format!("{} {}{detail} {} ms\n", hop.server, hop.outcome, hop.rtt_ms)
+            })
+            .collect(),
+        OutputFormat::Csv => {
+            let mut out = "server,name,outcome,detail,rcode,rtt_ms\n".to_string();
+            for hop in trace.hops.iter().map(Hop::new) {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{}",
+                    hop.server,
+                    csv_field(hop.name),
+                    hop.outcome,
+                    csv_field(hop.detail.unwrap_or_default()),
+                    hop.rcode.unwrap_or_default(),
+                    hop.rtt_ms
+                )
+                .unwrap();
+            }
+            out
+        }
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let output = TraceOutput {
+                name,
+                record_type,
+                hops: trace.hops.iter().map(Hop::new).collect(),
+                answers: trace.result.as_ref().ok().map(Answer::list),
+                error: trace.result.as_ref().err().map(ToString::to_string),
+            };
+            serialize(format, &output)
+        }
+    }
+}
+
+fn serialize(format: OutputFormat, value: &impl Serialize) -> String {
+    if format == OutputFormat::Json {
+        let mut out = serde_json::to_string_pretty(value).expect("output serializes");
+        out.push('\n');
+        out
+    } else {
+        serde_yaml_ng::to_string(value).expect("output serializes")
+    }
+}
+
+/// Resolves `args.name` every `args.interval`, bypassing the cache so that
+/// each lookup sees what the authoritative servers currently say, and writes
+/// what [`render_watch`] makes of it to `out`. Runs until `args.count` lookups
+/// are made, if given, or until the future is dropped.
+pub async fn watch<T: DnsTransport>(
+    resolver: &DNSResolver<T>,
+    args: &WatchArgs,
+    out: &mut impl io::Write,
+    color: bool,
+) -> io::Result<()> {
+    let options = QueryOptions {
+        cache: CachePolicy::Bypass,
+        ..QueryOptions::default()
+    };
+    let started = Instant::now();
+    let mut ticks = tokio::time::interval(args.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut previous = None;
+    let mut made = 0;
+    while args.count.is_none_or(|count| made < count) {
+        ticks.tick().await;
+        let current = resolver
+            .resolve_record_with(&args.name, args.record_type.clone(), &options)
+            .await
+            .map_err(|e| e.to_string());
+        let lines = render_watch(started.elapsed(), previous.as_ref(), &current, color);
+        out.write_all(lines.as_bytes())?;
+        out.flush()?;
+        previous = Some(current);
+        made += 1;
+    }
+    Ok(())
+}
+
+/// What one lookup of a watched name found: its records, or the error.
+pub type Observation = std::result::Result<Vec<DNSRecord>, String>;
+
+/// What `dnsr watch` prints for the lookup made `at` into the watch: every
+/// record the first time, and after that the records added (`+`), removed
+/// (`-`) or whose TTL changed (`~`) since `previous`. With `color`, changes are
+/// highlighted for a terminal.
+pub fn render_watch(
+    at: Duration,
+    previous: Option<&Observation>,
+    current: &Observation,
+    color: bool,
+) -> String {
+    let paint = |code: &str, line: String| {
+        if color {
+            format!("\x1b[{code}m{line}\x1b[0m\n")
+        } else {
+            format!("{line}\n")
+        }
+    };
+    let at = at.as_secs();
+    let (current, previous) = match (current, previous) {
+        (Err(e), Some(Err(before))) if e == before => return format!(";; +{at}s: unchanged\n"),
+        (Err(e), _) => return paint("1;31", format!(";; +{at}s: {e}")),
+        (Ok(current), Some(Ok(previous))) => (current, previous),
+        (Ok(current), _) => {
+            let plural = if current.len() == 1 { "" } else { "s" };
+            let mut out = format!(";; +{at}s: {} record{plural}\n", current.len());
+            for record in current {
+                writeln!(out, "  {record}").unwrap();
+            }
+            return out;
+        }
+    };
+    let mut changes = String::new();
+    for record in current {
+        let before = previous.iter().find(|before| {
+            before.name == record.name && before.type_ == record.type_ && before.data == record.data
+        });
+        match before {
+            None => changes.push_str(&paint("32", format!("+ {record}"))),
+            Some(before) if before.ttl != record.ttl => {
+                let line = format!("~ {record}\t(TTL was {})", before.ttl);
+                changes.push_str(&paint("33", line));
+            }
+            Some(_) => {}
+        }
+    }
+    for record in previous {
+        let kept = current.iter().any(|after| {
+            after.name == record.name && after.type_ == record.type_ && after.data == record.data
+        });
+        if !kept {
+            changes.push_str(&paint("31", format!("- {record}")));
+        }
+    }
+    if changes.is_empty() {
+        format!(";; +{at}s: unchanged\n")
+    } else {
+        paint("1", format!(";; +{at}s: changed")) + &changes
+    }
+}
+
+/// `propagation` as `format` prints it. The dig and short formats list the
+/// resolvers grouped by the answer they gave, most common first, then those
+/// that gave none.
+pub fn render_propagation(format: OutputFormat, propagation: &Propagation) -> String {
+    let describe = |answer: &PropagatedAnswer| match answer {
+        PropagatedAnswer::Records(records) => records.join(" "),
+        PropagatedAnswer::NxDomain => "NXDOMAIN".to_string(),
+        PropagatedAnswer::NoData => "NODATA".to_string(),
+        PropagatedAnswer::Failed(error) => error.clone(),
+    };
+    let line = |answer: &ResolverAnswer| {
+        let ttl = answer
+            .ttl
+            .map(|ttl| format!("\tTTL {ttl}"))
+            .unwrap_or_default();
+        format!(
+            "{}\t{}\t{} ms{ttl}",
+            answer.resolver.name,
+            answer.resolver.address,
+            answer.rtt.as_millis()
+        )
+    };
+    match format {
+        OutputFormat::Dig => {
+            let groups = propagation.groups();
+            let question = format!(
+                "{} {}",
+                crate::fqdn(&propagation.name),
+                propagation.record_type
+            );
+            let mut out = if propagation.agrees() {
+                format!(";; {question}: answers agree\n")
+            } else {
+                format!(";; {question}: {} different answers\n", groups.len())
+            };
+            for (answer, resolvers) in groups {
+                writeln!(out, "\n;; {}:", describe(answer)).unwrap();
+                for resolver in resolvers {
+                    writeln!(out, "{}", line(resolver)).unwrap();
+                }
+            }
+            let failures: Vec<_> = propagation.failures().collect();
+            if !failures.is_empty() {
+                writeln!(out, "\n;; No answer:").unwrap();
+                for failure in failures {
+                    writeln!(out, "{}\t{}", line(failure), describe(&failure.answer)).unwrap();
+                }
+            }
+            out
+        }
+        OutputFormat::Short => propagation
+            .answers
+            .iter()
+            .map(|answer| format!("{}\t{}\n", answer.resolver.name, describe(&answer.answer)))
+            .collect(),
+        OutputFormat::Json | OutputFormat::Yaml => serialize(format, propagation),
+        OutputFormat::Csv => {
+            let mut out = "resolver,address,status,answer,ttl,rtt_ms\n".to_string();
+            for answer in &propagation.answers {
+                let status = match answer.answer {
+                    PropagatedAnswer::Records(_) => "records",
+                    PropagatedAnswer::NxDomain => "nxdomain",
+                    PropagatedAnswer::NoData => "nodata",
+                    PropagatedAnswer::Failed(_) => "failed",
+                };
+                writeln!(
+                    out,
+                    "{},{},{status},{},{},{}",
+                    answer.resolver.name,
+                    answer.resolver.address,
+                    csv_field(&describe(&answer.answer)),
+                    answer.ttl.map(|ttl| ttl.to_string()).unwrap_or_default(),
+                    answer.rtt.as_millis()
+                )
+                .unwrap();
+            }
+            out
+        }
+    }
+}
+
+/// `chain` as `format` prints it. The dig format lists each link with its
+/// status and the algorithm and key tag of each signature, then why the first
+/// link that is not secure is not.
+pub fn render_dnssec(format: OutputFormat, chain: &DnssecChain) -> String {
+    let status = |link: &ChainLink| match &link.status {
+        LinkStatus::Secure => ("secure", None),
+        LinkStatus::Insecure(reason) => ("insecure", Some(reason.clone())),
+        LinkStatus::Bogus(error) => ("bogus", Some(error.to_string())),
+        LinkStatus::Indeterminate(reason) => ("indeterminate", Some(reason.clone())),
+    };
+    let signatures = |link: &ChainLink| {
+        link.signatures
+            .iter()
+            .map(|rrsig| {
+                let algorithm = algorithm_name(rrsig.algorithm)
+                    .map_or_else(|| rrsig.algorithm.to_string(), str::to_string);
+                format!("{algorithm}/{}", rrsig.key_tag)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    match format {
+        OutputFormat::Dig => {
+            let question = format!("{} {}", crate::fqdn(&chain.name), chain.record_type);
+            let mut out = match chain.break_point() {
+                None if chain.is_secure() => format!(";; {question}: secure\n"),
+                None => format!(";; {question}: nothing to check\n"),
+                Some(link) => format!(
+                    ";; {question}: {} at {} {}\n",
+                    status(link).0,
+                    crate::fqdn(&link.name),
+                    link.record_type
+                ),
+            };
+            for link in &chain.links {
+                let (status, reason) = status(link);
+                writeln!(
+                    out,
+                    "{}\t{}\t{status}\t{}\t;; from {}",
+                    crate::fqdn(&link.name),
+                    link.record_type,
+                    signatures(link),
+                    link.server
+                )
+                .unwrap();
+                if let Some(reason) = reason {
+                    writeln!(out, ";; {reason}").unwrap();
+                }
+            }
+            out
+        }
+        OutputFormat::Short => chain
+            .links
+            .iter()
+            .map(|link| {
+                let name = crate::fqdn(&link.name);
+                format!("{name} {} {}\n", link.record_type, status(link).0)
+            })
+            .collect(),
+        OutputFormat::Json | OutputFormat::Yaml => serialize(format, chain),
+        OutputFormat::Csv => {
+            let mut out = "name,type,zone,server,status,reason,signatures\n".to_string();
+            for link in &chain.links {
+                let (status, reason) = status(link);
+                writeln!(
+                    out,
+                    "{},{},{},{},{status},{},{}",
+                    crate::fqdn(&link.name),
+                    link.record_type,
+                    crate::fqdn(&link.zone),
+                    link.server,
+                    csv_field(&reason.unwrap_or_default()),
+                    signatures(link)
+                )
+                .unwrap();
+            }
+            out
+        }
+    }
+}
+
+/// `delegation` as `format` prints it. The dig format shows the parent's and
+/// the zone's NS lists, how each nameserver address answered, and then every
+/// issue found.
+pub fn render_delegation(format: OutputFormat, delegation: &Delegation) -> String {
+    let outcome = |outcome: &ServerOutcome| match outcome {
+        ServerOutcome::Authoritative { serial } => format!("serial {serial}"),
+        ServerOutcome::Lame(reason) => format!("lame: {reason}"),
+        ServerOutcome::Unreachable(error) => format!("unreachable: {error}"),
+    };
+    let names = |names: &[String]| {
+        if names.is_empty() {
+            return "none".to_string();
+        }
+        names
+            .iter()
+            .map(|name| crate::fqdn(name))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let issues = delegation.issues();
+    match format {
+        OutputFormat::Dig => {
+            let zone = crate::fqdn(&delegation.zone);
+            let mut out = match issues.len() {
+                0 => format!(";; {zone} delegation: healthy\n"),
+                1 => format!(";; {zone} delegation: 1 issue\n"),
+                n => format!(";; {zone} delegation: {n} issues\n"),
+            };
+            if let Some(server) = delegation.parent_server {
+                writeln!(
+                    out,
+                    ";; Parent ({server}): {}",
+                    names(&delegation.parent_ns)
+                )
+                .unwrap();
+            }
+            writeln!(out, ";; Zone: {}\n", names(&delegation.child_ns)).unwrap();
+            for nameserver in &delegation.nameservers {
+                let name = crate::fqdn(&nameserver.name);
+                if let Some(error) = &nameserver.error {
+                    writeln!(out, "{name}\t-\t{error}").unwrap();
+                }
+                for check in &nameserver.addresses {
+                    writeln!(
+                        out,
+                        "{name}\t{}\t{}\t{} ms",
+                        check.address,
+                        outcome(&check.outcome),
+                        check.rtt.as_millis()
+                    )
+                    .unwrap();
+                }
+            }
+            if !issues.is_empty() {
+                writeln!(out, "\n;; Issues:").unwrap();
+                for issue in &issues {
+                    writeln!(out, ";; {issue}").unwrap();
+                }
+            }
+            out
+        }
+        OutputFormat::Short => issues.iter().map(|issue| format!("{issue}\n")).collect(),
+        OutputFormat::Json | OutputFormat::Yaml => serialize(format, delegation),
+        OutputFormat::Csv => {
+            let mut out = "nameserver,address,at_parent,at_child,glue,status,rtt_ms\n".to_string();
+            for nameserver in &delegation.nameservers {
+                let glue = nameserver
+                    .glue
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let row = |address: String, status: String, rtt: String| {
+                    format!(
+                        "{},{address},{},{},{glue},{},{rtt}\n",
+                        crate::fqdn(&nameserver.name),
+                        nameserver.at_parent,
+                        nameserver.at_child,
+                        csv_field(&status)
+                    )
+                };
+                if let Some(error) = &nameserver.error {
+                    out += &row(String::new(), error.clone(), String::new());
+                }
+                for check in &nameserver.addresses {
+                    out += &row(
+                        check.address.to_string(),
+                        outcome(&check.outcome),
+                        check.rtt.as_millis().to_string(),
+                    );
+                }
+            }
+            out
+        }
+    }
+}
+
+/// Reads the names and record types of `input`, in the format of `--file`.
+pub fn read_targets(input: impl BufRead) -> io::Result<Vec<(String, RecordType)>> {
+    let mut targets = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let Some(name) = fields.next().filter(|name| !name.starts_with('#')) else {
+            continue;
+        };
+        let record_type = fields
+            .next()
+            .map_or(Ok(RecordType::A), str::parse)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        targets.push((name.to_string(), record_type));
+    }
+    Ok(targets)
+}
+
+/// What [`bench`] measured.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BenchReport {
+    pub sent: u64,
+    /// From the first query sent to the last response received.
+    pub elapsed: Duration,
+    /// How many responses had each response code.
+    pub rcodes: BTreeMap<String, u64>,
+    pub timeouts: u64,
+    /// Queries that failed any other way, such as with a malformed response.
+    pub errors: u64,
+    /// Round-trip times of the responses, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// The latency that `quantile` (0 to 1) of the responses came within.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[(last as f64 * quantile).round() as usize])
+    }
+
+    /// The share of queries without a NOERROR or NXDOMAIN response.
+    pub fn error_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        let ok: u64 = [Rcode::NoError, Rcode::NxDomain]
+            .iter()
+            .filter_map(|rcode| self.rcodes.get(&rcode.to_string()))
+            .sum();
+        (self.sent - ok) as f64 / self.sent as f64
+    }
+
+    /// Queries sent per second, as achieved.
+    pub fn qps(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn record(&mut self, outcome: Result<Rcode>, rtt: Duration) {
+        match outcome {
+            Ok(rcode) => {
+                *self.rcodes.entry(rcode.to_string()).or_default() += 1;
+                self.latencies.push(rtt);
+            }
+            Err(DnsError::Timeout(_)) => self.timeouts += 1,
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BenchSummary<'a> {
+    sent: u64,
+    elapsed_ms: u64,
+    qps: f64,
+    responses: &'a BTreeMap<String, u64>,
+    timeouts: u64,
+    errors: u64,
+    error_rate: f64,
+    latency_ms: Option<Latency>,
+}
+
+/// Percentiles of the round-trip times, in milliseconds.
+#[derive(Serialize)]
+struct Latency {
+    min: f64,
+    p50: f64,
+    p90: f64,
+    p95: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl<'a> BenchSummary<'a> {
+    fn new(report: &'a BenchReport) -> Self {
+        let ms = |quantile| {
+            report
+                .percentile(quantile)
+                .map(|rtt| rtt.as_micros() as f64 / 1000.0)
+        };
+        let latency_ms = (|| {
+            Some(Latency {
+                min: ms(0.0)?,
+                p50: ms(0.5)?,
+                p90: ms(0.9)?,
+                p95: ms(0.95)?,
+                p99: ms(0.99)?,
+                max: ms(1.0)?,
+            })
+        })();
+        BenchSummary {
+            sent: report.sent,
+            elapsed_ms: report.elapsed.as_millis() as u64,
+            qps: report.qps(),
+            responses: &report.rcodes,
+            timeouts: report.timeouts,
+            errors: report.errors,
+            error_rate: report.error_rate(),
+            latency_ms,
+        }
+    }
+}
+
+/// Sends `args.qps` queries a second for `args.duration`, cycling through
+/// `targets`, then waits for the last responses. Queries go to `servers` in
+/// turn with recursion desired, or are resolved from the upstreams without
+/// the cache if there are none.
+pub async fn bench<T: DnsTransport + Clone + 'static>(
+    resolver: &DNSResolver<T>,
+    servers: &[Ipv4Addr],
+    targets: &[(String, RecordType)],
+    args: &BenchArgs,
+) -> BenchReport {
+    let mut report = BenchReport::default();
+    let total = (args.duration.as_secs_f64() * args.qps as f64).ceil() as u64;
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / args.qps);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let mut running = JoinSet::new();
+    let started = Instant::now();
+    for (i, (name, record_type)) in targets.iter().cycle().take(total as usize).enumerate() {
+        ticks.tick().await;
+        let resolver = resolver.clone();
+        let name = name.clone();
+        let record_type = record_type.clone();
+        let server = servers.get(i % servers.len().max(1)).copied();
+        running.spawn(async move {
+            let sent = Instant::now();
+            let outcome = match server {
+                Some(server) => {
+                    let options = QueryOptions {
+                        recursion_desired: Some(true),
+                        ..QueryOptions::default()
+                    };
+                    resolver
+                        .lookup_class(&name, &server, record_type, Class::In, &options)
+                        .await
+                        .map(|response| response.header.flags.rcode)
+                }
+                None => {
+                    let options = QueryOptions {
+                        cache: CachePolicy::Bypass,
+                        ..QueryOptions::default()
+                    };
+                    match resolver
+                        .resolve_detailed(&name, record_type, &options)
+                        .await
+                    {
+                        Ok(_) | Err(DnsError::NoRecordsFound(_)) => Ok(Rcode::NoError),
+                        Err(DnsError::NxDomain(_)) => Ok(Rcode::NxDomain),
+                        Err(DnsError::ServFail { .. }) => Ok(Rcode::ServFail),
+                        Err(DnsError::ErrorResponse { rcode, .. }) => Ok(rcode),
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            (outcome, sent.elapsed())
+        });
+        report.sent += 1;
+        while let Some(done) = running.try_join_next() {
+            let (outcome, rtt) = done.expect("bench queries do not panic");
+            report.record(outcome, rtt);
+        }
+    }
+    while let Some(done) = running.join_next().await {
+        let (outcome, rtt) = done.expect("bench queries do not panic");
+        report.record(outcome, rtt);
+    }
+    // Sending takes the whole duration, even if the last query is answered
+    // before it is up.
+    report.elapsed = started.elapsed().max(args.duration);
+    report.latencies.sort();
+    report
+}
+
+/// `report` as `format` prints it. The dig format gives the achieved rate,
+/// latency percentiles, response codes and error rate; CSV gives one metric
+/// per row.
+pub fn render_bench(format: OutputFormat, report: &BenchReport) -> String {
+    let summary = BenchSummary::new(report);
+    let latency = summary.latency_ms.as_ref().map(|latency| {
+        [
+            ("min", latency.min),
+            ("p50", latency.p50),
+            ("p90", latency.p90),
+            ("p95", latency.p95),
+            ("p99", latency.p99),
+            ("max", latency.max),
+        ]
+    });
+    match format {
+        OutputFormat::Dig => {
+            let mut out = format!(
+                ";; {} queries in {:.1} s ({:.1} qps)\n",
+                report.sent,
+                report.elapsed.as_secs_f64(),
+                summary.qps
+            );
+            if let Some(latency) = latency {
+                let latency = latency
+                    .iter()
+                    .map(|(name, ms)| format!("{name} {ms:.1} ms"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                writeln!(out, ";; Latency: {latency}").unwrap();
+            }
+            let responses = report
+                .rcodes
+                .iter()
+                .map(|(rcode, count)| format!("{rcode} {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                out,
+                ";; Responses: {}",
+                if responses.is_empty() {
+                    "none"
+                } else {
+                    &responses
+                }
+            )
+            .unwrap();
+            writeln!(
+                out,
+                ";; Timeouts: {}, other errors: {}, error rate {:.2}%",
+                report.timeouts,
+                report.errors,
+                summary.error_rate * 100.0
+            )
+            .unwrap();
+            out
+        }
+        OutputFormat::Short => {
+            let (p50, p99) = summary.latency_ms.as_ref().map_or_else(
+                || ("-".to_string(), "-".to_string()),
+                |latency| {
+                    (
+                        format!("{:.1} ms", latency.p50),
+                        format!("{:.1} ms", latency.p99),
+                    )
+                },
+            );
+            format!(
+                "{} sent, {:.1} qps, {:.2}% errors, p50 {p50}, p99 {p99}\n",
+                report.sent,
+                summary.qps,
+                summary.error_rate * 100.0
+            )
+        }
+        OutputFormat::Json | OutputFormat::Yaml => serialize(format, &summary),
+        OutputFormat::Csv => {
+            let mut out = format!(
+                "metric,value\nsent,{}\nelapsed_ms,{}\nqps,{:.1}\ntimeouts,{}\nerrors,{}\nerror_rate,{:.4}\n",
+                summary.sent,
+                summary.elapsed_ms,
+                summary.qps,
+                summary.timeouts,
+                summary.errors,
+                summary.error_rate
+            );
+            for (rcode, count) in &report.rcodes {
+                writeln!(out, "{rcode},{count}").unwrap();
+            }
+            for (name, ms) in latency.into_iter().flatten() {
+                writeln!(out, "latency_{name}_ms,{ms:.3}").unwrap();
+            }
+            out
+        }
+    }
+}
+
+/// One address of a PTR sweep, in JSON, YAML and CSV.
+#[derive(Serialize)]
+struct Reverse<'a> {
+    address: Ipv4Addr,
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Looks up the PTR record of every address in `network`, at most
+/// `concurrency` at a time, and writes them to `out` in address order as they
+/// are ready: the dig format as a table with a header, short as only the
+/// addresses that have a name, JSON as one object per line, YAML as a
+/// sequence and CSV under a single header. `resolved` counts the addresses
+/// with a name.
+pub async fn sweep_ptr<T: DnsTransport + Clone + 'static>(
+    resolver: &DNSResolver<T>,
+    network: Ipv4Network,
+    concurrency: usize,
+    format: OutputFormat,
+    out: &mut impl io::Write,
+) -> io::Result<BulkSummary> {
+    match format {
+        OutputFormat::Dig => writeln!(out, "{:<15}  PTR", "ADDRESS")?,
+        OutputFormat::Csv => out.write_all(b"address,name,error\n")?,
+        _ => {}
+    }
+    let mut summary = BulkSummary::default();
+    let mut running = VecDeque::new();
+    for address in network.addresses() {
+        let resolver = resolver.clone();
+        running.push_back(tokio::spawn(async move {
+            let result = resolver.reverse_resolve(&address).await;
+            (address, result)
+        }));
+        if running.len() >= concurrency {
+            let next = running.pop_front().expect("lookups are running");
+            write_ptr_entry(next.await?, format, out, &mut summary)?;
+        }
+    }
+    for next in running {
+        write_ptr_entry(next.await?, format, out, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+fn write_ptr_entry(
+    (address, result): (Ipv4Addr, Result<String>),
+    format: OutputFormat,
+    out: &mut impl io::Write,
+    summary: &mut BulkSummary,
+) -> io::Result<()> {
+    match &result {
+        Ok(_) => summary.resolved += 1,
+        Err(_) => summary.failed += 1,
+    }
+    let error = result.as_ref().err().map(ToString::to_string);
+    let reverse = Reverse {
+        address,
+        name: result.as_deref().ok().map(crate::fqdn),
+        error: error.as_deref(),
+    };
+    let entry = match format {
+        OutputFormat::Dig => {
+            let name = match (&reverse.name, &reverse.error) {
+                (Some(name), _) => name.clone(),
+                (None, Some(error)) => format!("-  ;; {error}"),
+                (None, None) => "-".to_string(),
+            };
+            format!("{address:<15}  {name}\n")
+        }
+        OutputFormat::Short => match &reverse.name {
+            Some(name) => format!("{address}\t{name}\n"),
+            None => String::new(),
+        },
+        OutputFormat::Json => {
+            let mut line = serde_json::to_string(&reverse).map_err(io::Error::other)?;
+            line.push('\n');
+            line
+        }
+        OutputFormat::Yaml => serde_yaml_ng::to_string(&[reverse]).map_err(io::Error::other)?,
+        OutputFormat::Csv => format!(
+            "{address},{},{}\n",
+            reverse.name.as_deref().unwrap_or_default(),
+            csv_field(reverse.error.unwrap_or_default())
+        ),
+    };
+    out.write_all(entry.as_bytes())
+}
+
+/// Writes the completion script for `shell`, generated from [`Cli`].
+pub fn completions(shell: Shell, out: &mut impl io::Write) -> io::Result<()> {
+    // The generator panics on write errors, such as a closed pipe, so it
+    // writes to memory first.
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "dnsr", &mut script);
+    out.write_all(&script)
+}
+
+/// Writes the man page for `dnsr`, generated from [`Cli`].
+pub fn man_page(out: &mut impl io::Write) -> io::Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(out)
+}
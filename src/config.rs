@@ -0,0 +1,435 @@
+//! Resolver configuration, assembled with [`DNSResolverBuilder`].
+
+use crate::DNSResolver;
+use crate::edns::ClientSubnet;
+use crate::error::{DnsError, Result};
+use crate::hosts::{Hosts, SYSTEM_HOSTS};
+use crate::observer::{Observers, QueryObserver};
+use crate::resolver::SharedRng;
+use crate::stats::ServerTable;
+use crate::transport::{DnsTransport, TcpTransport, UdpTransport};
+use rand::RngCore;
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// a.root-servers.net, where resolution starts unless configured otherwise.
+pub const DEFAULT_UPSTREAM: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RETRIES: u32 = 1;
+/// Enough for heavy batch resolution, well short of common descriptor limits.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 512;
+/// Default upper bound on how long a negative answer is remembered.
+pub const DEFAULT_NEGATIVE_TTL_CEILING: Duration = Duration::from_secs(15 * 60);
+
+/// The smallest UDP payload every DNS implementation must accept (RFC 1035).
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// Which address families [`DNSResolver::lookup_ip`] queries, and which
+/// comes first in its results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    #[default]
+    Ipv4ThenIpv6,
+    Ipv6ThenIpv4,
+}
+
+/// Which upstream the first query of a resolution goes to, and which next if
+/// it does not respond. Mostly of interest when forwarding to recursive
+/// resolvers, where any upstream can answer every query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamPolicy {
+    /// Fastest first, by the round-trip times measured so far.
+    #[default]
+    Fastest,
+    /// In the configured order, moving on only when one fails.
+    Sequential,
+    /// In a random order for each resolution, spreading the load evenly.
+    Random,
+    /// In the configured order, starting one further along for each
+    /// resolution, shared by every clone of the resolver.
+    RoundRobin,
+    /// All at once, taking the first response and dropping the other
+    /// queries. Fastest when upstreams are erratic, at the cost of load.
+    Race,
+}
+
+/// What an upstream is told of the client a query is made for, set with
+/// [`DNSResolverBuilder::upstream_client_subnet`]. Upstreams without a
+/// policy get the [`DNSResolverBuilder::client_subnet`], if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ClientSubnetPolicy {
+    /// No EDNS Client Subnet option, keeping clients private.
+    Strip,
+    /// The subnet of [`QueryOptions::client_subnet`], shortened to at most
+    /// these prefix lengths, falling back to the configured subnet.
+    Inject {
+        #[serde(default = "default_ipv4_prefix")]
+        ipv4_prefix: u8,
+        #[serde(default = "default_ipv6_prefix")]
+        ipv6_prefix: u8,
+    },
+}
+
+impl ClientSubnetPolicy {
+    /// Injects the /24 or /56 RFC 7871 recommends revealing at most.
+    pub const INJECT: ClientSubnetPolicy = ClientSubnetPolicy::Inject {
+        ipv4_prefix: 24,
+        ipv6_prefix: 56,
+    };
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    56
+}
+
+/// Whether a lookup reads and writes the shared caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    /// Answers from the caches when possible, and stores what is resolved.
+    #[default]
+    Use,
+    /// Always queries, storing the fresh answer.
+    Refresh,
+    /// Neither reads nor writes the caches.
+    Bypass,
+}
+
+/// How a lookup's queries are carried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportPreference {
+    /// The transport the resolver was built with.
+    #[default]
+    Configured,
+    /// Plain TCP to the same servers. Not available on wasm32.
+    Tcp,
+}
+
+/// Per-call overrides of the resolver configuration, accepted by
+/// [`DNSResolver::resolve_record_with`]. The default overrides nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryOptions {
+    /// Overrides [`DNSResolverBuilder::recursion_desired`].
+    pub recursion_desired: Option<bool>,
+    /// Overrides [`DNSResolverBuilder::dnssec_ok`].
+    pub dnssec_ok: Option<bool>,
+    /// Overrides [`DNSResolverBuilder::nsid`].
+    pub nsid: Option<bool>,
+    pub transport: TransportPreference,
+    /// Overrides [`DNSResolverBuilder::timeout`].
+    pub timeout: Option<Duration>,
+    /// Has no effect when caching is off for the resolver.
+    pub cache: CachePolicy,
+    /// The subnet of the client the lookup is made for, which upstreams
+    /// set to [`ClientSubnetPolicy::Inject`] are told of.
+    pub client_subnet: Option<ClientSubnet>,
+}
+
+/// Configures a [`DNSResolver`]. Every setting has a default, so
+/// `DNSResolver::builder().build()` behaves like [`DNSResolver::default`].
+#[derive(Debug, Clone)]
+pub struct DNSResolverBuilder<T = UdpTransport> {
+    resolver: DNSResolver<T>,
+}
+
+impl Default for DNSResolverBuilder {
+    fn default() -> Self {
+        DNSResolverBuilder {
+            resolver: DNSResolver {
+                transport: UdpTransport::default(),
+                upstreams: Arc::new(RwLock::new(vec![DEFAULT_UPSTREAM])),
+                ports: Arc::default(),
+                upstream_policy: UpstreamPolicy::default(),
+                next_upstream: Arc::new(AtomicUsize::new(0)),
+                timeout: DEFAULT_TIMEOUT,
+                adaptive_timeout: true,
+                retries: DEFAULT_RETRIES,
+                max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                in_flight: Arc::new(Semaphore::new(DEFAULT_MAX_IN_FLIGHT)),
+                recursion_desired: false,
+                // moka reads the system clock, which browsers do not provide.
+                use_cache: cfg!(all(feature = "cache", not(target_arch = "wasm32"))),
+                negative_ttl_ceiling: DEFAULT_NEGATIVE_TTL_CEILING,
+                udp_payload_size: None,
+                request_nsid: false,
+                client_subnet: None,
+                client_subnets: Arc::default(),
+                ip_strategy: IpStrategy::default(),
+                hosts: SYSTEM_HOSTS.clone(),
+                dnssec_ok: false,
+                checking_disabled: false,
+                randomize_case: false,
+                rng: None,
+                observers: Observers::default(),
+                servers: ServerTable::default(),
+                tcp: TcpTransport::default(),
+                #[cfg(feature = "dnstap")]
+                dnstap: None,
+            },
+        }
+    }
+}
+
+impl<T> DNSResolverBuilder<T> {
+    /// The servers resolution starts from, tried until one answers: in the
+    /// given order at first, then fastest first as their round-trip times are
+    /// measured. Usually root servers, but any server answering non-recursive queries
+    /// for the root zone will do.
+    pub fn upstreams(mut self, upstreams: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        self.resolver.upstreams = Arc::new(RwLock::new(upstreams.into_iter().collect()));
+        self
+    }
+
+    /// Queries `server` on `port` instead of 53, for upstreams listening
+    /// elsewhere, such as Consul's DNS interface on 8600. Applies whenever
+    /// `server` is queried, as an upstream or not.
+    pub fn upstream_port(mut self, server: Ipv4Addr, port: u16) -> Self {
+        Arc::make_mut(&mut self.resolver.ports).insert(server, port);
+        self
+    }
+
+    /// How the upstreams are chosen among. Defaults to
+    /// [`UpstreamPolicy::Fastest`].
+    pub fn upstream_policy(mut self, policy: UpstreamPolicy) -> Self {
+        self.resolver.upstream_policy = policy;
+        self
+    }
+
+    /// Carries queries over `transport` instead of UDP. Required on wasm32,
+    /// where browsers only allow `HttpsTransport`.
+    pub fn transport<U: DnsTransport>(self, transport: U) -> DNSResolverBuilder<U> {
+        let r = self.resolver;
+        DNSResolverBuilder {
+            resolver: DNSResolver {
+                transport,
+                upstreams: r.upstreams,
+                ports: r.ports,
+                upstream_policy: r.upstream_policy,
+                next_upstream: r.next_upstream,
+                timeout: r.timeout,
+                adaptive_timeout: r.adaptive_timeout,
+                retries: r.retries,
+                max_in_flight: r.max_in_flight,
+                in_flight: r.in_flight,
+                recursion_desired: r.recursion_desired,
+                use_cache: r.use_cache,
+                negative_ttl_ceiling: r.negative_ttl_ceiling,
+                udp_payload_size: r.udp_payload_size,
+                request_nsid: r.request_nsid,
+                client_subnet: r.client_subnet,
+                client_subnets: r.client_subnets,
+                ip_strategy: r.ip_strategy,
+                hosts: r.hosts,
+                dnssec_ok: r.dnssec_ok,
+                checking_disabled: r.checking_disabled,
+                randomize_case: r.randomize_case,
+                rng: r.rng,
+                observers: r.observers,
+                servers: r.servers,
+                tcp: r.tcp,
+                #[cfg(feature = "dnstap")]
+                dnstap: r.dnstap,
+            },
+        }
+    }
+
+    /// How long to wait for each response before retrying. With
+    /// [`DNSResolverBuilder::adaptive_timeout`], only the last attempt on a
+    /// server waits this long.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.resolver.timeout = timeout;
+        self
+    }
+
+    /// Whether retries wait only about as long as the server's measured round
+    /// trips suggest, from 50 ms up to [`DNSResolverBuilder::timeout`] and
+    /// doubling each time, so that a lost packet to a fast server is resent
+    /// quickly. The last attempt still waits the full timeout. On by default.
+    pub fn adaptive_timeout(mut self, adaptive: bool) -> Self {
+        self.resolver.adaptive_timeout = adaptive;
+        self
+    }
+
+    /// How many times a timed out query is resent to the same server.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.resolver.retries = retries;
+        self
+    }
+
+    /// The most queries this resolver and its clones have outstanding at
+    /// once; further queries wait for one to finish. Defaults to
+    /// [`DEFAULT_MAX_IN_FLIGHT`].
+    pub fn max_in_flight(mut self, limit: usize) -> Self {
+        self.resolver.max_in_flight = limit;
+        self
+    }
+
+    /// Sets the RD flag, for upstreams that are recursive resolvers rather
+    /// than root servers. Their answers end the resolution immediately.
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.resolver.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Whether answers are looked up in and stored to the shared caches.
+    /// Always off on wasm32 and without the `cache` feature.
+    pub fn cache(mut self, use_cache: bool) -> Self {
+        self.resolver.use_cache =
+            use_cache && cfg!(all(feature = "cache", not(target_arch = "wasm32")));
+        self
+    }
+
+    /// Caps how long NXDOMAIN/NODATA answers are cached, regardless of the zone's SOA.
+    pub fn negative_ttl_ceiling(mut self, ceiling: Duration) -> Self {
+        self.resolver.negative_ttl_ceiling = ceiling;
+        self
+    }
+
+    /// Advertises EDNS with this UDP payload size on every query, and sizes the
+    /// receive buffer to match.
+    pub fn udp_payload_size(mut self, size: u16) -> Self {
+        self.resolver.udp_payload_size = Some(size);
+        self
+    }
+
+    /// Asks every queried server to identify itself with the EDNS NSID option.
+    /// The identifier is available through [`DNSPacket::edns`](crate::DNSPacket::edns)
+    /// on packets returned by [`DNSResolver::lookup`].
+    pub fn nsid(mut self, request_nsid: bool) -> Self {
+        self.resolver.request_nsid = request_nsid;
+        self
+    }
+
+    /// Attaches an EDNS Client Subnet option to every query. The scope the server
+    /// answered for is available through [`Edns::client_subnet`](crate::edns::Edns::client_subnet).
+    pub fn client_subnet(mut self, client_subnet: ClientSubnet) -> Self {
+        self.resolver.client_subnet = Some(client_subnet);
+        self
+    }
+
+    /// Overrides what `server` is told of the client a query is made for,
+    /// such as a forwarder to strip EDNS Client Subnet from for privacy or
+    /// a CDN's resolver to pass the client's subnet on to.
+    pub fn upstream_client_subnet(mut self, server: Ipv4Addr, policy: ClientSubnetPolicy) -> Self {
+        Arc::make_mut(&mut self.resolver.client_subnets).insert(server, policy);
+        self
+    }
+
+    pub fn ip_strategy(mut self, ip_strategy: IpStrategy) -> Self {
+        self.resolver.ip_strategy = ip_strategy;
+        self
+    }
+
+    /// Replaces the system hosts file consulted by [`DNSResolver::lookup_ip`].
+    /// Pass `Hosts::default()` to skip it.
+    pub fn hosts(mut self, hosts: Hosts) -> Self {
+        self.resolver.hosts = Arc::new(hosts);
+        self
+    }
+
+    /// Sets the EDNS DO bit, asking servers to include DNSSEC records.
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.resolver.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// Sets the CD flag, asking validating servers not to DNSSEC validate.
+    pub fn checking_disabled(mut self, checking_disabled: bool) -> Self {
+        self.resolver.checking_disabled = checking_disabled;
+        self
+    }
+
+    /// Randomizes the case of every query name and requires responses to echo
+    /// it exactly (DNS 0x20), making forged responses harder to get accepted.
+    pub fn randomize_case(mut self, randomize_case: bool) -> Self {
+        self.resolver.randomize_case = randomize_case;
+        self
+    }
+
+    /// Draws query IDs and 0x20 case from `rng` instead of the thread-local
+    /// generator, so a seeded generator makes every query reproducible.
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.resolver.rng = Some(SharedRng(Arc::new(Mutex::new(rng))));
+        self
+    }
+
+    /// Registers `observer` to be told about every query and cache hit. Any
+    /// number of observers can be registered; they are called in order.
+    pub fn observer(mut self, observer: Arc<dyn QueryObserver>) -> Self {
+        self.resolver.observers.0.push(observer);
+        self
+    }
+
+    /// Logs every query sent and response received as dnstap
+    /// `RESOLVER_QUERY`/`RESOLVER_RESPONSE` messages.
+    #[cfg(feature = "dnstap")]
+    pub fn dnstap(mut self, writer: crate::dnstap::DnstapWriter) -> Self {
+        self.resolver.dnstap = Some(std::sync::Arc::new(std::sync::Mutex::new(writer)));
+        self
+    }
+
+    /// Checks the configuration is usable.
+    pub fn build(self) -> Result<DNSResolver<T>> {
+        let mut resolver = self.resolver;
+        let no_upstreams = resolver
+            .upstreams
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty();
+        if no_upstreams {
+            return Err(DnsError::InvalidConfig(
+                "At least one upstream server is required".to_string(),
+            ));
+        }
+        if let Some(server) = resolver.ports.iter().find(|(_, port)| **port == 0) {
+            return Err(DnsError::InvalidConfig(format!(
+                "The port of {} must not be zero",
+                server.0
+            )));
+        }
+        for (server, policy) in resolver.client_subnets.iter() {
+            if let ClientSubnetPolicy::Inject {
+                ipv4_prefix,
+                ipv6_prefix,
+            } = policy
+                && (*ipv4_prefix > 32 || *ipv6_prefix > 128)
+            {
+                return Err(DnsError::InvalidConfig(format!(
+                    "The client subnet prefix lengths of {server} must be at most 32 for IPv4 and 128 for IPv6"
+                )));
+            }
+        }
+        if resolver.timeout.is_zero() {
+            return Err(DnsError::InvalidConfig(
+                "The query timeout must be positive".to_string(),
+            ));
+        }
+        if let Some(size) = resolver.udp_payload_size
+            && size < MIN_UDP_PAYLOAD_SIZE
+        {
+            return Err(DnsError::InvalidConfig(format!(
+                "UDP payload size {size} is below the minimum of {MIN_UDP_PAYLOAD_SIZE}"
+            )));
+        }
+        if !(1..=Semaphore::MAX_PERMITS).contains(&resolver.max_in_flight) {
+            return Err(DnsError::InvalidConfig(format!(
+                "The in-flight query limit must be between 1 and {}, not {}",
+                Semaphore::MAX_PERMITS,
+                resolver.max_in_flight
+            )));
+        }
+        resolver.in_flight = Arc::new(Semaphore::new(resolver.max_in_flight));
+        Ok(resolver)
+    }
+}
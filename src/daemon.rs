@@ -0,0 +1,1443 @@
+//! A long-running recursive resolver, like a small unbound: it listens for
+//! DNS queries over UDP and TCP and answers them by resolving iteratively
+//! from the root servers, or by forwarding to recursive resolvers, through
+//! the shared record and negative caches. With the `dot` and `doq` features
+//! it can also answer DNS over TLS (RFC 7858) and DNS over QUIC (RFC 9250),
+//! and with the `blocklist` feature refuse names listed in
+//! [blocklists](crate::blocklist), by client. With the `server` feature it
+//! can serve an HTTP API, DNS over HTTPS included. Forwarders can each be
+//! told the subnet of the client a query is for or nothing of it. Zones can
+//! be served from master files, which are [reloaded](crate::authority) as
+//! they are edited, or as secondaries transferred from their primaries,
+//! and their addresses health checked to leave out those that are down.
+//! [Firewall](crate::firewall) rules refuse, drop or answer NXDOMAIN for
+//! queries by name and type before anything else, and UDP clients can be
+//! [rate limited](crate::ratelimit) so the daemon cannot amplify floods.
+//! Configured from a TOML file and started with `dnsr serve`.
+//!
+//! ```toml
+//! listen = "127.0.0.1:5353"
+//! root_servers = ["198.41.0.4", "199.9.14.201"]
+//! timeout_ms = 2000
+//! negative_ttl_ceiling_secs = 300
+//! api_listen = "127.0.0.1:8053"
+//! blocklists = ["https://example.com/hosts.txt"]
+//! blocklist_refresh_secs = 86400
+//! geoip_database = "/var/lib/GeoIP/GeoLite2-Country.mmdb"
+//!
+//! [[zones]]
+//! origin = "home.arpa"
+//! file = "/etc/dnsr/home.arpa.zone"
+//! allow_transfer = ["192.168.1.3"]
+//!
+//! [[zones.regional]]
+//! name = "www"
+//! continents = ["EU"]
+//! addresses = ["192.0.2.10"]
+//!
+//! [[zones.weighted]]
+//! name = "www"
+//! weights = { "192.0.2.1" = 3, "192.0.2.2" = 1 }
+//!
+//! [[zones.health_checks]]
+//! name = "www"
+//! protocol = "http"
+//! port = 80
+//! path = "/healthz"
+//!
+//! [[zones]]
+//! origin = "example.com"
+//! primaries = ["192.0.2.53"]
+//!
+//! [[firewall]]
+//! types = ["ANY"]
+//! action = "refuse"
+//!
+//! [rate_limit]
+//! responses_per_second = 10
+//! exempt = ["192.168.1.0/24"]
+//!
+//! [[groups]]
+//! name = "kids"
+//! clients = ["192.168.1.64/26"]
+//! deny = ["video.example"]
+//!
+//! [tls]
+//! listen = "0.0.0.0:853"
+//! certificate = "/etc/dnsr/fullchain.pem"
+//! private_key = "/etc/dnsr/key.pem"
+//!
+//! [quic]
+//! listen = "0.0.0.0:853"
+//! certificate = "/etc/dnsr/fullchain.pem"
+//! private_key = "/etc/dnsr/key.pem"
+//! ```
+
+use crate::authority::Zones;
+#[cfg(feature = "blocklist")]
+use crate::blocklist::{Blocklists, ClientPolicies, ClientPolicy};
+use crate::builder::MessageBuilder;
+use crate::config::{
+    ClientSubnetPolicy, DEFAULT_MAX_IN_FLIGHT, DEFAULT_NEGATIVE_TTL_CEILING, DEFAULT_RETRIES,
+    DEFAULT_TIMEOUT, DEFAULT_UPSTREAM, QueryOptions, UpstreamPolicy,
+};
+use crate::dashboard::DashboardStats;
+use crate::edns::{ClientSubnet, Edns};
+use crate::error::{DnsError, Result};
+use crate::firewall::Firewall;
+use crate::flags::{DNSFlags, Opcode, Rcode};
+#[cfg(feature = "querylog")]
+use crate::querylog::{self, QueryLog, QueryLogEntry};
+use crate::ratelimit::{RateLimiter, Verdict};
+use crate::transport::{DNS_PORT, DnsTransport};
+use crate::{Class, DNSPacket, DNSRecord, DNSRecordData, DNSResolver, RecordType};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "querylog")]
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{debug, warn};
+
+/// The largest response every client accepts over UDP without EDNS (RFC 1035).
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// How long a TCP connection may sit idle between queries before it is closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The port DNS over TLS is served on (RFC 7858).
+const DOT_PORT: u16 = 853;
+
+/// How often blocklists are downloaded again unless configured.
+const DEFAULT_BLOCKLIST_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The settings of a [`Daemon`], as read from its TOML file. Every field has
+/// a default, so an empty file is a valid configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// The address to listen on, over both UDP and TCP.
+    pub listen: SocketAddr,
+    /// The servers iterative resolution starts from, written `IP` or
+    /// `IP:PORT`. a.root-servers.net unless given.
+    #[serde(deserialize_with = "servers")]
+    pub root_servers: Vec<SocketAddrV4>,
+    /// Recursive resolvers to forward every query to instead of resolving
+    /// from the root, written like `root_servers`.
+    #[serde(deserialize_with = "servers")]
+    pub forwarders: Vec<SocketAddrV4>,
+    /// How the root servers or forwarders are chosen among, written
+    /// `fastest`, `sequential`, `random`, `round-robin` or `race`.
+    pub upstream_policy: UpstreamPolicy,
+    /// EDNS Client Subnet handling by forwarder address, as a table such as
+    /// `"9.9.9.9" = { mode = "inject", ipv4_prefix = 24 }`. Forwarders not
+    /// listed, like those set to `strip`, are told nothing of the client.
+    /// Answers tailored to a subnet are not cached.
+    pub client_subnet: HashMap<Ipv4Addr, ClientSubnetPolicy>,
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub max_in_flight: usize,
+    /// Whether answers, positive and negative, are cached.
+    pub cache: bool,
+    /// Caps how long negative answers are cached, in seconds.
+    pub negative_ttl_ceiling_secs: u64,
+    /// Whether queries sent upstream use DNS 0x20 case randomization.
+    pub randomize_case: bool,
+    /// Where and with which certificate to also serve DNS over TLS, which
+    /// needs the `dot` feature. Off unless given.
+    pub tls: Option<TlsConfig>,
+    /// Likewise for DNS over QUIC, which needs the `doq` feature. It can
+    /// share the port of DNS over TLS, being over UDP.
+    pub quic: Option<TlsConfig>,
+    /// Where to serve the HTTP API, which needs the `server` feature. Off
+    /// unless given.
+    pub api_listen: Option<SocketAddr>,
+    /// URLs or paths of hosts files or domain lists naming what to answer
+    /// NXDOMAIN for, which needs the `blocklist` feature.
+    pub blocklists: Vec<String>,
+    /// How often the blocklists are downloaded again, in seconds.
+    pub blocklist_refresh_secs: u64,
+    /// Groups of clients blocked differently from the rest, which need the
+    /// `blocklist` feature.
+    pub groups: Vec<ClientGroup>,
+    /// Where to keep the query history, which needs the `querylog`
+    /// feature. Off unless given.
+    pub query_log: Option<QueryLogConfig>,
+    /// A directory of files the HTTP API also serves, such as this crate's
+    /// `static` web UI.
+    pub web_root: Option<PathBuf>,
+    /// Zones answered from their master files rather than resolved.
+    pub zones: Vec<ZoneConfig>,
+    /// The MaxMind database the clients of regional records are located
+    /// in, which needs the `geoip` feature.
+    pub geoip_database: Option<PathBuf>,
+    /// Rules for queries to refuse, drop or answer NXDOMAIN for before they
+    /// are answered, the first matching winning.
+    pub firewall: Vec<FirewallRule>,
+    /// Limits on how fast clients are answered over UDP. Off unless given.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// The `[tls]` and `[quic]` tables of a [`DaemonConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// `127.0.0.1:853` unless given.
+    #[serde(default = "default_tls_listen")]
+    pub listen: SocketAddr,
+    /// A PEM file with the server's certificate, followed by any
+    /// intermediates its clients need.
+    pub certificate: PathBuf,
+    /// A PEM file with the certificate's private key, in PKCS #1, PKCS #8
+    /// or SEC1 form.
+    pub private_key: PathBuf,
+}
+
+fn default_tls_listen() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, DOT_PORT))
+}
+
+/// A `[[groups]]` table of a [`DaemonConfig`]: clients, such as children's
+/// devices, with a blocking policy of their own.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientGroup {
+    pub name: String,
+    /// The networks the group's clients are in.
+    pub clients: Vec<ClientNetwork>,
+    /// Whether the top-level blocklists also apply to the group.
+    #[serde(default = "default_true")]
+    pub default_blocklists: bool,
+    /// Blocklists applying to the group only.
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+    /// Names never blocked for the group, with everything under them.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Names always blocked for the group, with everything under them.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A `[[zones]]` table of a [`DaemonConfig`], a zone read from a file or,
+/// as a secondary, transferred from its primaries.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// The zone's apex, which relative names in the file are under.
+    pub origin: String,
+    /// The master file holding the zone's records.
+    pub file: Option<PathBuf>,
+    /// The servers to transfer the zone from, tried in order and written
+    /// like `root_servers`. NOTIFY messages are accepted from them.
+    #[serde(default, deserialize_with = "servers")]
+    pub primaries: Vec<SocketAddrV4>,
+    /// The networks of the clients allowed to transfer the zone, such as
+    /// its secondaries. None unless given.
+    #[serde(default)]
+    pub allow_transfer: Vec<ClientNetwork>,
+    /// Addresses given to clients in some regions in place of the zone's
+    /// own, the first table matching a client's region winning.
+    #[serde(default)]
+    pub regional: Vec<RegionalRecords>,
+    /// How often each address of a name is given out.
+    #[serde(default)]
+    pub weighted: Vec<WeightedRecords>,
+    /// Checks of the addresses of names, one a name.
+    #[serde(default)]
+    pub health_checks: Vec<HealthCheck>,
+}
+
+/// A `[[zones.regional]]` table of a [`ZoneConfig`]: the addresses a name
+/// has for clients in some countries or continents, as `geoip_database`
+/// locates them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionalRecords {
+    /// Relative to the zone's origin unless it ends in a dot.
+    pub name: String,
+    /// ISO 3166 country codes, such as `DE`.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Continent codes: `AF`, `AN`, `AS`, `EU`, `NA`, `OC` or `SA`.
+    #[serde(default)]
+    pub continents: Vec<String>,
+    /// IPv4 addresses answer A queries, and IPv6 addresses AAAA queries.
+    /// A client asking for a kind the table has none of gets the zone's
+    /// own.
+    pub addresses: Vec<IpAddr>,
+    #[serde(default = "default_regional_ttl")]
+    pub ttl: u32,
+}
+
+fn default_regional_ttl() -> u32 {
+    60
+}
+
+/// A `[[zones.weighted]]` table of a [`ZoneConfig`]: weights for the A and
+/// AAAA records of a name, whether from the zone or regional.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeightedRecords {
+    /// Relative to the zone's origin unless it ends in a dot.
+    pub name: String,
+    /// Weights by address, such as `{ "192.0.2.1" = 3, "192.0.2.2" = 1 }`.
+    /// Addresses not listed weigh 1, and those weighing 0 are left out of
+    /// answers unless every address does.
+    pub weights: HashMap<IpAddr, u32>,
+    #[serde(default)]
+    pub select: Selection,
+}
+
+/// How the addresses of a [`WeightedRecords`] are given out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selection {
+    /// One address an answer, each picked with a probability proportional
+    /// to its weight.
+    #[default]
+    One,
+    /// Every address, in an order drawn by weight, so heavier addresses
+    /// tend to come first.
+    Shuffle,
+}
+
+/// A `[[zones.health_checks]]` table of a [`ZoneConfig`]: a check of each A
+/// and AAAA address of a name, whether from the zone or regional, every
+/// interval. Addresses failing it are left out of answers until they pass
+/// again, unless every address of the name fails.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Relative to the zone's origin unless it ends in a dot.
+    pub name: String,
+    #[serde(default)]
+    pub protocol: HealthCheckProtocol,
+    pub port: u16,
+    /// What an HTTP check asks for, `/` unless given. The request's `Host`
+    /// is the name.
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval")]
+    pub interval_secs: u64,
+    /// How long an address has to pass, two seconds unless given.
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_health_check_timeout() -> u64 {
+    2000
+}
+
+/// What a [`HealthCheck`] asks of an address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthCheckProtocol {
+    /// That it accepts a TCP connection on the port.
+    #[default]
+    Tcp,
+    /// That it answers a plain HTTP GET on the port with a 2xx or 3xx
+    /// status.
+    Http,
+}
+
+/// A `[[firewall]]` table of a [`DaemonConfig`]: what to do with queries
+/// for some names, of some types.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FirewallRule {
+    /// Names such as `tracking.example`, matching only themselves, or
+    /// `*.tracking.example`, matching any name under it. Any name unless
+    /// given.
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Types such as `ANY` or `TXT`. Any type unless given.
+    #[serde(default)]
+    pub types: Vec<String>,
+    pub action: FirewallAction,
+}
+
+/// What a [`FirewallRule`] does with the queries it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirewallAction {
+    /// Answers REFUSED.
+    Refuse,
+    /// Sends nothing back, or closes the connection. DNS over HTTPS, which
+    /// has no such thing, answers REFUSED.
+    Drop,
+    /// Answers NXDOMAIN, like a blocked name.
+    Nxdomain,
+}
+
+/// The `[rate_limit]` table of a [`DaemonConfig`]: Response Rate Limiting
+/// (RRL) of UDP clients, counted by network as BIND counts them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// How many responses a second each network is sent, bursts of up to
+    /// as many included.
+    pub responses_per_second: u32,
+    /// One in how many responses past the limit is sent truncated rather
+    /// than dropped: 2 unless given, 1 for all of them, 0 for none.
+    #[serde(default = "default_slip")]
+    pub slip: u32,
+    /// The prefix length of the IPv4 networks clients are counted by, 24
+    /// unless given.
+    #[serde(default = "default_rate_limit_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    /// Likewise for IPv6, 56 unless given.
+    #[serde(default = "default_rate_limit_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// Networks of clients never limited, such as the local network.
+    #[serde(default)]
+    pub exempt: Vec<ClientNetwork>,
+}
+
+fn default_slip() -> u32 {
+    2
+}
+
+fn default_rate_limit_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_rate_limit_ipv6_prefix() -> u8 {
+    56
+}
+
+/// The `[query_log]` table of a [`DaemonConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryLogConfig {
+    /// The SQLite database file, created if missing.
+    pub path: PathBuf,
+    /// How many days queries are kept for, a week unless given.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+fn default_retention_days() -> u64 {
+    7
+}
+
+/// A network of clients in CIDR notation, such as `192.168.1.64/26` or
+/// `fd00::/64`; a bare address is a network of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientNetwork {
+    subnet: ClientSubnet,
+}
+
+impl ClientNetwork {
+    /// Whether `client` is in the network. IPv4 clients of a dual-stack
+    /// listener, seen as IPv4-mapped IPv6 addresses, count as IPv4.
+    pub fn contains(&self, client: IpAddr) -> bool {
+        ClientSubnet::new(client.to_canonical(), self.subnet.source_prefix).address
+            == self.subnet.address
+    }
+}
+
+impl FromStr for ClientNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (address, prefix_len) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("Invalid network {s}, expected an address and prefix length"))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length {len}"))?,
+            None => max_prefix_len,
+        };
+        Ok(ClientNetwork {
+            subnet: ClientSubnet::new(address, prefix_len),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, DNS_PORT)),
+            root_servers: Vec::new(),
+            forwarders: Vec::new(),
+            upstream_policy: UpstreamPolicy::default(),
+            client_subnet: HashMap::new(),
+            timeout_ms: DEFAULT_TIMEOUT.as_millis() as u64,
+            retries: DEFAULT_RETRIES,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            cache: true,
+            negative_ttl_ceiling_secs: DEFAULT_NEGATIVE_TTL_CEILING.as_secs(),
+            randomize_case: false,
+            tls: None,
+            quic: None,
+            api_listen: None,
+            blocklists: Vec::new(),
+            blocklist_refresh_secs: DEFAULT_BLOCKLIST_REFRESH.as_secs(),
+            groups: Vec::new(),
+            query_log: None,
+            web_root: None,
+            zones: Vec::new(),
+            geoip_database: None,
+            firewall: Vec::new(),
+            rate_limit: None,
+        }
+    }
+}
+
+/// Deserializes a list of `IP` or `IP:PORT` strings.
+fn servers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<SocketAddrV4>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|server| {
+            server
+                .parse()
+                .map(|ip| SocketAddrV4::new(ip, DNS_PORT))
+                .or_else(|_| server.parse())
+                .map_err(|_| {
+                    serde::de::Error::custom(format!(
+                        "Invalid server {server}, expected an IPv4 address and optional port"
+                    ))
+                })
+        })
+        .collect()
+}
+
+impl DaemonConfig {
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| DnsError::InvalidConfig(e.message().to_string()))
+    }
+
+    /// Reads the configuration from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// The resolver answering the daemon's queries.
+    pub fn resolver(&self) -> Result<DNSResolver> {
+        if !self.root_servers.is_empty() && !self.forwarders.is_empty() {
+            return Err(DnsError::InvalidConfig(
+                "Set either root_servers or forwarders, not both".to_string(),
+            ));
+        }
+        let forwarding = !self.forwarders.is_empty();
+        let servers = match (forwarding, self.root_servers.is_empty()) {
+            (true, _) => self.forwarders.clone(),
+            (false, false) => self.root_servers.clone(),
+            (false, true) => vec![SocketAddrV4::new(DEFAULT_UPSTREAM, DNS_PORT)],
+        };
+        let mut builder = DNSResolver::builder()
+            .upstreams(servers.iter().map(|server| *server.ip()))
+            .recursion_desired(forwarding)
+            .upstream_policy(self.upstream_policy)
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .retries(self.retries)
+            .max_in_flight(self.max_in_flight)
+            .cache(self.cache)
+            .negative_ttl_ceiling(Duration::from_secs(self.negative_ttl_ceiling_secs))
+            .randomize_case(self.randomize_case);
+        for server in &servers {
+            builder = builder
+                .upstream_port(*server.ip(), server.port())
+                .upstream_client_subnet(*server.ip(), ClientSubnetPolicy::Strip);
+        }
+        for (server, policy) in &self.client_subnet {
+            if !self
+                .forwarders
+                .iter()
+                .any(|forwarder| forwarder.ip() == server)
+            {
+                return Err(DnsError::InvalidConfig(format!(
+                    "client_subnet is set for {server}, which is not a forwarder"
+                )));
+            }
+            builder = builder.upstream_client_subnet(*server, *policy);
+        }
+        builder.build()
+    }
+}
+
+/// A recursive resolver listening on a UDP socket and a TCP listener bound
+/// to the same address, and optionally for DNS over TLS and QUIC, answering
+/// each query with [`DNSResolver::answer`] unless a blocklist covers it.
+#[derive(Debug)]
+pub struct Daemon {
+    udp: UdpSocket,
+    tcp: TcpListener,
+    #[cfg(feature = "dot")]
+    tls: Option<tls::TlsListener>,
+    #[cfg(feature = "doq")]
+    quic: Option<quic::QuicListener>,
+    #[cfg(feature = "server")]
+    api: Option<TcpListener>,
+    responder: Responder,
+    rate_limiter: Option<RateLimiter>,
+    #[cfg(feature = "blocklist")]
+    blocklist_refresh: Duration,
+}
+
+impl Daemon {
+    /// Builds the resolver `config` describes, loads any TLS certificate and
+    /// binds the listening addresses. With port 0, TCP listens on the port
+    /// UDP was given.
+    pub async fn bind(config: &DaemonConfig) -> Result<Self> {
+        let resolver = config.resolver()?;
+        #[cfg(feature = "dot")]
+        let tls = match &config.tls {
+            Some(tls) => Some(tls::TlsListener::bind(tls).await?),
+            None => None,
+        };
+        #[cfg(not(feature = "dot"))]
+        if config.tls.is_some() {
+            return Err(DnsError::InvalidConfig(
+                "DNS over TLS needs the dot feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "doq")]
+        let quic = config
+            .quic
+            .as_ref()
+            .map(quic::QuicListener::bind)
+            .transpose()?;
+        #[cfg(not(feature = "doq"))]
+        if config.quic.is_some() {
+            return Err(DnsError::InvalidConfig(
+                "DNS over QUIC needs the doq feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "server")]
+        let api = match config.api_listen {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        #[cfg(not(feature = "server"))]
+        if config.api_listen.is_some() {
+            return Err(DnsError::InvalidConfig(
+                "The HTTP API needs the server feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "querylog")]
+        let query_log = config
+            .query_log
+            .as_ref()
+            .map(|log| {
+                let retention = Duration::from_secs(log.retention_days * 24 * 60 * 60);
+                QueryLog::open(&log.path, retention)
+            })
+            .transpose()?;
+        #[cfg(not(feature = "querylog"))]
+        if config.query_log.is_some() {
+            return Err(DnsError::InvalidConfig(
+                "The query log needs the querylog feature".to_string(),
+            ));
+        }
+        #[cfg(not(feature = "blocklist"))]
+        if !config.blocklists.is_empty() || !config.groups.is_empty() {
+            return Err(DnsError::InvalidConfig(
+                "Blocklists need the blocklist feature".to_string(),
+            ));
+        }
+        let zones = Zones::load(&config.zones)?;
+        if config.geoip_database.is_none()
+            && config.zones.iter().any(|zone| !zone.regional.is_empty())
+        {
+            return Err(DnsError::InvalidConfig(
+                "Regional records need a geoip_database".to_string(),
+            ));
+        }
+        #[cfg(feature = "geoip")]
+        let zones = match &config.geoip_database {
+            Some(path) => zones.with_geoip(crate::geoip::GeoIp::open(path)?),
+            None => zones,
+        };
+        #[cfg(not(feature = "geoip"))]
+        if config.geoip_database.is_some() {
+            return Err(DnsError::InvalidConfig(
+                "GeoIP needs the geoip feature".to_string(),
+            ));
+        }
+        let firewall = Firewall::new(&config.firewall)?;
+        let rate_limiter = config
+            .rate_limit
+            .as_ref()
+            .map(RateLimiter::new)
+            .transpose()?;
+        let udp = UdpSocket::bind(config.listen).await?;
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+        Ok(Daemon {
+            udp,
+            tcp,
+            #[cfg(feature = "dot")]
+            tls,
+            #[cfg(feature = "doq")]
+            quic,
+            #[cfg(feature = "server")]
+            api,
+            responder: Responder {
+                resolver,
+                zones,
+                firewall,
+                dashboard: DashboardStats::default(),
+                #[cfg(feature = "server")]
+                web_root: config.web_root.clone(),
+                #[cfg(feature = "querylog")]
+                query_log,
+                #[cfg(feature = "blocklist")]
+                policies: ClientPolicies::new(
+                    Blocklists::new(config.blocklists.iter().cloned()),
+                    config.groups.iter().cloned().map(ClientPolicy::new),
+                ),
+            },
+            rate_limiter,
+            #[cfg(feature = "blocklist")]
+            blocklist_refresh: Duration::from_secs(config.blocklist_refresh_secs.max(1)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// The address DNS over TLS is served on, if it is.
+    pub fn tls_addr(&self) -> Option<io::Result<SocketAddr>> {
+        #[cfg(feature = "dot")]
+        let addr = self.tls.as_ref().map(tls::TlsListener::local_addr);
+        #[cfg(not(feature = "dot"))]
+        let addr = None;
+        addr
+    }
+
+    /// The address DNS over QUIC is served on, if it is.
+    pub fn quic_addr(&self) -> Option<io::Result<SocketAddr>> {
+        #[cfg(feature = "doq")]
+        let addr = self.quic.as_ref().map(quic::QuicListener::local_addr);
+        #[cfg(not(feature = "doq"))]
+        let addr = None;
+        addr
+    }
+
+    /// The address the HTTP API is served on, if it is.
+    pub fn api_addr(&self) -> Option<io::Result<SocketAddr>> {
+        #[cfg(feature = "server")]
+        let addr = self.api.as_ref().map(TcpListener::local_addr);
+        #[cfg(not(feature = "server"))]
+        let addr = None;
+        addr
+    }
+
+    /// Answers queries until accepting a connection fails. Each query is
+    /// answered in a task of its own. Blocklists are downloaded first in
+    /// the background, so queries are answered without blocking until then.
+    /// Zone files are watched for changes throughout, secondary zones
+    /// transferred and refreshed, and health checks run.
+    pub async fn run(self) -> io::Result<()> {
+        let mut refresh_tasks = self.responder.zones.spawn_refresh();
+        let mut health_tasks = self.responder.zones.spawn_health_checks();
+        let watch_task = self
+            .responder
+            .zones
+            .watch()
+            .inspect_err(|e| warn!(error = %e, "Zone files will not be reloaded"))
+            .ok();
+        #[cfg(feature = "blocklist")]
+        let refresh_task = self
+            .responder
+            .policies
+            .spawn_refresh(self.blocklist_refresh);
+        #[cfg(feature = "server")]
+        let api_task = self.api.map(|api| {
+            let app = self.responder.api();
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(axum::serve(api, service).into_future())
+        });
+        let udp = Arc::new(self.udp);
+        let udp_task = tokio::spawn(serve_udp(udp, self.responder.clone(), self.rate_limiter));
+        #[cfg(feature = "dot")]
+        let tls_task = self
+            .tls
+            .map(|tls| tokio::spawn(tls.serve(self.responder.clone())));
+        #[cfg(feature = "doq")]
+        let quic_task = self
+            .quic
+            .map(|quic| tokio::spawn(quic.serve(self.responder.clone())));
+        let result = serve_tcp(self.tcp, self.responder).await;
+        udp_task.abort();
+        #[cfg(feature = "dot")]
+        if let Some(tls_task) = tls_task {
+            tls_task.abort();
+        }
+        #[cfg(feature = "doq")]
+        if let Some(quic_task) = quic_task {
+            quic_task.abort();
+        }
+        #[cfg(feature = "server")]
+        if let Some(api_task) = api_task {
+            api_task.abort();
+        }
+        #[cfg(feature = "blocklist")]
+        refresh_task.abort();
+        if let Some(watch_task) = watch_task {
+            watch_task.abort();
+        }
+        refresh_tasks.abort_all();
+        health_tasks.abort_all();
+        result
+    }
+}
+
+/// What answers the daemon's queries, whichever transport they came over.
+#[derive(Debug, Clone)]
+struct Responder {
+    resolver: DNSResolver,
+    zones: Zones,
+    firewall: Firewall,
+    dashboard: DashboardStats,
+    #[cfg(feature = "server")]
+    web_root: Option<PathBuf>,
+    #[cfg(feature = "blocklist")]
+    policies: ClientPolicies,
+    #[cfg(feature = "querylog")]
+    query_log: Option<QueryLog>,
+}
+
+impl Responder {
+    /// The response to `query` from `client`, which decides what is blocked,
+    /// or `None` if the firewall drops it. Names in the daemon's zones are
+    /// answered from them, the rest resolved.
+    async fn respond(&self, query: &DNSPacket, client: IpAddr) -> Option<DNSPacket> {
+        #[cfg(feature = "querylog")]
+        let started = Instant::now();
+        let action = self.firewall.action(query);
+        if let (Some(action), [question]) = (action, query.questions.as_slice()) {
+            debug!(%client, name = %question.name, type_ = %question.type_, ?action, "Firewall rule matched");
+        }
+        let blocked = action.is_some() || self.is_blocked(query, client);
+        let authoritative = match query.header.flags.opcode {
+            Opcode::Query if !blocked => self.zones.answer(query, client),
+            Opcode::Notify => Some(self.zones.notify(query, client)),
+            _ => None,
+        };
+        let (response, from_cache) = match (action, authoritative) {
+            (Some(FirewallAction::Drop), _) => (None, false),
+            (Some(FirewallAction::Refuse), _) => {
+                (Some(local_response(query, Rcode::Refused)), false)
+            }
+            _ if blocked => (Some(local_response(query, Rcode::NxDomain)), false),
+            (_, Some(response)) => (Some(response), false),
+            (_, None) => {
+                let (response, from_cache) =
+                    self.resolver.answer_detailed(query, Some(client)).await;
+                (Some(response), from_cache)
+            }
+        };
+        if let [question] = query.questions.as_slice() {
+            self.dashboard
+                .record(client, &question.name, blocked, from_cache);
+        }
+        #[cfg(feature = "querylog")]
+        if let (Some(query_log), [question]) = (&self.query_log, query.questions.as_slice()) {
+            query_log.record(QueryLogEntry {
+                timestamp_ms: querylog::unix_ms(),
+                client: client.to_canonical(),
+                name: question.name.trim_end_matches('.').to_ascii_lowercase(),
+                record_type: question.type_.to_string(),
+                rcode: response.as_ref().map_or("DROPPED".to_string(), |response| {
+                    response.header.flags.rcode.to_string()
+                }),
+                latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                cache_hit: from_cache,
+                blocked,
+            });
+        }
+        response
+    }
+
+    fn is_blocked(&self, query: &DNSPacket, client: IpAddr) -> bool {
+        #[cfg(feature = "blocklist")]
+        if let [question] = query.questions.as_slice()
+            && query.header.flags.opcode == Opcode::Query
+            && self.policies.is_blocked(client, &question.name)
+        {
+            debug!(%client, name = %question.name, "Answering NXDOMAIN for a blocked name");
+            return true;
+        }
+        #[cfg(not(feature = "blocklist"))]
+        let _ = (query, client);
+        false
+    }
+
+    /// The routes of the daemon's HTTP API: DNS over HTTPS on `/dns-query`,
+    /// answered like any other query, the dashboard's statistics, the state
+    /// of the blocklists and the query history, along with the web UI's
+    /// lookups and any files under `web_root`.
+    #[cfg(feature = "server")]
+    fn api(&self) -> axum::Router {
+        use crate::server::{
+            check_propagation, client_stats, dashboard, doh_get, doh_post, one_client_stats,
+            resolve_dns, resolve_ip, stats,
+        };
+        use axum::routing::get;
+
+        let router = axum::Router::new()
+            .route(
+                "/dns-query",
+                get(doh_get::<Responder>).post(doh_post::<Responder>),
+            )
+            .with_state(self.clone())
+            .merge(
+                axum::Router::new()
+                    .route("/api/stats", get(dashboard))
+                    .with_state((self.dashboard.clone(), self.resolver.clone())),
+            )
+            .merge(
+                axum::Router::new()
+                    .route("/api/stats/clients", get(client_stats))
+                    .route("/api/stats/clients/{client}", get(one_client_stats))
+                    .with_state(self.dashboard.clone()),
+            )
+            .merge(
+                axum::Router::new()
+                    .route("/resolve", get(resolve_dns))
+                    .route("/reverse_resolve", get(resolve_ip))
+                    .route("/stats", get(stats))
+                    .route("/propagation", get(check_propagation))
+                    .with_state(self.resolver.clone()),
+            );
+        #[cfg(feature = "blocklist")]
+        let router = router.merge(
+            axum::Router::new()
+                .route("/api/blocklists", get(crate::server::blocklists))
+                .with_state(self.policies.clone()),
+        );
+        #[cfg(feature = "querylog")]
+        let router = match &self.query_log {
+            Some(query_log) => router.merge(
+                axum::Router::new()
+                    .route("/api/queries", get(crate::server::queries))
+                    .route("/api/queries/summary", get(crate::server::query_summary))
+                    .with_state(query_log.clone()),
+            ),
+            None => router,
+        };
+        match &self.web_root {
+            Some(web_root) => {
+                router.fallback_service(tower_http::services::ServeDir::new(web_root))
+            }
+            None => router,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl crate::server::DohBackend for Responder {
+    async fn answer(&self, client: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+        // A body that does not parse is the client's fault, not an upstream's.
+        let query = DNSPacket::parse(query).map_err(|e| DnsError::InvalidMessage(e.to_string()))?;
+        if query.header.flags.qr {
+            return Err(DnsError::InvalidMessage(
+                "Expected a query, not a response".to_string(),
+            ));
+        }
+        let response = self.respond(&query, client.ip()).await;
+        Ok(response
+            .unwrap_or_else(|| local_response(&query, Rcode::Refused))
+            .to_bytes())
+    }
+}
+
+/// The NXDOMAIN a blocked name is answered with, or the REFUSED of the
+/// firewall, carrying no SOA, so clients do not cache it past a blocklist
+/// or configuration update. A question that cannot be echoed gets a
+/// FORMERR without it instead.
+fn local_response(query: &DNSPacket, rcode: Rcode) -> DNSPacket {
+    let flags = DNSFlags {
+        qr: true,
+        opcode: query.header.flags.opcode,
+        rd: query.header.flags.rd,
+        ra: true,
+        rcode,
+        ..DNSFlags::default()
+    };
+    let mut builder = MessageBuilder::response_to(query).flags(flags);
+    if query.edns.is_some() {
+        builder = builder.edns(Edns::default());
+    }
+    builder.build().unwrap_or_else(|_| {
+        let flags = DNSFlags {
+            rcode: Rcode::FormErr,
+            ..flags
+        };
+        MessageBuilder::bare_response(query, flags)
+    })
+}
+
+/// Answers UDP queries, each in a task of its own, those of clients past
+/// their rate limit with nothing or an empty truncated response.
+async fn serve_udp(
+    socket: Arc<UdpSocket>,
+    responder: Responder,
+    rate_limiter: Option<RateLimiter>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        // Errors here come from one peer, such as an ICMP port unreachable
+        // reported back, and do not stop the others being served.
+        let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Ok(query) = DNSPacket::parse(&buf[..len]) else {
+            debug!(%peer, "Ignoring a malformed query");
+            continue;
+        };
+        if query.header.flags.qr {
+            continue;
+        }
+        match rate_limiter
+            .as_ref()
+            .map(|limiter| limiter.check(peer.ip()))
+        {
+            Some(Verdict::Drop) => {
+                debug!(%peer, "Dropping a response past the rate limit");
+                continue;
+            }
+            Some(Verdict::Slip) => {
+                if let Some(slipped) = slipped_response(&query) {
+                    let _ = socket.send_to(&slipped, peer).await;
+                }
+                continue;
+            }
+            Some(Verdict::Respond) | None => {}
+        }
+        let socket = socket.clone();
+        let responder = responder.clone();
+        tokio::spawn(async move {
+            if let Some(response) = responder.respond(&query, peer.ip()).await {
+                let _ = socket.send_to(&udp_bytes(&query, response), peer).await;
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, responder: Responder) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(serve_connection(stream, responder.clone(), peer.ip()));
+    }
+}
+
+/// Answers the length-prefixed queries of one TCP or TLS connection in turn,
+/// until the client closes it, sends something that is not a query or goes
+/// idle.
+async fn serve_connection<S>(mut stream: S, responder: Responder, client: IpAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let Ok(Ok(len)) = tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await else {
+            return;
+        };
+        let mut buf = vec![0; len as usize];
+        if stream.read_exact(&mut buf).await.is_err() {
+            return;
+        }
+        let Ok(query) = DNSPacket::parse(&buf) else {
+            return;
+        };
+        if query.header.flags.qr {
+            return;
+        }
+        let transfer = match responder.firewall.action(&query) {
+            Some(_) => None,
+            None => responder.zones.transfer(&query, client),
+        };
+        let responses = match transfer {
+            Some(transfer) => transfer,
+            None => match responder.respond(&query, client).await {
+                Some(response) => vec![response],
+                None => return,
+            },
+        };
+        let mut message = Vec::new();
+        for response in responses {
+            let response = response.to_bytes();
+            let Ok(len) = u16::try_from(response.len()) else {
+                return;
+            };
+            message.extend_from_slice(&len.to_be_bytes());
+            message.extend_from_slice(&response);
+        }
+        // Flushed for TLS, which otherwise holds the response back.
+        if stream.write_all(&message).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// DNS over TLS: a TCP listener whose connections are TLS wrapped, carrying
+/// the same length-prefixed messages as plain TCP.
+#[cfg(feature = "dot")]
+mod tls {
+    use super::{Responder, TCP_IDLE_TIMEOUT, TlsConfig, serve_connection};
+    use crate::error::{DnsError, Result};
+    use std::fmt;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    pub(super) struct TlsListener {
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    }
+
+    impl fmt::Debug for TlsListener {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("TlsListener")
+                .field("listener", &self.listener)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl TlsListener {
+        pub(super) async fn bind(config: &TlsConfig) -> Result<Self> {
+            let server_config = server_config(config, b"dot")?;
+            Ok(TlsListener {
+                listener: TcpListener::bind(config.listen).await?,
+                acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            })
+        }
+
+        pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.listener.local_addr()
+        }
+
+        pub(super) async fn serve(self, responder: Responder) -> io::Result<()> {
+            loop {
+                let (stream, peer) = self.listener.accept().await?;
+                let acceptor = self.acceptor.clone();
+                let responder = responder.clone();
+                tokio::spawn(async move {
+                    let handshake = tokio::time::timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream));
+                    if let Ok(Ok(stream)) = handshake.await {
+                        serve_connection(stream, responder, peer.ip()).await;
+                    }
+                });
+            }
+        }
+    }
+
+    /// A TLS configuration presenting the certificate `config` names, and
+    /// offering `alpn` as the only application protocol.
+    pub(super) fn server_config(config: &TlsConfig, alpn: &[u8]) -> Result<ServerConfig> {
+        let certificates = CertificateDer::pem_slice_iter(&read(&config.certificate)?)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| invalid_pem(&config.certificate, e))?;
+        let key = PrivateKeyDer::from_pem_slice(&read(&config.private_key)?)
+            .map_err(|e| invalid_pem(&config.private_key, e))?;
+        let mut server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(|e| DnsError::InvalidConfig(e.to_string()))?;
+        server_config.alpn_protocols = vec![alpn.to_vec()];
+        Ok(server_config)
+    }
+
+    fn read(path: &Path) -> Result<Vec<u8>> {
+        std::fs::read(path)
+            .map_err(|e| DnsError::InvalidConfig(format!("Cannot read {}: {e}", path.display())))
+    }
+
+    fn invalid_pem(path: &Path, e: impl fmt::Display) -> DnsError {
+        DnsError::InvalidConfig(format!("Invalid PEM in {}: {e}", path.display()))
+    }
+}
+
+/// What a client past its rate limit is sometimes sent: the header and
+/// question alone with TC set, so a real client retries over TCP, while
+/// amplifying nothing. `None` if the question cannot be echoed, as when a
+/// label holds a dot, the query then being dropped.
+fn slipped_response(query: &DNSPacket) -> Option<Vec<u8>> {
+    let response = MessageBuilder::response_to(query)
+        .flags(DNSFlags {
+            qr: true,
+            tc: true,
+            opcode: query.header.flags.opcode,
+            rd: query.header.flags.rd,
+            ra: true,
+            ..DNSFlags::default()
+        })
+        .build()
+        .ok()?;
+    Some(response.to_bytes())
+}
+
+/// `response` encoded for UDP: whole if it fits the payload size the query
+/// advertised, and otherwise only its header and question with TC set, so
+/// the client retries over TCP.
+fn udp_bytes(query: &DNSPacket, response: DNSPacket) -> Vec<u8> {
+    let limit = query.edns.as_ref().map_or(MIN_UDP_PAYLOAD_SIZE, |edns| {
+        edns.udp_payload_size.max(MIN_UDP_PAYLOAD_SIZE)
+    });
+    let bytes = response.to_bytes();
+    if bytes.len() <= limit as usize {
+        return bytes;
+    }
+    let mut builder = MessageBuilder::response_to(query).flags(DNSFlags {
+        tc: true,
+        ..response.header.flags
+    });
+    if let Some(edns) = response.edns {
+        builder = builder.edns(edns);
+    }
+    builder
+        .build()
+        .map_or(bytes, |truncated| truncated.to_bytes())
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// The response a recursive server gives to `query`: its question
+    /// resolved, with RA set. Where CNAMEs were followed, the answer starts
+    /// with a single CNAME from the queried name to the name holding the
+    /// records, the chain between them not being kept. Failures other than
+    /// NXDOMAIN and NODATA are answered with SERVFAIL, queries with an
+    /// opcode other than QUERY with NOTIMP, and those without exactly one
+    /// question with FORMERR.
+    /// Upstreams set to [`ClientSubnetPolicy::Inject`] are told the subnet
+    /// the query carries, if any.
+    pub async fn answer(&self, query: &DNSPacket) -> DNSPacket {
+        self.answer_detailed(query, None).await.0
+    }
+
+    /// Like [`DNSResolver::answer`], also telling whether the records came
+    /// from the cache. Without a subnet in the query, upstreams injecting
+    /// one are told that of `client`.
+    pub(crate) async fn answer_detailed(
+        &self,
+        query: &DNSPacket,
+        client: Option<IpAddr>,
+    ) -> (DNSPacket, bool) {
+        let mut from_cache = false;
+        let flags = DNSFlags {
+            qr: true,
+            opcode: query.header.flags.opcode,
+            rd: query.header.flags.rd,
+            ra: true,
+            ..DNSFlags::default()
+        };
+        let mut builder = MessageBuilder::response_to(query).flags(flags);
+        if query.edns.is_some() {
+            builder = builder.edns(Edns::default());
+        }
+        let rcode = match query.questions.as_slice() {
+            _ if query.header.flags.opcode != Opcode::Query => Rcode::NotImp,
+            [question] => {
+                let name = &question.name;
+                let client_subnet = query
+                    .edns
+                    .as_ref()
+                    .and_then(Edns::client_subnet)
+                    .cloned()
+                    .or_else(|| {
+                        let client = client?.to_canonical();
+                        Some(ClientSubnet::new(client, u8::MAX))
+                    });
+                let options = QueryOptions {
+                    client_subnet,
+                    ..QueryOptions::default()
+                };
+                match self
+                    .resolve_detailed(name, question.type_.clone(), &options)
+                    .await
+                {
+                    Ok(result) => {
+                        from_cache = result.from_cache;
+                        let owner = result.records.first().map(|record| &record.name);
+                        if let Some(owner) = owner.filter(|owner| !same_name(owner, name)) {
+                            builder = builder.answer(DNSRecord::new(
+                                name,
+                                RecordType::Cname,
+                                Class::In,
+                                result.min_ttl().unwrap_or_default(),
+                                DNSRecordData::Name(owner.clone()),
+                            ));
+                        }
+                        builder = result
+                            .records
+                            .into_iter()
+                            .fold(builder, MessageBuilder::answer);
+                        Rcode::NoError
+                    }
+                    Err(DnsError::NxDomain(_)) => Rcode::NxDomain,
+                    Err(DnsError::NoRecordsFound(_)) => Rcode::NoError,
+                    Err(DnsError::InvalidName(_)) => Rcode::FormErr,
+                    Err(e) => {
+                        debug!(%name, error = %e, "Answering SERVFAIL");
+                        Rcode::ServFail
+                    }
+                }
+            }
+            _ => Rcode::FormErr,
+        };
+        let response = builder.rcode(rcode).build().unwrap_or_else(|_| {
+            MessageBuilder::query()
+                .id(query.header.id)
+                .flags(DNSFlags {
+                    rcode: Rcode::ServFail,
+                    ..flags
+                })
+                .build()
+                .expect("a response without questions or records is valid")
+        });
+        (response, from_cache)
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// DNS over QUIC (RFC 9250): each query arrives on a bidirectional stream of
+/// its own, length-prefixed as over TCP and with a message ID of 0, and its
+/// response is sent back on the same stream.
+#[cfg(feature = "doq")]
+mod quic {
+    use super::{Responder, TlsConfig, tls};
+    use crate::DNSPacket;
+    use crate::error::{DnsError, Result};
+    use quinn::crypto::rustls::QuicServerConfig;
+    use quinn::{Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use tracing::debug;
+
+    /// Closes a connection whose client broke the protocol, such as by
+    /// sending a query with a non-zero ID.
+    const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+
+    #[derive(Debug)]
+    pub(super) struct QuicListener {
+        endpoint: Endpoint,
+    }
+
+    impl QuicListener {
+        pub(super) fn bind(config: &TlsConfig) -> Result<Self> {
+            let crypto = QuicServerConfig::try_from(tls::server_config(config, b"doq")?)
+                .map_err(|e| DnsError::InvalidConfig(e.to_string()))?;
+            let server_config = ServerConfig::with_crypto(Arc::new(crypto));
+            Ok(QuicListener {
+                endpoint: Endpoint::server(server_config, config.listen)?,
+            })
+        }
+
+        pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.endpoint.local_addr()
+        }
+
+        /// Accepts connections until the endpoint is closed.
+        pub(super) async fn serve(self, responder: Responder) -> io::Result<()> {
+            while let Some(incoming) = self.endpoint.accept().await {
+                let responder = responder.clone();
+                tokio::spawn(async move {
+                    if let Ok(connection) = incoming.await {
+                        serve_connection(connection, responder).await;
+                    }
+                });
+            }
+            Ok(())
+        }
+    }
+
+    async fn serve_connection(connection: Connection, responder: Responder) {
+        let client = connection.remote_address().ip();
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            let connection = connection.clone();
+            let responder = responder.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_stream(send, recv, &responder, client).await {
+                    debug!(error = %e, "Closing a DNS over QUIC connection");
+                    connection.close(DOQ_PROTOCOL_ERROR, b"");
+                }
+            });
+        }
+    }
+
+    /// Answers the one query a stream carries.
+    async fn serve_stream(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        responder: &Responder,
+        client: IpAddr,
+    ) -> Result<()> {
+        let message = recv
+            .read_to_end(2 + u16::MAX as usize)
+            .await
+            .map_err(|e| DnsError::MalformedPacket(e.to_string()))?;
+        let framed = message
+            .split_first_chunk()
+            .filter(|(prefix, query)| u16::from_be_bytes(**prefix) as usize == query.len());
+        let Some((_, query)) = framed else {
+            return Err(DnsError::MalformedPacket(
+                "Length prefix does not match the stream".to_string(),
+            ));
+        };
+        let query = DNSPacket::parse(query)?;
+        if query.header.id != 0 || query.header.flags.qr {
+            return Err(DnsError::MalformedPacket(
+                "Expected a query with an ID of 0".to_string(),
+            ));
+        }
+        let Some(response) = responder.respond(&query, client).await else {
+            // Finishing the stream without a response.
+            send.finish().map_err(|e| io::Error::other(e.to_string()))?;
+            return Ok(());
+        };
+        let response = response.to_bytes();
+        let len = u16::try_from(response.len())
+            .map_err(|_| DnsError::InvalidMessage("Response too long".to_string()))?;
+        let mut message = len.to_be_bytes().to_vec();
+        message.extend_from_slice(&response);
+        send.write_all(&message).await.map_err(io::Error::from)?;
+        send.finish().map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+//! Running totals of the queries the daemon answers, kept in memory for the
+//! web UI's live dashboard at `/api/stats`: the most queried and most
+//! blocked names, queries per second over the last hour, how often the
+//! cache answered, and what each client asked for.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many distinct names are counted before the least queried half is
+/// forgotten, bounding memory however many names clients ask for.
+pub(crate) const MAX_TRACKED_NAMES: usize = 10_000;
+
+/// Likewise for the names each client asked for.
+const MAX_TRACKED_NAMES_PER_CLIENT: usize = 1_000;
+
+/// How many clients are counted before the least active half is forgotten.
+pub(crate) const MAX_TRACKED_CLIENTS: usize = 1_000;
+
+/// How many names each ranking lists.
+const TOP_NAMES: usize = 10;
+
+/// How many clients `/api/stats` ranks.
+const TOP_CLIENTS: usize = 10;
+
+/// How many minutes of query rates are kept.
+const RATE_MINUTES: u64 = 60;
+
+const MINUTE_MS: u64 = 60_000;
+
+/// Totals since the daemon started, with rankings and rates. Clones share
+/// the counts.
+#[derive(Debug, Clone, Default)]
+pub struct DashboardStats {
+    inner: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    queries: u64,
+    blocked: u64,
+    cache_hits: u64,
+    names: HashMap<String, u64>,
+    blocked_names: HashMap<String, u64>,
+    /// Queries in each minute, as minutes since the Unix epoch, oldest first.
+    minutes: VecDeque<(u64, u64)>,
+    clients: HashMap<IpAddr, ClientCounts>,
+}
+
+#[derive(Debug, Default)]
+struct ClientCounts {
+    queries: u64,
+    blocked: u64,
+    names: HashMap<String, u64>,
+}
+
+impl ClientCounts {
+    fn snapshot(&self, client: IpAddr) -> ClientSnapshot {
+        ClientSnapshot {
+            client,
+            queries: self.queries,
+            blocked: self.blocked,
+            top_domains: top(&self.names),
+        }
+    }
+}
+
+/// What `/api/stats` reports of the queries answered.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DashboardSnapshot {
+    pub queries: u64,
+    pub blocked: u64,
+    pub cache_hits: u64,
+    /// The share of queries answered from the cache, once there are any.
+    pub cache_hit_ratio: Option<f64>,
+    /// The most queried names, the most queried first.
+    pub top_domains: Vec<NameCount>,
+    /// The names most often blocked.
+    pub top_blocked: Vec<NameCount>,
+    /// The average rate of each minute of the last hour, oldest first, the
+    /// current minute so far last.
+    pub qps: Vec<RateSample>,
+    /// The clients that sent the most queries, the busiest first.
+    pub top_clients: Vec<ClientSnapshot>,
+}
+
+/// What one client asked for, for `/api/stats/clients`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientSnapshot {
+    pub client: IpAddr,
+    pub queries: u64,
+    pub blocked: u64,
+    /// The names the client queried most.
+    pub top_domains: Vec<NameCount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NameCount {
+    pub name: String,
+    pub queries: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RateSample {
+    /// The start of the minute, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub qps: f64,
+}
+
+impl DashboardStats {
+    /// Counts a query from `client` for `name`, which was blocked or
+    /// answered from the cache as told.
+    pub fn record(&self, client: IpAddr, name: &str, blocked: bool, cache_hit: bool) {
+        self.record_at(unix_ms(), client, name, blocked, cache_hit);
+    }
+
+    pub(crate) fn record_at(
+        &self,
+        now_ms: u64,
+        client: IpAddr,
+        name: &str,
+        blocked: bool,
+        cache_hit: bool,
+    ) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut counts = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        counts.queries += 1;
+        counts.cache_hits += u64::from(cache_hit);
+        if blocked {
+            counts.blocked += 1;
+            count(&mut counts.blocked_names, &name, MAX_TRACKED_NAMES);
+        }
+        count(&mut counts.names, &name, MAX_TRACKED_NAMES);
+        let client = client.to_canonical();
+        if !counts.clients.contains_key(&client) && counts.clients.len() >= MAX_TRACKED_CLIENTS {
+            forget_least(&mut counts.clients, |client| client.queries);
+        }
+        let client = counts.clients.entry(client).or_default();
+        client.queries += 1;
+        client.blocked += u64::from(blocked);
+        count(&mut client.names, &name, MAX_TRACKED_NAMES_PER_CLIENT);
+        let minute = now_ms / MINUTE_MS;
+        match counts.minutes.back_mut() {
+            Some((last, queries)) if *last == minute => *queries += 1,
+            _ => counts.minutes.push_back((minute, 1)),
+        }
+        while counts
+            .minutes
+            .front()
+            .is_some_and(|(first, _)| first + RATE_MINUTES <= minute)
+        {
+            counts.minutes.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self) -> DashboardSnapshot {
+        self.snapshot_at(unix_ms())
+    }
+
+    pub(crate) fn snapshot_at(&self, now_ms: u64) -> DashboardSnapshot {
+        let counts = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let current = now_ms / MINUTE_MS;
+        let elapsed_secs = ((now_ms % MINUTE_MS) as f64 / 1000.0).max(1.0);
+        let qps = (current + 1 - RATE_MINUTES..=current)
+            .map(|minute| {
+                let queries = counts
+                    .minutes
+                    .iter()
+                    .find(|(m, _)| *m == minute)
+                    .map_or(0, |(_, queries)| *queries);
+                let secs = if minute == current {
+                    elapsed_secs
+                } else {
+                    60.0
+                };
+                RateSample {
+                    timestamp_ms: minute * MINUTE_MS,
+                    qps: queries as f64 / secs,
+                }
+            })
+            .collect();
+        DashboardSnapshot {
+            queries: counts.queries,
+            blocked: counts.blocked,
+            cache_hits: counts.cache_hits,
+            cache_hit_ratio: (counts.queries > 0)
+                .then(|| counts.cache_hits as f64 / counts.queries as f64),
+            top_domains: top(&counts.names),
+            top_blocked: top(&counts.blocked_names),
+            qps,
+            top_clients: clients(&counts.clients, TOP_CLIENTS),
+        }
+    }
+
+    /// Every client counted, the busiest first.
+    pub fn clients(&self) -> Vec<ClientSnapshot> {
+        let counts = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        clients(&counts.clients, usize::MAX)
+    }
+
+    /// What `client` asked for, if it has been counted.
+    pub fn client(&self, client: IpAddr) -> Option<ClientSnapshot> {
+        let counts = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let client = client.to_canonical();
+        counts
+            .clients
+            .get(&client)
+            .map(|counts| counts.snapshot(client))
+    }
+}
+
+fn clients(clients: &HashMap<IpAddr, ClientCounts>, limit: usize) -> Vec<ClientSnapshot> {
+    let mut ranked: Vec<(&IpAddr, &ClientCounts)> = clients.iter().collect();
+    ranked.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(b.0)));
+    ranked
+        .into_iter()
+        .take(limit)
+        .map(|(client, counts)| counts.snapshot(*client))
+        .collect()
+}
+
+/// Counts a query for `name`, first forgetting the less queried half of
+/// `names` if it already holds `limit` of them.
+fn count(names: &mut HashMap<String, u64>, name: &str, limit: usize) {
+    match names.get_mut(name) {
+        Some(queries) => *queries += 1,
+        None => {
+            if names.len() >= limit {
+                forget_least(names, |queries| *queries);
+            }
+            names.insert(name.to_string(), 1);
+        }
+    }
+}
+
+/// Forgets the half of `counts` with the fewest queries. Only half goes
+/// however many tie, so that where most were queried once, as is usual,
+/// the rest are not all forgotten.
+fn forget_least<K: Clone + Eq + Hash, V>(counts: &mut HashMap<K, V>, queries: impl Fn(&V) -> u64) {
+    let mut least: Vec<(u64, K)> = counts
+        .iter()
+        .map(|(key, value)| (queries(value), key.clone()))
+        .collect();
+    let forgotten = least.len() / 2;
+    least.select_nth_unstable_by_key(forgotten, |(queries, _)| *queries);
+    for (_, key) in &least[..forgotten] {
+        counts.remove(key);
+    }
+}
+
+fn top(names: &HashMap<String, u64>) -> Vec<NameCount> {
+    let mut ranked: Vec<NameCount> = names
+        .iter()
+        .map(|(name, queries)| NameCount {
+            name: name.clone(),
+            queries: *queries,
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.queries.cmp(&a.queries).then_with(|| a.name.cmp(&b.name)));
+    ranked.truncate(TOP_NAMES);
+    ranked
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
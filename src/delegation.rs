@@ -0,0 +1,490 @@
+//! Checking that a zone is delegated properly: that its parent and its own
+//! servers list the same nameservers, that those have glue and answer
+//! authoritatively, and that they all serve the same version of the zone.
+
+use crate::config::{CachePolicy, QueryOptions};
+use crate::flags::Rcode;
+use crate::transport::DnsTransport;
+use crate::{
+    Class, DNSPacket, DNSRecord, DNSRecordData, DNSResolver, HopOutcome, RecordType, fqdn,
+};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// How one nameserver address answered for the zone's SOA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerOutcome {
+    /// An authoritative SOA answer, with the zone's serial.
+    Authoritative { serial: u32 },
+    /// A response, but not an authoritative one for the zone: a referral, an
+    /// error code or an answer without the AA flag.
+    Lame(String),
+    /// No response at all.
+    Unreachable(String),
+}
+
+/// One address of a nameserver and how it answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressCheck {
+    pub address: Ipv4Addr,
+    pub outcome: ServerOutcome,
+    pub rtt: Duration,
+}
+
+/// A nameserver of the zone, as listed by its parent, by itself, or both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameserverCheck {
+    /// The server's name, lowercase and without a trailing dot.
+    pub name: String,
+    pub at_parent: bool,
+    pub at_child: bool,
+    /// The A and AAAA glue the parent gave for the server.
+    pub glue: Vec<IpAddr>,
+    /// The IPv4 addresses checked: the glue if any, or else the server's
+    /// resolved addresses.
+    pub addresses: Vec<AddressCheck>,
+    /// Why the server's addresses could not be resolved.
+    pub error: Option<String>,
+}
+
+impl NameserverCheck {
+    /// Whether the server is within the zone, so that it can only be found
+    /// through glue.
+    pub fn needs_glue(&self, zone: &str) -> bool {
+        let zone = zone.trim_end_matches('.');
+        zone.is_empty()
+            || self
+                .name
+                .strip_suffix(&zone.to_ascii_lowercase())
+                .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+    }
+}
+
+/// Something wrong with a delegation, as found by
+/// [`DNSResolver::check_delegation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DelegationIssue {
+    /// No referral to the zone was seen on the way from the root, so the
+    /// parent's view is unknown.
+    NoReferral,
+    /// Listed by the parent but not by the zone's own NS records.
+    OnlyAtParent(String),
+    /// Listed by the zone but not by the parent, so never referred to.
+    OnlyAtChild(String),
+    /// A nameserver within the zone that the parent gives no glue for.
+    MissingGlue(String),
+    /// A nameserver whose addresses could not be found.
+    NoAddress { nameserver: String, error: String },
+    Lame {
+        nameserver: String,
+        address: Ipv4Addr,
+        reason: String,
+    },
+    Unreachable {
+        nameserver: String,
+        address: Ipv4Addr,
+        error: String,
+    },
+    /// Servers answering with different SOA serials, each serial with the
+    /// servers giving it.
+    SerialMismatch(Vec<(u32, Vec<String>)>),
+}
+
+impl fmt::Display for DelegationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelegationIssue::NoReferral => write!(f, "No referral to the zone from its parent"),
+            DelegationIssue::OnlyAtParent(name) => {
+                write!(f, "{} is listed by the parent but not the zone", fqdn(name))
+            }
+            DelegationIssue::OnlyAtChild(name) => {
+                write!(f, "{} is listed by the zone but not the parent", fqdn(name))
+            }
+            DelegationIssue::MissingGlue(name) => {
+                write!(f, "{} is within the zone but has no glue", fqdn(name))
+            }
+            DelegationIssue::NoAddress { nameserver, error } => {
+                write!(f, "{} has no address: {error}", fqdn(nameserver))
+            }
+            DelegationIssue::Lame {
+                nameserver,
+                address,
+                reason,
+            } => write!(f, "{} at {address} is lame: {reason}", fqdn(nameserver)),
+            DelegationIssue::Unreachable {
+                nameserver,
+                address,
+                error,
+            } => write!(
+                f,
+                "{} at {address} is unreachable: {error}",
+                fqdn(nameserver)
+            ),
+            DelegationIssue::SerialMismatch(serials) => {
+                write!(f, "SOA serials differ:")?;
+                for (serial, servers) in serials {
+                    write!(f, " {serial} from {}", servers.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The parent's and the zone's view of a delegation, and how each
+/// nameserver answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delegation {
+    /// The zone, without a trailing dot.
+    pub zone: String,
+    /// The parent server that referred to the zone.
+    pub parent_server: Option<Ipv4Addr>,
+    /// The NS names in the parent's referral, sorted.
+    pub parent_ns: Vec<String>,
+    /// The NS names the zone's own servers gave, sorted.
+    pub child_ns: Vec<String>,
+    /// Every nameserver in either list, sorted by name.
+    pub nameservers: Vec<NameserverCheck>,
+}
+
+impl Delegation {
+    /// Everything wrong with the delegation, in the order the nameservers are
+    /// listed, then any serial mismatch.
+    pub fn issues(&self) -> Vec<DelegationIssue> {
+        let mut issues = Vec::new();
+        if self.parent_server.is_none() {
+            issues.push(DelegationIssue::NoReferral);
+        }
+        let mut serials: Vec<(u32, Vec<String>)> = Vec::new();
+        for nameserver in &self.nameservers {
+            let name = &nameserver.name;
+            if self.parent_server.is_some() && !nameserver.at_parent {
+                issues.push(DelegationIssue::OnlyAtChild(name.clone()));
+            }
+            if !self.child_ns.is_empty() && !nameserver.at_child {
+                issues.push(DelegationIssue::OnlyAtParent(name.clone()));
+            }
+            if nameserver.at_parent
+                && nameserver.glue.is_empty()
+                && nameserver.needs_glue(&self.zone)
+            {
+                issues.push(DelegationIssue::MissingGlue(name.clone()));
+            }
+            if let Some(error) = &nameserver.error {
+                issues.push(DelegationIssue::NoAddress {
+                    nameserver: name.clone(),
+                    error: error.clone(),
+                });
+            }
+            for check in &nameserver.addresses {
+                match &check.outcome {
+                    ServerOutcome::Authoritative { serial } => {
+                        let server = format!("{} ({})", fqdn(name), check.address);
+                        match serials.iter_mut().find(|(seen, _)| seen == serial) {
+                            Some((_, servers)) => servers.push(server),
+                            None => serials.push((*serial, vec![server])),
+                        }
+                    }
+                    ServerOutcome::Lame(reason) => issues.push(DelegationIssue::Lame {
+                        nameserver: name.clone(),
+                        address: check.address,
+                        reason: reason.clone(),
+                    }),
+                    ServerOutcome::Unreachable(error) => {
+                        issues.push(DelegationIssue::Unreachable {
+                            nameserver: name.clone(),
+                            address: check.address,
+                            error: error.clone(),
+                        })
+                    }
+                }
+            }
+        }
+        if serials.len() > 1 {
+            issues.push(DelegationIssue::SerialMismatch(serials));
+        }
+        issues
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.issues().is_empty()
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    zone: String,
+    healthy: bool,
+    parent_server: Option<Ipv4Addr>,
+    parent_ns: Vec<String>,
+    child_ns: Vec<String>,
+    nameservers: Vec<Nameserver<'a>>,
+    issues: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Nameserver<'a> {
+    name: String,
+    at_parent: bool,
+    at_child: bool,
+    glue: &'a [IpAddr],
+    addresses: Vec<Address<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Address<'a> {
+    address: Ipv4Addr,
+    /// One of authoritative, lame or unreachable.
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    rtt_ms: u64,
+}
+
+/// A flat object with the parent's and the zone's NS lists, each nameserver
+/// with its glue and how each of its addresses answered, and the issues found
+/// as messages.
+impl Serialize for Delegation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names = |names: &[String]| names.iter().map(|name| fqdn(name)).collect();
+        let nameservers = self
+            .nameservers
+            .iter()
+            .map(|nameserver| Nameserver {
+                name: fqdn(&nameserver.name),
+                at_parent: nameserver.at_parent,
+                at_child: nameserver.at_child,
+                glue: &nameserver.glue,
+                addresses: nameserver
+                    .addresses
+                    .iter()
+                    .map(|check| {
+                        let (status, serial, error) = match &check.outcome {
+                            ServerOutcome::Authoritative { serial } => {
+                                ("authoritative", Some(*serial), None)
+                            }
+                            ServerOutcome::Lame(reason) => ("lame", None, Some(reason.as_str())),
+                            ServerOutcome::Unreachable(error) => {
+                                ("unreachable", None, Some(error.as_str()))
+                            }
+                        };
+                        Address {
+                            address: check.address,
+                            status,
+                            serial,
+                            error,
+                            rtt_ms: check.rtt.as_millis() as u64,
+                        }
+                    })
+                    .collect(),
+                error: nameserver.error.as_deref(),
+            })
+            .collect();
+        let issues = self.issues();
+        Report {
+            zone: fqdn(&self.zone),
+            healthy: issues.is_empty(),
+            parent_server: self.parent_server,
+            parent_ns: names(&self.parent_ns),
+            child_ns: names(&self.child_ns),
+            nameservers,
+            issues: issues.iter().map(ToString::to_string).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The NS names of `section` owned by `zone`, lowercase and sorted.
+fn ns_names(section: &[DNSRecord], zone: &str) -> Vec<String> {
+    let mut names: Vec<String> = section
+        .iter()
+        .filter(|record| {
+            record.type_ == RecordType::Ns
+                && record.name.trim_end_matches('.').eq_ignore_ascii_case(zone)
+        })
+        .filter_map(|record| match &record.data {
+            DNSRecordData::Name(name) => Some(name.trim_end_matches('.').to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+impl<T: DnsTransport + Clone + 'static> DNSResolver<T> {
+    /// Traces `zone`'s NS records from the upstreams to find the parent's
+    /// referral and the zone's own answer, then asks every nameserver either
+    /// names for the zone's SOA, at each of its glue or resolved addresses, all
+    /// at once and without recursion. Only IPv4 addresses are asked; AAAA glue
+    /// is reported but not checked. Nothing is read from the caches.
+    pub async fn check_delegation(&self, zone: &str) -> Delegation {
+        let zone = zone.trim_end_matches('.').to_string();
+        let options = QueryOptions {
+            cache: CachePolicy::Bypass,
+            ..QueryOptions::default()
+        };
+        let trace = self
+            .resolve_trace_with(&zone, RecordType::Ns, &options)
+            .await;
+        let referral = trace.hops.iter().rev().find(|hop| {
+            matches!(&hop.outcome, HopOutcome::Referral { zone: to, .. } if to.trim_end_matches('.').eq_ignore_ascii_case(&zone))
+        });
+        let parent: Option<(Ipv4Addr, &DNSPacket)> =
+            referral.and_then(|hop| Some((hop.server, hop.response.as_ref()?)));
+        let parent_ns = parent.map_or_else(Vec::new, |(_, response)| {
+            ns_names(&response.authorities, &zone)
+        });
+        let mut child_ns = match &trace.result {
+            Ok(records) => ns_names(records, &zone),
+            Err(_) => Vec::new(),
+        };
+
+        let mut names = parent_ns.clone();
+        names.extend(child_ns.iter().cloned());
+        names.sort();
+        names.dedup();
+        let mut lookups = JoinSet::new();
+        for (index, name) in names.iter().enumerate() {
+            let glue: Vec<IpAddr> = parent
+                .iter()
+                .flat_map(|(_, response)| &response.additionals)
+                .filter(|record| record.name.trim_end_matches('.').eq_ignore_ascii_case(name))
+                .filter_map(|record| match record.data {
+                    DNSRecordData::Ipv4Addr(ip) => Some(IpAddr::V4(ip)),
+                    DNSRecordData::Ipv6Addr(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect();
+            let this = self.clone();
+            let name = name.clone();
+            let zone = zone.clone();
+            lookups.spawn(async move {
+                let check = this.check_nameserver(&zone, name, glue).await;
+                (index, check)
+            });
+        }
+        let mut nameservers: Vec<Option<(NameserverCheck, Vec<String>)>> = vec![None; names.len()];
+        while let Some(joined) = lookups.join_next().await {
+            let (index, check) = joined.expect("delegation checks do not panic");
+            nameservers[index] = Some(check);
+        }
+        let mut checks = Vec::new();
+        for (check, ns) in nameservers.into_iter().flatten() {
+            // Without an answer in the trace, the first server to answer
+            // authoritatively speaks for the zone.
+            if child_ns.is_empty() {
+                child_ns = ns;
+            }
+            checks.push(check);
+        }
+        for check in &mut checks {
+            check.at_parent = parent_ns.contains(&check.name);
+            check.at_child = child_ns.contains(&check.name);
+        }
+        Delegation {
+            zone,
+            parent_server: parent.map(|(server, _)| server),
+            parent_ns,
+            child_ns,
+            nameservers: checks,
+        }
+    }
+
+    /// Finds `name`'s addresses, from `glue` if it has any, and asks each for
+    /// the zone's SOA. Returns the NS names the first authoritative server
+    /// gave along with it.
+    async fn check_nameserver(
+        &self,
+        zone: &str,
+        name: String,
+        glue: Vec<IpAddr>,
+    ) -> (NameserverCheck, Vec<String>) {
+        let mut addresses: Vec<Ipv4Addr> = glue
+            .iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(*ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        let mut error = None;
+        if addresses.is_empty() {
+            match self
+                .resolve_detailed(&name, RecordType::A, &QueryOptions::default())
+                .await
+            {
+                Ok(result) => {
+                    addresses = result
+                        .records
+                        .iter()
+                        .filter_map(|record| match record.data {
+                            DNSRecordData::Ipv4Addr(ip) => Some(ip),
+                            _ => None,
+                        })
+                        .collect()
+                }
+                Err(e) => error = Some(e.to_string()),
+            }
+        }
+        let options = QueryOptions {
+            recursion_desired: Some(false),
+            ..QueryOptions::default()
+        };
+        let mut checks = Vec::new();
+        let mut child_ns = Vec::new();
+        for address in addresses {
+            let sent = Instant::now();
+            let response = self
+                .lookup_class(zone, &address, RecordType::Soa, Class::In, &options)
+                .await;
+            let rtt = sent.elapsed();
+            let outcome = match response {
+                Err(e) => ServerOutcome::Unreachable(e.to_string()),
+                Ok(response) if response.header.flags.rcode != Rcode::NoError => {
+                    ServerOutcome::Lame(format!("answered {}", response.header.flags.rcode))
+                }
+                Ok(response) if !response.header.flags.aa => {
+                    ServerOutcome::Lame("answer is not authoritative".to_string())
+                }
+                Ok(response) => {
+                    let serial = response
+                        .answers
+                        .iter()
+                        .find_map(|record| match &record.data {
+                            DNSRecordData::Soa(soa) => Some(soa.serial),
+                            _ => None,
+                        });
+                    if child_ns.is_empty() {
+                        child_ns = ns_names(&response.authorities, zone);
+                    }
+                    match serial {
+                        Some(serial) => ServerOutcome::Authoritative { serial },
+                        None => ServerOutcome::Lame("no SOA record in the answer".to_string()),
+                    }
+                }
+            };
+            checks.push(AddressCheck {
+                address,
+                outcome,
+                rtt,
+            });
+        }
+        let check = NameserverCheck {
+            name,
+            at_parent: false,
+            at_child: false,
+            glue,
+            addresses: checks,
+            error,
+        };
+        (check, child_ns)
+    }
+}
@@ -0,0 +1,1082 @@
+//! Following the DNSSEC chain of trust from the root's keys down to an answer
+//! (RFC 4033 to 4035), to show which links validate and where it breaks.
+//!
+//! Only signatures are checked: that NSEC and NSEC3 records actually cover a
+//! denied name is not.
+
+use crate::config::{CachePolicy, QueryOptions};
+use crate::error::ValidationError;
+use crate::transport::DnsTransport;
+use crate::{
+    Class, DNSPacket, DNSRecord, DNSRecordData, DNSResolver, HopOutcome, LookupResult,
+    NameCompressor, RecordType, Trace, decode_name, fqdn,
+};
+use ring::{digest, signature};
+use serde::{Serialize, Serializer};
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The root zone's key signing keys as published by IANA: KSK-2017 and
+/// KSK-2024.
+const ROOT_ANCHORS: [(u16, &str); 2] = [
+    (
+        20326,
+        "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ),
+    (
+        38696,
+        "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+    ),
+];
+
+/// DS records for the root's key signing keys, the usual trust anchors for
+/// [`DNSResolver::check_dnssec`].
+pub fn root_trust_anchors() -> Vec<Ds> {
+    ROOT_ANCHORS
+        .iter()
+        .map(|&(key_tag, digest)| Ds {
+            key_tag,
+            algorithm: 8,
+            digest_type: 2,
+            digest: (0..digest.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).expect("anchors are hex"))
+                .collect(),
+        })
+        .collect()
+}
+
+/// The mnemonic of a DNSSEC algorithm number (RFC 8624).
+pub fn algorithm_name(algorithm: u8) -> Option<&'static str> {
+    Some(match algorithm {
+        1 => "RSAMD5",
+        3 => "DSA",
+        5 => "RSASHA1",
+        6 => "DSA-NSEC3-SHA1",
+        7 => "RSASHA1-NSEC3-SHA1",
+        8 => "RSASHA256",
+        10 => "RSASHA512",
+        12 => "ECC-GOST",
+        13 => "ECDSAP256SHA256",
+        14 => "ECDSAP384SHA384",
+        15 => "ED25519",
+        16 => "ED448",
+        _ => return None,
+    })
+}
+
+/// A DNSKEY record's rdata (RFC 4034 section 2).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dnskey {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    /// Set on keys that sign the zone's records.
+    pub const ZONE: u16 = 0x0100;
+    /// Set on key signing keys, the ones DS records point to.
+    pub const SECURE_ENTRY_POINT: u16 = 0x0001;
+
+    pub fn parse(rdata: &[u8]) -> Result<Self, ValidationError> {
+        let [f1, f2, protocol, algorithm, public_key @ ..] = rdata else {
+            return Err(ValidationError::Malformed(RecordType::Dnskey));
+        };
+        Ok(Dnskey {
+            flags: u16::from_be_bytes([*f1, *f2]),
+            protocol: *protocol,
+            algorithm: *algorithm,
+            public_key: public_key.to_vec(),
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.flags.to_be_bytes().to_vec();
+        rdata.extend([self.protocol, self.algorithm]);
+        rdata.extend(&self.public_key);
+        rdata
+    }
+
+    /// The tag RRSIG and DS records use to refer to the key (RFC 4034
+    /// appendix B).
+    pub fn key_tag(&self) -> u16 {
+        let mut sum = self
+            .to_rdata()
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                if i % 2 == 0 {
+                    (byte as u32) << 8
+                } else {
+                    byte as u32
+                }
+            })
+            .sum::<u32>();
+        sum += (sum >> 16) & 0xFFFF;
+        sum as u16
+    }
+
+    /// Whether the key may sign records of its zone at all.
+    fn signs_zone(&self) -> bool {
+        self.flags & Self::ZONE != 0 && self.protocol == 3
+    }
+}
+
+/// A DS record's rdata (RFC 4034 section 5): a digest of a child zone's key,
+/// published by the parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(rdata: &[u8]) -> Result<Self, ValidationError> {
+        let [t1, t2, algorithm, digest_type, digest @ ..] = rdata else {
+            return Err(ValidationError::Malformed(RecordType::Ds));
+        };
+        Ok(Ds {
+            key_tag: u16::from_be_bytes([*t1, *t2]),
+            algorithm: *algorithm,
+            digest_type: *digest_type,
+            digest: digest.to_vec(),
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.key_tag.to_be_bytes().to_vec();
+        rdata.extend([self.algorithm, self.digest_type]);
+        rdata.extend(&self.digest);
+        rdata
+    }
+
+    /// The DS record for `key` of the zone `owner`, with a SHA-1 (1), SHA-256
+    /// (2) or SHA-384 (4) digest.
+    pub fn from_key(owner: &str, key: &Dnskey, digest_type: u8) -> Result<Self, ValidationError> {
+        let algorithm = match digest_type {
+            1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            2 => &digest::SHA256,
+            4 => &digest::SHA384,
+            _ => return Err(ValidationError::UnsupportedDigest(digest_type)),
+        };
+        let mut data = Vec::new();
+        canonical_name(&mut data, owner);
+        data.extend(key.to_rdata());
+        Ok(Ds {
+            key_tag: key.key_tag(),
+            algorithm: key.algorithm,
+            digest_type,
+            digest: digest::digest(algorithm, &data).as_ref().to_vec(),
+        })
+    }
+
+    /// Whether this is the DS record for `key` of the zone `owner`.
+    pub fn matches(&self, owner: &str, key: &Dnskey) -> bool {
+        self.key_tag == key.key_tag()
+            && self.algorithm == key.algorithm
+            && Ds::from_key(owner, key, self.digest_type).is_ok_and(|ds| ds == *self)
+    }
+
+    /// Whether this crate can check keys against the record. RFC 4035 section
+    /// 5.2 treats a zone whose DS records are all unsupported as unsigned.
+    fn is_supported(&self) -> bool {
+        matches!(self.digest_type, 1 | 2 | 4) && matches!(self.algorithm, 5 | 7 | 8 | 10 | 13..=15)
+    }
+}
+
+/// An RRSIG record's rdata (RFC 4034 section 3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rrsig {
+    /// The record type signed, by number.
+    pub type_covered: u16,
+    pub algorithm: u8,
+    /// Labels in the signed owner name, fewer than the owner's own for records
+    /// synthesized from a wildcard.
+    pub labels: u8,
+    pub original_ttl: u32,
+    /// Seconds since the Unix epoch, modulo 2^32.
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    /// The zone whose key made the signature.
+    pub signer: String,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(rdata: &[u8]) -> Result<Self, ValidationError> {
+        let malformed = || ValidationError::Malformed(RecordType::Rrsig);
+        let fixed = rdata.get(..18).ok_or_else(malformed)?;
+        let u32_at = |at: usize| u32::from_be_bytes(fixed[at..at + 4].try_into().unwrap());
+        // The signer's name is never compressed, so it decodes on its own.
+        let (signer, len) = decode_name(rdata, 18).map_err(|_| malformed())?;
+        Ok(Rrsig {
+            type_covered: u16::from_be_bytes([fixed[0], fixed[1]]),
+            algorithm: fixed[2],
+            labels: fixed[3],
+            original_ttl: u32_at(4),
+            expiration: u32_at(8),
+            inception: u32_at(12),
+            key_tag: u16::from_be_bytes([fixed[16], fixed[17]]),
+            signer,
+            signature: rdata[18 + len..].to_vec(),
+        })
+    }
+
+    /// The rdata up to the signature, with the signer's name in canonical form.
+    fn header(&self) -> Vec<u8> {
+        let mut buf = self.type_covered.to_be_bytes().to_vec();
+        buf.extend([self.algorithm, self.labels]);
+        buf.extend(self.original_ttl.to_be_bytes());
+        buf.extend(self.expiration.to_be_bytes());
+        buf.extend(self.inception.to_be_bytes());
+        buf.extend(self.key_tag.to_be_bytes());
+        canonical_name(&mut buf, &self.signer);
+        buf
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.header();
+        rdata.extend(&self.signature);
+        rdata
+    }
+
+    /// What the signature is made over (RFC 4034 section 3.1.8.1): the RRSIG
+    /// rdata without the signature, then `records` in canonical form and order.
+    pub(crate) fn signed_data(&self, records: &[&DNSRecord]) -> Vec<u8> {
+        let mut rdatas: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| canonical_rdata(&record.data))
+            .collect();
+        rdatas.sort();
+        rdatas.dedup();
+        let mut data = self.header();
+        let Some(first) = records.first() else {
+            return data;
+        };
+        let mut owner = Vec::new();
+        let labels: Vec<&str> = first.name.trim_end_matches('.').split('.').collect();
+        if label_count(&first.name) > self.labels as usize {
+            // Expanded from a wildcard: signed as the wildcard's own name.
+            owner.extend([1, b'*']);
+            canonical_name(
+                &mut owner,
+                &labels[labels.len() - self.labels as usize..].join("."),
+            );
+        } else {
+            canonical_name(&mut owner, &first.name);
+        }
+        for rdata in rdatas {
+            data.extend(&owner);
+            data.extend((first.type_.clone() as u16).to_be_bytes());
+            data.extend((first.class.clone() as u16).to_be_bytes());
+            data.extend(self.original_ttl.to_be_bytes());
+            data.extend((rdata.len() as u16).to_be_bytes());
+            data.extend(rdata);
+        }
+        data
+    }
+
+    /// Checks the signature over `records` with `key`, and that `now` is
+    /// within its validity period, all in serial number arithmetic.
+    fn verify(
+        &self,
+        records: &[&DNSRecord],
+        key: &Dnskey,
+        now: u32,
+    ) -> Result<(), ValidationError> {
+        if (now.wrapping_sub(self.inception) as i32) < 0 {
+            return Err(ValidationError::NotYetValid(self.inception));
+        }
+        if (self.expiration.wrapping_sub(now) as i32) < 0 {
+            return Err(ValidationError::Expired(self.expiration));
+        }
+        let message = self.signed_data(records);
+        let key_data = &key.public_key;
+        let verified = match self.algorithm {
+            5 | 7 | 8 | 10 => {
+                // RFC 3110: the exponent's length in one byte, or in two after
+                // a zero, then the exponent and the modulus.
+                let (e_len, rest) = match key_data.as_slice() {
+                    [0, hi, lo, rest @ ..] => (u16::from_be_bytes([*hi, *lo]) as usize, rest),
+                    [len, rest @ ..] => (*len as usize, rest),
+                    [] => return Err(ValidationError::Malformed(RecordType::Dnskey)),
+                };
+                if rest.len() < e_len {
+                    return Err(ValidationError::Malformed(RecordType::Dnskey));
+                }
+                let (e, n) = rest.split_at(e_len);
+                let params = match self.algorithm {
+                    5 | 7 => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+                    8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    _ => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                };
+                signature::RsaPublicKeyComponents { n, e }.verify(params, &message, &self.signature)
+            }
+            13 | 14 => {
+                // DNSKEY holds the bare point, without the uncompressed marker.
+                let mut point = vec![0x04];
+                point.extend(key_data);
+                let algorithm = if self.algorithm == 13 {
+                    &signature::ECDSA_P256_SHA256_FIXED
+                } else {
+                    &signature::ECDSA_P384_SHA384_FIXED
+                };
+                signature::UnparsedPublicKey::new(algorithm, point)
+                    .verify(&message, &self.signature)
+            }
+            15 => signature::UnparsedPublicKey::new(&signature::ED25519, key_data)
+                .verify(&message, &self.signature),
+            algorithm => return Err(ValidationError::UnsupportedAlgorithm(algorithm)),
+        };
+        verified.map_err(|_| ValidationError::BadSignature(self.key_tag))
+    }
+}
+
+/// Checks that one of `rrsigs` is a valid signature over `records` by one of
+/// the `keys` of `zone`, at `now` in seconds since the Unix epoch. Returns the
+/// signature that validated, or why the last one tried did not.
+pub fn verify_rrset<'a>(
+    records: &[&DNSRecord],
+    rrsigs: &'a [Rrsig],
+    zone: &str,
+    keys: &[Dnskey],
+    now: u32,
+) -> Result<&'a Rrsig, ValidationError> {
+    let mut error = ValidationError::NoSignature;
+    for rrsig in rrsigs {
+        if !same_name(&rrsig.signer, zone) {
+            error = ValidationError::WrongSigner {
+                signer: fqdn(&rrsig.signer),
+                zone: fqdn(zone),
+            };
+            continue;
+        }
+        let mut candidates = keys
+            .iter()
+            .filter(|key| {
+                key.signs_zone()
+                    && key.algorithm == rrsig.algorithm
+                    && key.key_tag() == rrsig.key_tag
+            })
+            .peekable();
+        if candidates.peek().is_none() {
+            error = ValidationError::NoKey(rrsig.key_tag);
+        }
+        for key in candidates {
+            match rrsig.verify(records, key, now) {
+                Ok(()) => return Ok(rrsig),
+                Err(e) => error = e,
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Writes `name` uncompressed and in lowercase, as DNSSEC signs it.
+fn canonical_name(buf: &mut Vec<u8>, name: &str) {
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+        }
+    }
+    buf.push(0);
+}
+
+/// The rdata with its names in canonical form (RFC 4034 section 6.2).
+fn canonical_rdata(data: &DNSRecordData) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match data {
+        DNSRecordData::Name(name) => canonical_name(&mut buf, name),
+        DNSRecordData::Soa(soa) => {
+            canonical_name(&mut buf, &soa.mname);
+            canonical_name(&mut buf, &soa.rname);
+            for field in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                buf.extend(field.to_be_bytes());
+            }
+        }
+        DNSRecordData::Mx(mx) => {
+            buf.extend(mx.preference.to_be_bytes());
+            canonical_name(&mut buf, &mx.exchange);
+        }
+        DNSRecordData::Srv(srv) => {
+            for field in [srv.priority, srv.weight, srv.port] {
+                buf.extend(field.to_be_bytes());
+            }
+            canonical_name(&mut buf, &srv.target);
+        }
+        DNSRecordData::Naptr(naptr) => {
+            buf.extend(naptr.order.to_be_bytes());
+            buf.extend(naptr.preference.to_be_bytes());
+            for string in [&naptr.flags, &naptr.services, &naptr.regexp] {
+                buf.push(string.len() as u8);
+                buf.extend(string.as_bytes());
+            }
+            canonical_name(&mut buf, &naptr.replacement);
+        }
+        data => data.write(&mut buf, &mut NameCompressor::default()),
+    }
+    buf
+}
+
+/// Labels in `name`, not counting the root or a leading wildcard.
+fn label_count(name: &str) -> usize {
+    let name = name.trim_end_matches('.');
+    let name = name.strip_prefix('*').unwrap_or(name);
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Whether `name` is strictly below `zone`.
+fn is_below(name: &str, zone: &str) -> bool {
+    let (name, zone) = (name.trim_end_matches('.'), zone.trim_end_matches('.'));
+    if zone.is_empty() {
+        return !name.is_empty();
+    }
+    name.len() > zone.len()
+        && name.as_bytes()[name.len() - zone.len() - 1] == b'.'
+        && name[name.len() - zone.len()..].eq_ignore_ascii_case(zone)
+}
+
+/// The records of `section` owned by `name` with type `type_`.
+fn rrset<'a>(section: &'a [DNSRecord], name: &str, type_: &RecordType) -> Vec<&'a DNSRecord> {
+    section
+        .iter()
+        .filter(|record| record.type_ == *type_ && same_name(&record.name, name))
+        .collect()
+}
+
+/// The RRSIGs of `section` over the `type_` records owned by `name`.
+fn signatures(
+    section: &[DNSRecord],
+    name: &str,
+    type_: &RecordType,
+) -> Result<Vec<Rrsig>, ValidationError> {
+    let mut rrsigs = Vec::new();
+    for record in rrset(section, name, &RecordType::Rrsig) {
+        let DNSRecordData::Data(rdata) = &record.data else {
+            continue;
+        };
+        let rrsig = Rrsig::parse(rdata)?;
+        if rrsig.type_covered == type_.clone() as u16 {
+            rrsigs.push(rrsig);
+        }
+    }
+    Ok(rrsigs)
+}
+
+impl LookupResult {
+    /// [`LookupResult::signatures`], parsed but not validated.
+    pub fn rrsigs(&self) -> Result<Vec<Rrsig>, ValidationError> {
+        self.signatures
+            .iter()
+            .map(|record| match &record.data {
+                DNSRecordData::Data(rdata) => Rrsig::parse(rdata),
+                _ => Err(ValidationError::Malformed(RecordType::Rrsig)),
+            })
+            .collect()
+    }
+}
+
+/// Whether records validated, in a [`ChainLink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkStatus {
+    Secure,
+    /// Provably unsigned, such as a delegation without DS records, which ends
+    /// the chain without breaking it.
+    Insecure(String),
+    /// Signed, but not in a way that validates.
+    Bogus(ValidationError),
+    /// Not checked, because the records could not be fetched.
+    Indeterminate(String),
+}
+
+/// One RRset checked on the way down, with the keys of the zone it is from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainLink {
+    /// The owner of the records, without a trailing dot.
+    pub name: String,
+    pub record_type: RecordType,
+    /// The zone whose keys were used: the parent for DS records and the zone
+    /// itself for its DNSKEY records.
+    pub zone: String,
+    /// The server the records came from.
+    pub server: Ipv4Addr,
+    /// Every RRSIG over the records, valid or not.
+    pub signatures: Vec<Rrsig>,
+    pub status: LinkStatus,
+}
+
+/// The chain of trust down to a name, link by link from the root's keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnssecChain {
+    pub name: String,
+    pub record_type: RecordType,
+    pub links: Vec<ChainLink>,
+}
+
+impl DnssecChain {
+    /// Whether every link validated, down to the records asked for or their
+    /// denial.
+    pub fn is_secure(&self) -> bool {
+        !self.links.is_empty()
+            && self
+                .links
+                .iter()
+                .all(|link| link.status == LinkStatus::Secure)
+    }
+
+    /// The first link that did not validate, where the chain ends or breaks.
+    pub fn break_point(&self) -> Option<&ChainLink> {
+        self.links
+            .iter()
+            .find(|link| link.status != LinkStatus::Secure)
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    secure: bool,
+    links: Vec<Link<'a>>,
+}
+
+#[derive(Serialize)]
+struct Link<'a> {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    zone: String,
+    server: Ipv4Addr,
+    /// One of secure, insecure, bogus or indeterminate.
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    signatures: Vec<Signature>,
+}
+
+#[derive(Serialize)]
+struct Signature {
+    algorithm: String,
+    key_tag: u16,
+    signer: String,
+    inception: u32,
+    expiration: u32,
+}
+
+/// A flat object with whether the chain is secure and, for each link, its
+/// status, why it is not secure, and the algorithm and key of each signature.
+impl Serialize for DnssecChain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let links = self
+            .links
+            .iter()
+            .map(|link| {
+                let (status, reason) = match &link.status {
+                    LinkStatus::Secure => ("secure", None),
+                    LinkStatus::Insecure(reason) => ("insecure", Some(reason.clone())),
+                    LinkStatus::Bogus(error) => ("bogus", Some(error.to_string())),
+                    LinkStatus::Indeterminate(reason) => ("indeterminate", Some(reason.clone())),
+                };
+                Link {
+                    name: fqdn(&link.name),
+                    record_type: &link.record_type,
+                    zone: fqdn(&link.zone),
+                    server: link.server,
+                    status,
+                    reason,
+                    signatures: link
+                        .signatures
+                        .iter()
+                        .map(|rrsig| Signature {
+                            algorithm: algorithm_name(rrsig.algorithm)
+                                .map_or_else(|| rrsig.algorithm.to_string(), str::to_string),
+                            key_tag: rrsig.key_tag,
+                            signer: fqdn(&rrsig.signer),
+                            inception: rrsig.inception,
+                            expiration: rrsig.expiration,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Report {
+            name: &self.name,
+            record_type: &self.record_type,
+            secure: self.is_secure(),
+            links,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// Resolves `name` from the upstreams as [`resolve_trace`] does, with the
+    /// DO bit set, and checks each link of the chain of trust on the way: the
+    /// root's keys against `anchors`, usually [`root_trust_anchors`], then at
+    /// each referral the child's DS records with the parent's keys and the
+    /// child's keys against those DS records, and finally the answer or the
+    /// NSEC and NSEC3 records denying it. Stops at the first link that is not
+    /// secure.
+    ///
+    /// [`resolve_trace`]: DNSResolver::resolve_trace
+    pub async fn check_dnssec(
+        &self,
+        name: &str,
+        record_type: RecordType,
+        anchors: &[Ds],
+    ) -> DnssecChain {
+        let options = QueryOptions {
+            dnssec_ok: Some(true),
+            cache: CachePolicy::Bypass,
+            ..QueryOptions::default()
+        };
+        let trace = self
+            .resolve_trace_with(name, record_type.clone(), &options)
+            .await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let mut walk = Walk {
+            resolver: self,
+            options,
+            now,
+            links: Vec::new(),
+        };
+        walk.follow(&trace, anchors).await;
+        DnssecChain {
+            name: name.to_string(),
+            record_type,
+            links: walk.links,
+        }
+    }
+}
+
+/// A zone whose keys have validated.
+#[derive(Clone)]
+struct Zone {
+    name: String,
+    keys: Vec<Dnskey>,
+}
+
+struct Walk<'a, T> {
+    resolver: &'a DNSResolver<T>,
+    options: QueryOptions,
+    now: u32,
+    links: Vec<ChainLink>,
+}
+
+impl<T: DnsTransport> Walk<'_, T> {
+    fn push(
+        &mut self,
+        name: &str,
+        record_type: RecordType,
+        zone: &str,
+        server: Ipv4Addr,
+        signatures: Vec<Rrsig>,
+        status: LinkStatus,
+    ) -> bool {
+        let secure = status == LinkStatus::Secure;
+        self.links.push(ChainLink {
+            name: name.to_string(),
+            record_type,
+            zone: zone.to_string(),
+            server,
+            signatures,
+            status,
+        });
+        secure
+    }
+
+    async fn follow(&mut self, trace: &Trace, anchors: &[Ds]) {
+        let hops: Vec<_> = trace
+            .hops
+            .iter()
+            .filter_map(|hop| Some((hop, hop.response.as_ref()?)))
+            .collect();
+        let Some((first, _)) = hops.first() else {
+            let server = trace
+                .hops
+                .first()
+                .map_or(Ipv4Addr::UNSPECIFIED, |hop| hop.server);
+            let reason = match &trace.result {
+                Err(e) => e.to_string(),
+                Ok(_) => "No server responded".to_string(),
+            };
+            self.push(
+                "",
+                RecordType::Dnskey,
+                "",
+                server,
+                Vec::new(),
+                LinkStatus::Indeterminate(reason),
+            );
+            return;
+        };
+        let Some(root) = self.enter_zone("", first.server, anchors).await else {
+            return;
+        };
+        let mut zone = root.clone();
+        for (i, &(hop, response)) in hops.iter().enumerate() {
+            let name = &hop.question.name;
+            match &hop.outcome {
+                HopOutcome::Referral { zone: child, .. } => {
+                    let Some((next, _)) = hops.get(i + 1) else {
+                        break;
+                    };
+                    let section = &response.authorities;
+                    match self
+                        .delegate(&zone, child, section, section, hop.server, next.server)
+                        .await
+                    {
+                        Some(child) => zone = child,
+                        None => return,
+                    }
+                }
+                HopOutcome::Answer => {
+                    self.check_records(&mut zone, name, &hop.question.type_, response, hop.server)
+                        .await;
+                    return;
+                }
+                // The alias's target is resolved from the root again.
+                HopOutcome::Cname(_) => {
+                    if !self
+                        .check_records(&mut zone, name, &RecordType::Cname, response, hop.server)
+                        .await
+                    {
+                        return;
+                    }
+                    zone = root.clone();
+                }
+                HopOutcome::NxDomain | HopOutcome::NoData => {
+                    self.check_denial(&mut zone, name, response, hop.server)
+                        .await;
+                    return;
+                }
+                HopOutcome::Failed(error) => {
+                    let status = LinkStatus::Indeterminate(error.clone());
+                    self.push(
+                        name,
+                        hop.question.type_.clone(),
+                        &zone.name,
+                        hop.server,
+                        Vec::new(),
+                        status,
+                    );
+                    return;
+                }
+            }
+        }
+        if let (Err(e), Some(last)) = (&trace.result, trace.hops.last()) {
+            let status = LinkStatus::Indeterminate(e.to_string());
+            let question = &last.question;
+            self.push(
+                &question.name,
+                question.type_.clone(),
+                &zone.name,
+                last.server,
+                Vec::new(),
+                status,
+            );
+        }
+    }
+
+    /// Fetches the DNSKEY records of `name` from `server` and checks them:
+    /// one must match one of `ds`, and that key must sign them all.
+    async fn enter_zone(&mut self, name: &str, server: Ipv4Addr, ds: &[Ds]) -> Option<Zone> {
+        let response = match self
+            .resolver
+            .lookup_class(name, &server, RecordType::Dnskey, Class::In, &self.options)
+            .await
+        {
+            Ok(response) => match response.get_rcode_error(name, server) {
+                Some(e) => Err(e),
+                None => Ok(response),
+            },
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                let status = LinkStatus::Indeterminate(e.to_string());
+                self.push(name, RecordType::Dnskey, name, server, Vec::new(), status);
+                return None;
+            }
+        };
+        let records = rrset(&response.answers, name, &RecordType::Dnskey);
+        let mut rrsigs = Vec::new();
+        let mut keys = Vec::new();
+        let checked = (|| {
+            rrsigs = signatures(&response.answers, name, &RecordType::Dnskey)?;
+            for record in &records {
+                if let DNSRecordData::Data(rdata) = &record.data {
+                    keys.push(Dnskey::parse(rdata)?);
+                }
+            }
+            let trusted: Vec<Dnskey> = keys
+                .iter()
+                .filter(|key| ds.iter().any(|ds| ds.matches(name, key)))
+                .cloned()
+                .collect();
+            if trusted.is_empty() {
+                return Err(ValidationError::NoMatchingDs);
+            }
+            verify_rrset(&records, &rrsigs, name, &trusted, self.now).map(|_| ())
+        })();
+        let status = match checked {
+            Ok(()) => LinkStatus::Secure,
+            Err(e) => LinkStatus::Bogus(e),
+        };
+        if self.push(name, RecordType::Dnskey, name, server, rrsigs, status) {
+            Some(Zone {
+                name: name.to_string(),
+                keys,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Checks the DS records for `child` in `ds_section`, signed by `zone`,
+    /// then the child's keys from `child_server`. Without DS records, the
+    /// NSEC or NSEC3 records of `denial_section` must prove there are none.
+    async fn delegate(
+        &mut self,
+        zone: &Zone,
+        child: &str,
+        ds_section: &[DNSRecord],
+        denial_section: &[DNSRecord],
+        parent_server: Ipv4Addr,
+        child_server: Ipv4Addr,
+    ) -> Option<Zone> {
+        let records = rrset(ds_section, child, &RecordType::Ds);
+        if records.is_empty() {
+            let (rrsigs, status) = match self.verify_denial(zone, denial_section) {
+                Ok(rrsigs) => (
+                    rrsigs,
+                    LinkStatus::Insecure(format!("No DS records for {}", fqdn(child))),
+                ),
+                Err(e) => (Vec::new(), LinkStatus::Bogus(e)),
+            };
+            self.push(
+                child,
+                RecordType::Ds,
+                &zone.name,
+                parent_server,
+                rrsigs,
+                status,
+            );
+            return None;
+        }
+        let mut rrsigs = Vec::new();
+        let checked = (|| {
+            rrsigs = signatures(ds_section, child, &RecordType::Ds)?;
+            verify_rrset(&records, &rrsigs, &zone.name, &zone.keys, self.now)?;
+            records
+                .iter()
+                .filter_map(|record| match &record.data {
+                    DNSRecordData::Data(rdata) => Some(Ds::parse(rdata)),
+                    _ => None,
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })();
+        let ds = match checked {
+            Ok(ds) if !ds.iter().any(Ds::is_supported) => {
+                let status =
+                    LinkStatus::Insecure("No DS record with a supported algorithm".to_string());
+                self.push(
+                    child,
+                    RecordType::Ds,
+                    &zone.name,
+                    parent_server,
+                    rrsigs,
+                    status,
+                );
+                return None;
+            }
+            Ok(ds) => {
+                self.push(
+                    child,
+                    RecordType::Ds,
+                    &zone.name,
+                    parent_server,
+                    rrsigs,
+                    LinkStatus::Secure,
+                );
+                ds
+            }
+            Err(e) => {
+                self.push(
+                    child,
+                    RecordType::Ds,
+                    &zone.name,
+                    parent_server,
+                    rrsigs,
+                    LinkStatus::Bogus(e),
+                );
+                return None;
+            }
+        };
+        self.enter_zone(child, child_server, &ds).await
+    }
+
+    /// Moves `zone` down to the zone that made `rrsigs`, if that is below it.
+    /// A server for both a zone and its child answers for the child without a
+    /// referral, so the cut only shows in the signer's name.
+    async fn reach_signer(&mut self, zone: &mut Zone, rrsigs: &[Rrsig], server: Ipv4Addr) -> bool {
+        let Some(signer) = rrsigs
+            .iter()
+            .map(|rrsig| rrsig.signer.clone())
+            .find(|signer| is_below(signer, &zone.name))
+        else {
+            return true;
+        };
+        let response = match self
+            .resolver
+            .lookup_class(&signer, &server, RecordType::Ds, Class::In, &self.options)
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let status = LinkStatus::Indeterminate(e.to_string());
+                self.push(
+                    &signer,
+                    RecordType::Ds,
+                    &zone.name,
+                    server,
+                    Vec::new(),
+                    status,
+                );
+                return false;
+            }
+        };
+        let parent = zone.clone();
+        match self
+            .delegate(
+                &parent,
+                &signer,
+                &response.answers,
+                &response.authorities,
+                server,
+                server,
+            )
+            .await
+        {
+            Some(child) => {
+                *zone = child;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks the `type_` records owned by `name` in the answer with the keys
+    /// of the zone that signed them.
+    async fn check_records(
+        &mut self,
+        zone: &mut Zone,
+        name: &str,
+        type_: &RecordType,
+        response: &DNSPacket,
+        server: Ipv4Addr,
+    ) -> bool {
+        let records = rrset(&response.answers, name, type_);
+        let rrsigs = match signatures(&response.answers, name, type_) {
+            Ok(rrsigs) => rrsigs,
+            Err(e) => {
+                return self.push(
+                    name,
+                    type_.clone(),
+                    &zone.name,
+                    server,
+                    Vec::new(),
+                    LinkStatus::Bogus(e),
+                );
+            }
+        };
+        if !self.reach_signer(zone, &rrsigs, server).await {
+            return false;
+        }
+        let status = match verify_rrset(&records, &rrsigs, &zone.name, &zone.keys, self.now) {
+            Ok(_) => LinkStatus::Secure,
+            Err(e) => LinkStatus::Bogus(e),
+        };
+        self.push(name, type_.clone(), &zone.name, server, rrsigs, status)
+    }
+
+    /// Checks the NSEC or NSEC3 records of a negative answer for `name`.
+    async fn check_denial(
+        &mut self,
+        zone: &mut Zone,
+        name: &str,
+        response: &DNSPacket,
+        server: Ipv4Addr,
+    ) {
+        let section = &response.authorities;
+        let rrsigs: Vec<Rrsig> = [RecordType::Nsec, RecordType::Nsec3]
+            .iter()
+            .flat_map(|type_| {
+                section
+                    .iter()
+                    .filter(|record| record.type_ == *type_)
+                    .flat_map(|record| signatures(section, &record.name, type_).unwrap_or_default())
+            })
+            .collect();
+        if !self.reach_signer(zone, &rrsigs, server).await {
+            return;
+        }
+        let type_ = if section
+            .iter()
+            .any(|record| record.type_ == RecordType::Nsec3)
+        {
+            RecordType::Nsec3
+        } else {
+            RecordType::Nsec
+        };
+        let (rrsigs, status) = match self.verify_denial(zone, section) {
+            Ok(rrsigs) => (rrsigs, LinkStatus::Secure),
+            Err(e) => (rrsigs, LinkStatus::Bogus(e)),
+        };
+        self.push(name, type_, &zone.name, server, rrsigs, status);
+    }
+
+    /// Checks every NSEC and NSEC3 RRset in `section` with the keys of
+    /// `zone`, returning the signatures that validated.
+    fn verify_denial(
+        &self,
+        zone: &Zone,
+        section: &[DNSRecord],
+    ) -> Result<Vec<Rrsig>, ValidationError> {
+        let mut owners: Vec<(&str, RecordType)> = Vec::new();
+        for record in section {
+            let owner = (record.name.as_str(), record.type_.clone());
+            if matches!(owner.1, RecordType::Nsec | RecordType::Nsec3) && !owners.contains(&owner) {
+                owners.push(owner);
+            }
+        }
+        if owners.is_empty() {
+            return Err(ValidationError::NoDenialProof);
+        }
+        let mut verified = Vec::new();
+        for (owner, type_) in owners {
+            let records = rrset(section, owner, &type_);
+            let rrsigs = signatures(section, owner, &type_)?;
+            let rrsig = verify_rrset(&records, &rrsigs, &zone.name, &zone.keys, self.now)?;
+            verified.push(rrsig.clone());
+        }
+        Ok(verified)
+    }
+}
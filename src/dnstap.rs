@@ -0,0 +1,218 @@
+//! Query logging in the dnstap format (<https://dnstap.info>): protobuf
+//! `Dnstap` messages carried in Frame Streams, written to a file or to a
+//! collector listening on a unix socket.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frame Streams control frame types.
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+/// `Dnstap.Type.MESSAGE`, the only type defined.
+const DNSTAP_MESSAGE: u64 = 1;
+const FAMILY_INET: u64 = 1;
+const FAMILY_INET6: u64 = 2;
+const PROTOCOL_UDP: u64 = 1;
+
+/// Where in the resolution a message was seen, from `Message.Type` in dnstap.proto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    AuthQuery = 1,
+    AuthResponse = 2,
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+    StubQuery = 9,
+    StubResponse = 10,
+    ToolQuery = 11,
+    ToolResponse = 12,
+}
+
+impl MessageType {
+    fn is_query(self) -> bool {
+        self as u8 % 2 == 1
+    }
+}
+
+/// Writes dnstap frames to a Frame Streams sink. A stop frame is sent when
+/// the writer is dropped.
+pub struct DnstapWriter {
+    out: Box<dyn Write + Send>,
+    identity: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for DnstapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnstapWriter")
+            .field("identity", &self.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DnstapWriter {
+    /// Starts a unidirectional stream on `out`, as used for files.
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer = DnstapWriter {
+            out: Box::new(out),
+            identity: None,
+        };
+        writer.write_control(CONTROL_START)?;
+        writer.out.flush()?;
+        Ok(writer)
+    }
+
+    /// Creates (or truncates) a dnstap file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Connects to a collector such as `fstrm_capture` listening on the unix
+    /// socket at `path`, performing the bidirectional handshake.
+    #[cfg(unix)]
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        use std::io::Read;
+
+        let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.write_all(&control_frame(CONTROL_READY))?;
+        let mut header = [0; 12];
+        stream.read_exact(&mut header)?;
+        let control_len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        let control_type = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        // The content types the collector accepts are not checked; it would
+        // reject ours by closing the connection.
+        io::copy(
+            &mut (&mut stream).take(control_len.saturating_sub(4) as u64),
+            &mut io::sink(),
+        )?;
+        if header[0..4] != [0; 4] || control_type != CONTROL_ACCEPT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dnstap collector did not accept the stream",
+            ));
+        }
+        Self::new(stream)
+    }
+
+    /// Identifies this server in every message, like the `identity` option of
+    /// other dnstap producers.
+    pub fn with_identity(mut self, identity: &str) -> Self {
+        self.identity = Some(identity.as_bytes().to_vec());
+        self
+    }
+
+    /// Logs one wire-format DNS message sent over UDP from `query_address` to
+    /// `response_address` (or the reverse, for responses) at `time`.
+    pub fn log(
+        &mut self,
+        type_: MessageType,
+        query_address: SocketAddr,
+        response_address: SocketAddr,
+        message: &[u8],
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut inner = Vec::new();
+        varint_field(&mut inner, 1, type_ as u64);
+        let family = match query_address.ip() {
+            IpAddr::V4(_) => FAMILY_INET,
+            IpAddr::V6(_) => FAMILY_INET6,
+        };
+        varint_field(&mut inner, 2, family);
+        varint_field(&mut inner, 3, PROTOCOL_UDP);
+        bytes_field(&mut inner, 4, &ip_octets(query_address.ip()));
+        bytes_field(&mut inner, 5, &ip_octets(response_address.ip()));
+        varint_field(&mut inner, 6, query_address.port().into());
+        varint_field(&mut inner, 7, response_address.port().into());
+        // Queries and responses use separate time and message fields.
+        let (sec, nsec, body) = if type_.is_query() {
+            (8, 9, 10)
+        } else {
+            (12, 13, 14)
+        };
+        varint_field(&mut inner, sec, since_epoch.as_secs());
+        key(&mut inner, nsec, 5);
+        inner.extend(since_epoch.subsec_nanos().to_le_bytes());
+        bytes_field(&mut inner, body, message);
+
+        let mut frame = Vec::new();
+        if let Some(identity) = &self.identity {
+            bytes_field(&mut frame, 1, identity);
+        }
+        bytes_field(&mut frame, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        bytes_field(&mut frame, 14, &inner);
+        varint_field(&mut frame, 15, DNSTAP_MESSAGE);
+
+        self.out.write_all(&(frame.len() as u32).to_be_bytes())?;
+        self.out.write_all(&frame)?;
+        self.out.flush()
+    }
+
+    fn write_control(&mut self, control_type: u32) -> io::Result<()> {
+        self.out.write_all(&control_frame(control_type))
+    }
+}
+
+impl Drop for DnstapWriter {
+    fn drop(&mut self) {
+        let _ = self.write_control(CONTROL_STOP);
+        let _ = self.out.flush();
+    }
+}
+
+/// An escaped control frame; START and READY carry our content type.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP {
+        payload.extend(CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    varint(buf, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    key(buf, field, 0);
+    varint(buf, value);
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    key(buf, field, 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend(bytes);
+}
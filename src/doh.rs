@@ -0,0 +1,61 @@
+//! DNS over HTTPS message encoding (RFC 8484). GET requests carry the
+//! wire-format query in the `dns` parameter as unpadded base64url.
+
+use crate::DNSPacket;
+use crate::error::{DnsError, Result};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes `packet` for the `dns` parameter of a GET request. RFC 8484
+/// recommends an ID of 0 in queries so identical requests are cache friendly.
+pub fn to_base64url(packet: &DNSPacket) -> String {
+    encode(&packet.to_bytes())
+}
+
+/// Decodes the `dns` parameter of a GET request. Trailing padding is tolerated
+/// even though RFC 8484 says it is omitted.
+pub fn from_base64url(param: &str) -> Result<DNSPacket> {
+    DNSPacket::parse(&decode(param)?)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        // Three bytes fill four characters; shorter chunks need one more
+        // character than they have bytes.
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub(crate) fn decode(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    if text.len() % 4 == 1 {
+        return Err(DnsError::MalformedPacket(format!(
+            "Invalid base64url length {}",
+            text.len()
+        )));
+    }
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let Some(value) = ALPHABET.iter().position(|&a| a == c) else {
+                return Err(DnsError::MalformedPacket(format!(
+                    "Invalid base64url character {:?}",
+                    c as char
+                )));
+            };
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,147 @@
+//! ENUM (RFC 6116): finding the URIs, such as SIP or tel URIs, registered for
+//! an E.164 telephone number through the NAPTR records under e164.arpa.
+//!
+//! Only terminal rules for E2U services are applied. Rules that hand off to
+//! another domain through their replacement field are not followed.
+
+use crate::error::{DnsError, Result};
+use crate::transport::DnsTransport;
+use crate::{DNSRecordData, DNSResolver, Naptr, RecordType};
+use regex::RegexBuilder;
+
+/// The zone ENUM names are delegated under.
+const ENUM_ZONE: &str = "e164.arpa";
+
+/// An E.164 number has at most 15 digits.
+const MAX_DIGITS: usize = 15;
+
+/// `number` as the string ENUM rules are applied to: `+` followed by its
+/// digits. Spaces, dashes, dots and parentheses between digits are dropped.
+pub fn application_string(number: &str) -> Result<String> {
+    let invalid = || DnsError::InvalidNumber(number.to_string());
+    let digits = number.trim().strip_prefix('+').ok_or_else(invalid)?;
+    let mut aus = String::from("+");
+    for c in digits.chars() {
+        match c {
+            '0'..='9' => aus.push(c),
+            ' ' | '-' | '.' | '(' | ')' => {}
+            _ => return Err(invalid()),
+        }
+    }
+    if !(2..=MAX_DIGITS + 1).contains(&aus.len()) {
+        return Err(invalid());
+    }
+    Ok(aus)
+}
+
+/// The domain holding the NAPTR records of `number`: its digits reversed,
+/// one per label, under e164.arpa.
+pub fn enum_domain(number: &str) -> Result<String> {
+    let aus = application_string(number)?;
+    let mut labels: Vec<String> = aus[1..].chars().rev().map(String::from).collect();
+    labels.push(ENUM_ZONE.to_string());
+    Ok(labels.join("."))
+}
+
+impl Naptr {
+    /// Applies the substitution in `regexp` to `input` as sed would,
+    /// replacing the first match. `None` if the expression is malformed or
+    /// does not match.
+    pub fn rewrite(&self, input: &str) -> Option<String> {
+        let mut chars = self.regexp.chars();
+        let delimiter = chars.next()?;
+        if delimiter == '\\' || delimiter == 'i' || delimiter.is_ascii_digit() {
+            return None;
+        }
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            let part = parts.last_mut().expect("there is always a part");
+            if escaped {
+                if c != delimiter {
+                    part.push('\\');
+                }
+                part.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                part.push(c);
+            }
+        }
+        let [pattern, replacement, flags] = <[String; 3]>::try_from(parts).ok()?;
+        let case_insensitive = match flags.as_str() {
+            "" => false,
+            "i" => true,
+            _ => return None,
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .ok()?;
+        let captures = regex.captures(input)?;
+        let matched = captures.get(0)?;
+        let mut output = input[..matched.start()].to_string();
+        let mut replacement = replacement.chars();
+        while let Some(c) = replacement.next() {
+            if c != '\\' {
+                output.push(c);
+                continue;
+            }
+            match replacement.next() {
+                Some(digit @ '1'..='9') => {
+                    let group = digit as usize - '0' as usize;
+                    output.push_str(captures.get(group).map_or("", |m| m.as_str()));
+                }
+                Some(other) => output.push(other),
+                None => output.push('\\'),
+            }
+        }
+        output.push_str(&input[matched.end()..]);
+        Some(output)
+    }
+}
+
+/// Whether `services` names the E2U (E.164 to URI) application, as in
+/// `E2U+sip` or `E2U+voice:tel`.
+fn is_e2u(services: &str) -> bool {
+    services
+        .get(..4)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("E2U+"))
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// The URIs registered for the telephone `number`, most preferred first.
+    /// Only the rules of the lowest order that yields a URI are used, as RFC
+    /// 3403 has clients stop at the first order that matches.
+    pub async fn enum_lookup(&self, number: &str) -> Result<Vec<String>> {
+        let aus = application_string(number)?;
+        let mut rules: Vec<Naptr> = self
+            .resolve_record(&enum_domain(&aus)?, RecordType::Naptr)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Naptr(naptr) => Some(naptr),
+                _ => None,
+            })
+            .collect();
+        rules.sort_by_key(|rule| (rule.order, rule.preference));
+        let mut uris = Vec::new();
+        let mut matched_order = None;
+        for rule in rules {
+            if matched_order.is_some_and(|order| order != rule.order) {
+                break;
+            }
+            if !rule.flags.eq_ignore_ascii_case("u") || !is_e2u(&rule.services) {
+                continue;
+            }
+            if let Some(uri) = rule.rewrite(&aus) {
+                uris.push(uri);
+                matched_order = Some(rule.order);
+            }
+        }
+        Ok(uris)
+    }
+}
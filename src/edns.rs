@@ -0,0 +1,320 @@
+//! EDNS(0) support (RFC 6891): the OPT pseudo-record carried in the additional
+//! section, and the options inside it.
+
+use crate::error::{DnsError, Result};
+use crate::{RecordType, u16_at, u32_at};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// UDP payload size advertised in our queries, the value recommended by DNS Flag Day 2020.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+const DO_BIT: u32 = 1 << 15;
+
+/// Option codes from the IANA "DNS EDNS0 Option Codes" registry.
+const NSID: u16 = 3;
+const CLIENT_SUBNET: u16 = 8;
+
+/// Address families from the IANA "Address Family Numbers" registry.
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// EDNS Client Subnet (RFC 7871): the network a query is made on behalf of, so
+/// geo-aware authorities can tailor their answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    /// Number of leading bits of `address` revealed to the server.
+    pub source_prefix: u8,
+    /// Number of leading bits the answer is valid for. Zero in queries; set by
+    /// the server in its response.
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Builds a query subnet, zeroing the address bits past `source_prefix`.
+    pub fn new(address: IpAddr, source_prefix: u8) -> Self {
+        let source_prefix = source_prefix.min(max_prefix(&address));
+        ClientSubnet {
+            address: mask(address, source_prefix),
+            source_prefix,
+            scope_prefix: 0,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let address_len = (self.source_prefix as usize).div_ceil(8);
+        let mut bytes = family.to_be_bytes().to_vec();
+        bytes.push(self.source_prefix);
+        bytes.push(self.scope_prefix);
+        bytes.extend(&octets[..address_len]);
+        bytes
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let family = u16::from_be_bytes(data.get(0..2)?.try_into().ok()?);
+        let source_prefix = *data.get(2)?;
+        let scope_prefix = *data.get(3)?;
+        let address = data.get(4..)?;
+        if address.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let address = match family {
+            FAMILY_IPV4 if address.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if address.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+}
+
+fn max_prefix(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip).checked_shr(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(
+                bits.checked_shl(32 - prefix as u32).unwrap_or(0),
+            ))
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip).checked_shr(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(
+                bits.checked_shl(128 - prefix as u32).unwrap_or(0),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdnsOption {
+    /// Name server identifier (RFC 5001). Empty in a query, it asks the server
+    /// to reply with its identifier.
+    Nsid(Vec<u8>),
+    ClientSubnet(ClientSubnet),
+    Unknown {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => NSID,
+            EdnsOption::ClientSubnet(_) => CLIENT_SUBNET,
+            EdnsOption::Unknown { code, .. } => *code,
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        match self {
+            EdnsOption::Nsid(data) => data.clone(),
+            EdnsOption::ClientSubnet(subnet) => subnet.to_bytes(),
+            EdnsOption::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    fn parse(code: u16, data: &[u8]) -> Self {
+        let subnet = match code {
+            CLIENT_SUBNET => ClientSubnet::parse(data),
+            _ => None,
+        };
+        match (code, subnet) {
+            (NSID, _) => EdnsOption::Nsid(data.to_vec()),
+            (_, Some(subnet)) => EdnsOption::ClientSubnet(subnet),
+            _ => EdnsOption::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+/// Option as shown in dig's OPT pseudosection, such as `CLIENT-SUBNET: 192.0.2.0/24/0`.
+impl fmt::Display for EdnsOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdnsOption::Nsid(data) => {
+                write!(f, "NSID: {}", hex(data))?;
+                if !data.is_empty() {
+                    let text: String = data
+                        .iter()
+                        .map(|&byte| {
+                            if byte.is_ascii_graphic() || byte == b' ' {
+                                byte as char
+                            } else {
+                                '.'
+                            }
+                        })
+                        .collect();
+                    write!(f, " (\"{text}\")")?;
+                }
+                Ok(())
+            }
+            EdnsOption::ClientSubnet(subnet) => write!(
+                f,
+                "CLIENT-SUBNET: {}/{}/{}",
+                subnet.address, subnet.source_prefix, subnet.scope_prefix
+            ),
+            EdnsOption::Unknown { code, data } => write!(f, "OPT={code}: {}", hex(data)),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Contents of an OPT record. Its class and TTL fields are repurposed to carry
+/// the payload size, extended RCODE, version and flags.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    /// Upper eight bits of the twelve-bit extended RCODE.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The DO bit, asking for DNSSEC records in the response.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// The lines of dig's OPT pseudosection, without its heading.
+impl fmt::Display for Edns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = if self.dnssec_ok { " do" } else { "" };
+        write!(
+            f,
+            "; EDNS: version: {}, flags:{flags}; udp: {}",
+            self.version, self.udp_payload_size
+        )?;
+        for option in &self.options {
+            write!(f, "\n; {option}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Edns {
+    /// EDNS for a query that asks the server to identify itself.
+    pub fn with_nsid_request() -> Self {
+        Edns {
+            options: vec![EdnsOption::Nsid(Vec::new())],
+            ..Edns::default()
+        }
+    }
+
+    /// The server identifier returned in an NSID option, if any.
+    pub fn nsid(&self) -> Option<&[u8]> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::Nsid(data) if !data.is_empty() => Some(data.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// The client subnet option, carrying the server's scope in a response.
+    pub fn client_subnet(&self) -> Option<&ClientSubnet> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::ClientSubnet(subnet) => Some(subnet),
+            _ => None,
+        })
+    }
+
+    /// Writes the complete OPT record, including its root owner name.
+    pub(crate) fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(0);
+        buf.extend((RecordType::Opt as u16).to_be_bytes());
+        buf.extend(self.udp_payload_size.to_be_bytes());
+        let ttl = ((self.extended_rcode as u32) << 24)
+            | ((self.version as u32) << 16)
+            | if self.dnssec_ok { DO_BIT } else { 0 };
+        buf.extend(ttl.to_be_bytes());
+        let len_at = buf.len();
+        buf.extend([0, 0]);
+        for option in &self.options {
+            let data = option.data();
+            buf.extend(option.code().to_be_bytes());
+            buf.extend((data.len() as u16).to_be_bytes());
+            buf.extend(data);
+        }
+        let rdata_len = (buf.len() - len_at - 2) as u16;
+        buf[len_at..len_at + 2].copy_from_slice(&rdata_len.to_be_bytes());
+    }
+
+    /// Parses an OPT record starting at its type field, once the owner name has
+    /// been skipped. Returns the record and the number of bytes consumed.
+    pub(crate) fn parse(buf: &[u8], cursor_start: usize) -> Result<(Self, usize)> {
+        let mut cursor = cursor_start;
+        let udp_payload_size = u16_at(buf, cursor + 2)?;
+        let ttl = u32_at(buf, cursor + 4)?;
+        let rdata_len = u16_at(buf, cursor + 8)? as usize;
+        cursor += 10;
+        let rdata_end = cursor + rdata_len;
+        if rdata_end > buf.len() {
+            return Err(DnsError::Truncated);
+        }
+        let malformed = |reason: String| Err(DnsError::MalformedPacket(reason));
+        let mut options = Vec::new();
+        while cursor < rdata_end {
+            if cursor + 4 > rdata_end {
+                return malformed("Truncated EDNS option header".to_string());
+            }
+            let code = u16_at(buf, cursor)?;
+            let len = u16_at(buf, cursor + 2)? as usize;
+            cursor += 4;
+            if cursor + len > rdata_end {
+                return malformed(format!("EDNS option {code} overruns the OPT record"));
+            }
+            options.push(EdnsOption::parse(code, &buf[cursor..cursor + len]));
+            cursor += len;
+        }
+        Ok((
+            Edns {
+                udp_payload_size,
+                extended_rcode: (ttl >> 24) as u8,
+                version: (ttl >> 16) as u8,
+                dnssec_ok: ttl & DO_BIT != 0,
+                options,
+            },
+            cursor - cursor_start,
+        ))
+    }
+}
@@ -0,0 +1,153 @@
+use crate::RecordType;
+use crate::flags::Rcode;
+use std::net::Ipv4Addr;
+use thiserror::Error;
+
+pub type Result<T, E = DnsError> = std::result::Result<T, E>;
+
+/// Every way encoding, decoding or resolving a name can fail.
+#[derive(Debug, Error)]
+pub enum DnsError {
+    /// No response arrived from the server in time.
+    #[error("Timed out waiting for a response from {0}")]
+    Timeout(Ipv4Addr),
+    /// The name does not exist (NXDOMAIN).
+    #[error("Domain name {0} does not exist")]
+    NxDomain(String),
+    /// The name exists but has no records of the requested type.
+    #[error("No records found for {0}")]
+    NoRecordsFound(String),
+    /// The caller's deadline passed before resolution finished.
+    #[error("Deadline exceeded resolving {0}")]
+    DeadlineExceeded(String),
+    /// The caller cancelled the resolution.
+    #[error("Resolution of {0} was cancelled")]
+    Cancelled(String),
+    /// The server failed to process the query (SERVFAIL).
+    #[error("Server {server} failed to resolve {domain}")]
+    ServFail { domain: String, server: Ipv4Addr },
+    /// Any other non-success response code, such as REFUSED.
+    #[error("Server {server} answered {rcode} for {domain}")]
+    ErrorResponse {
+        domain: String,
+        server: Ipv4Addr,
+        rcode: Rcode,
+    },
+    /// A server answered with neither records nor a referral to follow, or
+    /// the CNAMEs or referrals went on too long, as in a loop.
+    #[error("Could not resolve {0}")]
+    Unresolvable(String),
+    /// The message ends before all of its contents have been read.
+    #[error("Message ends before all of its contents")]
+    Truncated,
+    /// The message is not valid DNS, or not a response to our query.
+    #[error("Malformed packet: {0}")]
+    MalformedPacket(String),
+    /// A record type this crate does not know how to handle.
+    #[error("Unsupported record type {0}")]
+    UnsupportedType(u16),
+    /// A record type mnemonic that names no type.
+    #[error("Unknown record type {0}")]
+    UnknownType(String),
+    #[error(transparent)]
+    InvalidName(#[from] NameError),
+    /// A telephone number that is not `+` followed by up to 15 digits.
+    #[error("Invalid E.164 number {0}")]
+    InvalidNumber(String),
+    /// A message that cannot be encoded, rejected by
+    /// [`MessageBuilder::build`](crate::builder::MessageBuilder::build).
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+    /// A resolver configuration rejected by
+    /// [`DNSResolverBuilder::build`](crate::config::DNSResolverBuilder::build).
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// A master file that does not parse, or records that do not make a
+    /// zone.
+    #[error("Invalid zone: {0}")]
+    InvalidZone(String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+/// A domain name that breaks the RFC 1035 size limits or is otherwise
+/// malformed, found while encoding or decoding.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NameError {
+    /// A label longer than 63 bytes, with its length.
+    #[error("Label of {0} bytes exceeds the 63-byte limit")]
+    LabelTooLong(usize),
+    /// A name longer than 255 bytes in wire format, with its length.
+    #[error("Name of {0} bytes exceeds the 255-byte limit")]
+    NameTooLong(usize),
+    /// An empty label inside a name, as in `a..b`.
+    #[error("Empty label in {0}")]
+    EmptyLabel(String),
+    /// A Unicode label that has no Punycode form.
+    #[error("Label {0} cannot be converted to an A-label")]
+    InvalidLabel(String),
+    /// A compression pointer at the given offset that does not point to an
+    /// earlier part of the message.
+    #[error("Invalid compression pointer at offset {0}")]
+    BadPointer(usize),
+    /// The name runs past the end of the message.
+    #[error("Name runs past the end of the message")]
+    Truncated,
+}
+
+/// Why records did not validate against the keys of their zone, found while
+/// walking the chain of trust in [`dnssec`](crate::dnssec).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationError {
+    /// The records came without an RRSIG covering them.
+    #[error("No RRSIG covers the records")]
+    NoSignature,
+    /// An RRSIG by a zone other than the one the records are from.
+    #[error("Signed by {signer} instead of {zone}")]
+    WrongSigner { signer: String, zone: String },
+    /// No DNSKEY of the zone has the key tag and algorithm of the RRSIG.
+    #[error("No DNSKEY with key tag {0}")]
+    NoKey(u16),
+    /// None of the zone's keys has a DS record at the parent.
+    #[error("No DNSKEY matches a DS record of the parent")]
+    NoMatchingDs,
+    /// A signing algorithm this crate cannot verify, by number.
+    #[error("Unsupported algorithm {0}")]
+    UnsupportedAlgorithm(u8),
+    /// A DS digest type this crate cannot compute, by number.
+    #[error("Unsupported digest type {0}")]
+    UnsupportedDigest(u8),
+    /// The RRSIG's expiration, in seconds since the Unix epoch, has passed.
+    #[error("Signature expired at {0}")]
+    Expired(u32),
+    /// The RRSIG's inception, in seconds since the Unix epoch, is yet to come.
+    #[error("Signature not valid until {0}")]
+    NotYetValid(u32),
+    /// The signature by the key with this tag does not match the records.
+    #[error("Signature by key {0} does not verify")]
+    BadSignature(u16),
+    /// A negative answer without signed NSEC or NSEC3 records proving it.
+    #[error("No signed NSEC or NSEC3 records prove the denial")]
+    NoDenialProof,
+    /// Rdata too short for its type.
+    #[error("Malformed {0} record")]
+    Malformed(RecordType),
+}
+
+/// Why a `v=spf1` record does not parse as an
+/// [`SpfRecord`](crate::spf::SpfRecord).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SpfError {
+    /// The text does not start with `v=spf1`.
+    #[error("Not an SPF record")]
+    NotSpf,
+    /// A mechanism RFC 7208 does not define.
+    #[error("Unknown mechanism {0}")]
+    UnknownMechanism(String),
+    /// A mechanism with a missing or malformed argument.
+    #[error("Invalid term {0}")]
+    InvalidTerm(String),
+    /// `redirect` or `exp` given more than once.
+    #[error("Modifier {0} given more than once")]
+    DuplicateModifier(String),
+}
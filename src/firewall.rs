@@ -0,0 +1,105 @@
+//! Rules the daemon applies to queries before answering them, such as
+//! refusing ANY queries or dropping TXT queries for names under a domain.
+//! Rules are tried in order, and the first matching a query's name and type
+//! decides what is done with it; queries no rule matches are answered as
+//! usual.
+
+use crate::daemon::{FirewallAction, FirewallRule};
+use crate::error::{DnsError, Result};
+use crate::flags::Opcode;
+use crate::zone::is_subdomain;
+use crate::{DNSPacket, RecordType};
+
+/// The daemon's rules, ready to match queries against.
+#[derive(Debug, Clone, Default)]
+pub struct Firewall {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Empty to match any name.
+    names: Vec<NamePattern>,
+    /// Empty to match any type.
+    types: Vec<RecordType>,
+    action: FirewallAction,
+}
+
+/// Lowercase, without the trailing dot.
+#[derive(Debug, Clone)]
+enum NamePattern {
+    /// The name itself.
+    Exact(String),
+    /// Any name under the domain, written `*.domain`.
+    Under(String),
+}
+
+impl NamePattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let lower = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let pattern = match lower.strip_prefix("*.") {
+            Some(domain) => NamePattern::Under(domain.to_string()),
+            None if lower == "*" => NamePattern::Under(String::new()),
+            None => NamePattern::Exact(lower.clone()),
+        };
+        match &pattern {
+            NamePattern::Exact(name) | NamePattern::Under(name) if name.contains('*') => Err(
+                DnsError::InvalidConfig(format!("{lower}: a wildcard can only be the first label")),
+            ),
+            _ => Ok(pattern),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(exact) => name.eq_ignore_ascii_case(exact),
+            NamePattern::Under(domain) => {
+                !name.eq_ignore_ascii_case(domain) && is_subdomain(name, domain)
+            }
+        }
+    }
+}
+
+impl Firewall {
+    /// Checks each rule's names and types.
+    pub fn new(rules: &[FirewallRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    names: rule
+                        .names
+                        .iter()
+                        .map(|name| NamePattern::parse(name))
+                        .collect::<Result<_>>()?,
+                    types: rule
+                        .types
+                        .iter()
+                        .map(|type_| type_.parse())
+                        .collect::<Result<_>>()?,
+                    action: rule.action,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Firewall { rules })
+    }
+
+    /// What the first rule matching `query` says to do with it, if any does.
+    /// Only standard queries with a single question are matched.
+    pub fn action(&self, query: &DNSPacket) -> Option<FirewallAction> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        if query.header.flags.opcode != Opcode::Query {
+            return None;
+        }
+        let name = question.name.trim_end_matches('.');
+        self.rules
+            .iter()
+            .find(|rule| {
+                (rule.names.is_empty() || rule.names.iter().any(|pattern| pattern.matches(name)))
+                    && (rule.types.is_empty() || rule.types.contains(&question.type_))
+            })
+            .map(|rule| rule.action)
+    }
+}
@@ -0,0 +1,161 @@
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Response code from the low four bits of the header (RFC 1035, RFC 2136).
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[repr(u8)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Rcode {
+    NoError = 0,
+    FormErr = 1,
+    ServFail = 2,
+    NxDomain = 3,
+    NotImp = 4,
+    Refused = 5,
+    YxDomain = 6,
+    YxRrset = 7,
+    NxRrset = 8,
+    NotAuth = 9,
+    NotZone = 10,
+    #[num_enum(catch_all)]
+    Unknown(u8),
+}
+
+// Not derived: num_enum reads `#[default]` as its own attribute, which
+// conflicts with `catch_all`.
+#[allow(clippy::derivable_impls)]
+impl Default for Rcode {
+    fn default() -> Self {
+        Rcode::NoError
+    }
+}
+
+/// Mnemonic as printed by dig, such as `NXDOMAIN`.
+impl fmt::Display for Rcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rcode::Unknown(code) => write!(f, "RCODE{code}"),
+            rcode => write!(f, "{}", format!("{rcode:?}").to_uppercase()),
+        }
+    }
+}
+
+/// Kind of message, from bits 11-14 of the header (RFC 1035, RFC 1996, RFC 2136).
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[repr(u8)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Opcode {
+    Query = 0,
+    /// Inverse query, obsoleted by RFC 3425.
+    IQuery = 1,
+    Status = 2,
+    Notify = 4,
+    Update = 5,
+    #[num_enum(catch_all)]
+    Unknown(u8),
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for Opcode {
+    fn default() -> Self {
+        Opcode::Query
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::Unknown(code) => write!(f, "OPCODE{code}"),
+            opcode => write!(f, "{}", format!("{opcode:?}").to_uppercase()),
+        }
+    }
+}
+
+/// The second 16-bit word of the DNS header, split into its named fields
+/// (RFC 1035 section 4.1.1, plus AD/CD from RFC 4035).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DNSFlags {
+    /// Set on responses, clear on queries.
+    pub qr: bool,
+    pub opcode: Opcode,
+    /// Authoritative answer.
+    pub aa: bool,
+    /// Truncated; the full answer needs a transport without the UDP size limit.
+    pub tc: bool,
+    /// Recursion desired.
+    pub rd: bool,
+    /// Recursion available.
+    pub ra: bool,
+    /// Authentic data, set when the answer was DNSSEC validated.
+    pub ad: bool,
+    /// Checking disabled, asking the server not to DNSSEC validate.
+    pub cd: bool,
+    pub rcode: Rcode,
+}
+
+const QR: u16 = 1 << 15;
+const OPCODE_SHIFT: u16 = 11;
+const AA: u16 = 1 << 10;
+const TC: u16 = 1 << 9;
+const RD: u16 = 1 << 8;
+const RA: u16 = 1 << 7;
+const AD: u16 = 1 << 5;
+const CD: u16 = 1 << 4;
+const FOUR_BITS: u16 = 0b1111;
+
+impl From<u16> for DNSFlags {
+    fn from(bits: u16) -> Self {
+        DNSFlags {
+            qr: bits & QR != 0,
+            opcode: Opcode::from(((bits >> OPCODE_SHIFT) & FOUR_BITS) as u8),
+            aa: bits & AA != 0,
+            tc: bits & TC != 0,
+            rd: bits & RD != 0,
+            ra: bits & RA != 0,
+            ad: bits & AD != 0,
+            cd: bits & CD != 0,
+            rcode: Rcode::from((bits & FOUR_BITS) as u8),
+        }
+    }
+}
+
+impl From<DNSFlags> for u16 {
+    fn from(flags: DNSFlags) -> Self {
+        let bit = |set: bool, mask: u16| if set { mask } else { 0 };
+        bit(flags.qr, QR)
+            | ((u8::from(flags.opcode) as u16 & FOUR_BITS) << OPCODE_SHIFT)
+            | bit(flags.aa, AA)
+            | bit(flags.tc, TC)
+            | bit(flags.rd, RD)
+            | bit(flags.ra, RA)
+            | bit(flags.ad, AD)
+            | bit(flags.cd, CD)
+            | (u8::from(flags.rcode) as u16 & FOUR_BITS)
+    }
+}
+
+/// The set flags by their lowercase names, space separated.
+impl fmt::Display for DNSFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.qr, "qr"),
+            (self.aa, "aa"),
+            (self.tc, "tc"),
+            (self.rd, "rd"),
+            (self.ra, "ra"),
+            (self.ad, "ad"),
+            (self.cd, "cd"),
+        ];
+        let set: Vec<&str> = names
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", set.join(" "))
+    }
+}
@@ -0,0 +1,42 @@
+//! Locating clients in a MaxMind database, such as GeoLite2 Country, so the
+//! daemon's zones can give clients the addresses of nearby servers.
+
+use crate::authority::Location;
+use crate::error::{DnsError, Result};
+use maxminddb::{Reader, geoip2};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// An open MaxMind database of countries or cities. Clones share it.
+#[derive(Debug, Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Reads the database at `path` into memory.
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| DnsError::InvalidConfig(format!("Cannot read {}: {e}", path.display())))?;
+        Ok(GeoIp {
+            reader: Arc::new(reader),
+        })
+    }
+
+    /// The country and continent of `address`, if the database has them.
+    pub fn locate(&self, address: IpAddr) -> Option<Location> {
+        let country: geoip2::Country = self.reader.lookup(address.to_canonical()).ok()?;
+        let location = Location {
+            country: country
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string),
+            continent: country
+                .continent
+                .and_then(|continent| continent.code)
+                .map(str::to_string),
+        };
+        (location != Location::default()).then_some(location)
+    }
+}
@@ -0,0 +1,66 @@
+//! Static name to address mappings from a hosts file, consulted by
+//! [`DNSResolver::lookup_ip`](crate::DNSResolver::lookup_ip) before any query
+//! is sent.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+
+#[cfg(windows)]
+const SYSTEM_HOSTS_PATH: &str = r"C:\Windows\System32\drivers\etc\hosts";
+#[cfg(not(windows))]
+const SYSTEM_HOSTS_PATH: &str = "/etc/hosts";
+
+/// The system hosts file, read once on first use. A missing or unreadable
+/// file counts as empty.
+pub(crate) static SYSTEM_HOSTS: LazyLock<Arc<Hosts>> =
+    LazyLock::new(|| Arc::new(Hosts::load(SYSTEM_HOSTS_PATH).unwrap_or_default()));
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hosts {
+    addresses: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Parses lines of an address followed by its names, with `#` starting a
+    /// comment. Lines whose first field is not an address are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut hosts = Hosts::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(address)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            for name in fields {
+                hosts.insert(name, address);
+            }
+        }
+        hosts
+    }
+
+    pub fn insert(&mut self, name: &str, address: IpAddr) {
+        let addresses = self.addresses.entry(normalize(name)).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    /// The addresses listed for `name`, in file order. Names are matched
+    /// case-insensitively, with or without a trailing dot.
+    pub fn lookup(&self, name: &str) -> &[IpAddr] {
+        self.addresses
+            .get(&normalize(name))
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,408 @@
+//! DNS messages in JSON (RFC 8427), so packets can be logged, diffed and
+//! replayed in a well-defined interchange format.
+//!
+//! Rdata of A, AAAA, NS, CNAME, PTR and SOA records is given in presentation
+//! format under an `rdata<TYPE>` member; anything else, including TXT, MX, SRV,
+//! NAPTR and the EDNS OPT record, is carried as `RDATAHEX`.
+
+use crate::edns::Edns;
+use crate::error::{DnsError, Result};
+use crate::flags::{DNSFlags, Opcode, Rcode};
+use crate::{
+    Class, DNSHeader, DNSPacket, DNSQuestion, DNSRecord, DNSRecordData, Mx, NameCompressor, Naptr,
+    RecordType, Soa, Srv, check_name, decode_name, parse_character_strings, u16_at,
+};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    #[serde(rename = "ID")]
+    id: u16,
+    #[serde(rename = "QR", deserialize_with = "flag")]
+    qr: bool,
+    #[serde(rename = "Opcode")]
+    opcode: u8,
+    #[serde(rename = "AA", deserialize_with = "flag")]
+    aa: bool,
+    #[serde(rename = "TC", deserialize_with = "flag")]
+    tc: bool,
+    #[serde(rename = "RD", deserialize_with = "flag")]
+    rd: bool,
+    #[serde(rename = "RA", deserialize_with = "flag")]
+    ra: bool,
+    #[serde(rename = "AD", default, deserialize_with = "flag")]
+    ad: bool,
+    #[serde(rename = "CD", default, deserialize_with = "flag")]
+    cd: bool,
+    #[serde(rename = "RCODE")]
+    rcode: u8,
+    // The counts are informational; sections are sized by their arrays.
+    #[serde(rename = "QDCOUNT", default, skip_deserializing)]
+    qdcount: u16,
+    #[serde(rename = "ANCOUNT", default, skip_deserializing)]
+    ancount: u16,
+    #[serde(rename = "NSCOUNT", default, skip_deserializing)]
+    nscount: u16,
+    #[serde(rename = "ARCOUNT", default, skip_deserializing)]
+    arcount: u16,
+    #[serde(rename = "questionRRs", default)]
+    questions: Vec<Question>,
+    #[serde(rename = "answerRRs", default)]
+    answers: Vec<Record>,
+    #[serde(rename = "authorityRRs", default)]
+    authorities: Vec<Record>,
+    #[serde(rename = "additionalRRs", default)]
+    additionals: Vec<Record>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Question {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    type_: u16,
+    #[serde(rename = "CLASS")]
+    class: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    type_: u16,
+    /// The requestor's payload size for OPT records.
+    #[serde(rename = "CLASS")]
+    class: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    #[serde(flatten)]
+    rdata: Rdata,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Rdata {
+    #[serde(rename = "rdataA")]
+    A(Ipv4Addr),
+    #[serde(rename = "rdataAAAA")]
+    Aaaa(Ipv6Addr),
+    #[serde(rename = "rdataNS")]
+    Ns(String),
+    #[serde(rename = "rdataCNAME")]
+    Cname(String),
+    #[serde(rename = "rdataPTR")]
+    Ptr(String),
+    #[serde(rename = "rdataSOA")]
+    Soa(String),
+    #[serde(rename = "RDATAHEX")]
+    Hex(String),
+}
+
+/// RFC 8427 booleans appear both as JSON booleans and as 0/1 in the wild.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Int(u8),
+    }
+    match Flag::deserialize(deserializer)? {
+        Flag::Bool(set) => Ok(set),
+        Flag::Int(0) => Ok(false),
+        Flag::Int(1) => Ok(true),
+        Flag::Int(other) => Err(D::Error::custom(format!("invalid flag value {other}"))),
+    }
+}
+
+impl From<&DNSPacket> for Message {
+    fn from(packet: &DNSPacket) -> Self {
+        let flags = packet.header.flags;
+        let mut additionals: Vec<Record> = packet.additionals.iter().map(Record::from).collect();
+        if let Some(edns) = &packet.edns {
+            additionals.push(Record::from(edns));
+        }
+        Message {
+            id: packet.header.id,
+            qr: flags.qr,
+            opcode: flags.opcode.into(),
+            aa: flags.aa,
+            tc: flags.tc,
+            rd: flags.rd,
+            ra: flags.ra,
+            ad: flags.ad,
+            cd: flags.cd,
+            rcode: flags.rcode.into(),
+            qdcount: packet.questions.len() as u16,
+            ancount: packet.answers.len() as u16,
+            nscount: packet.authorities.len() as u16,
+            arcount: additionals.len() as u16,
+            questions: packet.questions.iter().map(Question::from).collect(),
+            answers: packet.answers.iter().map(Record::from).collect(),
+            authorities: packet.authorities.iter().map(Record::from).collect(),
+            additionals,
+        }
+    }
+}
+
+impl TryFrom<Message> for DNSPacket {
+    type Error = DnsError;
+
+    fn try_from(message: Message) -> Result<Self> {
+        let records = |records: Vec<Record>| -> Result<Vec<DNSRecord>> {
+            records.into_iter().map(DNSRecord::try_from).collect()
+        };
+        let mut additionals = Vec::new();
+        let mut edns = None;
+        for record in message.additionals {
+            if record.type_ != RecordType::Opt as u16 {
+                additionals.push(DNSRecord::try_from(record)?);
+            } else if edns.replace(record.to_edns()?).is_some() {
+                return Err(malformed("More than one OPT record".to_string()));
+            }
+        }
+        let questions = message
+            .questions
+            .into_iter()
+            .map(DNSQuestion::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let answers = records(message.answers)?;
+        let authorities = records(message.authorities)?;
+        let header = DNSHeader {
+            id: message.id,
+            flags: DNSFlags {
+                qr: message.qr,
+                opcode: Opcode::from(message.opcode),
+                aa: message.aa,
+                tc: message.tc,
+                rd: message.rd,
+                ra: message.ra,
+                ad: message.ad,
+                cd: message.cd,
+                rcode: Rcode::from(message.rcode),
+            },
+            num_questions: questions.len() as u16,
+            num_answers: answers.len() as u16,
+            num_authorities: authorities.len() as u16,
+            num_additionals: (additionals.len() + edns.is_some() as usize) as u16,
+        };
+        Ok(DNSPacket {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            edns,
+        })
+    }
+}
+
+impl From<&DNSQuestion> for Question {
+    fn from(question: &DNSQuestion) -> Self {
+        Question {
+            name: question.name.clone(),
+            type_: question.type_.clone() as u16,
+            class: question.class.clone() as u16,
+        }
+    }
+}
+
+impl TryFrom<Question> for DNSQuestion {
+    type Error = DnsError;
+
+    fn try_from(question: Question) -> Result<Self> {
+        check_name(&question.name)?;
+        Ok(DNSQuestion::new(
+            question.name,
+            record_type(question.type_)?,
+            class(question.class)?,
+        ))
+    }
+}
+
+impl From<&DNSRecord> for Record {
+    fn from(record: &DNSRecord) -> Self {
+        let rdata = match (&record.data, &record.type_) {
+            (DNSRecordData::Ipv4Addr(ip), _) => Rdata::A(*ip),
+            (DNSRecordData::Ipv6Addr(ip), _) => Rdata::Aaaa(*ip),
+            (DNSRecordData::Name(name), RecordType::Ns) => Rdata::Ns(name.clone()),
+            (DNSRecordData::Name(name), RecordType::Cname) => Rdata::Cname(name.clone()),
+            (DNSRecordData::Name(name), RecordType::Ptr) => Rdata::Ptr(name.clone()),
+            (DNSRecordData::Soa(_), _) => Rdata::Soa(record.data.to_string()),
+            (DNSRecordData::Data(data), _) => Rdata::Hex(to_hex(data)),
+            (
+                DNSRecordData::Txt(_)
+                | DNSRecordData::Mx(_)
+                | DNSRecordData::Srv(_)
+                | DNSRecordData::Naptr(_),
+                _,
+            ) => {
+                let mut buf = Vec::new();
+                record.data.write(&mut buf, &mut NameCompressor::default());
+                Rdata::Hex(to_hex(&buf))
+            }
+            // A single name is never compressed against itself.
+            (DNSRecordData::Name(name), _) => {
+                let mut buf = Vec::new();
+                NameCompressor::default().write_name(&mut buf, name);
+                Rdata::Hex(to_hex(&buf))
+            }
+        };
+        Record {
+            name: record.name.clone(),
+            type_: record.type_.clone() as u16,
+            class: record.class.clone() as u16,
+            ttl: record.ttl,
+            rdata,
+        }
+    }
+}
+
+impl From<&Edns> for Record {
+    fn from(edns: &Edns) -> Self {
+        let mut buf = Vec::new();
+        edns.write(&mut buf);
+        // Root name, then type, class, TTL and rdata length.
+        let ttl = u32::from_be_bytes(buf[5..9].try_into().unwrap());
+        Record {
+            name: String::new(),
+            type_: RecordType::Opt as u16,
+            class: edns.udp_payload_size,
+            ttl,
+            rdata: Rdata::Hex(to_hex(&buf[11..])),
+        }
+    }
+}
+
+impl Record {
+    fn to_edns(&self) -> Result<Edns> {
+        let Rdata::Hex(hex) = &self.rdata else {
+            return Err(malformed(
+                "OPT record data must be given as RDATAHEX".to_string(),
+            ));
+        };
+        let rdata = from_hex(hex)?;
+        let mut buf = Vec::new();
+        buf.extend(self.type_.to_be_bytes());
+        buf.extend(self.class.to_be_bytes());
+        buf.extend(self.ttl.to_be_bytes());
+        buf.extend((rdata.len() as u16).to_be_bytes());
+        buf.extend(rdata);
+        Ok(Edns::parse(&buf, 0)?.0)
+    }
+}
+
+impl TryFrom<Record> for DNSRecord {
+    type Error = DnsError;
+
+    fn try_from(record: Record) -> Result<Self> {
+        let type_ = record_type(record.type_)?;
+        let data = match record.rdata {
+            Rdata::A(ip) => DNSRecordData::Ipv4Addr(ip),
+            Rdata::Aaaa(ip) => DNSRecordData::Ipv6Addr(ip),
+            Rdata::Ns(name) | Rdata::Cname(name) | Rdata::Ptr(name) => {
+                check_name(&name)?;
+                DNSRecordData::Name(name.trim_end_matches('.').to_string())
+            }
+            Rdata::Soa(soa) => DNSRecordData::Soa(parse_soa(&soa)?),
+            Rdata::Hex(hex) if type_ == RecordType::Txt => {
+                DNSRecordData::Txt(parse_character_strings(&from_hex(&hex)?)?)
+            }
+            Rdata::Hex(hex) if type_ == RecordType::Mx => {
+                let data = from_hex(&hex)?;
+                let (exchange, _) = decode_name(&data, 2)?;
+                DNSRecordData::Mx(Mx {
+                    preference: u16_at(&data, 0)?,
+                    exchange,
+                })
+            }
+            Rdata::Hex(hex) if type_ == RecordType::Srv => {
+                let data = from_hex(&hex)?;
+                let (target, _) = decode_name(&data, 6)?;
+                DNSRecordData::Srv(Srv {
+                    priority: u16_at(&data, 0)?,
+                    weight: u16_at(&data, 2)?,
+                    port: u16_at(&data, 4)?,
+                    target,
+                })
+            }
+            Rdata::Hex(hex) if type_ == RecordType::Naptr => {
+                DNSRecordData::Naptr(Naptr::parse(&from_hex(&hex)?, 0)?.0)
+            }
+            Rdata::Hex(hex) => DNSRecordData::Data(from_hex(&hex)?),
+        };
+        check_name(&record.name)?;
+        Ok(DNSRecord::new(
+            &record.name,
+            type_,
+            class(record.class)?,
+            record.ttl,
+            data,
+        ))
+    }
+}
+
+fn malformed(reason: String) -> DnsError {
+    DnsError::MalformedPacket(reason)
+}
+
+fn record_type(value: u16) -> Result<RecordType> {
+    RecordType::try_from(value).map_err(|_| DnsError::UnsupportedType(value))
+}
+
+fn class(value: u16) -> Result<Class> {
+    Class::try_from(value).map_err(|_| malformed(format!("Unsupported class {value}")))
+}
+
+fn parse_soa(text: &str) -> Result<Soa> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let [mname, rname, serial, refresh, retry, expire, minimum] = fields[..] else {
+        return Err(malformed(format!("Malformed SOA rdata: {text}")));
+    };
+    check_name(mname)?;
+    check_name(rname)?;
+    let number = |field: &str| {
+        field
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("Malformed SOA rdata: {text}")))
+    };
+    Ok(Soa {
+        mname: mname.trim_end_matches('.').to_string(),
+        rname: rname.trim_end_matches('.').to_string(),
+        serial: number(serial)?,
+        refresh: number(refresh)?,
+        retry: number(retry)?,
+        expire: number(expire)?,
+        minimum: number(minimum)?,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(malformed(format!("Malformed RDATAHEX: {hex}")));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| malformed(format!("Malformed RDATAHEX: {hex}")))
+        })
+        .collect()
+}
+
+impl Serialize for DNSPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Message::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DNSPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DNSPacket::try_from(Message::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
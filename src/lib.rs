@@ -1,19 +1,110 @@
+#[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+pub mod authority;
+#[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+pub mod background;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(all(feature = "blocklist", not(target_arch = "wasm32")))]
+pub mod blocklist;
+pub mod builder;
+#[cfg(feature = "cache")]
 mod cache;
+#[cfg(all(feature = "cli", not(target_arch = "wasm32")))]
+pub mod cli;
+#[cfg(feature = "resolver")]
+pub mod config;
+#[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+pub mod daemon;
+#[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+pub mod dashboard;
+#[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+pub mod delegation;
+#[cfg(all(feature = "dnssec", not(target_arch = "wasm32")))]
+pub mod dnssec;
+#[cfg(feature = "dnstap")]
+pub mod dnstap;
+pub mod doh;
+#[cfg(feature = "enum")]
+pub mod e164;
+pub mod edns;
+pub mod error;
+#[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+pub mod firewall;
+pub mod flags;
+#[cfg(all(feature = "geoip", not(target_arch = "wasm32")))]
+pub mod geoip;
+#[cfg(feature = "resolver")]
+pub mod hosts;
+pub mod idna;
+pub mod json;
+#[cfg(feature = "resolver")]
+pub mod mail;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "resolver")]
+pub mod observer;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+#[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+pub mod propagation;
+#[cfg(all(feature = "querylog", not(target_arch = "wasm32")))]
+pub mod querylog;
+#[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+pub mod ratelimit;
+#[cfg(feature = "resolver")]
+mod resolver;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub mod server;
+#[cfg(feature = "resolver")]
+pub mod service;
+#[cfg(feature = "resolver")]
+pub mod spf;
+#[cfg(feature = "resolver")]
+mod stats;
+#[cfg(all(
+    any(test, feature = "testutil"),
+    feature = "resolver",
+    not(target_arch = "wasm32")
+))]
+pub mod testutil;
+#[cfg(feature = "resolver")]
+mod trace;
+#[cfg(feature = "resolver")]
+pub mod transport;
+pub mod view;
+#[cfg(all(target_arch = "wasm32", feature = "doh"))]
+pub mod wasm;
+#[cfg(feature = "bytes")]
+pub mod wire;
+pub mod zone;
 
-use crate::cache::{DOMAIN_TO_IP_CACHE, IP_TO_DOMAIN_CACHE};
-use anyhow::Result;
+#[cfg(feature = "resolver")]
+pub use crate::resolver::{
+    DNSResolver, LookupResult, MailExchanger, ServerIdentity, WildcardCheck,
+};
+#[cfg(feature = "resolver")]
+pub use crate::stats::{LATENCY_BUCKETS_MS, ServerHealth, ServerStats};
+#[cfg(feature = "resolver")]
+pub use crate::trace::{HopOutcome, Trace, TraceHop};
+
+use crate::builder::MessageBuilder;
+use crate::edns::Edns;
+use crate::error::{DnsError, NameError, Result};
+use crate::flags::{DNSFlags, Opcode, Rcode};
 use num_enum::TryFromPrimitive;
 use rand::random;
-use std::net::Ipv4Addr;
-use tokio::net::UdpSocket;
-use tokio::time::{Duration, timeout};
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
-struct DNSHeader {
+/// The fixed twelve-byte header of a message. The section counts are those
+/// read off the wire, or computed when the message was built.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DNSHeader {
     id: u16,
-    flags: u16,
+    flags: DNSFlags,
     num_questions: u16,
     num_answers: u16,
     num_authorities: u16,
@@ -21,7 +112,32 @@ struct DNSHeader {
 }
 
 impl DNSHeader {
-    fn new(flags: u16, num_questions: u16) -> Self {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn flags(&self) -> DNSFlags {
+        self.flags
+    }
+
+    pub fn num_questions(&self) -> u16 {
+        self.num_questions
+    }
+
+    pub fn num_answers(&self) -> u16 {
+        self.num_answers
+    }
+
+    pub fn num_authorities(&self) -> u16 {
+        self.num_authorities
+    }
+
+    /// Includes the OPT record, if any.
+    pub fn num_additionals(&self) -> u16 {
+        self.num_additionals
+    }
+
+    fn new(flags: DNSFlags, num_questions: u16) -> Self {
         DNSHeader {
             id: random(),
             flags,
@@ -32,33 +148,47 @@ impl DNSHeader {
         }
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        [
-            self.id.to_be_bytes(),
-            self.flags.to_be_bytes(),
-            self.num_questions.to_be_bytes(),
-            self.num_answers.to_be_bytes(),
-            self.num_authorities.to_be_bytes(),
-            self.num_additionals.to_be_bytes(),
-        ]
-        .concat()
+    fn write(&self, buf: &mut Vec<u8>) {
+        for field in [
+            self.id,
+            u16::from(self.flags),
+            self.num_questions,
+            self.num_answers,
+            self.num_authorities,
+            self.num_additionals,
+        ] {
+            buf.extend(field.to_be_bytes());
+        }
     }
 
     fn parse(bytes: &[u8]) -> Result<Self> {
         Ok(Self {
-            id: u16::from_be_bytes(bytes[0..2].try_into()?),
-            flags: u16::from_be_bytes(bytes[2..4].try_into()?),
-            num_questions: u16::from_be_bytes(bytes[4..6].try_into()?),
-            num_answers: u16::from_be_bytes(bytes[6..8].try_into()?),
-            num_authorities: u16::from_be_bytes(bytes[8..10].try_into()?),
-            num_additionals: u16::from_be_bytes(bytes[10..12].try_into()?),
+            id: u16_at(bytes, 0)?,
+            flags: DNSFlags::from(u16_at(bytes, 2)?),
+            num_questions: u16_at(bytes, 4)?,
+            num_answers: u16_at(bytes, 6)?,
+            num_authorities: u16_at(bytes, 8)?,
+            num_additionals: u16_at(bytes, 10)?,
         })
     }
 }
 
-#[derive(Debug, Clone, Default, TryFromPrimitive, PartialEq)]
+/// Reads the big-endian `u16` at `at`, failing if the message ends first.
+pub(crate) fn u16_at(buf: &[u8], at: usize) -> Result<u16> {
+    let bytes = buf.get(at..at + 2).ok_or(DnsError::Truncated)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads the big-endian `u32` at `at`, failing if the message ends first.
+pub(crate) fn u32_at(buf: &[u8], at: usize) -> Result<u32> {
+    let bytes = buf.get(at..at + 4).ok_or(DnsError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[derive(Debug, Clone, Default, TryFromPrimitive, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[repr(u16)]
-enum RecordType {
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordType {
     #[default]
     A = 1,
     Ns = 2,
@@ -67,19 +197,118 @@ enum RecordType {
     Cname = 5,
     Soa = 6,
     Ptr = 12,
+    Mx = 15,
+    Txt = 16,
     Aaaa = 28,
+    /// Service location (RFC 2782).
+    Srv = 33,
+    /// Naming authority pointer: a rewrite rule (RFC 3403).
+    Naptr = 35,
+    Opt = 41,
+    /// Delegation signer: the digest of a child zone's key (RFC 4034).
+    Ds = 43,
+    Rrsig = 46,
+    Nsec = 47,
+    Dnskey = 48,
+    Nsec3 = 50,
+    /// A whole zone, asked for in a question only (RFC 5936).
+    Axfr = 252,
+    /// Every record of a name, asked for in a question only (RFC 8482).
+    Any = 255,
 }
 
-#[derive(Debug, Clone, Default, TryFromPrimitive, PartialEq)]
+#[derive(Debug, Clone, Default, TryFromPrimitive, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[repr(u16)]
-enum Class {
+#[serde(rename_all = "UPPERCASE")]
+pub enum Class {
     #[default]
     In = 1,
+    /// CHAOS, used by servers to report their identity and version.
+    Ch = 3,
+    /// Hesiod.
+    Hs = 4,
+    /// Only meaningful in dynamic update prerequisites (RFC 2136).
+    None = 254,
+    /// Matches any class in a question.
+    Any = 255,
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_uppercase())
+    }
+}
+
+/// Parses a type mnemonic such as `AAAA`, in any case, or the generic
+/// `TYPE28` form of RFC 3597.
+impl FromStr for RecordType {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(value) = upper
+            .strip_prefix("TYPE")
+            .and_then(|code| code.parse::<u16>().ok())
+        {
+            return RecordType::try_from(value).map_err(|_| DnsError::UnsupportedType(value));
+        }
+        [
+            RecordType::A,
+            RecordType::Ns,
+            RecordType::Md,
+            RecordType::Mf,
+            RecordType::Cname,
+            RecordType::Soa,
+            RecordType::Ptr,
+            RecordType::Mx,
+            RecordType::Txt,
+            RecordType::Aaaa,
+            RecordType::Srv,
+            RecordType::Naptr,
+            RecordType::Opt,
+            RecordType::Ds,
+            RecordType::Rrsig,
+            RecordType::Nsec,
+            RecordType::Dnskey,
+            RecordType::Nsec3,
+            RecordType::Axfr,
+            RecordType::Any,
+        ]
+        .into_iter()
+        .find(|type_| type_.to_string() == upper)
+        .ok_or_else(|| DnsError::UnknownType(s.to_string()))
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_uppercase())
+    }
+}
+
+fn parse_type(buf: &[u8], cursor: usize) -> Result<RecordType> {
+    let value = u16_at(buf, cursor)?;
+    RecordType::try_from(value).map_err(|_| DnsError::UnsupportedType(value))
+}
+
+fn parse_class(buf: &[u8], cursor: usize) -> Result<Class> {
+    let value = u16_at(buf, cursor)?;
+    Class::try_from(value).map_err(|_| DnsError::MalformedPacket(format!("Unknown class {value}")))
+}
+
+/// Writes `name` fully qualified, with its trailing dot.
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{name}.")
+    }
 }
 
-#[derive(Debug, Clone)]
-struct DNSQuestion {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DNSQuestion {
     name: String,
+    #[serde(rename = "type")]
     type_: RecordType,
     class: Class,
 }
@@ -88,116 +317,578 @@ impl DNSQuestion {
     fn new(name: String, type_: RecordType, class: Class) -> Self {
         Self { name, type_, class }
     }
-    fn to_bytes(&self) -> Vec<u8> {
-        [
-            self.name.as_bytes(),
-            &(self.type_.clone() as u16).to_be_bytes(),
-            &(self.class.clone() as u16).to_be_bytes(),
-        ]
-        .concat()
+
+    /// The queried name, without a trailing dot.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn record_type(&self) -> &RecordType {
+        &self.type_
+    }
+
+    pub fn class(&self) -> &Class {
+        &self.class
+    }
+
+    fn write(&self, buf: &mut Vec<u8>, names: &mut NameCompressor) {
+        names.write_name(buf, &self.name);
+        buf.extend((self.type_.clone() as u16).to_be_bytes());
+        buf.extend((self.class.clone() as u16).to_be_bytes());
     }
 
     fn parse(buf: &[u8], cursor_start: usize) -> Result<(Self, usize)> {
         let mut cursor = cursor_start;
-        let (name, length) = decode_name(buf, cursor);
+        let (name, length) = decode_name(buf, cursor)?;
         cursor += length;
         Ok((
             Self {
                 name,
-                type_: RecordType::try_from(u16::from_be_bytes(
-                    buf[cursor..cursor + 2].try_into()?,
-                ))
-                .unwrap(),
-                class: Class::try_from(u16::from_be_bytes(buf[cursor + 2..cursor + 4].try_into()?))
-                    .unwrap(),
+                type_: parse_type(buf, cursor)?,
+                class: parse_class(buf, cursor + 2)?,
             },
             cursor + 4 - cursor_start,
         ))
     }
 }
 
-fn decode_name(buf: &[u8], cursor_start: usize) -> (String, usize) {
-    let mut cursor = cursor_start;
-    let mut length = buf[cursor] as usize;
-    let mut components = Vec::new();
-    while length != 0 {
-        if length & 0b11000000 != 0 {
-            // DNS component max length is 63 bytes so in case first 2 bits are set,
-            // then takes the bottom 6 bits of the length byte, plus the next byte,
-            // and converts that to a pointer.
-            components.push(decode_compressed_name(buf, cursor));
-            cursor += 2;
-            return (components.join("."), cursor - cursor_start);
-        } else {
-            let start = cursor + 1;
-            cursor += length + 1;
-            components.push(String::from_utf8_lossy(&buf[start..cursor]).into_owned());
-            length = buf[cursor] as usize;
+/// Question line as printed by dig, commented out with a leading `;`.
+impl fmt::Display for DNSQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ";{}\t\t{}\t{}", fqdn(&self.name), self.class, self.type_)
+    }
+}
+
+/// Remembers where each name suffix was written in a message so later
+/// occurrences can be replaced by a pointer (RFC 1035 section 4.1.4).
+#[derive(Debug, Default)]
+struct NameCompressor {
+    /// Where the message starts in the buffer written to.
+    base: usize,
+    /// Offsets keyed by [`suffix_hash`]; a hit is confirmed against the
+    /// message, so a collision only costs a missed compression.
+    offsets: HashMap<u64, u16>,
+}
+
+impl NameCompressor {
+    /// Pointers only have 14 bits for the offset.
+    const MAX_OFFSET: usize = 0x3FFF;
+
+    /// For a message written from `base` onwards.
+    fn new(base: usize) -> Self {
+        Self {
+            base,
+            offsets: HashMap::new(),
         }
     }
-    // Added one for the zero at the end
-    cursor += 1;
-    (components.join("."), cursor - cursor_start)
+
+    fn write_name(&mut self, buf: &mut Vec<u8>, name: &str) {
+        let mut rest = name.strip_suffix('.').unwrap_or(name);
+        while !rest.is_empty() {
+            let hash = suffix_hash(rest);
+            if let Some(&offset) = self.offsets.get(&hash)
+                && wire_name_eq(&buf[self.base..], offset as usize, rest)
+            {
+                buf.extend((0b11000000_00000000 | offset).to_be_bytes());
+                return;
+            }
+            let offset = buf.len() - self.base;
+            if offset <= Self::MAX_OFFSET {
+                self.offsets.entry(hash).or_insert(offset as u16);
+            }
+            let (label, tail) = rest.split_once('.').unwrap_or((rest, ""));
+            buf.push(label.len() as u8);
+            buf.extend(label.as_bytes());
+            rest = tail;
+        }
+        buf.push(0);
+    }
+}
+
+/// FNV-1a over the lowercased name, since name comparisons ignore ASCII case.
+fn suffix_hash(suffix: &str) -> u64 {
+    suffix.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte.to_ascii_lowercase() as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Whether the name at `start` in `buf` is `name`, ignoring ASCII case.
+/// `name` is dotted, without a trailing dot.
+fn wire_name_eq(buf: &[u8], start: usize, name: &str) -> bool {
+    let mut expected = name.split('.').filter(|_| !name.is_empty());
+    let mut matches = true;
+    let walked = walk_name(buf, start, |label| {
+        matches &= expected
+            .next()
+            .is_some_and(|part| part.as_bytes().eq_ignore_ascii_case(label));
+    });
+    walked.is_ok() && matches && expected.next().is_none()
+}
+
+/// Longest label allowed by RFC 1035 section 2.3.4.
+const MAX_LABEL_LEN: usize = 63;
+/// Longest name allowed in wire format, including length bytes and the root label.
+const MAX_NAME_LEN: usize = 255;
+
+/// Checks a presentation-format name against the label and name size limits.
+fn check_name(name: &str) -> Result<(), NameError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return Ok(());
+    }
+    let mut wire_len = 1;
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(NameError::EmptyLabel(name.to_string()));
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(NameError::LabelTooLong(label.len()));
+        }
+        wire_len += label.len() + 1;
+    }
+    if wire_len > MAX_NAME_LEN {
+        return Err(NameError::NameTooLong(wire_len));
+    }
+    Ok(())
+}
+
+/// Decodes the possibly compressed name at `cursor_start`, returning it with
+/// the number of bytes it takes up at that position.
+fn decode_name(buf: &[u8], cursor_start: usize) -> Result<(String, usize), NameError> {
+    let mut components = Vec::new();
+    let consumed = walk_name(buf, cursor_start, |label| {
+        components.push(String::from_utf8_lossy(label).into_owned());
+    })?;
+    Ok((components.join("."), consumed))
 }
 
-fn decode_compressed_name(buf: &[u8], cursor_start: usize) -> String {
-    let cursor =
-        u16::from_be_bytes([(buf[cursor_start] & 0b00111111), buf[cursor_start + 1]]) as usize;
-    decode_name(buf, cursor).0
+/// Follows the name at `cursor_start`, handing each label to `on_label`
+/// without copying it, and returns the bytes the name occupies in place.
+fn walk_name<'a>(
+    buf: &'a [u8],
+    cursor_start: usize,
+    mut on_label: impl FnMut(&'a [u8]),
+) -> Result<usize, NameError> {
+    let mut cursor = cursor_start;
+    let mut wire_len = 1;
+    // Set once the first pointer is followed; the rest of the name lives elsewhere.
+    let mut consumed = None;
+    loop {
+        let length = *buf.get(cursor).ok_or(NameError::Truncated)? as usize;
+        match length & 0b11000000 {
+            0b11000000 => {
+                // Takes the bottom 6 bits of the length byte, plus the next byte,
+                // and converts that to a pointer.
+                let low = *buf.get(cursor + 1).ok_or(NameError::Truncated)?;
+                let target = u16::from_be_bytes([length as u8 & 0b00111111, low]) as usize;
+                // Only pointing backwards rules out loops: any cycle would have
+                // to pass through a label and so hit the name length limit.
+                if target >= cursor {
+                    return Err(NameError::BadPointer(cursor));
+                }
+                consumed.get_or_insert_with(|| cursor + 2 - cursor_start);
+                cursor = target;
+            }
+            0 if length == 0 => break,
+            0 => {
+                let start = cursor + 1;
+                cursor += length + 1;
+                wire_len += length + 1;
+                if wire_len > MAX_NAME_LEN {
+                    return Err(NameError::NameTooLong(wire_len));
+                }
+                on_label(buf.get(start..cursor).ok_or(NameError::Truncated)?);
+            }
+            // The 0b01 and 0b10 prefixes would be labels longer than 63 bytes.
+            _ => return Err(NameError::LabelTooLong(length)),
+        }
+    }
+    // Added one for the zero at the end
+    Ok(consumed.unwrap_or_else(|| cursor + 1 - cursor_start))
 }
 
-#[allow(unused)]
-#[derive(Debug, Clone)]
-enum DNSRecordData {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DNSRecordData {
     Data(Vec<u8>),
     Name(String),
     Ipv4Addr(Ipv4Addr),
+    Ipv6Addr(Ipv6Addr),
+    Soa(Soa),
+    Mx(Mx),
+    Srv(Srv),
+    Naptr(Naptr),
+    /// The character-strings of a TXT record.
+    Txt(Vec<String>),
+}
+
+impl DNSRecordData {
+    fn write(&self, buf: &mut Vec<u8>, names: &mut NameCompressor) {
+        match self {
+            DNSRecordData::Data(data) => buf.extend(data),
+            DNSRecordData::Name(name) => names.write_name(buf, name),
+            DNSRecordData::Ipv4Addr(ip) => buf.extend(ip.octets()),
+            DNSRecordData::Ipv6Addr(ip) => buf.extend(ip.octets()),
+            DNSRecordData::Soa(soa) => {
+                names.write_name(buf, &soa.mname);
+                names.write_name(buf, &soa.rname);
+                for field in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.extend(field.to_be_bytes());
+                }
+            }
+            DNSRecordData::Mx(mx) => {
+                buf.extend(mx.preference.to_be_bytes());
+                names.write_name(buf, &mx.exchange);
+            }
+            DNSRecordData::Srv(srv) => {
+                for field in [srv.priority, srv.weight, srv.port] {
+                    buf.extend(field.to_be_bytes());
+                }
+                // The target is never compressed (RFC 2782).
+                NameCompressor::default().write_name(buf, &srv.target);
+            }
+            DNSRecordData::Naptr(naptr) => {
+                buf.extend(naptr.order.to_be_bytes());
+                buf.extend(naptr.preference.to_be_bytes());
+                for string in [&naptr.flags, &naptr.services, &naptr.regexp] {
+                    buf.push(string.len() as u8);
+                    buf.extend(string.as_bytes());
+                }
+                // Nor is the replacement (RFC 3403).
+                NameCompressor::default().write_name(buf, &naptr.replacement);
+            }
+            DNSRecordData::Txt(strings) => {
+                for string in strings {
+                    buf.push(string.len() as u8);
+                    buf.extend(string.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+impl Naptr {
+    /// Reads NAPTR rdata starting at `cursor` in `buf`, returning it with the
+    /// number of bytes read.
+    fn parse(buf: &[u8], cursor: usize) -> Result<(Self, usize)> {
+        let order = u16_at(buf, cursor)?;
+        let preference = u16_at(buf, cursor + 2)?;
+        let mut offset = cursor + 4;
+        let mut strings = Vec::with_capacity(3);
+        for _ in 0..3 {
+            let len = *buf.get(offset).ok_or(DnsError::Truncated)? as usize;
+            let string = buf
+                .get(offset + 1..offset + 1 + len)
+                .ok_or(DnsError::Truncated)?;
+            strings.push(String::from_utf8_lossy(string).into_owned());
+            offset += 1 + len;
+        }
+        let (replacement, len) = decode_name(buf, offset)?;
+        offset += len;
+        let [flags, services, regexp] = strings.try_into().expect("three strings were read");
+        let naptr = Naptr {
+            order,
+            preference,
+            flags,
+            services,
+            regexp,
+            replacement,
+        };
+        Ok((naptr, offset - cursor))
+    }
+}
+
+/// Splits TXT rdata into its length-prefixed character-strings.
+fn parse_character_strings(data: &[u8]) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut cursor = 0;
+    while cursor < data.len() {
+        let end = cursor + 1 + data[cursor] as usize;
+        if end > data.len() {
+            return Err(DnsError::MalformedPacket(
+                "Character-string overruns the record data".to_string(),
+            ));
+        }
+        strings.push(String::from_utf8_lossy(&data[cursor + 1..end]).into_owned());
+        cursor = end;
+    }
+    Ok(strings)
+}
+
+/// Presentation format of the rdata, as used in master files.
+impl fmt::Display for DNSRecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Generic encoding for rdata of unknown types (RFC 3597).
+            DNSRecordData::Data(data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                    for byte in data {
+                        write!(f, "{byte:02x}")?;
+                    }
+                }
+                Ok(())
+            }
+            DNSRecordData::Name(name) => write!(f, "{}", fqdn(name)),
+            DNSRecordData::Ipv4Addr(ip) => write!(f, "{ip}"),
+            DNSRecordData::Ipv6Addr(ip) => write!(f, "{ip}"),
+            DNSRecordData::Soa(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(&soa.mname),
+                fqdn(&soa.rname),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            DNSRecordData::Mx(mx) => write!(f, "{} {}", mx.preference, fqdn(&mx.exchange)),
+            DNSRecordData::Srv(srv) => write!(
+                f,
+                "{} {} {} {}",
+                srv.priority,
+                srv.weight,
+                srv.port,
+                fqdn(&srv.target)
+            ),
+            DNSRecordData::Naptr(naptr) => {
+                write!(f, "{} {} ", naptr.order, naptr.preference)?;
+                for string in [&naptr.flags, &naptr.services, &naptr.regexp] {
+                    write_character_string(f, string)?;
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", fqdn(&naptr.replacement))
+            }
+            DNSRecordData::Txt(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write_character_string(f, string)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A character-string in quotes, escaping quotes, backslashes and bytes that
+/// are not printable ASCII.
+fn write_character_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for byte in string.bytes() {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            b' '..=b'~' => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{byte:03}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
 }
 
-#[allow(unused)]
-#[derive(Debug, Clone)]
-struct DNSRecord {
+/// A mail exchanger; lower preferences are tried first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mx {
+    pub preference: u16,
+    /// The mail server, or the root name for a null MX (RFC 7505) stating the
+    /// domain accepts no mail.
+    pub exchange: String,
+}
+
+/// A server for a service; lower priorities are tried first, and servers of
+/// equal priority are picked in proportion to their weight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// The server, or the root name when the service is not offered.
+    pub target: String,
+}
+
+/// A rewrite rule; lower orders are applied first, and preference breaks ties
+/// within an order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: String,
+    pub services: String,
+    /// A substitution of the form `!pattern!replacement!`, with any
+    /// delimiter, or empty if `replacement` is used instead.
+    pub regexp: String,
+    /// The next name to look up, or the root name if `regexp` is used.
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DNSRecord {
     name: String,
+    #[serde(rename = "type")]
     type_: RecordType,
     class: Class,
     ttl: u32,
     data: DNSRecordData,
 }
 
+/// The record as a master file line: owner, TTL, class, type and rdata.
+impl fmt::Display for DNSRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            fqdn(&self.name),
+            self.ttl,
+            self.class,
+            self.type_,
+            self.data
+        )
+    }
+}
+
 impl DNSRecord {
+    pub fn new(name: &str, type_: RecordType, class: Class, ttl: u32, data: DNSRecordData) -> Self {
+        Self {
+            name: name.to_string(),
+            type_,
+            class,
+            ttl,
+            data,
+        }
+    }
+
+    /// The owner name, without a trailing dot.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn record_type(&self) -> &RecordType {
+        &self.type_
+    }
+
+    pub fn class(&self) -> &Class {
+        &self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn data(&self) -> &DNSRecordData {
+        &self.data
+    }
+
+    fn bad_length(type_: &RecordType, data_len: usize) -> DnsError {
+        DnsError::MalformedPacket(format!("{type_} record with {data_len} bytes of data"))
+    }
+
+    fn write(&self, buf: &mut Vec<u8>, names: &mut NameCompressor) {
+        names.write_name(buf, &self.name);
+        buf.extend((self.type_.clone() as u16).to_be_bytes());
+        buf.extend((self.class.clone() as u16).to_be_bytes());
+        buf.extend(self.ttl.to_be_bytes());
+        // RDLENGTH is only known once the (possibly compressed) rdata is written.
+        let len_at = buf.len();
+        buf.extend([0, 0]);
+        self.data.write(buf, names);
+        let data_len = (buf.len() - len_at - 2) as u16;
+        buf[len_at..len_at + 2].copy_from_slice(&data_len.to_be_bytes());
+    }
+
     fn parse(buf: &[u8], start_cursor: usize) -> Result<(Self, usize)> {
         let mut cursor = start_cursor;
-        let (name, length) = decode_name(buf, cursor);
+        let (name, length) = decode_name(buf, cursor)?;
         cursor += length;
-        let type_ =
-            RecordType::try_from(u16::from_be_bytes(buf[cursor..cursor + 2].try_into()?)).unwrap();
-        let class =
-            Class::try_from(u16::from_be_bytes(buf[cursor + 2..cursor + 4].try_into()?)).unwrap();
-        let ttl = u32::from_be_bytes(buf[cursor + 4..cursor + 8].try_into()?);
-        let data_len = u16::from_be_bytes(buf[cursor + 8..cursor + 10].try_into()?) as usize;
+        let type_ = parse_type(buf, cursor)?;
+        let class = parse_class(buf, cursor + 2)?;
+        let ttl = u32_at(buf, cursor + 4)?;
+        let data_len = u16_at(buf, cursor + 8)? as usize;
         cursor += 10;
-        let data = match type_ {
+        let rdata = buf
+            .get(cursor..cursor + data_len)
+            .ok_or(DnsError::Truncated)?;
+        // Each arm also tells how many bytes it read, which must be all of
+        // RDLENGTH: a name running past it, or short of it, is malformed.
+        let (data, used) = match type_ {
             RecordType::A => {
-                let ip = Ipv4Addr::new(
-                    buf[cursor],
-                    buf[cursor + 1],
-                    buf[cursor + 2],
-                    buf[cursor + 3],
-                );
-                cursor += 4;
-                DNSRecordData::Ipv4Addr(ip)
+                let octets: [u8; 4] = rdata
+                    .try_into()
+                    .map_err(|_| DNSRecord::bad_length(&type_, data_len))?;
+                (DNSRecordData::Ipv4Addr(Ipv4Addr::from(octets)), 4)
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = rdata
+                    .try_into()
+                    .map_err(|_| DNSRecord::bad_length(&type_, data_len))?;
+                (DNSRecordData::Ipv6Addr(Ipv6Addr::from(octets)), 16)
             }
             RecordType::Ns | RecordType::Cname | RecordType::Ptr => {
-                let (name, len) = decode_name(buf, cursor);
-                cursor += len;
-                DNSRecordData::Name(name)
+                let (name, len) = decode_name(buf, cursor)?;
+                (DNSRecordData::Name(name), len)
             }
-            _ => {
-                let data = buf[cursor..cursor + data_len].to_vec();
-                cursor += data_len;
-                DNSRecordData::Data(data)
+            RecordType::Soa => {
+                let (mname, mname_len) = decode_name(buf, cursor)?;
+                let (rname, rname_len) = decode_name(buf, cursor + mname_len)?;
+                let fields = cursor + mname_len + rname_len;
+                let field = |i: usize| u32_at(buf, fields + 4 * i);
+                let soa = Soa {
+                    mname,
+                    rname,
+                    serial: field(0)?,
+                    refresh: field(1)?,
+                    retry: field(2)?,
+                    expire: field(3)?,
+                    minimum: field(4)?,
+                };
+                (DNSRecordData::Soa(soa), mname_len + rname_len + 20)
             }
+            RecordType::Mx => {
+                let preference = u16_at(buf, cursor)?;
+                let (exchange, len) = decode_name(buf, cursor + 2)?;
+                let mx = Mx {
+                    preference,
+                    exchange,
+                };
+                (DNSRecordData::Mx(mx), 2 + len)
+            }
+            RecordType::Srv => {
+                let field = |i: usize| u16_at(buf, cursor + 2 * i);
+                let (priority, weight, port) = (field(0)?, field(1)?, field(2)?);
+                let (target, len) = decode_name(buf, cursor + 6)?;
+                let srv = Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                };
+                (DNSRecordData::Srv(srv), 6 + len)
+            }
+            RecordType::Naptr => {
+                let (naptr, len) = Naptr::parse(buf, cursor)?;
+                (DNSRecordData::Naptr(naptr), len)
+            }
+            RecordType::Txt => (
+                DNSRecordData::Txt(parse_character_strings(rdata)?),
+                data_len,
+            ),
+            _ => (DNSRecordData::Data(rdata.to_vec()), data_len),
         };
+        if used != data_len {
+            return Err(DNSRecord::bad_length(&type_, data_len));
+        }
+        cursor += data_len;
         Ok((
             Self {
                 name,
@@ -211,19 +902,102 @@ impl DNSRecord {
     }
 }
 
-#[allow(unused)]
-#[derive(Debug, Clone)]
+/// Its serde implementation follows the RFC 8427 layout, see [`json`].
+#[derive(Debug, Clone, PartialEq)]
 pub struct DNSPacket {
     header: DNSHeader,
     questions: Vec<DNSQuestion>,
     answers: Vec<DNSRecord>,
     authorities: Vec<DNSRecord>,
     additionals: Vec<DNSRecord>,
+    /// The OPT pseudo-record, kept out of `additionals` since it is not real data.
+    edns: Option<Edns>,
 }
 
 impl DNSPacket {
-    fn parse(buf: &[u8]) -> Result<Self> {
+    /// Serializes the packet to wire format, compressing repeated names. Header
+    /// section counts are taken from the sections themselves, so they always agree
+    /// with what is written.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(512);
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Like [`DNSPacket::to_bytes`], appending to `buf` so its allocation can
+    /// be reused or the message framed without copying.
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        let header = DNSHeader {
+            num_questions: self.questions.len() as u16,
+            num_answers: self.answers.len() as u16,
+            num_authorities: self.authorities.len() as u16,
+            num_additionals: (self.additionals.len() + self.edns.is_some() as usize) as u16,
+            ..self.header.clone()
+        };
+        let mut names = NameCompressor::new(buf.len());
+        header.write(buf);
+        for question in &self.questions {
+            question.write(buf, &mut names);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            record.write(buf, &mut names);
+        }
+        if let Some(edns) = &self.edns {
+            edns.write(buf);
+        }
+    }
+
+    /// The wire format preceded by its two-byte length, as sent over TCP
+    /// (RFC 1035 section 4.2.2).
+    #[cfg(any(
+        all(feature = "resolver", not(target_arch = "wasm32")),
+        feature = "bytes"
+    ))]
+    pub(crate) fn to_tcp_bytes(&self) -> Result<Vec<u8>> {
+        // Room for the length, filled in once the message is written after it.
+        let mut message = vec![0, 0];
+        self.write_to(&mut message);
+        let len = u16::try_from(message.len() - 2).map_err(|_| {
+            DnsError::InvalidMessage(format!("Message of {} bytes", message.len() - 2))
+        })?;
+        message[..2].copy_from_slice(&len.to_be_bytes());
+        Ok(message)
+    }
+
+    pub fn header(&self) -> &DNSHeader {
+        &self.header
+    }
+
+    pub fn questions(&self) -> &[DNSQuestion] {
+        &self.questions
+    }
+
+    pub fn answers(&self) -> &[DNSRecord] {
+        &self.answers
+    }
+
+    pub fn authorities(&self) -> &[DNSRecord] {
+        &self.authorities
+    }
+
+    /// The additional records, not including the OPT record; see [`DNSPacket::edns`].
+    pub fn additionals(&self) -> &[DNSRecord] {
+        &self.additionals
+    }
+
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    /// Parses a complete wire-format DNS message.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
         let header = DNSHeader::parse(buf)?;
+
         const DNS_HEADER_LEN: usize = 12;
         let mut cursor = DNS_HEADER_LEN;
         let mut questions = Vec::new();
@@ -248,7 +1022,21 @@ impl DNSPacket {
         }
 
         let mut additionals = Vec::new();
+        let mut edns = None;
         for _ in 0..header.num_additionals {
+            let (name, name_len) = decode_name(buf, cursor)?;
+            let type_ = u16_at(buf, cursor + name_len)?;
+            if type_ == RecordType::Opt as u16 {
+                if edns.is_some() || !name.is_empty() {
+                    return Err(DnsError::MalformedPacket(
+                        "Misplaced or repeated OPT record".to_string(),
+                    ));
+                }
+                let (opt, length) = Edns::parse(buf, cursor + name_len)?;
+                edns = Some(opt);
+                cursor += name_len + length;
+                continue;
+            }
             let (additional, length) = DNSRecord::parse(buf, cursor)?;
             additionals.push(additional);
             cursor += length;
@@ -259,169 +1047,210 @@ impl DNSPacket {
             answers,
             authorities,
             additionals,
+            edns,
         })
     }
 
-    fn get_answer_ip(&self) -> Option<Ipv4Addr> {
-        for answer in &self.answers {
-            if let DNSRecordData::Ipv4Addr(name) = answer.data {
-                return Some(name);
-            }
-        }
-        None
-    }
-
-    fn get_answer_domain(&self) -> Option<&str> {
-        for answer in &self.answers {
-            if let DNSRecordData::Name(name) = &answer.data {
-                return Some(name.as_str());
-            }
-        }
-        None
-    }
-
-    fn get_nameserver_ip(&self) -> Option<Ipv4Addr> {
-        for record in &self.additionals {
-            if let DNSRecordData::Ipv4Addr(ip) = record.data {
-                return Some(ip);
-            }
+    /// Standard queries must carry exactly one question (RFC 9619). For any
+    /// other count returns the FORMERR response a server should send back.
+    pub fn reject_multi_question(&self) -> Option<DNSPacket> {
+        if self.header.flags.opcode != Opcode::Query || self.questions.len() == 1 {
+            return None;
         }
-        None
+        MessageBuilder::response_to(self)
+            .rcode(Rcode::FormErr)
+            .build()
+            .ok()
     }
 
-    fn get_nameserver_domain(&self) -> Option<&str> {
-        for record in &self.authorities {
-            if let DNSRecordData::Name(name) = &record.data {
-                return Some(name.as_str());
-            }
+    /// Drops the authority and additional records a response does not need,
+    /// like BIND's `minimal-responses`, making it smaller and a poorer tool
+    /// for amplification. A negative answer keeps the SOA it is cached by
+    /// (RFC 2308) with its DNSSEC proof, and a referral its NS records and
+    /// their glue. The OPT record is kept.
+    pub fn minimize(&mut self) {
+        let rcode = self.header.flags.rcode;
+        let negative =
+            rcode == Rcode::NxDomain || (rcode == Rcode::NoError && self.answers.is_empty());
+        let has_soa = self.authorities.iter().any(|r| r.type_ == RecordType::Soa);
+        if negative && has_soa {
+            self.authorities.retain(|r| {
+                matches!(
+                    r.type_,
+                    RecordType::Soa | RecordType::Nsec | RecordType::Nsec3 | RecordType::Rrsig
+                )
+            });
+            self.additionals.clear();
+        } else if negative && rcode == Rcode::NoError {
+            self.authorities.retain(|r| r.type_ == RecordType::Ns);
+            let servers: Vec<String> = self
+                .authorities
+                .iter()
+                .filter_map(|r| match &r.data {
+                    DNSRecordData::Name(server) => Some(server.trim_end_matches('.').to_string()),
+                    _ => None,
+                })
+                .collect();
+            self.additionals.retain(|r| {
+                matches!(r.type_, RecordType::A | RecordType::Aaaa)
+                    && servers
+                        .iter()
+                        .any(|server| server.eq_ignore_ascii_case(r.name.trim_end_matches('.')))
+            });
+        } else {
+            self.authorities.clear();
+            self.additionals.clear();
         }
-        None
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct DNSResolver {
-    id_addr: Ipv4Addr,
-}
-
-impl Default for DNSResolver {
-    fn default() -> Self {
-        DNSResolver::new("198.41.0.4")
     }
 }
 
-impl DNSResolver {
-    pub fn new(id_addr: &str) -> Self {
-        DNSResolver {
-            id_addr: id_addr.parse::<Ipv4Addr>().unwrap(),
+/// Renders the packet the way dig does, header first and then each non-empty
+/// section under its `;; ... SECTION:` heading.
+impl fmt::Display for DNSPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = self.header.flags;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            flags.opcode, flags.rcode, self.header.id
+        )?;
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags,
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len() + self.edns.is_some() as usize
+        )?;
+        if let Some(edns) = &self.edns {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n{edns}\n")?;
         }
-    }
-
-    fn encode_dns_name(name: &str) -> Vec<u8> {
-        let mut encoded = Vec::new();
-        for component in name.split('.') {
-            encoded.push(component.len() as u8);
-            encoded.extend(component.as_bytes());
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{question}")?;
+            }
         }
-        encoded.push(0);
-        encoded
-    }
-
-    fn build_query(domain_name: &str, record_type: RecordType, class: Class) -> Vec<u8> {
-        let encoded_name = Self::encode_dns_name(domain_name);
-        let header = DNSHeader::new(0, 1).to_bytes();
-        let questions =
-            DNSQuestion::new(String::from_utf8(encoded_name).unwrap(), record_type, class)
-                .to_bytes();
-        [header, questions].concat()
-    }
-
-    async fn lookup(
-        domain_name: &str,
-        ip_addr: &Ipv4Addr,
-        record_type: RecordType,
-    ) -> Result<DNSPacket> {
-        info!("Querying {ip_addr} for {domain_name}");
-        let query = Self::build_query(domain_name, record_type, Class::In);
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-        socket.send_to(&query, (*ip_addr, 53)).await?;
-
-        let mut buf = [0; 1024];
-        let recv_result = timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await;
-        let size = match recv_result {
-            Ok(Ok((size, _src))) => size,
-            Ok(Err(e)) => return Err(e.into()),
-            Err(_) => return Err(anyhow::anyhow!("Timed out waiting for response")),
-        };
-        DNSPacket::parse(&buf[..size])
-    }
-
-    pub async fn resolve(&self, domain_name: &str) -> Result<Ipv4Addr> {
-        if let Some(ip) = DOMAIN_TO_IP_CACHE.get(domain_name).await {
-            return Ok(ip);
-        }
-        let mut ip_addr = self.id_addr;
-        loop {
-            let dns_packet = Self::lookup(domain_name, &ip_addr, RecordType::A).await?;
-            if let Some(ip) = dns_packet.get_answer_ip() {
-                DOMAIN_TO_IP_CACHE.insert(domain_name.to_string(), ip).await;
-                return Ok(ip);
-            } else if let Some(name) = dns_packet.get_answer_domain() {
-                return Box::pin(self.resolve(name)).await;
-            } else if let Some(ns_ip) = dns_packet.get_nameserver_ip() {
-                ip_addr = ns_ip;
-            } else if let Some(name) = dns_packet.get_nameserver_domain() {
-                ip_addr = Box::pin(self.resolve(name)).await?;
-            } else {
-                anyhow::bail!("Could not resolve DNS domain name");
+        for (heading, records) in [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.additionals),
+        ] {
+            if !records.is_empty() {
+                writeln!(f, "\n;; {heading} SECTION:")?;
+                for record in records {
+                    writeln!(f, "{record}")?;
+                }
             }
         }
+        Ok(())
     }
+}
 
-    pub async fn reverse_resolve(&self, req_ip_addr: &Ipv4Addr) -> Result<String> {
-        if let Some(domain) = IP_TO_DOMAIN_CACHE.get(req_ip_addr).await {
-            return Ok(domain);
-        }
-        let mut ns_ip_addr = self.id_addr;
-        let ip_addr = req_ip_addr.octets();
-        let ip_domain = format!(
-            "{}.{}.{}.{}.in-addr.arpa",
-            ip_addr[3], ip_addr[2], ip_addr[1], ip_addr[0]
-        );
-        loop {
-            let dns_packet = Self::lookup(&ip_domain, &ns_ip_addr, RecordType::Ptr).await?;
-            if let Some(domain) = dns_packet.get_answer_domain() {
-                IP_TO_DOMAIN_CACHE
-                    .insert(*req_ip_addr, domain.to_string())
-                    .await;
-                return Ok(domain.to_string());
-            } else if let Some(ns_ip) = dns_packet.get_nameserver_ip() {
-                ns_ip_addr = ns_ip;
-            } else if let Some(name) = dns_packet.get_nameserver_domain() {
-                ns_ip_addr = self.resolve(name).await?;
-            } else {
-                anyhow::bail!("Could not reverse resolve the ip addr");
-            }
-        }
+/// A query for `domain_name` with a random ID and no flags set.
+#[cfg(any(feature = "resolver", test))]
+pub(crate) fn build_query(
+    domain_name: &str,
+    record_type: RecordType,
+    class: Class,
+    edns: Option<Edns>,
+) -> Result<DNSPacket> {
+    let mut builder = MessageBuilder::query().question(domain_name, record_type, class);
+    if let Some(edns) = edns {
+        builder = builder.edns(edns);
     }
+    builder.build()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Class, DNSResolver, RecordType, decode_name};
+    #[cfg(feature = "cache")]
+    use crate::background::BackgroundOptions;
+    use crate::builder::MessageBuilder;
+    use crate::edns::{ClientSubnet, Edns, EdnsOption};
+    use crate::error::{DnsError, NameError};
+    use crate::flags::{DNSFlags, Opcode, Rcode};
+    #[cfg(feature = "bytes")]
+    use crate::wire;
+    use crate::{
+        Class, DNSHeader, DNSPacket, DNSQuestion, DNSRecord, DNSRecordData, NameCompressor,
+        RecordType, Soa, build_query, decode_name,
+    };
+    use crate::{doh, idna, view::PacketView, zone};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    #[cfg(feature = "resolver")]
+    use {
+        crate::{
+            DNSResolver, Mx, ServerIdentity, Srv,
+            config::IpStrategy,
+            edns::DEFAULT_UDP_PAYLOAD_SIZE,
+            error::Result,
+            hosts::Hosts,
+            resolver::randomize_case,
+            stats::ServerTable,
+            transport::{
+                DnsTransport, MockTransport, TcpTransport, net::SharedSocket,
+                pipeline::Connections, pool::BufferPool,
+            },
+        },
+        std::net::{IpAddr, SocketAddr},
+        std::time::Duration,
+    };
 
     #[test]
     fn test_encode_dns_name() {
-        assert_eq!(
-            DNSResolver::encode_dns_name("google.com"),
-            b"\x06google\x03com\x00"
-        );
+        let encode_name = |name| {
+            let mut buf = Vec::new();
+            NameCompressor::default().write_name(&mut buf, name);
+            buf
+        };
+        assert_eq!(encode_name("google.com"), b"\x06google\x03com\x00");
+        assert_eq!(encode_name("google.com."), b"\x06google\x03com\x00");
+        assert_eq!(encode_name(""), b"\x00");
     }
 
     #[test]
     fn test_class() {
         assert_eq!(Class::In as u16, 1);
         assert_eq!(Class::try_from(1).unwrap(), Class::In);
+        assert_eq!(Class::try_from(3).unwrap(), Class::Ch);
+        assert_eq!(Class::Any.to_string(), "ANY");
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_chaos_txt() {
+        let query = build_query("version.bind", RecordType::Txt, Class::Ch, None).unwrap();
+        assert_eq!(
+            &query.to_bytes()[12..],
+            b"\x07version\x04bind\x00\x00\x10\x00\x03"
+        );
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "version.bind",
+                RecordType::Txt,
+                Class::Ch,
+                0,
+                DNSRecordData::Txt(vec!["unbound ".to_string(), "1.19.\"3\"".to_string()]),
+            ))
+            .build()
+            .unwrap();
+        let parsed = DNSPacket::parse(&response.to_bytes()).unwrap();
+        assert_eq!(parsed, response);
+        assert!(parsed.answers_query(&query));
+        assert_eq!(parsed.get_answer_txt().unwrap(), "unbound 1.19.\"3\"");
+        assert_eq!(
+            parsed.answers[0].to_string(),
+            "version.bind.\t0\tCH\tTXT\t\"unbound \" \"1.19.\\\"3\\\"\""
+        );
+        let json = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(serde_json::from_value::<DNSPacket>(json).unwrap(), parsed);
+
+        let mut bytes = response.to_bytes();
+        // Unassigned class in the question.
+        bytes[28..30].copy_from_slice(&[0, 9]);
+        assert!(DNSPacket::parse(&bytes).is_err());
     }
 
     #[test]
@@ -441,12 +1270,15 @@ mod tests {
     fn test_build_query() {
         // validate after the random id
         assert_eq!(
-            &DNSResolver::build_query("example.com", RecordType::A, Class::In)[2..],
+            &build_query("example.com", RecordType::A, Class::In, None)
+                .unwrap()
+                .to_bytes()[2..],
             b"\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01"
         );
     }
 
     #[test]
+    #[allow(clippy::char_lit_as_u8, clippy::unnecessary_cast)]
     fn test_decode_name() {
         let mut buf = [0; 17];
         buf[0] = 3;
@@ -467,8 +1299,6661 @@ mod tests {
         buf[15] = 'm' as u8;
         buf[16] = 0;
 
-        let (name, usize) = decode_name(&buf, 0);
+        let (name, usize) = decode_name(&buf, 0).unwrap();
         assert_eq!(name, "www.example.com");
         assert_eq!(usize as usize, 17);
     }
+
+    #[test]
+    fn test_decode_name_pointer_chain() {
+        // A pointer to a name that ends in a pointer itself, further back
+        // than where the first pointer is.
+        let mut buf = vec![7];
+        buf.extend_from_slice(b"example");
+        buf.extend_from_slice(&[3, b'c', b'o', b'm', 0]);
+        buf.extend_from_slice(&[2, b'n', b's', 0xc0, 0]);
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&[0xc0, 13]);
+        let (name, len) = decode_name(&buf, 20).unwrap();
+        assert_eq!(name, "ns.example.com");
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let record = |type_, data| DNSRecord {
+            name: "example.com".to_string(),
+            type_,
+            class: Class::In,
+            ttl: 300,
+            data,
+        };
+        let packet = DNSPacket {
+            header: DNSHeader {
+                id: 0x1234,
+                flags: DNSFlags::from(0x8180),
+                num_questions: 1,
+                num_answers: 3,
+                num_authorities: 1,
+                num_additionals: 0,
+            },
+            questions: vec![DNSQuestion::new(
+                "example.com".to_string(),
+                RecordType::A,
+                Class::In,
+            )],
+            answers: vec![
+                record(
+                    RecordType::A,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(93, 184, 216, 34)),
+                ),
+                record(
+                    RecordType::Aaaa,
+                    DNSRecordData::Ipv6Addr(Ipv6Addr::LOCALHOST),
+                ),
+                record(
+                    RecordType::Cname,
+                    DNSRecordData::Name("www.example.com".to_string()),
+                ),
+            ],
+            authorities: vec![record(
+                RecordType::Soa,
+                DNSRecordData::Soa(Soa {
+                    mname: "ns.icann.org".to_string(),
+                    rname: "noc.dns.icann.org".to_string(),
+                    serial: 2024081401,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 3600,
+                }),
+            )],
+            additionals: vec![],
+            edns: None,
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(DNSPacket::parse(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_edns_nsid() {
+        let query = build_query(
+            "example.com",
+            RecordType::A,
+            Class::In,
+            Some(Edns::with_nsid_request()),
+        )
+        .unwrap()
+        .to_bytes();
+        assert_eq!(&query[10..12], b"\x00\x01");
+        assert_eq!(
+            &query[29..],
+            b"\x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x04\x00\x03\x00\x00"
+        );
+
+        let response = MessageBuilder::response_to(&DNSPacket::parse(&query).unwrap())
+            .edns(Edns {
+                options: vec![
+                    EdnsOption::Nsid(b"fra1.node".to_vec()),
+                    EdnsOption::Unknown {
+                        code: 10,
+                        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                    },
+                ],
+                dnssec_ok: true,
+                ..Edns::default()
+            })
+            .build()
+            .unwrap();
+        let parsed = DNSPacket::parse(&response.to_bytes()).unwrap();
+        assert_eq!(parsed, response);
+        assert!(parsed.additionals.is_empty());
+        let edns = parsed.edns().unwrap();
+        assert_eq!(edns.nsid(), Some(&b"fra1.node"[..]));
+        assert!(edns.dnssec_ok);
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = DNSFlags::from(0x8583);
+        assert!(flags.qr && flags.aa && flags.rd && flags.ra);
+        assert!(!flags.tc && !flags.ad && !flags.cd);
+        assert_eq!(flags.opcode, Opcode::Query);
+        assert_eq!(flags.rcode, Rcode::NxDomain);
+        assert_eq!(DNSFlags::from(0x800c).rcode, Rcode::Unknown(12));
+        assert_eq!(u16::from(flags), 0x8583);
+        assert_eq!(DNSFlags::from(0x2800).opcode, Opcode::Update);
+        assert_eq!(DNSFlags::from(0x1800).opcode, Opcode::Unknown(3));
+        let notify = DNSFlags {
+            opcode: Opcode::Notify,
+            ad: true,
+            cd: true,
+            ..DNSFlags::default()
+        };
+        assert_eq!(u16::from(notify), 0x2030);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_client_subnet() {
+        let subnet = ClientSubnet::new("192.0.2.129".parse().unwrap(), 24);
+        assert_eq!(
+            subnet.address,
+            "192.0.2.0".parse::<std::net::IpAddr>().unwrap()
+        );
+        let query = DNSResolver::builder()
+            .client_subnet(subnet.clone())
+            .build()
+            .unwrap();
+        let edns = query
+            .query_edns(false, false, query.client_subnet.clone())
+            .unwrap();
+        let mut buf = Vec::new();
+        edns.write(&mut buf);
+        assert_eq!(&buf[11..], b"\x00\x08\x00\x07\x00\x01\x18\x00\xc0\x00\x02");
+
+        let v6 = ClientSubnet::new("2001:db8:ffff::1".parse().unwrap(), 36);
+        let response = Edns {
+            options: vec![EdnsOption::ClientSubnet(ClientSubnet {
+                scope_prefix: 32,
+                ..v6.clone()
+            })],
+            ..Edns::default()
+        };
+        let mut buf = Vec::new();
+        response.write(&mut buf);
+        let (parsed, _) = Edns::parse(&buf, 1).unwrap();
+        let returned = parsed.client_subnet().unwrap();
+        assert_eq!(
+            returned.address,
+            "2001:db8:f000::".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(returned.scope_prefix, 32);
+        assert_eq!(returned.source_prefix, 36);
+    }
+
+    #[test]
+    fn test_zone_file() {
+        let record = |name: &str, type_, data| DNSRecord::new(name, type_, Class::In, 3600, data);
+        let soa = record(
+            "example.com",
+            RecordType::Soa,
+            DNSRecordData::Soa(Soa {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        );
+        // Zone transfers start and end with the SOA.
+        let records = vec![
+            soa.clone(),
+            record(
+                "www.example.com",
+                RecordType::A,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ),
+            record(
+                "example.com",
+                RecordType::Ns,
+                DNSRecordData::Name("ns1.example.com".to_string()),
+            ),
+            record(
+                "Alpha.example.com",
+                RecordType::Md,
+                DNSRecordData::Data(vec![0xde, 0xad]),
+            ),
+            soa,
+        ];
+        assert_eq!(
+            zone::to_zone_file("example.com", &records),
+            "$ORIGIN example.com.\n\
+             example.com.\t3600\tIN\tSOA\tns1.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300\n\
+             example.com.\t3600\tIN\tNS\tns1.example.com.\n\
+             Alpha.example.com.\t3600\tIN\tMD\t\\# 2 dead\n\
+             www.example.com.\t3600\tIN\tA\t192.0.2.1\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_zone_file() {
+        let text = "$TTL 1h\n\
+            @\tIN SOA ns1 hostmaster.example.com. (\n\
+            \t\t2024010101 ; serial\n\
+            \t\t2h 1h 2w 300 )\n\
+            \tNS ns1\n\
+            \tMX 10 mail\n\
+            ns1 300 IN A 192.0.2.53\n\
+            www IN 60 AAAA 2001:db8::1\n\
+            \tTXT \"v=spf1 -all\" \"two\\032words\"\n\
+            $ORIGIN sub.example.com.\n\
+            _sip._udp SRV 10 60 5060 sip\n\
+            alpha MD \\# 2 dead\n";
+        let records = zone::parse_zone_file("example.com.", text).unwrap();
+        let lines: Vec<String> = records.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "example.com.\t3600\tIN\tSOA\tns1.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300",
+                "example.com.\t3600\tIN\tNS\tns1.example.com.",
+                "example.com.\t3600\tIN\tMX\t10 mail.example.com.",
+                "ns1.example.com.\t300\tIN\tA\t192.0.2.53",
+                "www.example.com.\t60\tIN\tAAAA\t2001:db8::1",
+                "www.example.com.\t3600\tIN\tTXT\t\"v=spf1 -all\" \"two words\"",
+                "_sip._udp.sub.example.com.\t3600\tIN\tSRV\t10 60 5060 sip.sub.example.com.",
+                "alpha.sub.example.com.\t3600\tIN\tMD\t\\# 2 dead",
+            ]
+        );
+        // What is written out reads back the same.
+        let written = zone::to_zone_file("example.com", &records);
+        let mut reread = zone::parse_zone_file("example.com", &written).unwrap();
+        let mut sorted = records.clone();
+        let key = |record: &DNSRecord| record.to_string();
+        reread.sort_by_key(key);
+        sorted.sort_by_key(key);
+        assert_eq!(reread, sorted);
+
+        for invalid in [
+            "www A 192.0.2.1",
+            "$TTL 60\nwww A 192.0.2.300",
+            "$TTL 60\nwww MX mail",
+            "$TTL 60\nwww HINFO cpu os",
+            "$TTL 60\nwww TXT (\"open\"",
+            "$INCLUDE other.zone",
+        ] {
+            assert!(
+                matches!(
+                    zone::parse_zone_file("example.com", invalid),
+                    Err(DnsError::InvalidZone(_))
+                ),
+                "{invalid}"
+            );
+        }
+        // Strings are character-strings, of at most 255 bytes.
+        let txt = |string: &str| format!("$TTL 60\nwww TXT \"{string}\"");
+        assert!(zone::parse_zone_file("example.com", &txt(&"x".repeat(255))).is_ok());
+        let long = "x".repeat(256);
+        for invalid in [
+            txt(&long),
+            format!("$TTL 60\nwww NAPTR 100 10 \"u\" \"E2U+sip\" \"{long}\" ."),
+        ] {
+            assert!(matches!(
+                zone::parse_zone_file("example.com", &invalid),
+                Err(DnsError::InvalidZone(_))
+            ));
+        }
+        let error = zone::parse_zone_file("example.com", "$TTL 60\n\nwww A nowhere").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid zone: line 3: Invalid IPv4 address nowhere"
+        );
+    }
+
+    #[test]
+    fn test_zone_answer() {
+        let text = "$ORIGIN example.com.\n\
+            $TTL 3600\n\
+            @ SOA ns1 hostmaster 1 7200 3600 1209600 300\n\
+            \tNS ns1\n\
+            ns1 A 192.0.2.53\n\
+            www A 192.0.2.1\n\
+            alias CNAME www\n\
+            loop CNAME loop\n\
+            *.apps TXT \"wildcard\"\n\
+            sub NS ns.sub\n\
+            \tDS \\# 4 deadbeef\n\
+            ns.sub A 192.0.2.54\n";
+        let zone = zone::Zone::parse("example.com", text).unwrap();
+        assert!(zone.contains("WWW.Example.com."));
+        assert!(!zone.contains("notexample.com"));
+        assert!(matches!(
+            zone::Zone::parse("example.com", "$TTL 60\nwww A 192.0.2.1"),
+            Err(DnsError::InvalidZone(_))
+        ));
+        assert!(matches!(
+            zone::Zone::parse(
+                "example.com",
+                "$TTL 60\n@ SOA ns1 hostmaster 1 2 3 4 5\nexample.org. A 192.0.2.1"
+            ),
+            Err(DnsError::InvalidZone(_))
+        ));
+        let ask = |name: &str, type_| {
+            let query = MessageBuilder::query()
+                .question(name, type_, Class::In)
+                .build()
+                .unwrap();
+            zone.answer(&query).unwrap()
+        };
+
+        let response = ask("alias.example.com", RecordType::A);
+        assert!(response.header.flags.aa);
+        assert_eq!(response.header.flags.rcode, Rcode::NoError);
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[0].type_, RecordType::Cname);
+        assert_eq!(
+            response.answers[1].data,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert!(response.authorities.is_empty());
+
+        // NODATA and NXDOMAIN carry the SOA, its TTL capped at the minimum.
+        let response = ask("www.example.com", RecordType::Aaaa);
+        assert_eq!(response.header.flags.rcode, Rcode::NoError);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].type_, RecordType::Soa);
+        assert_eq!(response.authorities[0].ttl, 300);
+        let response = ask("missing.example.com", RecordType::A);
+        assert_eq!(response.header.flags.rcode, Rcode::NxDomain);
+        assert_eq!(response.authorities[0].type_, RecordType::Soa);
+
+        let response = ask("x.apps.example.com", RecordType::Txt);
+        assert_eq!(response.answers[0].name, "x.apps.example.com");
+        assert_eq!(
+            response.answers[0].data,
+            DNSRecordData::Txt(vec!["wildcard".to_string()])
+        );
+
+        // A loop stops after a few CNAMEs, without an error.
+        let response = ask("loop.example.com", RecordType::A);
+        assert_eq!(response.header.flags.rcode, Rcode::NoError);
+        assert_eq!(response.answers.len(), 8);
+
+        // Below a delegation, the subzone's servers are referred to.
+        let response = ask("www.sub.example.com", RecordType::A);
+        assert!(!response.header.flags.aa);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].type_, RecordType::Ns);
+        assert_eq!(
+            response.additionals[0].data,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 54))
+        );
+        let response = ask("sub.example.com", RecordType::Ds);
+        assert!(response.header.flags.aa);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_idna() {
+        assert_eq!(
+            idna::to_ascii("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            idna::to_ascii("Bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            idna::to_ascii("ドメイン名例.jp").unwrap(),
+            "xn--eckwd4c7cu47r2wf.jp"
+        );
+        assert_eq!(idna::to_ascii("example.com").unwrap(), "example.com");
+        assert_eq!(idna::to_unicode("xn--bcher-kva.example"), "bücher.example");
+        assert_eq!(
+            idna::to_unicode("XN--eckwd4c7cu47r2wf.jp"),
+            "ドメイン名例.jp"
+        );
+        assert_eq!(idna::to_unicode("xn--.example"), "xn--.example");
+    }
+
+    #[test]
+    fn test_name_compression() {
+        let mut buf = vec![0; 12];
+        let mut names = NameCompressor::default();
+        names.write_name(&mut buf, "example.com");
+        names.write_name(&mut buf, "www.example.com");
+        names.write_name(&mut buf, "WWW.Example.com");
+        assert_eq!(&buf[12..], b"\x07example\x03com\x00\x03www\xc0\x0c\xc0\x19");
+        assert_eq!(
+            decode_name(&buf, 25).unwrap(),
+            ("www.example.com".to_string(), 6)
+        );
+        assert_eq!(
+            decode_name(&buf, 31).unwrap(),
+            ("www.example.com".to_string(), 2)
+        );
+
+        // Pointers stay relative to the message when it is written after a prefix.
+        let query = build_query("www.example.com", RecordType::A, Class::In, None).unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "www.example.com",
+                RecordType::Cname,
+                Class::In,
+                60,
+                DNSRecordData::Name("example.com".to_string()),
+            ))
+            .build()
+            .unwrap();
+        let mut framed = vec![0xff; 2];
+        response.write_to(&mut framed);
+        assert_eq!(&framed[2..], response.to_bytes());
+        assert_eq!(DNSPacket::parse(&framed[2..]).unwrap(), response);
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let query = MessageBuilder::query()
+            .id(7)
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("example.com", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .rcode(Rcode::NxDomain)
+            .build()
+            .unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(u16::from(response.header.flags), 0x8103);
+        assert_eq!(response.questions, query.questions);
+
+        assert!(MessageBuilder::query().build().is_err());
+        assert!(
+            MessageBuilder::response_to(&query)
+                .rcode(Rcode::Unknown(16))
+                .build()
+                .is_err()
+        );
+        let mismatched = DNSRecord::new(
+            "example.com",
+            RecordType::A,
+            Class::In,
+            60,
+            DNSRecordData::Name("example.net".to_string()),
+        );
+        assert!(
+            MessageBuilder::response_to(&query)
+                .answer(mismatched)
+                .build()
+                .is_err()
+        );
+
+        // A full additional section leaves no room for the OPT record.
+        let glue = DNSRecord::new(
+            "ns.example.com",
+            RecordType::A,
+            Class::In,
+            60,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        let full = std::iter::repeat_n(glue, u16::MAX as usize).fold(
+            MessageBuilder::response_to(&query),
+            MessageBuilder::additional,
+        );
+        let response = full.clone().build().unwrap();
+        assert_eq!(response.header.num_additionals, u16::MAX);
+        assert!(full.edns(Edns::default()).build().is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let query = MessageBuilder::query()
+            .id(19678)
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("example.com", RecordType::Aaaa, Class::In)
+            .build()
+            .unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::Aaaa,
+                Class::In,
+                300,
+                DNSRecordData::Ipv6Addr("2001:db8::1".parse().unwrap()),
+            ))
+            .authority(DNSRecord::new(
+                "example.com",
+                RecordType::Md,
+                Class::In,
+                60,
+                DNSRecordData::Data(vec![0xbe, 0xef]),
+            ))
+            .edns(Edns::with_nsid_request())
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["ID"], 19678);
+        assert_eq!(json["QR"], true);
+        assert_eq!(json["ARCOUNT"], 1);
+        assert_eq!(
+            json["questionRRs"][0],
+            serde_json::json!({"NAME": "example.com", "TYPE": 28, "CLASS": 1})
+        );
+        assert_eq!(json["answerRRs"][0]["rdataAAAA"], "2001:db8::1");
+        assert_eq!(json["authorityRRs"][0]["RDATAHEX"], "BEEF");
+        assert_eq!(json["additionalRRs"][0]["RDATAHEX"], "00030000");
+        assert_eq!(serde_json::from_value::<DNSPacket>(json).unwrap(), response);
+
+        let parsed: DNSPacket = serde_json::from_str(
+            r#"{"ID": 1, "QR": 1, "Opcode": 0, "AA": 0, "TC": 0, "RD": 0, "RA": 0,
+                "RCODE": 3, "authorityRRs": [{"NAME": "example.com", "TYPE": 6,
+                "CLASS": 1, "TTL": 900, "rdataSOA":
+                "ns.example.com. hostmaster.example.com. 1 7200 3600 1209600 300"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.header.flags.rcode, Rcode::NxDomain);
+        assert_eq!(
+            parsed.authorities[0].data.to_string(),
+            "ns.example.com. hostmaster.example.com. 1 7200 3600 1209600 300"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let query = MessageBuilder::query()
+            .id(4242)
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("example.com", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .flags(DNSFlags {
+                qr: true,
+                rd: true,
+                ra: true,
+                ..DNSFlags::default()
+            })
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(93, 184, 216, 34)),
+            ))
+            .edns(Edns {
+                options: vec![EdnsOption::Nsid(b"fra1".to_vec())],
+                ..Edns::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            response.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4242\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags:; udp: 1232\n\
+             ; NSID: 66 72 61 31 (\"fra1\")\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;example.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             example.com.\t300\tIN\tA\t93.184.216.34\n"
+        );
+        assert_eq!(Rcode::Unknown(12).to_string(), "RCODE12");
+        assert_eq!(Opcode::Notify.to_string(), "NOTIFY");
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_multiple_questions() {
+        let query = MessageBuilder::query()
+            .question("example.com", RecordType::A, Class::In)
+            .question("example.com", RecordType::Aaaa, Class::In)
+            .build()
+            .unwrap();
+        let parsed = DNSPacket::parse(&query.to_bytes()).unwrap();
+        assert_eq!(parsed.header.num_questions, 2);
+        assert_eq!(parsed, query);
+
+        let rejection = parsed.reject_multi_question().unwrap();
+        assert_eq!(rejection.header.flags.rcode, Rcode::FormErr);
+        assert_eq!(rejection.questions, query.questions);
+        assert!(rejection.answers_query(&query));
+
+        let single = build_query("example.com", RecordType::A, Class::In, None).unwrap();
+        assert!(single.reject_multi_question().is_none());
+        let mut response = MessageBuilder::response_to(&single).build().unwrap();
+        assert!(response.answers_query(&single));
+        response.questions[0].name = "EXAMPLE.com".to_string();
+        assert!(response.answers_query(&single));
+        assert!(!rejection.answers_query(&single));
+    }
+
+    #[test]
+    fn test_minimal_responses() {
+        let record = |name: &str, type_, data| DNSRecord::new(name, type_, Class::In, 300, data);
+        let ns = |server: &str| {
+            record(
+                "example.com",
+                RecordType::Ns,
+                DNSRecordData::Name(server.to_string()),
+            )
+        };
+        let glue = |name: &str| {
+            record(
+                name,
+                RecordType::A,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            )
+        };
+        let soa = record(
+            "example.com",
+            RecordType::Soa,
+            DNSRecordData::Soa(Soa {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }),
+        );
+        let response = |rcode, answers: Vec<DNSRecord>, authorities: Vec<DNSRecord>| {
+            let query = build_query("www.example.com", RecordType::A, Class::In, None).unwrap();
+            let builder = MessageBuilder::response_to(&query)
+                .rcode(rcode)
+                .edns(Edns::default())
+                .additional(glue("ns1.example.com."))
+                .additional(glue("unrelated.example.net"));
+            let builder = answers.into_iter().fold(builder, MessageBuilder::answer);
+            authorities
+                .into_iter()
+                .fold(builder, MessageBuilder::authority)
+                .build()
+                .unwrap()
+        };
+
+        // Answered: only the answer section and EDNS remain.
+        let mut answered = response(
+            Rcode::NoError,
+            vec![glue("www.example.com")],
+            vec![ns("ns1.example.com")],
+        );
+        answered.minimize();
+        assert_eq!(answered.answers.len(), 1);
+        assert!(answered.authorities.is_empty() && answered.additionals.is_empty());
+        assert!(answered.edns.is_some());
+        let parsed = DNSPacket::parse(&answered.to_bytes()).unwrap();
+        assert_eq!(parsed.header.num_authorities, 0);
+
+        // Negative: the SOA stays for caching.
+        let mut negative = response(
+            Rcode::NxDomain,
+            Vec::new(),
+            vec![soa.clone(), ns("ns1.example.com")],
+        );
+        negative.minimize();
+        assert_eq!(negative.authorities, [soa]);
+        assert!(negative.additionals.is_empty());
+
+        // Referral: the NS records stay, with their glue only.
+        let mut referral = response(
+            Rcode::NoError,
+            Vec::new(),
+            vec![ns("ns1.example.com"), ns("ns2.example.org")],
+        );
+        referral.minimize();
+        assert_eq!(referral.authorities.len(), 2);
+        assert_eq!(referral.additionals, [glue("ns1.example.com.")]);
+    }
+
+    #[test]
+    fn test_name_limits() {
+        let label = "a".repeat(63);
+        let longest = format!("{label}.{label}.{label}.{}", "a".repeat(61));
+        let query = build_query(&longest, RecordType::A, Class::In, None).unwrap();
+        let parsed = DNSPacket::parse(&query.to_bytes()).unwrap();
+        assert_eq!(parsed.questions[0].name, longest);
+
+        let error = |name: &str| match build_query(name, RecordType::A, Class::In, None) {
+            Err(DnsError::InvalidName(error)) => error,
+            other => panic!("Expected a name error, got {other:?}"),
+        };
+        assert_eq!(error(&format!("{longest}a")), NameError::NameTooLong(256));
+        assert_eq!(
+            error(&format!("{label}a.example")),
+            NameError::LabelTooLong(64)
+        );
+        assert_eq!(
+            error("www..example"),
+            NameError::EmptyLabel("www..example".to_string())
+        );
+
+        // Labels of 0x40 bytes and above have reserved prefix bits.
+        assert_eq!(
+            decode_name(b"\x40abc", 0),
+            Err(NameError::LabelTooLong(0x40))
+        );
+        assert_eq!(decode_name(b"\x07example", 0), Err(NameError::Truncated));
+        assert_eq!(
+            decode_name(b"\x00\xc0\x01", 1),
+            Err(NameError::BadPointer(1))
+        );
+        // A label pointing back at itself only ends at the name length limit.
+        let error = decode_name(b"\x03www\xc0\x00", 0).unwrap_err();
+        assert!(matches!(error, NameError::NameTooLong(_)));
+    }
+
+    #[test]
+    fn test_doh_base64url() {
+        let query = MessageBuilder::query()
+            .id(0)
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("www.example.com", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        // The GET example from RFC 8484 section 4.1.1.
+        let encoded = doh::to_base64url(&query);
+        assert_eq!(encoded, "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB");
+        assert_eq!(doh::from_base64url(&encoded).unwrap(), query);
+        assert_eq!(doh::from_base64url(&format!("{encoded}==")).unwrap(), query);
+
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "www.example.com",
+                RecordType::Aaaa,
+                Class::In,
+                60,
+                DNSRecordData::Ipv6Addr("2001:db8::1".parse().unwrap()),
+            ))
+            .build()
+            .unwrap();
+        let encoded = doh::to_base64url(&response);
+        assert!(!encoded.contains(['+', '/', '=']));
+        assert_eq!(doh::from_base64url(&encoded).unwrap(), response);
+
+        assert!(doh::from_base64url("AAAB+AAB").is_err());
+        assert!(doh::from_base64url("AAABA").is_err());
+    }
+
+    #[cfg(feature = "pcap")]
+    #[test]
+    fn test_pcap() {
+        use crate::pcap::{self, Transport};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let query = build_query("example.com", RecordType::A, Class::In, None)
+            .unwrap()
+            .to_bytes();
+        let record = |seconds: u32, micros: u32, frame: &[u8]| {
+            let mut bytes = Vec::new();
+            for field in [seconds, micros, frame.len() as u32, frame.len() as u32] {
+                bytes.extend(field.to_le_bytes());
+            }
+            bytes.extend(frame);
+            bytes
+        };
+        let ethernet = |ethertype: &[u8], ip: &[u8]| [&[0; 12], ethertype, ip].concat();
+
+        let udp_len = (8 + query.len()) as u16;
+        let ipv4 = [
+            &[0x45, 0][..],
+            &(20 + udp_len).to_be_bytes(),
+            &[0, 0, 0x40, 0, 64, 17, 0, 0],
+            &[192, 0, 2, 1],
+            &[192, 0, 2, 53],
+            &[0xc0, 0x01, 0, 53],
+            &udp_len.to_be_bytes(),
+            &[0, 0],
+            &query,
+        ]
+        .concat();
+        let mut web = ipv4.clone();
+        web[22..24].copy_from_slice(&80u16.to_be_bytes());
+
+        let tcp_len = (20 + 2 + query.len()) as u16;
+        let ipv6 = [
+            &[0x60, 0, 0, 0][..],
+            &tcp_len.to_be_bytes(),
+            &[6, 64],
+            &Ipv6Addr::LOCALHOST.octets(),
+            &Ipv6Addr::LOCALHOST.octets(),
+            &[0xc0, 0x02, 0, 53],
+            &[0; 8],
+            &[0x50, 0x18, 0, 0, 0, 0, 0, 0],
+            &(query.len() as u16).to_be_bytes(),
+            &query,
+        ]
+        .concat();
+
+        let capture = [
+            &[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0][..],
+            &[0; 8],
+            &65535u32.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &record(1700000000, 250, &ethernet(&[0x08, 0x00], &ipv4)),
+            &record(1700000001, 0, &ethernet(&[0x08, 0x00], &web)),
+            &record(1700000002, 0, &ethernet(&[0x86, 0xdd], &ipv6)),
+        ]
+        .concat();
+        let packets = pcap::read(&capture).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(
+            packets[0].timestamp,
+            UNIX_EPOCH + Duration::from_secs(1700000000) + Duration::from_micros(250)
+        );
+        assert_eq!(packets[0].source, "192.0.2.1:49153".parse().unwrap());
+        assert_eq!(packets[0].destination, "192.0.2.53:53".parse().unwrap());
+        assert_eq!(packets[0].transport, Transport::Udp);
+        assert_eq!(packets[0].packet.to_bytes(), query);
+        assert_eq!(packets[1].destination, "[::1]:53".parse().unwrap());
+        assert_eq!(packets[1].transport, Transport::Tcp);
+        assert_eq!(packets[1].packet.to_bytes(), query);
+
+        assert!(pcap::read(b"not a capture file").is_err());
+    }
+
+    #[cfg(feature = "dnstap")]
+    #[test]
+    fn test_dnstap() {
+        use crate::dnstap::{DnstapWriter, MessageType};
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let mut writer = DnstapWriter::new(out.clone()).unwrap().with_identity("ns1");
+        let start = b"\0\0\0\0\0\0\0\x22\0\0\0\x02\0\0\0\x01\0\0\0\x16protobuf:dnstap.Dnstap";
+        assert_eq!(out.0.lock().unwrap().as_slice(), start);
+
+        writer
+            .log(
+                MessageType::ResolverQuery,
+                "192.0.2.1:49153".parse().unwrap(),
+                "198.41.0.4:53".parse().unwrap(),
+                b"\x12\x34",
+                UNIX_EPOCH + Duration::new(1700000000, 5),
+            )
+            .unwrap();
+        drop(writer);
+
+        let bytes = out.0.lock().unwrap().clone();
+        let frame = &bytes[start.len()..];
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        let version = env!("CARGO_PKG_VERSION");
+        let expected = [
+            &b"\x0a\x03ns1\x12"[..],
+            &[version.len() as u8],
+            version.as_bytes(),
+            b"\x72\x27",
+            b"\x08\x03\x10\x01\x18\x01",
+            b"\x22\x04\xc0\x00\x02\x01\x2a\x04\xc6\x29\x00\x04",
+            b"\x30\x81\x80\x03\x38\x35",
+            b"\x40\x80\xe2\xcf\xaa\x06\x4d\x05\x00\x00\x00",
+            b"\x52\x02\x12\x34",
+            b"\x78\x01",
+        ]
+        .concat();
+        assert_eq!(&frame[4..4 + len], expected.as_slice());
+        assert_eq!(&frame[4 + len..], b"\0\0\0\0\0\0\0\x04\0\0\0\x03");
+    }
+
+    #[test]
+    fn test_serde_wire_types() {
+        let record = DNSRecord::new(
+            "example.com",
+            RecordType::Soa,
+            Class::In,
+            3600,
+            DNSRecordData::Soa(Soa {
+                mname: "ns.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        );
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["type"], "SOA");
+        assert_eq!(json["class"], "IN");
+        assert_eq!(json["data"]["Soa"]["serial"], 1);
+        assert_eq!(serde_json::from_value::<DNSRecord>(json).unwrap(), record);
+
+        let header = DNSHeader::parse(b"\x12\x34\x81\x83\0\x01\0\0\0\0\0\0").unwrap();
+        let json = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["flags"]["rcode"], "NXDOMAIN");
+        assert_eq!(json["flags"]["opcode"], "QUERY");
+        assert_eq!(serde_json::from_value::<DNSHeader>(json).unwrap(), header);
+
+        let question = DNSQuestion::new("example.com".to_string(), RecordType::Aaaa, Class::Ch);
+        let json = serde_json::to_string(&question).unwrap();
+        assert_eq!(json, r#"{"name":"example.com","type":"AAAA","class":"CH"}"#);
+
+        let edns = Edns {
+            options: vec![EdnsOption::ClientSubnet(ClientSubnet::new(
+                "192.0.2.1".parse().unwrap(),
+                24,
+            ))],
+            ..Edns::default()
+        };
+        let json = serde_json::to_string(&edns).unwrap();
+        assert_eq!(serde_json::from_str::<Edns>(&json).unwrap(), edns);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let query = build_query("example.com", RecordType::A, Class::In, None).unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .edns(Edns::default())
+            .build()
+            .unwrap();
+        let packet = DNSPacket::parse(&response.to_bytes()).unwrap();
+        assert_eq!(packet.header().id(), query.header().id());
+        assert!(packet.header().flags().qr);
+        assert_eq!(packet.header().num_answers(), 1);
+        assert_eq!(packet.header().num_additionals(), 1);
+        assert!(packet.additionals().is_empty());
+        assert!(packet.authorities().is_empty());
+
+        let question = &packet.questions()[0];
+        assert_eq!(question.name(), "example.com");
+        assert_eq!(question.record_type(), &RecordType::A);
+        assert_eq!(question.class(), &Class::In);
+
+        let answer = &packet.answers()[0];
+        assert_eq!(answer.name(), "example.com");
+        assert_eq!(answer.ttl(), 300);
+        assert_eq!(
+            answer.data(),
+            &DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        assert!(matches!(
+            DNSPacket::parse(b"\x12"),
+            Err(DnsError::Truncated)
+        ));
+
+        let mut bytes = build_query("example.com", RecordType::A, Class::In, None)
+            .unwrap()
+            .to_bytes();
+        // Rewrite the question type to the unknown type 0xff00.
+        let at = bytes.len() - 4;
+        bytes[at..at + 2].copy_from_slice(&[0xff, 0x00]);
+        assert!(matches!(
+            DNSPacket::parse(&bytes),
+            Err(DnsError::UnsupportedType(0xff00))
+        ));
+
+        // RDATA is read to its RDLENGTH exactly, a name inside it ending
+        // neither before nor after.
+        let query = build_query("www.example.com", RecordType::Cname, Class::In, None).unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "www.example.com",
+                RecordType::Cname,
+                Class::In,
+                300,
+                DNSRecordData::Name("example.com".to_string()),
+            ))
+            .build()
+            .unwrap()
+            .to_bytes();
+        // Past the header, the question and the answer's compressed owner,
+        // type, class and TTL.
+        let at = 12 + 17 + 4 + 2 + 8;
+        let data_len = u16::from_be_bytes([response[at], response[at + 1]]);
+        assert_eq!(usize::from(data_len), response.len() - at - 2);
+        let mut padded = response.clone();
+        padded[at..at + 2].copy_from_slice(&(data_len + 2).to_be_bytes());
+        padded.extend([0, 0]);
+        let mut short = response;
+        short[at..at + 2].copy_from_slice(&(data_len - 1).to_be_bytes());
+        for bytes in [padded, short] {
+            assert!(matches!(
+                DNSPacket::parse(&bytes),
+                Err(DnsError::MalformedPacket(_))
+            ));
+        }
+
+        let error = DnsError::NxDomain("missing.example".to_string());
+        assert_eq!(
+            error.to_string(),
+            "Domain name missing.example does not exist"
+        );
+    }
+
+    #[test]
+    fn test_packet_view() {
+        let query = build_query("www.example.com", RecordType::A, Class::In, None).unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "www.example.com",
+                RecordType::Cname,
+                Class::In,
+                60,
+                DNSRecordData::Name("example.com".to_string()),
+            ))
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .edns(Edns::default())
+            .build()
+            .unwrap();
+        let bytes = response.to_bytes();
+        let view = PacketView::parse(&bytes).unwrap();
+        assert_eq!(view.header(), response.header());
+
+        let question = view.questions().next().unwrap();
+        assert!(question.name().eq_name("WWW.Example.COM."));
+        assert!(!question.name().eq_name("example.com"));
+        assert_eq!(question.record_type(), Some(RecordType::A));
+
+        let answers: Vec<_> = view.answers().collect();
+        assert_eq!(answers.len(), 2);
+        // The CNAME target is a compression pointer into the question.
+        let target = answers[0].target().unwrap();
+        assert_eq!(target.to_string(), "example.com");
+        assert!(answers[1].name().eq_name("example.com"));
+        assert_eq!(answers[1].ttl(), 300);
+        assert_eq!(answers[1].rdata(), &[192, 0, 2, 1]);
+        assert_eq!(answers[1].to_record().unwrap(), response.answers()[1]);
+        assert!(answers[1].target().is_none());
+
+        let opt = view.additionals().next().unwrap();
+        assert_eq!(opt.rtype(), RecordType::Opt as u16);
+        assert_eq!(view.to_packet().unwrap(), response);
+
+        assert!(matches!(
+            PacketView::parse(&bytes[..bytes.len() - 1]),
+            Err(DnsError::Truncated)
+        ));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_bytes_codec() {
+        use bytes::{BufMut, Bytes, BytesMut};
+
+        let query = build_query("example.com", RecordType::A, Class::In, None).unwrap();
+        let response = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .build()
+            .unwrap();
+        let datagram = response.encode();
+        assert_eq!(DNSPacket::parse(&datagram).unwrap(), response);
+        let view = PacketView::parse(&datagram).unwrap();
+        let rdata = view.answers().next().unwrap().rdata_bytes(&datagram);
+        assert_eq!(rdata, Bytes::from_static(&[192, 0, 2, 1]));
+
+        // Two messages arriving in pieces on one connection.
+        let framed = [query.encode_tcp().unwrap(), response.encode_tcp().unwrap()].concat();
+        let mut buf = BytesMut::new();
+        buf.put_slice(&framed[..10]);
+        assert!(wire::next_tcp_message(&mut buf).is_none());
+        buf.put_slice(&framed[10..]);
+        let first = wire::next_tcp_message(&mut buf).unwrap();
+        assert_eq!(DNSPacket::parse(&first).unwrap(), query);
+        let second = wire::next_tcp_message(&mut buf).unwrap();
+        assert_eq!(second, datagram);
+        assert!(buf.is_empty());
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new();
+        let mut first = pool.take(DEFAULT_UDP_PAYLOAD_SIZE as usize);
+        assert_eq!(first.len(), 1232);
+        first[0] = 1;
+        let address = first.as_ptr();
+        drop(first);
+
+        // A dropped buffer is handed out again, resized for the next payload.
+        let second = pool.take(512);
+        assert_eq!(second.len(), 512);
+        assert_eq!(second.as_ptr(), address);
+        let third = pool.take(512);
+        assert_ne!(third.as_ptr(), address);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_resolver_builder() {
+        let resolver = DNSResolver::builder()
+            .upstreams(["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()])
+            .transport(TcpTransport::default())
+            .timeout(Duration::from_secs(2))
+            .retries(3)
+            .udp_payload_size(4096)
+            .dnssec_ok(true)
+            .ip_strategy(IpStrategy::Ipv6ThenIpv4)
+            .build()
+            .unwrap();
+        assert_eq!(resolver.upstreams().len(), 2);
+        assert_eq!(resolver.ip_strategy, IpStrategy::Ipv6ThenIpv4);
+        let edns = resolver
+            .query_edns(resolver.dnssec_ok, false, None)
+            .unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert!(edns.dnssec_ok);
+        assert!(
+            DNSResolver::default()
+                .query_edns(false, false, None)
+                .is_none()
+        );
+
+        for builder in [
+            DNSResolver::builder().upstreams([]),
+            DNSResolver::builder().timeout(Duration::ZERO),
+            DNSResolver::builder().udp_payload_size(256),
+        ] {
+            assert!(matches!(builder.build(), Err(DnsError::InvalidConfig(_))));
+        }
+
+        let name = randomize_case("www.example.com", &mut rand::rng());
+        assert!(name.eq_ignore_ascii_case("www.example.com"));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_custom_transport() {
+        /// Answers every query itself, as a recursive resolver would.
+        struct Answering;
+
+        impl DnsTransport for Answering {
+            async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                assert!(query.header.flags.rd);
+                let question = &query.questions[0];
+                MessageBuilder::response_to(query)
+                    .answer(DNSRecord::new(
+                        &question.name,
+                        RecordType::A,
+                        Class::In,
+                        60,
+                        DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 7)),
+                    ))
+                    .build()
+            }
+        }
+
+        let resolver = DNSResolver::builder()
+            .transport(Answering)
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            resolver.resolve("transport.example").await.unwrap(),
+            Ipv4Addr::new(192, 0, 2, 7)
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_server_selection() {
+        /// Forwards to a mock, except that one server never answers.
+        struct Unreachable(IpAddr, MockTransport);
+
+        impl DnsTransport for Unreachable {
+            async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+                if server == self.0 {
+                    return Err(std::io::Error::other("unreachable").into());
+                }
+                self.1.exchange(query, server).await
+            }
+        }
+
+        let (down, up) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2));
+        let mock = MockTransport::new().answer(
+            "select.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "select.example",
+                RecordType::A,
+                Class::In,
+                60,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 9)),
+            )],
+        );
+        let resolver = DNSResolver::builder()
+            .transport(Unreachable(IpAddr::V4(down), mock))
+            .upstreams([down, up])
+            .cache(false)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            resolver.resolve("select.example").await.unwrap();
+        }
+
+        // Only the first resolution tried the failing upstream.
+        let stats = resolver.server_stats();
+        assert_eq!(stats[&down].failures, 1);
+        assert_eq!(stats[&up].failures, 0);
+        assert!(stats[&up].srtt < stats[&down].srtt);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_upstream_policy() {
+        use crate::config::{QueryOptions, UpstreamPolicy};
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        /// Forwards to a mock, except that one server never answers.
+        struct Stalled(IpAddr, MockTransport);
+
+        impl DnsTransport for Stalled {
+            async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+                if server == self.0 {
+                    std::future::pending::<()>().await;
+                }
+                self.1.exchange(query, server).await
+            }
+        }
+
+        let upstreams = [
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 3),
+        ];
+        let mock = MockTransport::new().answer(
+            "policy.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "policy.example",
+                RecordType::A,
+                Class::In,
+                60,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 9)),
+            )],
+        );
+        let answering = |policy| {
+            let mock = mock.clone();
+            async move {
+                let resolver = DNSResolver::builder()
+                    .transport(mock)
+                    .upstreams(upstreams)
+                    .upstream_policy(policy)
+                    .rng(StdRng::seed_from_u64(7))
+                    .cache(false)
+                    .build()
+                    .unwrap();
+                let mut servers = Vec::new();
+                for _ in 0..6 {
+                    let result = resolver
+                        .resolve_detailed("policy.example", RecordType::A, &QueryOptions::default())
+                        .await
+                        .unwrap();
+                    servers.push(result.server.unwrap());
+                }
+                servers
+            }
+        };
+        assert_eq!(
+            answering(UpstreamPolicy::Sequential).await,
+            [upstreams[0]; 6]
+        );
+        assert_eq!(
+            answering(UpstreamPolicy::RoundRobin).await,
+            [upstreams, upstreams].concat()
+        );
+        let random = answering(UpstreamPolicy::Random).await;
+        assert!(upstreams.iter().all(|upstream| random.contains(upstream)));
+
+        // A race is won by the server that answers, without waiting for the
+        // one that does not.
+        let resolver = DNSResolver::builder()
+            .transport(Stalled(IpAddr::V4(upstreams[0]), mock))
+            .upstreams(upstreams)
+            .upstream_policy(UpstreamPolicy::Race)
+            .cache(false)
+            .build()
+            .unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            resolver.resolve_detailed("policy.example", RecordType::A, &QueryOptions::default()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.server, Some(upstreams[1]));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_adaptive_timeout() {
+        let servers = ServerTable::default();
+        let (fast, slow) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2));
+        let ceiling = Duration::from_secs(5);
+        assert_eq!(servers.timeout(fast, ceiling, 0), ceiling);
+
+        // 10 ms with a variance of 5 ms gives 30 ms, raised to the 50 ms floor.
+        servers.record_response(fast, Duration::from_millis(10), Rcode::NoError);
+        assert_eq!(servers.timeout(fast, ceiling, 0), Duration::from_millis(50));
+        assert_eq!(servers.timeout(fast, ceiling, 1), Duration::from_millis(60));
+        assert_eq!(
+            servers.timeout(fast, ceiling, 2),
+            Duration::from_millis(120)
+        );
+
+        servers.record_response(slow, Duration::from_secs(2), Rcode::NoError);
+        assert_eq!(servers.timeout(slow, ceiling, 0), ceiling);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[test]
+    fn test_server_health() {
+        let servers = ServerTable::default();
+        let server = Ipv4Addr::new(192, 0, 2, 1);
+        for ms in [3, 8, 8, 40, 7000] {
+            servers.record_response(server, Duration::from_millis(ms), Rcode::NoError);
+        }
+        servers.record_response(server, Duration::from_millis(20), Rcode::ServFail);
+        servers.record_failure(server, Duration::from_secs(1), true);
+        servers.record_failure(server, Duration::from_millis(1), false);
+
+        let health = &servers.health()[&server];
+        assert_eq!(health.latency, [1, 2, 1, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(health.responses(), 6);
+        assert_eq!(health.rcodes[&Rcode::NoError], 5);
+        assert_eq!(health.rcodes[&Rcode::ServFail], 1);
+        assert_eq!((health.timeouts, health.network_errors), (1, 1));
+        assert_eq!(health.percentile(0.5), Some(Duration::from_millis(10)));
+        assert_eq!(health.percentile(0.8), Some(Duration::from_millis(50)));
+        assert_eq!(health.percentile(0.99), None);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_check_propagation() {
+        use crate::propagation::{PUBLIC_RESOLVERS, PropagatedAnswer};
+
+        let name = "moved.example";
+        let record = |last| {
+            DNSRecord::new(
+                name,
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, last)),
+            )
+        };
+        let response = |rcode, answers: Vec<DNSRecord>| {
+            let mut builder = MessageBuilder::query()
+                .question(name, RecordType::A, Class::In)
+                .flags(DNSFlags {
+                    qr: true,
+                    rcode,
+                    ..DNSFlags::default()
+                });
+            for answer in answers {
+                builder = builder.answer(answer);
+            }
+            builder.build().unwrap()
+        };
+        let [cloudflare, google, quad9, opendns, ..] = PUBLIC_RESOLVERS;
+        let mock = MockTransport::new()
+            .answer(name, RecordType::A, vec![record(2)])
+            .response_from(
+                quad9.address.into(),
+                name,
+                RecordType::A,
+                response(Rcode::NoError, vec![record(1)]),
+            )
+            .response_from(
+                opendns.address.into(),
+                name,
+                RecordType::A,
+                response(Rcode::Refused, Vec::new()),
+            );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+
+        let propagation = resolver
+            .check_propagation(name, RecordType::A, &PUBLIC_RESOLVERS[..4])
+            .await;
+        let resolvers: Vec<_> = propagation
+            .answers
+            .iter()
+            .map(|answer| answer.resolver)
+            .collect();
+        assert_eq!(resolvers, [cloudflare, google, quad9, opendns]);
+        assert!(!propagation.agrees());
+        let groups: Vec<_> = propagation
+            .groups()
+            .into_iter()
+            .map(|(answer, resolvers)| (answer.clone(), resolvers.len()))
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                (PropagatedAnswer::Records(vec!["192.0.2.2".to_string()]), 2),
+                (PropagatedAnswer::Records(vec!["192.0.2.1".to_string()]), 1),
+            ]
+        );
+        assert_eq!(propagation.failures().next().unwrap().resolver, opendns);
+        assert_eq!(propagation.answers[0].ttl, Some(300));
+
+        let json = serde_json::to_value(&propagation).unwrap();
+        assert_eq!(json["agrees"], false);
+        assert_eq!(json["resolvers"][2]["records"][0], "192.0.2.1");
+        assert_eq!(json["resolvers"][3]["status"], "failed");
+    }
+
+    #[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_check_delegation() {
+        use crate::delegation::{DelegationIssue, ServerOutcome};
+
+        let root = Ipv4Addr::new(192, 0, 2, 1);
+        let record = |name: &str, type_, data| DNSRecord::new(name, type_, Class::In, 3600, data);
+        let ns = |target: &str| {
+            record(
+                "example",
+                RecordType::Ns,
+                DNSRecordData::Name(target.to_string()),
+            )
+        };
+        let glue = |name: &str, last| {
+            record(
+                name,
+                RecordType::A,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, last)),
+            )
+        };
+        let referral = MessageBuilder::query()
+            .question("example", RecordType::Ns, Class::In)
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .authority(ns("ns1.example"))
+            .authority(ns("ns2.example"))
+            .authority(ns("lame.other"))
+            .additional(glue("ns1.example", 53))
+            .additional(glue("ns2.example", 54))
+            .build()
+            .unwrap();
+        let soa = |serial| {
+            MessageBuilder::query()
+                .question("example", RecordType::Soa, Class::In)
+                .flags(DNSFlags {
+                    qr: true,
+                    aa: true,
+                    ..DNSFlags::default()
+                })
+                .answer(record(
+                    "example",
+                    RecordType::Soa,
+                    DNSRecordData::Soa(Soa {
+                        mname: "ns1.example".to_string(),
+                        rname: "hostmaster.example".to_string(),
+                        serial,
+                        refresh: 3600,
+                        retry: 600,
+                        expire: 86400,
+                        minimum: 300,
+                    }),
+                ))
+                .build()
+                .unwrap()
+        };
+        let mut mock = MockTransport::new()
+            .response_from(root.into(), "example", RecordType::Ns, referral)
+            .answer(
+                "example",
+                RecordType::Ns,
+                vec![ns("ns1.example"), ns("ns2.example"), ns("ns3.example")],
+            )
+            .answer("ns3.example", RecordType::A, vec![glue("ns3.example", 55)])
+            .answer("lame.other", RecordType::A, vec![glue("lame.other", 99)]);
+        for (last, serial) in [(53, 10), (54, 9), (55, 10)] {
+            let server = Ipv4Addr::new(192, 0, 2, last);
+            mock = mock.response_from(server.into(), "example", RecordType::Soa, soa(serial));
+        }
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .upstreams([root])
+            .cache(false)
+            .build()
+            .unwrap();
+
+        let delegation = resolver.check_delegation("example.").await;
+        assert_eq!(delegation.parent_server, Some(root));
+        assert_eq!(
+            delegation.parent_ns,
+            ["lame.other", "ns1.example", "ns2.example"]
+        );
+        assert_eq!(
+            delegation.child_ns,
+            ["ns1.example", "ns2.example", "ns3.example"]
+        );
+        let ns1 = &delegation.nameservers[1];
+        assert_eq!(ns1.glue, [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53))]);
+        assert_eq!(
+            ns1.addresses[0].outcome,
+            ServerOutcome::Authoritative { serial: 10 }
+        );
+        assert_eq!(
+            delegation.issues(),
+            [
+                DelegationIssue::OnlyAtParent("lame.other".to_string()),
+                DelegationIssue::Lame {
+                    nameserver: "lame.other".to_string(),
+                    address: Ipv4Addr::new(192, 0, 2, 99),
+                    reason: "answered REFUSED".to_string(),
+                },
+                DelegationIssue::OnlyAtChild("ns3.example".to_string()),
+                DelegationIssue::SerialMismatch(vec![
+                    (
+                        10,
+                        vec![
+                            "ns1.example. (192.0.2.53)".to_string(),
+                            "ns3.example. (192.0.2.55)".to_string()
+                        ]
+                    ),
+                    (9, vec!["ns2.example. (192.0.2.54)".to_string()]),
+                ]),
+            ]
+        );
+        assert!(!delegation.is_healthy());
+        let json = serde_json::to_value(&delegation).unwrap();
+        assert_eq!(json["nameservers"][0]["addresses"][0]["status"], "lame");
+        assert_eq!(json["issues"].as_array().unwrap().len(), 4);
+    }
+
+    #[cfg(all(feature = "dnssec", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_check_dnssec() {
+        use crate::dnssec::{Dnskey, Ds, LinkStatus, Rrsig};
+        use crate::error::ValidationError;
+        use ring::rand::SystemRandom;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let root = Ipv4Addr::new(192, 0, 2, 1);
+        let nameserver = Ipv4Addr::new(192, 0, 2, 53);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let generate = || {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let key = Dnskey {
+                flags: Dnskey::ZONE | Dnskey::SECURE_ENTRY_POINT,
+                protocol: 3,
+                algorithm: 15,
+                public_key: pair.public_key().as_ref().to_vec(),
+            };
+            (pair, key)
+        };
+        let record = |name: &str, type_, data| DNSRecord::new(name, type_, Class::In, 3600, data);
+        // The RRSIG over `records` by `key` of `signer`.
+        let sign = |(pair, key): &(Ed25519KeyPair, Dnskey), signer: &str, records: &[DNSRecord]| {
+            let owner = records[0].name();
+            let mut rrsig = Rrsig {
+                type_covered: records[0].record_type().clone() as u16,
+                algorithm: 15,
+                labels: owner.split('.').filter(|label| !label.is_empty()).count() as u8,
+                original_ttl: 3600,
+                expiration: now + 3600,
+                inception: now - 3600,
+                key_tag: key.key_tag(),
+                signer: signer.to_string(),
+                signature: Vec::new(),
+            };
+            let records: Vec<&DNSRecord> = records.iter().collect();
+            rrsig.signature = pair.sign(&rrsig.signed_data(&records)).as_ref().to_vec();
+            record(
+                owner,
+                RecordType::Rrsig,
+                DNSRecordData::Data(rrsig.to_rdata()),
+            )
+        };
+        // A DNSKEY response for the zone, signed by its only key.
+        let dnskey = |zone: &(Ed25519KeyPair, Dnskey), name: &str| {
+            let keys = vec![record(
+                name,
+                RecordType::Dnskey,
+                DNSRecordData::Data(zone.1.to_rdata()),
+            )];
+            let rrsig = sign(zone, name, &keys);
+            [keys, vec![rrsig]].concat()
+        };
+
+        let root_zone = generate();
+        let example = generate();
+        let anchors = [Ds::from_key("", &root_zone.1, 2).unwrap()];
+        let ds = vec![record(
+            "example",
+            RecordType::Ds,
+            DNSRecordData::Data(Ds::from_key("example", &example.1, 2).unwrap().to_rdata()),
+        )];
+        let nsec = vec![record(
+            "unsigned",
+            RecordType::Nsec,
+            DNSRecordData::Data(vec![0, 0, 6, 0x20, 0, 0, 0, 0, 3]),
+        )];
+        let referral = |zone: &str, authorities: Vec<DNSRecord>| {
+            let mut builder = MessageBuilder::query()
+                .question(zone, RecordType::Ns, Class::In)
+                .flags(DNSFlags {
+                    qr: true,
+                    ..DNSFlags::default()
+                })
+                .authority(record(
+                    zone,
+                    RecordType::Ns,
+                    DNSRecordData::Name(format!("ns.{zone}")),
+                ))
+                .additional(record(
+                    &format!("ns.{zone}"),
+                    RecordType::A,
+                    DNSRecordData::Ipv4Addr(nameserver),
+                ));
+            for authority in authorities {
+                builder = builder.authority(authority);
+            }
+            builder.build().unwrap()
+        };
+        let address = vec![record(
+            "www.example",
+            RecordType::A,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+        )];
+        let forged = vec![record(
+            "bad.example",
+            RecordType::A,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 66)),
+        )];
+        // Signed over a different address than the one served.
+        let forged_rrsig = sign(
+            &example,
+            "example",
+            &[record(
+                "bad.example",
+                RecordType::A,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+            )],
+        );
+        let signed_referral = referral(
+            "example",
+            [ds.clone(), vec![sign(&root_zone, "", &ds)]].concat(),
+        );
+        let mut mock = MockTransport::new()
+            .answer("", RecordType::Dnskey, dnskey(&root_zone, ""))
+            .answer("example", RecordType::Dnskey, dnskey(&example, "example"))
+            .answer(
+                "www.example",
+                RecordType::A,
+                [address.clone(), vec![sign(&example, "example", &address)]].concat(),
+            )
+            .answer(
+                "bad.example",
+                RecordType::A,
+                [forged, vec![forged_rrsig]].concat(),
+            )
+            .response_from(
+                root.into(),
+                "www.unsigned",
+                RecordType::A,
+                referral(
+                    "unsigned",
+                    [nsec.clone(), vec![sign(&root_zone, "", &nsec)]].concat(),
+                ),
+            );
+        for name in ["www.example", "bad.example"] {
+            mock = mock.response_from(root.into(), name, RecordType::A, signed_referral.clone());
+        }
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .upstreams([root])
+            .cache(false)
+            .build()
+            .unwrap();
+
+        let chain = resolver
+            .check_dnssec("www.example", RecordType::A, &anchors)
+            .await;
+        let links: Vec<_> = chain
+            .links
+            .iter()
+            .map(|link| (link.name.as_str(), link.record_type.clone(), link.server))
+            .collect();
+        assert_eq!(
+            links,
+            [
+                ("", RecordType::Dnskey, root),
+                ("example", RecordType::Ds, root),
+                ("example", RecordType::Dnskey, nameserver),
+                ("www.example", RecordType::A, nameserver),
+            ]
+        );
+        assert!(chain.is_secure(), "{chain:?}");
+        assert_eq!(chain.links[3].signatures[0].key_tag, example.1.key_tag());
+
+        let chain = resolver
+            .check_dnssec("bad.example", RecordType::A, &anchors)
+            .await;
+        let broken = chain.break_point().unwrap();
+        assert_eq!(broken.name, "bad.example");
+        assert_eq!(
+            broken.status,
+            LinkStatus::Bogus(ValidationError::BadSignature(example.1.key_tag()))
+        );
+
+        let chain = resolver
+            .check_dnssec("www.unsigned", RecordType::A, &anchors)
+            .await;
+        let last = chain.links.last().unwrap();
+        assert_eq!(
+            (last.name.as_str(), &last.record_type),
+            ("unsigned", &RecordType::Ds)
+        );
+        assert!(matches!(last.status, LinkStatus::Insecure(_)));
+
+        let untrusted = [Ds::from_key("", &example.1, 2).unwrap()];
+        let chain = resolver
+            .check_dnssec("www.example", RecordType::A, &untrusted)
+            .await;
+        assert_eq!(
+            chain.links[0].status,
+            LinkStatus::Bogus(ValidationError::NoMatchingDs)
+        );
+        let json = serde_json::to_value(&chain).unwrap();
+        assert_eq!(json["secure"], false);
+        assert_eq!(json["links"][0]["status"], "bogus");
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Answers slowly, remembering how many queries it held at once.
+        #[derive(Default)]
+        struct Slow {
+            current: AtomicUsize,
+            most: AtomicUsize,
+        }
+
+        impl DnsTransport for std::sync::Arc<Slow> {
+            async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.current.fetch_sub(1, Ordering::SeqCst);
+                MessageBuilder::response_to(query)
+                    .answer(DNSRecord::new(
+                        &query.questions[0].name,
+                        RecordType::A,
+                        Class::In,
+                        60,
+                        DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+                    ))
+                    .build()
+            }
+        }
+
+        assert!(DNSResolver::builder().max_in_flight(0).build().is_err());
+        let slow = std::sync::Arc::new(Slow::default());
+        let resolver = DNSResolver::builder()
+            .transport(slow.clone())
+            .recursion_desired(true)
+            .cache(false)
+            .max_in_flight(2)
+            .build()
+            .unwrap();
+        let mut lookups = tokio::task::JoinSet::new();
+        for i in 0..6 {
+            let resolver = resolver.clone();
+            lookups.spawn(async move { resolver.resolve(&format!("host{i}.example")).await });
+        }
+        while let Some(result) = lookups.join_next().await {
+            result.unwrap().unwrap();
+        }
+        assert_eq!(slow.most.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn test_cache_weight() {
+        use crate::cache::{CachedRecords, record_weight};
+
+        // Owner name of 17 encoded bytes, 10 of fixed fields, then rdata.
+        assert_eq!(record_weight("www.example.com", 4), 31);
+        assert_eq!(record_weight("", 0), 12);
+        let records = CachedRecords::new(vec![
+            DNSRecord::new(
+                "www.example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ),
+            DNSRecord::new(
+                "www.example.com",
+                RecordType::Cname,
+                Class::In,
+                300,
+                DNSRecordData::Name("example.com".to_string()),
+            ),
+        ]);
+        assert_eq!(records.weight(), 31 + 40);
+    }
+
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn test_background_task() {
+        let root = |name: &str, ip: Ipv4Addr| {
+            (
+                DNSRecord::new(
+                    ".",
+                    RecordType::Ns,
+                    Class::In,
+                    518400,
+                    DNSRecordData::Name(name.to_string()),
+                ),
+                DNSRecord::new(
+                    name,
+                    RecordType::A,
+                    Class::In,
+                    518400,
+                    DNSRecordData::Ipv4Addr(ip),
+                ),
+            )
+        };
+        let (a_ns, a_glue) = root("a.root.example", Ipv4Addr::new(192, 0, 2, 10));
+        let (b_ns, b_glue) = root("b.root.example", Ipv4Addr::new(192, 0, 2, 11));
+        let priming = MessageBuilder::query()
+            .question(".", RecordType::Ns, Class::In)
+            .answer(a_ns)
+            .answer(b_ns)
+            .additional(a_glue)
+            .additional(b_glue)
+            .build()
+            .unwrap();
+        let mock = MockTransport::new()
+            .response(".", RecordType::Ns, priming)
+            .answer(
+                "prefetch.example",
+                RecordType::Txt,
+                vec![DNSRecord::new(
+                    "prefetch.example",
+                    RecordType::Txt,
+                    Class::In,
+                    30,
+                    DNSRecordData::Txt(vec!["fresh".to_string()]),
+                )],
+            );
+        let resolver = DNSResolver::builder()
+            .transport(mock.clone())
+            .upstreams([Ipv4Addr::new(192, 0, 2, 1)])
+            .build()
+            .unwrap();
+        let prefetched = || {
+            mock.queries()
+                .iter()
+                .filter(|(_, question)| question.name() == "prefetch.example")
+                .count()
+        };
+        // Served once from the cache, so it is worth prefetching.
+        for _ in 0..2 {
+            resolver
+                .resolve_record("prefetch.example", RecordType::Txt)
+                .await
+                .unwrap();
+        }
+        assert_eq!(prefetched(), 1);
+
+        let task = resolver.spawn_background(
+            &tokio::runtime::Handle::current(),
+            BackgroundOptions {
+                interval: Duration::from_secs(3600),
+                prime_roots: true,
+                ..BackgroundOptions::default()
+            },
+        );
+        for _ in 0..100 {
+            if prefetched() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        task.shutdown().await;
+        assert_eq!(prefetched(), 2);
+        assert_eq!(
+            resolver.upstreams(),
+            [Ipv4Addr::new(192, 0, 2, 10), Ipv4Addr::new(192, 0, 2, 11)]
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_shared_udp_socket() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap();
+        let shared = SharedSocket::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let query = |id: u16| {
+            let mut bytes = build_query("example.com", RecordType::A, Class::In, None)
+                .unwrap()
+                .to_bytes();
+            bytes[..2].copy_from_slice(&id.to_be_bytes());
+            bytes
+        };
+        let (first, second) = (query(1), query(2));
+
+        // Answers both queries in reverse order, after a response nobody asked for.
+        let answer = async {
+            let mut buf = [0; 512];
+            let mut clients = Vec::new();
+            for _ in 0..2 {
+                let (size, client) = server.recv_from(&mut buf).await.unwrap();
+                clients.push((buf[..size].to_vec(), client));
+            }
+            server.send_to(&query(9), clients[0].1).await.unwrap();
+            for (query, client) in clients.into_iter().rev() {
+                server
+                    .send_to(&[&query[..], b"!"].concat(), client)
+                    .await
+                    .unwrap();
+            }
+        };
+        let (one, two, duplicate, ()) = tokio::join!(
+            shared.exchange(&first, address, 512),
+            shared.exchange(&second, address, 512),
+            shared.exchange(&first, address, 512),
+            answer,
+        );
+        let (buf, size) = one.unwrap().unwrap();
+        assert_eq!(&buf[..size], &[&first[..], b"!"].concat()[..]);
+        let (buf, size) = two.unwrap().unwrap();
+        assert_eq!(&buf[..size], &[&second[..], b"!"].concat()[..]);
+        assert!(duplicate.unwrap().is_none());
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_upstream_port() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let answer = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (size, client) = server.recv_from(&mut buf).await.unwrap();
+            let query = DNSPacket::parse(&buf[..size]).unwrap();
+            let response = MessageBuilder::response_to(&query)
+                .answer(DNSRecord::new(
+                    "example.com",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+                ))
+                .build()
+                .unwrap();
+            server.send_to(&response.to_bytes(), client).await.unwrap();
+        });
+
+        let localhost = Ipv4Addr::LOCALHOST;
+        let resolver = DNSResolver::builder()
+            .upstreams([localhost])
+            .upstream_port(localhost, port)
+            .timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        assert_eq!(resolver.port(&localhost), port);
+        assert_eq!(resolver.port(&Ipv4Addr::new(192, 0, 2, 1)), 53);
+        let response = resolver
+            .lookup("example.com", &localhost, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.answers[0].data,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80))
+        );
+        answer.await.unwrap();
+
+        assert!(matches!(
+            DNSResolver::builder().upstream_port(localhost, 0).build(),
+            Err(DnsError::InvalidConfig(_))
+        ));
+
+        #[cfg(feature = "cli")]
+        {
+            use crate::cli::{Cli, expand_dig_args};
+            use clap::Parser;
+
+            let args = [
+                "dnsr",
+                "@192.0.2.53:8600",
+                "--server",
+                "192.0.2.1",
+                "example.com",
+            ];
+            let cli = Cli::parse_from(expand_dig_args(args.map(Into::into)));
+            assert_eq!(
+                cli.servers,
+                [
+                    "192.0.2.53:8600".parse().unwrap(),
+                    "192.0.2.1:53".parse().unwrap()
+                ]
+            );
+            assert_eq!(cli.name.as_deref(), Some("example.com"));
+            let resolver = cli.resolver().unwrap();
+            assert_eq!(resolver.port(&Ipv4Addr::new(192, 0, 2, 53)), 8600);
+            assert!(Cli::try_parse_from(["dnsr", "--server", "192.0.2.1:dns", "x"]).is_err());
+        }
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_exchange_raw() {
+        // Trailing bytes no parser would keep, to show nothing is re-encoded.
+        let mut query = build_query("example.com", RecordType::A, Class::In, None)
+            .unwrap()
+            .to_bytes();
+        query.extend_from_slice(b"\xde\xad");
+        let mut reply = query.clone();
+        reply[2] |= 0x80;
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let (sent, expected) = (query.clone(), reply.clone());
+        let answer = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (size, client) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(buf[..size], sent[..]);
+            server.send_to(&expected, client).await.unwrap();
+        });
+        let localhost = Ipv4Addr::LOCALHOST;
+        let resolver = DNSResolver::builder()
+            .upstream_port(localhost, port)
+            .timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        assert_eq!(
+            resolver.exchange_raw(&query, &localhost).await.unwrap(),
+            reply
+        );
+        answer.await.unwrap();
+
+        // Other transports see the parsed query, and give back an encoded response.
+        let mock = MockTransport::new().answer(
+            "example.com",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+            )],
+        );
+        let resolver = DNSResolver::builder().transport(mock).build().unwrap();
+        let response = resolver.exchange_raw(&query, &localhost).await.unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert_eq!(response.header.id, u16::from_be_bytes([query[0], query[1]]));
+        assert_eq!(response.answers.len(), 1);
+        assert!(
+            resolver
+                .exchange_raw(&query[..5], &localhost)
+                .await
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_nsid_option() {
+        use crate::config::QueryOptions;
+
+        let response = MessageBuilder::query()
+            .flags(DNSFlags {
+                qr: true,
+                aa: true,
+                ..DNSFlags::default()
+            })
+            .answer(DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+            ))
+            .edns(Edns {
+                options: vec![EdnsOption::Nsid(b"fra1\x00".to_vec())],
+                ..Edns::default()
+            })
+            .build()
+            .unwrap();
+        let mock = MockTransport::new().response("example.com", RecordType::A, response);
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let options = QueryOptions {
+            nsid: Some(true),
+            ..QueryOptions::default()
+        };
+        let result = resolver
+            .resolve_detailed("example.com", RecordType::A, &options)
+            .await
+            .unwrap();
+        assert_eq!(result.nsid.as_deref(), Some(&b"fra1\x00"[..]));
+        let edns = resolver.query_edns(false, true, None).unwrap();
+        assert_eq!(edns.options, [EdnsOption::Nsid(Vec::new())]);
+
+        #[cfg(feature = "cli")]
+        {
+            use crate::cli::{Cli, OutputFormat, expand_dig_args, render};
+            use clap::Parser;
+
+            let args = ["dnsr", "+nsid", "example.com"];
+            let cli = Cli::parse_from(expand_dig_args(args.map(Into::into)));
+            assert_eq!(cli.query_options(), options);
+            let output = render(OutputFormat::Dig, "example.com", &RecordType::A, &result);
+            assert!(output.contains(";; NSID: 66 72 61 31 00 (\"fra1.\")\n"));
+            let output = render(OutputFormat::Json, "example.com", &RecordType::A, &result);
+            let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+            assert_eq!(json["nsid"], "fra1.");
+        }
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_dnssec_ok_signatures() {
+        use crate::config::QueryOptions;
+        use crate::observer::QueryObserver;
+
+        let rrsig = |covered: RecordType| {
+            let mut rdata = (covered as u16).to_be_bytes().to_vec();
+            rdata.extend([15, 2]);
+            rdata.extend(300u32.to_be_bytes());
+            rdata.extend(2_000_000_000u32.to_be_bytes());
+            rdata.extend(1_700_000_000u32.to_be_bytes());
+            rdata.extend(12345u16.to_be_bytes());
+            rdata.extend(b"\x07example\x03com\x00");
+            rdata.extend([0xab; 64]);
+            DNSRecord::new(
+                "example.com",
+                RecordType::Rrsig,
+                Class::In,
+                300,
+                DNSRecordData::Data(rdata),
+            )
+        };
+        let address = DNSRecord::new(
+            "example.com",
+            RecordType::A,
+            Class::In,
+            300,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+        );
+        let mock = MockTransport::new().answer(
+            "example.com",
+            RecordType::A,
+            vec![
+                address.clone(),
+                rrsig(RecordType::A),
+                rrsig(RecordType::Txt),
+            ],
+        );
+
+        /// Whether each query sent asked for DNSSEC records.
+        #[derive(Default)]
+        struct DoBits(std::sync::Mutex<Vec<bool>>);
+
+        impl QueryObserver for DoBits {
+            fn on_query_sent(&self, _server: IpAddr, query: &DNSPacket) {
+                let set = query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+                self.0.lock().unwrap().push(set);
+            }
+        }
+
+        let do_bits = std::sync::Arc::new(DoBits::default());
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .observer(do_bits.clone())
+            .build()
+            .unwrap();
+        let options = QueryOptions {
+            dnssec_ok: Some(true),
+            ..QueryOptions::default()
+        };
+        let result = resolver
+            .resolve_detailed("example.com", RecordType::A, &options)
+            .await
+            .unwrap();
+        assert_eq!(result.records, [address]);
+        assert_eq!(result.signatures, [rrsig(RecordType::A)]);
+        #[cfg(all(feature = "dnssec", not(target_arch = "wasm32")))]
+        {
+            let rrsigs = result.rrsigs().unwrap();
+            assert_eq!(rrsigs[0].key_tag, 12345);
+            assert_eq!(rrsigs[0].signer, "example.com");
+        }
+        resolver
+            .resolve_detailed("example.com", RecordType::A, &QueryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(*do_bits.0.lock().unwrap(), [true, false]);
+
+        #[cfg(feature = "cli")]
+        {
+            use crate::cli::{Cli, OutputFormat, expand_dig_args, render};
+            use clap::Parser;
+
+            let args = ["dnsr", "example.com", "+dnssec"];
+            let cli = Cli::parse_from(expand_dig_args(args.map(Into::into)));
+            assert_eq!(cli.query_options(), options);
+            let output = render(OutputFormat::Short, "example.com", &RecordType::A, &result);
+            let lines: Vec<_> = output.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[1].starts_with("\\# 95 0001"));
+        }
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_probe_server() {
+        let txt = |name: &str, text: &str| {
+            vec![DNSRecord::new(
+                name,
+                RecordType::Txt,
+                Class::Ch,
+                0,
+                DNSRecordData::Txt(vec![text.to_string()]),
+            )]
+        };
+        // hostname.bind is refused, as servers hiding it do.
+        let mock = MockTransport::new()
+            .answer(
+                "version.bind",
+                RecordType::Txt,
+                txt("version.bind", "9.18.24"),
+            )
+            .answer("id.server", RecordType::Txt, txt("id.server", "ams1"));
+        let resolver = DNSResolver::builder()
+            .transport(mock.clone())
+            .build()
+            .unwrap();
+        let server = Ipv4Addr::new(192, 0, 2, 53);
+        let identity = resolver.probe_server(&server).await.unwrap();
+        assert_eq!(
+            identity,
+            ServerIdentity {
+                version: Some("9.18.24".to_string()),
+                hostname: None,
+                id: Some("ams1".to_string()),
+            }
+        );
+        assert!(mock.queries().iter().all(|(_, q)| q.class == Class::Ch));
+
+        /// Never answers.
+        struct Silent;
+
+        impl DnsTransport for Silent {
+            async fn exchange(&self, _query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                std::future::pending().await
+            }
+        }
+
+        let resolver = DNSResolver::builder()
+            .transport(Silent)
+            .timeout(Duration::from_millis(10))
+            .retries(0)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            resolver.probe_server(&server).await,
+            Err(DnsError::Timeout(_))
+        ));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_pipelined_tcp() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let query = |id: u16| {
+            let mut query = build_query("example.com", RecordType::A, Class::In, None).unwrap();
+            query.header.id = id;
+            query
+        };
+        // Echoes queries back as responses, once `count` of them have arrived.
+        async fn answer(stream: &mut TcpStream, count: usize) {
+            let mut queries = Vec::new();
+            for _ in 0..count {
+                let mut message = vec![0; stream.read_u16().await.unwrap() as usize];
+                stream.read_exact(&mut message).await.unwrap();
+                message[2] |= 0x80;
+                queries.push(message);
+            }
+            for message in queries.into_iter().rev() {
+                stream.write_u16(message.len() as u16).await.unwrap();
+                stream.write_all(&message).await.unwrap();
+            }
+        }
+        let (closed, was_closed) = tokio::sync::oneshot::channel();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            answer(&mut stream, 2).await;
+            drop(stream);
+            closed.send(()).unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            answer(&mut stream, 1).await;
+        });
+
+        let connections = Connections::default();
+        let connect = || async { Ok(TcpStream::connect(address).await?) };
+        let (first, second) = (query(1), query(2));
+        let (one, two) = tokio::join!(
+            connections.exchange(address, &first, connect),
+            connections.exchange(address, &second, connect),
+        );
+        assert_eq!(one.unwrap().header.id, 1);
+        let two = two.unwrap();
+        assert_eq!(two.header.id, 2);
+        assert!(two.header.flags.qr);
+
+        // The server has hung up, so the next query goes on a new connection.
+        was_closed.await.unwrap();
+        let three = connections.exchange(address, &query(3), connect).await;
+        assert_eq!(three.unwrap().header.id, 3);
+        server.await.unwrap();
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_mock_transport() {
+        let root = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let referral = MessageBuilder::query()
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .authority(DNSRecord::new(
+                "example",
+                RecordType::Ns,
+                Class::In,
+                3600,
+                DNSRecordData::Name("ns.example".to_string()),
+            ))
+            .additional(DNSRecord::new(
+                "ns.example",
+                RecordType::A,
+                Class::In,
+                3600,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 53)),
+            ))
+            .build()
+            .unwrap();
+        let mock = MockTransport::new()
+            .response_from(root, "www.mock.example", RecordType::A, referral.clone())
+            .response_from(root, "missing.mock.example", RecordType::A, referral)
+            .answer(
+                "WWW.mock.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "www.mock.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+                )],
+            )
+            .nxdomain("missing.mock.example", RecordType::A);
+        let resolver = DNSResolver::builder()
+            .transport(mock.clone())
+            .upstreams([Ipv4Addr::new(192, 0, 2, 1)])
+            .cache(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            resolver.resolve("www.mock.example").await.unwrap(),
+            Ipv4Addr::new(192, 0, 2, 80)
+        );
+        let servers: Vec<IpAddr> = mock.queries().iter().map(|(server, _)| *server).collect();
+        assert_eq!(servers, [root, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53))]);
+        assert!(matches!(
+            resolver.resolve("missing.mock.example").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert!(matches!(
+            resolver.resolve("unknown.mock.example").await,
+            Err(DnsError::ErrorResponse {
+                rcode: Rcode::Refused,
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "replay")]
+    #[tokio::test]
+    async fn test_replay_transport() {
+        use crate::transport::ReplayTransport;
+
+        let path = std::env::temp_dir().join(format!("replay-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mock = MockTransport::new()
+            .answer(
+                "replayed.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "replayed.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 15)),
+                )],
+            )
+            .nxdomain("missing.replayed.example", RecordType::A);
+        let resolve = async |transport| {
+            let resolver = DNSResolver::builder()
+                .transport(transport)
+                .cache(false)
+                .build()
+                .unwrap();
+            (
+                resolver.resolve("replayed.example").await.unwrap(),
+                resolver.resolve("missing.replayed.example").await.is_err(),
+            )
+        };
+
+        let recording = ReplayTransport::new(&path, mock).unwrap();
+        assert!(recording.is_recording());
+        let recorded = resolve(recording).await;
+        assert_eq!(recorded, (Ipv4Addr::new(192, 0, 2, 15), true));
+
+        // Nothing reaches the inner transport once the fixture exists.
+        let offline = MockTransport::new();
+        let replaying = ReplayTransport::new(&path, offline.clone()).unwrap();
+        assert!(!replaying.is_recording());
+        assert_eq!(resolve(replaying).await, recorded);
+        assert!(offline.queries().is_empty());
+
+        let unknown = ReplayTransport::new(&path, MockTransport::new()).unwrap();
+        let query = build_query("other.example", RecordType::A, Class::In, None).unwrap();
+        let server = IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4));
+        assert!(matches!(
+            unknown.exchange(&query, server).await,
+            Err(DnsError::IoError(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_resolve_trace() {
+        use crate::HopOutcome;
+
+        let root = Ipv4Addr::new(192, 0, 2, 1);
+        let nameserver = Ipv4Addr::new(192, 0, 2, 53);
+        let referral = MessageBuilder::query()
+            .question("example", RecordType::Ns, Class::In)
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .authority(DNSRecord::new(
+                "example",
+                RecordType::Ns,
+                Class::In,
+                3600,
+                DNSRecordData::Name("ns.example".to_string()),
+            ))
+            .additional(DNSRecord::new(
+                "ns.example",
+                RecordType::A,
+                Class::In,
+                3600,
+                DNSRecordData::Ipv4Addr(nameserver),
+            ))
+            .build()
+            .unwrap();
+        let mut mock = MockTransport::new()
+            .answer(
+                "www.trace.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "www.trace.example",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("cdn.trace.example".to_string()),
+                )],
+            )
+            .answer(
+                "cdn.trace.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "cdn.trace.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+                )],
+            )
+            .nxdomain("missing.trace.example", RecordType::A);
+        for name in ["www", "cdn", "missing"] {
+            let name = format!("{name}.trace.example");
+            mock = mock.response_from(root.into(), &name, RecordType::A, referral.clone());
+        }
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .upstreams([root])
+            .build()
+            .unwrap();
+
+        let trace = resolver
+            .resolve_trace("www.trace.example", RecordType::A)
+            .await;
+        assert_eq!(trace.result.unwrap().len(), 1);
+        let delegation = HopOutcome::Referral {
+            zone: "example".to_string(),
+            nameservers: vec!["ns.example".to_string()],
+        };
+        let hops: Vec<_> = trace
+            .hops
+            .iter()
+            .map(|hop| (hop.server, hop.question.name(), hop.outcome.clone()))
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                (root, "www.trace.example", delegation.clone()),
+                (
+                    nameserver,
+                    "www.trace.example",
+                    HopOutcome::Cname("cdn.trace.example".to_string())
+                ),
+                (root, "cdn.trace.example", delegation),
+                (nameserver, "cdn.trace.example", HopOutcome::Answer),
+            ]
+        );
+        assert!(
+            trace
+                .hops
+                .iter()
+                .all(|hop| hop.rcode == Some(Rcode::NoError))
+        );
+        let glue = &trace.hops[0].response.as_ref().unwrap().additionals;
+        assert_eq!(glue[0].data, DNSRecordData::Ipv4Addr(nameserver));
+
+        let trace = resolver
+            .resolve_trace("missing.trace.example", RecordType::A)
+            .await;
+        assert!(matches!(trace.result, Err(DnsError::NxDomain(_))));
+        let last = trace.hops.last().unwrap();
+        assert_eq!(last.outcome, HopOutcome::NxDomain);
+        assert_eq!(last.rcode, Some(Rcode::NxDomain));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_service() {
+        use tower::Service;
+
+        let address = Ipv4Addr::new(192, 0, 2, 8);
+        let mock = MockTransport::new().answer(
+            "service.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "service.example",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            )],
+        );
+        let mut resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let ip = resolver.call("service.example".to_string()).await.unwrap();
+        assert_eq!(ip, address);
+
+        #[cfg(feature = "reqwest")]
+        {
+            use reqwest::dns::Resolve;
+
+            let name = "service.example".parse().unwrap();
+            let addrs: Vec<_> = Resolve::resolve(&resolver, name).await.unwrap().collect();
+            assert_eq!(addrs, [std::net::SocketAddr::from((address, 0))]);
+        }
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_lookup_ip() {
+        let hosts = Hosts::parse(
+            "127.0.0.1 localhost\n::1 localhost ip6-localhost # loopback\nnot-an-address foo\n",
+        );
+        assert_eq!(
+            hosts.lookup("LOCALHOST."),
+            [
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                IpAddr::from(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert!(hosts.lookup("foo").is_empty());
+
+        let v4 = Ipv4Addr::new(192, 0, 2, 9);
+        let v6: Ipv6Addr = "2001:db8::9".parse().unwrap();
+        let mock = MockTransport::new()
+            .answer(
+                "dual.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "dual.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(v4),
+                )],
+            )
+            .answer(
+                "dual.example",
+                RecordType::Aaaa,
+                vec![DNSRecord::new(
+                    "dual.example",
+                    RecordType::Aaaa,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv6Addr(v6),
+                )],
+            )
+            .nxdomain("v4.example", RecordType::Aaaa)
+            .answer(
+                "v4.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "v4.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(v4),
+                )],
+            );
+        let resolver = |strategy| {
+            DNSResolver::builder()
+                .transport(mock.clone())
+                .ip_strategy(strategy)
+                .hosts(hosts.clone())
+                .cache(false)
+                .build()
+                .unwrap()
+        };
+
+        let default = resolver(IpStrategy::default());
+        assert_eq!(
+            default.lookup_ip("dual.example").await.unwrap(),
+            [IpAddr::V4(v4), IpAddr::V6(v6)]
+        );
+        assert_eq!(
+            default.lookup_ip("v4.example").await.unwrap(),
+            [IpAddr::V4(v4)]
+        );
+        assert_eq!(
+            resolver(IpStrategy::Ipv6ThenIpv4)
+                .lookup_ip("dual.example")
+                .await
+                .unwrap(),
+            [IpAddr::V6(v6), IpAddr::V4(v4)]
+        );
+        assert_eq!(
+            resolver(IpStrategy::Ipv6Only)
+                .lookup_ip("localhost")
+                .await
+                .unwrap(),
+            [IpAddr::V6(Ipv6Addr::LOCALHOST)]
+        );
+        assert!(matches!(
+            resolver(IpStrategy::Ipv6Only).lookup_ip("v4.example").await,
+            Err(DnsError::NxDomain(_))
+        ));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_lookup_mx() {
+        let mx = |preference, exchange: &str| {
+            DNSRecord::new(
+                "mx.example",
+                RecordType::Mx,
+                Class::In,
+                300,
+                DNSRecordData::Mx(Mx {
+                    preference,
+                    exchange: exchange.to_string(),
+                }),
+            )
+        };
+        let response = MessageBuilder::query()
+            .id(1)
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .answer(mx(20, "backup.mx.example"))
+            .answer(mx(10, "mail.mx.example"))
+            .build()
+            .unwrap();
+        let bytes = response.to_bytes();
+        let parsed = DNSPacket::parse(&bytes).unwrap();
+        assert_eq!(parsed, response);
+        assert_eq!(
+            parsed.answers[1].to_string(),
+            "mx.example.\t300\tIN\tMX\t10 mail.mx.example."
+        );
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(serde_json::from_str::<DNSPacket>(&json).unwrap(), response);
+
+        let mail = Ipv4Addr::new(192, 0, 2, 25);
+        let mock = MockTransport::new()
+            .answer(
+                "mx.example",
+                RecordType::Mx,
+                vec![mx(20, "backup.mx.example"), mx(10, "mail.mx.example")],
+            )
+            .answer(
+                "mail.mx.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "mail.mx.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(mail),
+                )],
+            )
+            .answer("null.example", RecordType::Mx, vec![mx(0, "")])
+            .response(
+                "implicit.example",
+                RecordType::Mx,
+                MessageBuilder::query()
+                    .flags(DNSFlags {
+                        qr: true,
+                        aa: true,
+                        ..DNSFlags::default()
+                    })
+                    .authority(DNSRecord::new(
+                        "implicit.example",
+                        RecordType::Soa,
+                        Class::In,
+                        300,
+                        DNSRecordData::Soa(Soa {
+                            mname: "ns.implicit.example".to_string(),
+                            rname: "hostmaster.implicit.example".to_string(),
+                            serial: 1,
+                            refresh: 3600,
+                            retry: 600,
+                            expire: 86400,
+                            minimum: 300,
+                        }),
+                    ))
+                    .build()
+                    .unwrap(),
+            )
+            .answer(
+                "implicit.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "implicit.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(mail),
+                )],
+            );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .ip_strategy(IpStrategy::Ipv4Only)
+            .hosts(Hosts::default())
+            .cache(false)
+            .build()
+            .unwrap();
+        let exchangers = resolver.lookup_mx_addresses("mx.example").await.unwrap();
+        assert_eq!(exchangers.len(), 2);
+        assert_eq!(exchangers[0].mx.exchange, "mail.mx.example");
+        assert_eq!(exchangers[0].addresses, [IpAddr::V4(mail)]);
+        assert_eq!(exchangers[1].mx.preference, 20);
+        assert!(exchangers[1].addresses.is_empty());
+        assert!(resolver.lookup_mx("null.example").await.unwrap().is_empty());
+
+        let hosts = resolver.resolve_mail_hosts("mx.example").await.unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].mx.exchange, "mail.mx.example");
+        let hosts = resolver
+            .resolve_mail_hosts("implicit.example")
+            .await
+            .unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].mx.preference, 0);
+        assert_eq!(hosts[0].mx.exchange, "implicit.example");
+        assert_eq!(hosts[0].addresses, [IpAddr::V4(mail)]);
+        assert!(
+            resolver
+                .resolve_mail_hosts("null.example")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_resolve_service() {
+        let srv = |priority, port, target: &str| {
+            DNSRecord::new(
+                "_sip._tcp.srv.example",
+                RecordType::Srv,
+                Class::In,
+                300,
+                DNSRecordData::Srv(Srv {
+                    priority,
+                    weight: 5,
+                    port,
+                    target: target.to_string(),
+                }),
+            )
+        };
+        let response = MessageBuilder::query()
+            .id(1)
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .answer(srv(20, 5061, "backup.srv.example"))
+            .answer(srv(10, 5060, "sip.srv.example"))
+            .build()
+            .unwrap();
+        let parsed = DNSPacket::parse(&response.to_bytes()).unwrap();
+        assert_eq!(parsed, response);
+        assert_eq!(
+            parsed.answers[1].to_string(),
+            "_sip._tcp.srv.example.\t300\tIN\tSRV\t10 5 5060 sip.srv.example."
+        );
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(serde_json::from_str::<DNSPacket>(&json).unwrap(), response);
+        assert_eq!("srv".parse::<RecordType>().unwrap(), RecordType::Srv);
+
+        let address = |name: &str, ip| {
+            DNSRecord::new(
+                name,
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(ip),
+            )
+        };
+        let sip = Ipv4Addr::new(192, 0, 2, 60);
+        let backup = Ipv4Addr::new(192, 0, 2, 61);
+        let mock = MockTransport::new()
+            .answer(
+                "_sip._tcp.srv.example",
+                RecordType::Srv,
+                vec![
+                    srv(20, 5061, "backup.srv.example"),
+                    srv(10, 5060, "sip.srv.example"),
+                    srv(30, 5062, "gone.srv.example"),
+                ],
+            )
+            .answer(
+                "sip.srv.example",
+                RecordType::A,
+                vec![address("sip.srv.example", sip)],
+            )
+            .answer(
+                "backup.srv.example",
+                RecordType::A,
+                vec![address("backup.srv.example", backup)],
+            )
+            .answer(
+                "_sip._udp.srv.example",
+                RecordType::Srv,
+                vec![srv(0, 0, "")],
+            );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .ip_strategy(IpStrategy::Ipv4Only)
+            .hosts(Hosts::default())
+            .cache(false)
+            .build()
+            .unwrap();
+        let addresses = resolver
+            .resolve_service("sip", "tcp", "srv.example")
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses,
+            [
+                SocketAddr::new(IpAddr::V4(sip), 5060),
+                SocketAddr::new(IpAddr::V4(backup), 5061),
+            ]
+        );
+        let offered = resolver
+            .resolve_service("_sip", "_udp", "srv.example")
+            .await
+            .unwrap();
+        assert!(offered.is_empty());
+    }
+
+    #[cfg(feature = "enum")]
+    #[tokio::test]
+    async fn test_enum_lookup() {
+        use crate::Naptr;
+        use crate::e164::{application_string, enum_domain};
+
+        assert_eq!(
+            application_string("+1 (202) 555-0143").unwrap(),
+            "+12025550143"
+        );
+        assert!(matches!(
+            application_string("202 555 0143"),
+            Err(DnsError::InvalidNumber(_))
+        ));
+        assert!(application_string("+1234567890123456").is_err());
+        assert_eq!(enum_domain("+44 20").unwrap(), "0.2.4.4.e164.arpa");
+
+        let naptr = |order, preference, flags: &str, services: &str, regexp: &str| {
+            DNSRecord::new(
+                "3.4.1.0.5.5.5.2.0.2.1.e164.arpa",
+                RecordType::Naptr,
+                Class::In,
+                300,
+                DNSRecordData::Naptr(Naptr {
+                    order,
+                    preference,
+                    flags: flags.to_string(),
+                    services: services.to_string(),
+                    regexp: regexp.to_string(),
+                    replacement: String::new(),
+                }),
+            )
+        };
+        let sip = naptr(100, 10, "u", "E2U+sip", "!^\\+1(.*)$!sip:\\1@example.com!");
+        let response = MessageBuilder::query()
+            .id(1)
+            .flags(DNSFlags {
+                qr: true,
+                ..DNSFlags::default()
+            })
+            .answer(sip.clone())
+            .build()
+            .unwrap();
+        let parsed = DNSPacket::parse(&response.to_bytes()).unwrap();
+        assert_eq!(parsed, response);
+        assert_eq!(
+            parsed.answers[0].data().to_string(),
+            "100 10 \"u\" \"E2U+sip\" \"!^\\\\+1(.*)$!sip:\\\\1@example.com!\" ."
+        );
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(serde_json::from_str::<DNSPacket>(&json).unwrap(), response);
+
+        let mock = MockTransport::new().answer(
+            "3.4.1.0.5.5.5.2.0.2.1.e164.arpa",
+            RecordType::Naptr,
+            vec![
+                naptr(
+                    200,
+                    10,
+                    "u",
+                    "E2U+email:mailto",
+                    "!^.*$!mailto:info@example.com!",
+                ),
+                naptr(100, 20, "u", "E2U+voice:tel", "!^(.*)$!tel:\\1!"),
+                sip,
+                naptr(100, 5, "", "E2U+sip", ""),
+                naptr(100, 1, "u", "E2U+sip", "!([!i"),
+            ],
+        );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let uris = resolver.enum_lookup("+1 202-555-0143").await.unwrap();
+        assert_eq!(uris, ["sip:2025550143@example.com", "tel:+12025550143"]);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_audit_email_domain() {
+        let txt = |name: &str, text: &str| {
+            DNSRecord::new(
+                name,
+                RecordType::Txt,
+                Class::In,
+                300,
+                DNSRecordData::Txt(vec![text.to_string()]),
+            )
+        };
+        let mock = MockTransport::new()
+            .answer(
+                "mail.example",
+                RecordType::Mx,
+                vec![DNSRecord::new(
+                    "mail.example",
+                    RecordType::Mx,
+                    Class::In,
+                    300,
+                    DNSRecordData::Mx(Mx {
+                        preference: 10,
+                        exchange: "mx.mail.example".to_string(),
+                    }),
+                )],
+            )
+            .answer(
+                "mail.example",
+                RecordType::Txt,
+                vec![
+                    txt("mail.example", "google-site-verification=abc"),
+                    txt("mail.example", "v=spf1 mx -all"),
+                    txt("mail.example", "v=spf10"),
+                ],
+            )
+            .answer(
+                "_dmarc.mail.example",
+                RecordType::Txt,
+                vec![txt("_dmarc.mail.example", "v=DMARC1; p=reject")],
+            )
+            .answer(
+                "s1._domainkey.mail.example",
+                RecordType::Txt,
+                vec![txt(
+                    "s1._domainkey.mail.example",
+                    "v=DKIM1; k=rsa; p=MIGfMA0",
+                )],
+            )
+            .answer(
+                "old._domainkey.mail.example",
+                RecordType::Txt,
+                vec![txt("old._domainkey.mail.example", "v=DKIM1; p=")],
+            );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+
+        let audit = resolver
+            .audit_email_domain("mail.example.", Some("s1"))
+            .await;
+        assert_eq!(audit.domain, "mail.example");
+        assert_eq!(audit.mx[0].exchange, "mx.mail.example");
+        assert_eq!(audit.spf, ["v=spf1 mx -all"]);
+        assert_eq!(audit.dmarc, ["v=DMARC1; p=reject"]);
+        assert_eq!(audit.dkim, Some(true));
+        assert!(audit.errors.is_empty());
+        assert!(audit.is_complete());
+
+        let revoked = resolver
+            .audit_email_domain("mail.example", Some("old"))
+            .await;
+        assert_eq!(revoked.dkim, Some(false));
+        assert!(!revoked.is_complete());
+        assert_eq!(
+            resolver.audit_email_domain("mail.example", None).await.dkim,
+            None
+        );
+
+        // Unknown names are refused by the mock, which is not a missing record.
+        let refused = resolver
+            .audit_email_domain("other.example", Some("s1"))
+            .await;
+        assert!(refused.mx.is_empty() && refused.spf.is_empty());
+        assert_eq!(refused.dkim, None);
+        assert_eq!(refused.errors.len(), 4);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_spf() {
+        use crate::error::SpfError;
+        use crate::spf::{Mechanism, Qualifier, SpfRecord, SpfResult};
+
+        let record: SpfRecord =
+            "v=spf1 ip4:192.0.2.0/24 +a:mail.example/28 ?mx//64 ip6:2001:db8::/32 ~all redirect=other.example foo=bar"
+                .parse()
+                .unwrap();
+        assert_eq!(record.directives.len(), 5);
+        assert_eq!(
+            record.directives[0].mechanism,
+            Mechanism::Ip4(Ipv4Addr::new(192, 0, 2, 0), 24)
+        );
+        assert_eq!(
+            record.directives[1].mechanism,
+            Mechanism::A {
+                domain: Some("mail.example".to_string()),
+                v4_prefix: 28,
+                v6_prefix: 128,
+            }
+        );
+        assert_eq!(record.directives[2].qualifier, Qualifier::Neutral);
+        assert_eq!(
+            record.directives[2].mechanism,
+            Mechanism::Mx {
+                domain: None,
+                v4_prefix: 32,
+                v6_prefix: 64,
+            }
+        );
+        assert_eq!(
+            record.directives[3].mechanism,
+            Mechanism::Ip6("2001:db8::".parse().unwrap(), 32)
+        );
+        assert_eq!(record.directives[4].qualifier, Qualifier::SoftFail);
+        assert_eq!(record.redirect.as_deref(), Some("other.example"));
+        assert_eq!("v=spf10".parse::<SpfRecord>(), Err(SpfError::NotSpf));
+        assert_eq!(
+            "v=spf1 foo:bar".parse::<SpfRecord>(),
+            Err(SpfError::UnknownMechanism("foo".to_string()))
+        );
+        assert!(matches!(
+            "v=spf1 ip4:192.0.2.300".parse::<SpfRecord>(),
+            Err(SpfError::InvalidTerm(_))
+        ));
+        assert!(matches!(
+            "v=spf1 exp=a exp=b".parse::<SpfRecord>(),
+            Err(SpfError::DuplicateModifier(_))
+        ));
+
+        let txt = |name: &str, text: &str| {
+            vec![DNSRecord::new(
+                name,
+                RecordType::Txt,
+                Class::In,
+                300,
+                DNSRecordData::Txt(vec![text.to_string()]),
+            )]
+        };
+        let address = |name: &str, ip: [u8; 4]| {
+            vec![DNSRecord::new(
+                name,
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::from(ip)),
+            )]
+        };
+        let mock = MockTransport::new()
+            .answer(
+                "spf.example",
+                RecordType::Txt,
+                txt(
+                    "spf.example",
+                    "v=spf1 ip4:198.51.100.0/24 include:_spf.spf.example mx -all",
+                ),
+            )
+            .answer(
+                "_spf.spf.example",
+                RecordType::Txt,
+                txt("_spf.spf.example", "v=spf1 a:relay.spf.example ~all"),
+            )
+            .answer(
+                "relay.spf.example",
+                RecordType::A,
+                address("relay.spf.example", [203, 0, 113, 5]),
+            )
+            .answer(
+                "spf.example",
+                RecordType::Mx,
+                vec![DNSRecord::new(
+                    "spf.example",
+                    RecordType::Mx,
+                    Class::In,
+                    300,
+                    DNSRecordData::Mx(Mx {
+                        preference: 10,
+                        exchange: "mx.spf.example".to_string(),
+                    }),
+                )],
+            )
+            .answer(
+                "mx.spf.example",
+                RecordType::A,
+                address("mx.spf.example", [203, 0, 113, 25]),
+            )
+            .answer(
+                "redirect.example",
+                RecordType::Txt,
+                txt("redirect.example", "v=spf1 redirect=spf.example"),
+            )
+            .answer(
+                "loop.example",
+                RecordType::Txt,
+                txt("loop.example", "v=spf1 include:loop.example -all"),
+            )
+            .answer(
+                "macro.example",
+                RecordType::Txt,
+                txt(
+                    "macro.example",
+                    "v=spf1 exists:%{ir}.%{l1r-}._spf.%{d} -all",
+                ),
+            )
+            .answer(
+                "7.100.51.198.first._spf.macro.example",
+                RecordType::A,
+                address("7.100.51.198.first._spf.macro.example", [127, 0, 0, 2]),
+            )
+            .nxdomain("8.100.51.198.first._spf.macro.example", RecordType::A)
+            .nxdomain("none.example", RecordType::Txt);
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let check = |ip: [u8; 4], sender: &'static str| {
+            let resolver = &resolver;
+            async move {
+                resolver
+                    .check_spf(IpAddr::V4(Ipv4Addr::from(ip)), sender)
+                    .await
+            }
+        };
+        assert_eq!(
+            check([198, 51, 100, 7], "user@spf.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check([203, 0, 113, 5], "spf.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check([203, 0, 113, 25], "user@spf.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check([192, 0, 2, 1], "user@spf.example").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check([198, 51, 100, 7], "redirect.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(check([192, 0, 2, 1], "none.example").await, SpfResult::None);
+        assert!(matches!(
+            check([192, 0, 2, 1], "loop.example").await,
+            SpfResult::PermError(_)
+        ));
+        assert!(matches!(
+            check([192, 0, 2, 1], "refused.example").await,
+            SpfResult::TempError(_)
+        ));
+        assert_eq!(
+            check([198, 51, 100, 7], "first-user@macro.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check([198, 51, 100, 8], "first-user@macro.example").await,
+            SpfResult::Fail
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_fetch_dmarc() {
+        use crate::mail::{Dmarc, DmarcPolicy};
+
+        let dmarc = Dmarc::parse(
+            "example.com.",
+            "v=DMARC1; p=Reject; sp=quarantine; pct=50; rua=mailto:a@example.com, mailto:b@example.net",
+        )
+        .unwrap();
+        assert_eq!(dmarc.domain, "example.com");
+        assert_eq!(dmarc.policy, DmarcPolicy::Reject);
+        assert_eq!(dmarc.subdomain_policy, Some(DmarcPolicy::Quarantine));
+        assert_eq!(dmarc.percent, 50);
+        assert_eq!(
+            dmarc.aggregate_reports,
+            ["mailto:a@example.com", "mailto:b@example.net"]
+        );
+        assert_eq!(
+            dmarc.policy_for("mail.example.com"),
+            DmarcPolicy::Quarantine
+        );
+        assert_eq!(
+            Dmarc::parse("example.com", "v=DMARC1; p=none; pct=200")
+                .unwrap()
+                .percent,
+            100
+        );
+        assert!(Dmarc::parse("example.com", "p=none; v=DMARC1").is_none());
+        assert!(Dmarc::parse("example.com", "v=DMARC1; rua=mailto:a@example.com").is_none());
+
+        let txt = |name: &str, text: &str| {
+            vec![DNSRecord::new(
+                name,
+                RecordType::Txt,
+                Class::In,
+                300,
+                DNSRecordData::Txt(vec![text.to_string()]),
+            )]
+        };
+        let mock = MockTransport::new()
+            .answer(
+                "_dmarc.dmarc.example",
+                RecordType::Txt,
+                txt("_dmarc.dmarc.example", "v=DMARC1; p=reject; sp=none"),
+            )
+            .answer(
+                "_dmarc.own.dmarc.example",
+                RecordType::Txt,
+                txt("_dmarc.own.dmarc.example", "v=DMARC1; p=quarantine"),
+            )
+            .nxdomain("_dmarc.mail.dmarc.example", RecordType::Txt)
+            .nxdomain("_dmarc.none.example", RecordType::Txt)
+            .nxdomain("_dmarc.example", RecordType::Txt);
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let own = resolver
+            .fetch_dmarc("own.dmarc.example")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(own.domain, "own.dmarc.example");
+        assert_eq!(own.policy, DmarcPolicy::Quarantine);
+        let inherited = resolver
+            .fetch_dmarc("mail.dmarc.example")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inherited.domain, "dmarc.example");
+        assert_eq!(
+            inherited.policy_for("mail.dmarc.example"),
+            DmarcPolicy::None
+        );
+        assert!(
+            resolver
+                .fetch_dmarc("none.example")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_fetch_dkim() {
+        use crate::mail::DkimKey;
+
+        assert_eq!(
+            DkimKey::parse("k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=").unwrap(),
+            DkimKey {
+                version: None,
+                key_type: "ed25519".to_string(),
+                public_key: "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=".to_string(),
+            }
+        );
+        assert!(DkimKey::parse("v=DKIM1; p=").unwrap().is_revoked());
+        assert!(DkimKey::parse("v=DKIM1; k=rsa").is_none());
+        assert!(DkimKey::parse("k=rsa; v=DKIM1; p=MIGf").is_none());
+
+        let name = "s1._domainkey.dkim.example";
+        let mock = MockTransport::new()
+            .answer(
+                name,
+                RecordType::Txt,
+                vec![DNSRecord::new(
+                    name,
+                    RecordType::Txt,
+                    Class::In,
+                    300,
+                    DNSRecordData::Txt(vec![
+                        "v=DKIM1; k=rsa; p=MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A".to_string(),
+                        "MIIBCgKCAQEAwJ5 QIDAQAB".to_string(),
+                    ]),
+                )],
+            )
+            .nxdomain("s2._domainkey.dkim.example", RecordType::Txt);
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let key = resolver
+            .fetch_dkim("s1", "dkim.example.")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(key.version.as_deref(), Some("DKIM1"));
+        assert_eq!(key.key_type, "rsa");
+        assert_eq!(
+            key.public_key,
+            "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAwJ5QIDAQAB"
+        );
+        assert!(
+            resolver
+                .fetch_dkim("s2", "dkim.example")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_detect_wildcard() {
+        /// Answers every name under `wild.example` and denies the rest.
+        struct Wildcard;
+
+        impl DnsTransport for Wildcard {
+            async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                let question = &query.questions[0];
+                if !question.name.ends_with(".wild.example") {
+                    return MessageBuilder::response_to(query)
+                        .flags(DNSFlags {
+                            qr: true,
+                            aa: true,
+                            rcode: Rcode::NxDomain,
+                            ..DNSFlags::default()
+                        })
+                        .build();
+                }
+                MessageBuilder::response_to(query)
+                    .flags(DNSFlags {
+                        qr: true,
+                        aa: true,
+                        ..DNSFlags::default()
+                    })
+                    .answer(DNSRecord::new(
+                        &question.name,
+                        RecordType::A,
+                        Class::In,
+                        60,
+                        DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 99)),
+                    ))
+                    .build()
+            }
+        }
+
+        let resolver = DNSResolver::builder()
+            .transport(Wildcard)
+            .cache(false)
+            .build()
+            .unwrap();
+        let wild = resolver.detect_wildcard("wild.example.").await.unwrap();
+        assert!(wild.is_wildcard);
+        assert!(wild.probe.ends_with(".wild.example"));
+        assert_eq!(wild.probe.len(), 16 + ".wild.example".len());
+        assert_eq!(
+            wild.records[0].data,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 99))
+        );
+        let tame = resolver.detect_wildcard("tame.example").await.unwrap();
+        assert!(!tame.is_wildcard);
+        assert!(tame.records.is_empty());
+        assert_ne!(
+            resolver
+                .detect_wildcard("tame.example")
+                .await
+                .unwrap()
+                .probe,
+            tame.probe
+        );
+    }
+
+    #[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_testutil_wildcards() {
+        use crate::testutil::TestServer;
+
+        let record = |name: &str, type_, data| DNSRecord::new(name, type_, Class::In, 300, data);
+        let wild = Ipv4Addr::new(192, 0, 2, 90);
+        let www = Ipv4Addr::new(192, 0, 2, 91);
+        let server = TestServer::builder()
+            .records([
+                record("*.wild.test", RecordType::A, DNSRecordData::Ipv4Addr(wild)),
+                record(
+                    "*.wild.test",
+                    RecordType::Txt,
+                    DNSRecordData::Txt(vec!["wildcard".to_string()]),
+                ),
+                record("www.wild.test", RecordType::A, DNSRecordData::Ipv4Addr(www)),
+                record(
+                    "host.sub.wild.test",
+                    RecordType::A,
+                    DNSRecordData::Ipv4Addr(www),
+                ),
+                record(
+                    "*.alias.test",
+                    RecordType::Cname,
+                    DNSRecordData::Name("www.wild.test".to_string()),
+                ),
+            ])
+            .start()
+            .await
+            .unwrap();
+        let resolver = server.resolver().unwrap();
+
+        // Synthesized for any name that does not exist, however deep, with
+        // the queried name as owner.
+        for name in ["random.wild.test", "a.b.wild.test"] {
+            let records = resolver.resolve_record(name, RecordType::A).await.unwrap();
+            assert_eq!(records[0].name, name);
+            assert_eq!(records[0].data, DNSRecordData::Ipv4Addr(wild));
+        }
+        // A name that exists is not matched, nor are names under an empty
+        // non-terminal, which exists too.
+        assert_eq!(resolver.resolve("www.wild.test").await.unwrap(), www);
+        assert!(matches!(
+            resolver
+                .resolve_record("www.wild.test", RecordType::Txt)
+                .await,
+            Err(DnsError::NoRecordsFound(_))
+        ));
+        assert!(matches!(
+            resolver.resolve("sub.wild.test").await,
+            Err(DnsError::NoRecordsFound(_))
+        ));
+        assert!(matches!(
+            resolver.resolve("other.sub.wild.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        // The wildcard's owner is an ordinary name, and a wildcard CNAME is
+        // followed.
+        assert_eq!(resolver.resolve("*.wild.test").await.unwrap(), wild);
+        assert_eq!(resolver.resolve("cdn.alias.test").await.unwrap(), www);
+        assert!(matches!(
+            resolver.resolve("missing.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert!(
+            resolver
+                .detect_wildcard("wild.test")
+                .await
+                .unwrap()
+                .is_wildcard
+        );
+    }
+
+    #[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_testutil_server() {
+        use crate::testutil::TestServer;
+
+        let address = Ipv4Addr::new(192, 0, 2, 80);
+        let server = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.e2e.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .records([
+                DNSRecord::new(
+                    "alias.e2e.test",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("WWW.e2e.test".to_string()),
+                ),
+                DNSRecord::new(
+                    "e2e.test",
+                    RecordType::Txt,
+                    Class::In,
+                    300,
+                    DNSRecordData::Txt(vec!["hello".to_string()]),
+                ),
+            ])
+            .start()
+            .await
+            .unwrap();
+        assert!(server.addr().ip().is_loopback());
+        let resolver = server.resolver().unwrap();
+        assert_eq!(resolver.resolve("www.e2e.test").await.unwrap(), address);
+        let records = resolver
+            .resolve_record("alias.e2e.test", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(records[0].data, DNSRecordData::Ipv4Addr(address));
+        assert!(matches!(
+            resolver.resolve("missing.e2e.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert!(matches!(
+            resolver.resolve("e2e.test").await,
+            Err(DnsError::NoRecordsFound(_))
+        ));
+        let queries = server.queries();
+        assert_eq!(queries.len(), 4);
+        assert_eq!(queries[0].name().to_ascii_lowercase(), "www.e2e.test");
+    }
+
+    #[cfg(all(feature = "resolver", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_cname_loop() {
+        use crate::testutil::TestServer;
+
+        let alias = |name: &str, target: &str| {
+            DNSRecord::new(
+                name,
+                RecordType::Cname,
+                Class::In,
+                300,
+                DNSRecordData::Name(target.to_string()),
+            )
+        };
+        let server = TestServer::builder()
+            .records([
+                alias("a.loop.test", "b.loop.test"),
+                alias("b.loop.test", "a.loop.test"),
+            ])
+            .start()
+            .await
+            .unwrap();
+        let resolver = server.resolver().unwrap();
+        assert!(matches!(
+            resolver.resolve_record("a.loop.test", RecordType::A).await,
+            Err(DnsError::Unresolvable(_))
+        ));
+        assert!(matches!(
+            resolver.resolve("b.loop.test").await,
+            Err(DnsError::Unresolvable(_))
+        ));
+    }
+
+    #[cfg(all(feature = "resolver", feature = "cache", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_negative_cache() {
+        use crate::cache::NEGATIVE_CACHE;
+        use crate::testutil::TestServer;
+
+        let server = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.negative.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let resolver = |ceiling: u64| {
+            DNSResolver::builder()
+                .upstreams([Ipv4Addr::LOCALHOST])
+                .upstream_port(Ipv4Addr::LOCALHOST, server.addr().port())
+                .negative_ttl_ceiling(Duration::from_secs(ceiling))
+                .build()
+                .unwrap()
+        };
+
+        // NXDOMAIN and NODATA are cached for the SOA minimum, a minute on
+        // the test server, and answered from the cache until then.
+        let negative = [
+            ("missing.negative.test", RecordType::A),
+            ("www.negative.test", RecordType::Txt),
+        ];
+        let cached = resolver(900);
+        for (name, type_) in &negative {
+            assert!(cached.resolve_record(name, type_.clone()).await.is_err());
+            let key = (name.to_string(), type_.clone());
+            let entry = NEGATIVE_CACHE.get(&key).await.unwrap();
+            assert_eq!(entry.ttl, Duration::from_secs(60));
+        }
+        let queries = server.queries().len();
+        let [(nxdomain, a), (nodata, txt)] = negative;
+        assert!(matches!(
+            cached.resolve_record(nxdomain, a).await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert!(matches!(
+            cached.resolve_record(nodata, txt).await,
+            Err(DnsError::NoRecordsFound(_))
+        ));
+        assert_eq!(server.queries().len(), queries);
+
+        // The ceiling caps a longer minimum.
+        let capped = resolver(5);
+        let key = ("capped.negative.test".to_string(), RecordType::A);
+        assert!(capped.resolve_record(&key.0, RecordType::A).await.is_err());
+        let entry = NEGATIVE_CACHE.get(&key).await.unwrap();
+        assert_eq!(entry.ttl, Duration::from_secs(5));
+
+        // So does the SOA's own TTL, when below its minimum.
+        let query = MessageBuilder::query()
+            .question("missing.negative.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let soa = Soa {
+            mname: "ns.test".to_string(),
+            rname: "hostmaster.test".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        };
+        let response = MessageBuilder::response_to(&query)
+            .rcode(Rcode::NxDomain)
+            .authority(DNSRecord::new(
+                "test",
+                RecordType::Soa,
+                Class::In,
+                30,
+                DNSRecordData::Soa(soa),
+            ))
+            .build()
+            .unwrap();
+        let (_, ttl) = response
+            .get_negative_answer(Duration::from_secs(900))
+            .unwrap();
+        assert_eq!(ttl, Some(Duration::from_secs(30)));
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_daemon() {
+        use crate::config::{QueryOptions, TransportPreference, UpstreamPolicy};
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        assert!(matches!(
+            DaemonConfig::from_toml("listen = \"127.0.0.1:5353\"\nttl = 5"),
+            Err(DnsError::InvalidConfig(_))
+        ));
+        let both = DaemonConfig::from_toml(
+            "root_servers = [\"198.41.0.4\"]\nforwarders = [\"192.0.2.53:5353\"]",
+        )
+        .unwrap();
+        assert_eq!(both.forwarders[0].port(), 5353);
+        assert_eq!(both.upstream_policy, UpstreamPolicy::Fastest);
+        let rotating = DaemonConfig::from_toml("upstream_policy = \"round-robin\"").unwrap();
+        assert_eq!(rotating.upstream_policy, UpstreamPolicy::RoundRobin);
+        assert!(matches!(both.resolver(), Err(DnsError::InvalidConfig(_))));
+
+        let address = Ipv4Addr::new(192, 0, 2, 81);
+        let root = TestServer::builder()
+            .records([
+                DNSRecord::new(
+                    "www.daemon.test",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(address),
+                ),
+                DNSRecord::new(
+                    "alias.daemon.test",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("www.daemon.test".to_string()),
+                ),
+                DNSRecord::new(
+                    "a.loop.daemon.test",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("b.loop.daemon.test".to_string()),
+                ),
+                DNSRecord::new(
+                    "b.loop.daemon.test",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("a.loop.daemon.test".to_string()),
+                ),
+            ])
+            .start()
+            .await
+            .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\ncache = false",
+            root.addr()
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let task = tokio::spawn(daemon.run());
+
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        assert_eq!(client.resolve("www.daemon.test").await.unwrap(), address);
+        let tcp = QueryOptions {
+            transport: TransportPreference::Tcp,
+            ..QueryOptions::default()
+        };
+        let records = client
+            .resolve_record_with("www.daemon.test", RecordType::A, &tcp)
+            .await
+            .unwrap();
+        assert_eq!(records[0].data, DNSRecordData::Ipv4Addr(address));
+        assert!(matches!(
+            client.resolve("missing.daemon.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+
+        // The CNAME followed is answered from the queried name.
+        let query = MessageBuilder::query()
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("alias.daemon.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let response = client
+            .exchange_raw(&query.to_bytes(), &Ipv4Addr::LOCALHOST)
+            .await
+            .unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert!(response.header.flags.ra);
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[0].name, "alias.daemon.test");
+        assert_eq!(
+            response.answers[0].data,
+            DNSRecordData::Name("www.daemon.test".to_string())
+        );
+        assert_eq!(response.answers[1].data, DNSRecordData::Ipv4Addr(address));
+
+        // A CNAME loop upstream is answered with SERVFAIL, and the daemon
+        // goes on answering.
+        let query = MessageBuilder::query()
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("a.loop.daemon.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let response = client
+            .exchange_raw(&query.to_bytes(), &Ipv4Addr::LOCALHOST)
+            .await
+            .unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert_eq!(response.header.flags.rcode, Rcode::ServFail);
+        assert_eq!(client.resolve("www.daemon.test").await.unwrap(), address);
+
+        // Over TCP, a response instead of a query closes the connection
+        // unanswered.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let response = response.to_bytes();
+        stream
+            .write_all(&(response.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&response).await.unwrap();
+        let mut answered = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut answered))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(answered.is_empty());
+        task.abort();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_daemon_zones() {
+        use crate::authority::Zones;
+        use crate::daemon::{Daemon, DaemonConfig, ZoneConfig};
+
+        let dir = std::env::temp_dir().join(format!("dnsr-zones-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("home.arpa.zone");
+        let zone_text = |address: &str| {
+            format!(
+                "$TTL 60\n@ SOA ns hostmaster 1 3600 600 86400 60\n\tNS ns\nns A 192.0.2.53\nnas A {address}\n"
+            )
+        };
+        std::fs::write(&file, zone_text("192.0.2.10")).unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\ncache = false\n[[zones]]\norigin = \"home.arpa\"\nfile = {:?}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        assert_eq!(
+            config.zones,
+            [ZoneConfig {
+                origin: "home.arpa".to_string(),
+                file: Some(file.clone()),
+                primaries: Vec::new(),
+                allow_transfer: Vec::new(),
+                regional: Vec::new(),
+                weighted: Vec::new(),
+                health_checks: Vec::new(),
+            }]
+        );
+        let mut missing = config.clone();
+        missing.zones[0].file = Some(dir.join("missing.zone"));
+        assert!(Daemon::bind(&missing).await.is_err());
+
+        // A file that stops parsing leaves the zone as it was.
+        let zones = Zones::load(&config.zones).unwrap();
+        assert_eq!(zones.generation("home.arpa."), Some(1));
+        std::fs::write(dir.join("broken.zone"), "nas A 192.0.2.11").unwrap();
+        let broken = dir.join("broken.zone").canonicalize().unwrap();
+        zones.reload(&broken);
+        assert_eq!(zones.generation("home.arpa"), Some(1));
+        assert!(zones.find("nas.home.arpa").is_some());
+        assert!(zones.find("nas.example.com").is_none());
+
+        // A zone transfer whose question cannot be echoed, its first label
+        // being a dot, is answered FORMERR without it, over UDP or TCP.
+        let mut dotted = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        dotted.extend_from_slice(&[1, b'.', 3, b'c', b'o', b'm', 0, 0, 252, 0, 1]);
+        let dotted = DNSPacket::parse(&dotted).unwrap();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let responses = [
+            zones.answer_at(&dotted, None).unwrap(),
+            zones.transfer(&dotted, client).unwrap().remove(0),
+        ];
+        for response in responses {
+            assert_eq!(response.header.id, 0x1234);
+            assert_eq!(response.header.flags.rcode, Rcode::FormErr);
+            assert!(response.questions.is_empty());
+            assert!(DNSPacket::parse(&response.to_bytes()).is_ok());
+        }
+
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let task = tokio::spawn(daemon.run());
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.resolve("nas.home.arpa").await.unwrap(),
+            Ipv4Addr::new(192, 0, 2, 10)
+        );
+        assert!(matches!(
+            client.resolve("missing.home.arpa").await,
+            Err(DnsError::NxDomain(_))
+        ));
+
+        // Give the watcher time to start before the edit.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, zone_text("192.0.2.11")).unwrap();
+        let mut address = None;
+        for _ in 0..100 {
+            address = client.resolve("nas.home.arpa").await.ok();
+            if address == Some(Ipv4Addr::new(192, 0, 2, 11)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(address, Some(Ipv4Addr::new(192, 0, 2, 11)));
+        task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_regional_records() {
+        use crate::authority::{Location, Zones};
+        use crate::daemon::{Daemon, DaemonConfig};
+
+        let dir = std::env::temp_dir().join(format!("dnsr-regional-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("geo.test.zone");
+        std::fs::write(
+            &file,
+            "$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\nwww A 192.0.2.1\n\tAAAA 2001:db8::1\n",
+        )
+        .unwrap();
+        let toml = |extra: &str| {
+            format!(
+                "listen = \"127.0.0.1:0\"\n{extra}\n[[zones]]\norigin = \"geo.test\"\nfile = {:?}\n\
+                 [[zones.regional]]\nname = \"www\"\ncountries = [\"de\"]\naddresses = [\"198.51.100.1\"]\n\
+                 [[zones.regional]]\nname = \"www.geo.test.\"\ncontinents = [\"EU\", \"AF\"]\nttl = 30\n\
+                 addresses = [\"203.0.113.1\", \"2001:db8::2\"]",
+                file.display().to_string()
+            )
+        };
+        let config = DaemonConfig::from_toml(&toml("")).unwrap();
+        assert!(matches!(
+            Daemon::bind(&config).await,
+            Err(DnsError::InvalidConfig(_))
+        ));
+        let missing = DaemonConfig::from_toml(&toml(&format!(
+            "geoip_database = {:?}",
+            dir.join("missing.mmdb").display().to_string()
+        )))
+        .unwrap();
+        assert!(matches!(
+            Daemon::bind(&missing).await,
+            Err(DnsError::InvalidConfig(_))
+        ));
+        let mut outside = config.clone();
+        outside.zones[0].regional[0].name = "www.example.com.".to_string();
+        assert!(matches!(
+            Zones::load(&outside.zones),
+            Err(DnsError::InvalidConfig(_))
+        ));
+
+        let zones = Zones::load(&config.zones).unwrap();
+        let at = |country: &str, continent: &str| Location {
+            country: Some(country.to_string()),
+            continent: Some(continent.to_string()),
+        };
+        let ask = |type_, location: Option<&Location>| {
+            let query = MessageBuilder::query()
+                .question("www.geo.test", type_, Class::In)
+                .build()
+                .unwrap();
+            let response = zones.answer_at(&query, location).unwrap();
+            assert!(response.header.flags.aa);
+            response
+                .answers
+                .iter()
+                .map(|record| (record.data.to_string(), record.ttl))
+                .collect::<Vec<_>>()
+        };
+        let own = ask(RecordType::A, None);
+        assert_eq!(own, [("192.0.2.1".to_string(), 300)]);
+        assert_eq!(ask(RecordType::A, Some(&at("US", "NA"))), own);
+        // The first table matching wins.
+        assert_eq!(
+            ask(RecordType::A, Some(&at("DE", "EU"))),
+            [("198.51.100.1".to_string(), 60)]
+        );
+        assert_eq!(
+            ask(RecordType::A, Some(&at("FR", "EU"))),
+            [("203.0.113.1".to_string(), 30)]
+        );
+        // Without IPv6 addresses of its own, Germany falls to Europe's.
+        assert_eq!(
+            ask(RecordType::Aaaa, Some(&at("DE", "EU"))),
+            [("2001:db8::2".to_string(), 30)]
+        );
+
+        // A client's subnet is echoed with the answer scoped to all of it.
+        let query = MessageBuilder::query()
+            .question("www.geo.test", RecordType::A, Class::In)
+            .edns(Edns {
+                options: vec![EdnsOption::ClientSubnet(ClientSubnet::new(
+                    "192.0.2.0".parse().unwrap(),
+                    24,
+                ))],
+                ..Edns::default()
+            })
+            .build()
+            .unwrap();
+        let response = zones.answer_at(&query, Some(&at("FR", "EU"))).unwrap();
+        let subnet = response.edns.as_ref().unwrap().client_subnet().unwrap();
+        assert_eq!(subnet.scope_prefix, 24);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_weighted_records() {
+        use crate::authority::Zones;
+        use crate::daemon::{DaemonConfig, Selection};
+
+        let dir = std::env::temp_dir().join(format!("dnsr-weighted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("weighted.test.zone");
+        std::fs::write(
+            &file,
+            "$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\n\
+             www A 192.0.2.1\n\tA 192.0.2.2\n\tA 192.0.2.3\n\
+             alias CNAME www\n",
+        )
+        .unwrap();
+        let mut config = DaemonConfig::from_toml(&format!(
+            "[[zones]]\norigin = \"weighted.test\"\nfile = {:?}\n\
+             [[zones.weighted]]\nname = \"www\"\n\
+             weights = {{ \"192.0.2.1\" = 3, \"192.0.2.3\" = 0 }}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        assert_eq!(config.zones[0].weighted[0].select, Selection::One);
+        let answers = |config: &DaemonConfig, name: &str| {
+            let zones = Zones::load(&config.zones).unwrap();
+            let query = MessageBuilder::query()
+                .question(name, RecordType::A, Class::In)
+                .build()
+                .unwrap();
+            move || zones.answer_at(&query, None).unwrap().answers
+        };
+
+        // 192.0.2.1 is three times as likely as 192.0.2.2, and 192.0.2.3 is
+        // never given out. A CNAME to the name comes first all the same.
+        let answer = answers(&config, "alias.weighted.test");
+        let mut first = 0;
+        for _ in 0..4000 {
+            let answers = answer();
+            assert_eq!(answers.len(), 2);
+            assert_eq!(answers[0].type_, RecordType::Cname);
+            match &answers[1].data {
+                DNSRecordData::Ipv4Addr(ip) if *ip == Ipv4Addr::new(192, 0, 2, 1) => first += 1,
+                DNSRecordData::Ipv4Addr(ip) => assert_eq!(*ip, Ipv4Addr::new(192, 0, 2, 2)),
+                data => panic!("unexpected {data}"),
+            }
+        }
+        assert!((2800..3200).contains(&first), "{first}");
+
+        config.zones[0].weighted[0].select = Selection::Shuffle;
+        let answer = answers(&config, "www.weighted.test");
+        let mut first = 0;
+        for _ in 0..4000 {
+            let answers = answer();
+            assert_eq!(answers.len(), 2);
+            first += usize::from(answers[0].data.to_string() == "192.0.2.1");
+        }
+        assert!((2800..3200).contains(&first), "{first}");
+
+        // With every address weighing nothing, all are given out.
+        config.zones[0].weighted[0].weights = ["192.0.2.1", "192.0.2.2", "192.0.2.3"]
+            .map(|ip| (ip.parse().unwrap(), 0))
+            .into();
+        assert_eq!(answers(&config, "www.weighted.test")().len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_health_checked_records() {
+        use crate::authority::Zones;
+        use crate::daemon::{DaemonConfig, HealthCheckProtocol};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicU16, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A web server on 127.0.0.1 answering with `status`, and nothing
+        // on 127.0.0.2.
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let status = Arc::new(AtomicU16::new(200));
+        let served_status = status.clone();
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let status = served_status.load(Ordering::SeqCst);
+                let response = format!("HTTP/1.1 {status} Status\r\nContent-Length: 0\r\n\r\n");
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("dnsr-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("health.test.zone");
+        std::fs::write(
+            &file,
+            "$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 60\n\
+             www A 127.0.0.1\n\tA 127.0.0.2\n\
+             db A 127.0.0.1\n\tA 127.0.0.2\n",
+        )
+        .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "[[zones]]\norigin = \"health.test\"\nfile = {:?}\n\
+             [[zones.health_checks]]\nname = \"www\"\nprotocol = \"http\"\n\
+             port = {port}\npath = \"/healthz\"\n\
+             [[zones.health_checks]]\nname = \"db\"\nport = {port}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        let checks = &config.zones[0].health_checks;
+        assert_eq!(checks[0].protocol, HealthCheckProtocol::Http);
+        assert_eq!(checks[1].protocol, HealthCheckProtocol::Tcp);
+        assert_eq!(checks[1].interval_secs, 10);
+        let zones = Zones::load(&config.zones).unwrap();
+        let addresses = |name: &str| {
+            let query = MessageBuilder::query()
+                .question(name, RecordType::A, Class::In)
+                .build()
+                .unwrap();
+            let mut addresses: Vec<String> = zones
+                .answer_at(&query, None)
+                .unwrap()
+                .answers
+                .iter()
+                .map(|record| record.data.to_string())
+                .collect();
+            addresses.sort();
+            addresses
+        };
+        let both = ["127.0.0.1", "127.0.0.2"];
+
+        // Unchecked addresses are given out.
+        assert_eq!(addresses("www.health.test"), both);
+        zones.check_health().await;
+        assert_eq!(addresses("www.health.test"), ["127.0.0.1"]);
+        assert_eq!(addresses("db.health.test"), ["127.0.0.1"]);
+
+        // With every address down, all are given out; and an address is
+        // given out again once it recovers.
+        status.store(503, Ordering::SeqCst);
+        zones.check_health().await;
+        assert_eq!(addresses("www.health.test"), both);
+        assert_eq!(addresses("db.health.test"), ["127.0.0.1"]);
+        status.store(302, Ordering::SeqCst);
+        zones.check_health().await;
+        assert_eq!(addresses("www.health.test"), ["127.0.0.1"]);
+
+        // A name has one check at most.
+        let mut twice = config.clone();
+        twice.zones[0].health_checks[1].name = "www".to_string();
+        assert!(matches!(
+            Zones::load(&twice.zones),
+            Err(DnsError::InvalidConfig(_))
+        ));
+        server.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_daemon_firewall() {
+        use crate::daemon::{Daemon, DaemonConfig, FirewallAction};
+        use crate::firewall::Firewall;
+
+        let dir = std::env::temp_dir().join(format!("dnsr-firewall-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("example.test.zone");
+        std::fs::write(
+            &file,
+            "$TTL 60\n@ SOA ns hostmaster 1 3600 600 86400 60\n\
+             www A 192.0.2.1\n\tTXT \"www\"\n\
+             a.leak TXT \"leak\"\n\tA 192.0.2.2\n\
+             leak TXT \"leak\"\n\
+             pixel.tracking A 192.0.2.3\n",
+        )
+        .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\ncache = false\n\
+             [[firewall]]\ntypes = [\"ANY\"]\naction = \"refuse\"\n\
+             [[firewall]]\nnames = [\"*.leak.example.test\"]\ntypes = [\"TXT\"]\naction = \"drop\"\n\
+             [[firewall]]\nnames = [\"*.tracking.example.test\"]\naction = \"nxdomain\"\n\
+             [[zones]]\norigin = \"example.test\"\nfile = {:?}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        assert_eq!(config.firewall[1].action, FirewallAction::Drop);
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let task = tokio::spawn(daemon.run());
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let ask = async |name: &str, type_: RecordType| {
+            let query = MessageBuilder::query()
+                .question(name, type_, Class::In)
+                .build()
+                .unwrap();
+            socket.send_to(&query.to_bytes(), addr).await.unwrap();
+            let mut buf = [0; 512];
+            let received = tokio::time::timeout(Duration::from_millis(300), socket.recv(&mut buf));
+            let len = received.await.ok()?.unwrap();
+            Some(DNSPacket::parse(&buf[..len]).unwrap().header.flags.rcode)
+        };
+
+        assert_eq!(
+            ask("www.example.test", RecordType::A).await,
+            Some(Rcode::NoError)
+        );
+        assert_eq!(
+            ask("www.example.test", RecordType::Any).await,
+            Some(Rcode::Refused)
+        );
+        assert_eq!(ask("a.leak.example.test", RecordType::Txt).await, None);
+        // Only names under the domain, and only TXT, are dropped.
+        assert_eq!(
+            ask("a.leak.example.test", RecordType::A).await,
+            Some(Rcode::NoError)
+        );
+        assert_eq!(
+            ask("leak.example.test", RecordType::Txt).await,
+            Some(Rcode::NoError)
+        );
+        assert_eq!(
+            ask("pixel.tracking.example.test", RecordType::A).await,
+            Some(Rcode::NxDomain)
+        );
+        // A refused question that cannot be echoed, its first label being a
+        // dot, gets a FORMERR without it.
+        let mut dotted = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        dotted.extend_from_slice(&[1, b'.', 3, b'c', b'o', b'm', 0, 0, 255, 0, 1]);
+        socket.send_to(&dotted, addr).await.unwrap();
+        let mut buf = [0; 512];
+        let len = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = DNSPacket::parse(&buf[..len]).unwrap();
+        assert_eq!(response.header.flags.rcode, Rcode::FormErr);
+        assert!(response.questions.is_empty());
+        assert_eq!(
+            ask("www.example.test", RecordType::A).await,
+            Some(Rcode::NoError)
+        );
+
+        let mut wildcard = config.firewall.clone();
+        wildcard[0].names = vec!["www.*.example".to_string()];
+        assert!(matches!(
+            Firewall::new(&wildcard),
+            Err(DnsError::InvalidConfig(_))
+        ));
+        wildcard[0].names.clear();
+        wildcard[0].types = vec!["NOPE".to_string()];
+        assert!(matches!(
+            Firewall::new(&wildcard),
+            Err(DnsError::UnknownType(_))
+        ));
+        task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::ratelimit::{MAX_TRACKED_NETWORKS, RateLimiter, Verdict};
+        use std::time::Instant;
+
+        let config = DaemonConfig::from_toml(
+            "[rate_limit]\nresponses_per_second = 2\nexempt = [\"192.0.2.128/25\"]",
+        )
+        .unwrap();
+        let rate_limit = config.rate_limit.clone().unwrap();
+        assert_eq!((rate_limit.slip, rate_limit.ipv4_prefix), (2, 24));
+        let limiter = RateLimiter::new(&rate_limit).unwrap();
+        let start = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let verdicts: Vec<Verdict> = (0..6).map(|_| limiter.check_at(start, client)).collect();
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Respond,
+                Verdict::Respond,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop,
+                Verdict::Slip,
+            ]
+        );
+        // Clients are counted by network, so a neighbour is limited too,
+        // unless exempt; another network is not.
+        assert_eq!(
+            limiter.check_at(start, "192.0.2.2".parse().unwrap()),
+            Verdict::Drop
+        );
+        assert_eq!(
+            limiter.check_at(start, "192.0.2.200".parse().unwrap()),
+            Verdict::Respond
+        );
+        assert_eq!(
+            limiter.check_at(start, "198.51.100.1".parse().unwrap()),
+            Verdict::Respond
+        );
+        // The allowance refills at the rate, up to a second's worth.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(later, client), Verdict::Respond);
+        assert_eq!(limiter.check_at(later, client), Verdict::Drop);
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check_at(much_later, client), Verdict::Respond);
+        assert_eq!(limiter.check_at(much_later, client), Verdict::Respond);
+        assert_eq!(limiter.check_at(much_later, client), Verdict::Drop);
+
+        // A flood of new networks forgets those with the most allowance
+        // left, not that of a network being limited.
+        let flood = |from: usize, to: usize, at: Instant| {
+            for i in from..to {
+                let network = IpAddr::from(Ipv4Addr::from((i as u32) << 8));
+                limiter.check_at(at + Duration::from_micros(i as u64), network);
+            }
+        };
+        flood(1, MAX_TRACKED_NETWORKS, much_later);
+        let flooded = much_later + Duration::from_millis(200);
+        while limiter.check_at(flooded, client) == Verdict::Respond {}
+        flood(
+            MAX_TRACKED_NETWORKS,
+            MAX_TRACKED_NETWORKS * 6 / 5,
+            flooded - Duration::from_millis(99),
+        );
+        assert_ne!(
+            limiter.check_at(flooded + Duration::from_millis(30), client),
+            Verdict::Respond
+        );
+
+        let mut off = rate_limit.clone();
+        off.responses_per_second = 0;
+        assert!(matches!(
+            RateLimiter::new(&off),
+            Err(DnsError::InvalidConfig(_))
+        ));
+
+        // Over UDP, limited queries get nothing or a truncated response.
+        let dir = std::env::temp_dir().join(format!("dnsr-rrl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("rrl.test.zone");
+        std::fs::write(
+            &file,
+            "$TTL 60\n@ SOA ns hostmaster 1 3600 600 86400 60\nwww A 192.0.2.1\n",
+        )
+        .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\ncache = false\n\
+             [rate_limit]\nresponses_per_second = 2\n\
+             [[zones]]\norigin = \"rrl.test\"\nfile = {:?}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let task = tokio::spawn(daemon.run());
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let query = MessageBuilder::query()
+            .question("www.rrl.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        for _ in 0..6 {
+            socket.send_to(&query.to_bytes(), addr).await.unwrap();
+        }
+        // A question that cannot be echoed, its one label being a dot, is
+        // dropped rather than slipped.
+        let mut dotted = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        dotted.extend_from_slice(&[1, b'.', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1]);
+        for _ in 0..4 {
+            socket.send_to(&dotted, addr).await.unwrap();
+        }
+        let (mut answered, mut truncated) = (0, 0);
+        let mut buf = [0; 512];
+        while let Ok(received) =
+            tokio::time::timeout(Duration::from_millis(300), socket.recv(&mut buf)).await
+        {
+            let response = DNSPacket::parse(&buf[..received.unwrap()]).unwrap();
+            if response.header.flags.tc {
+                assert!(response.answers.is_empty());
+                truncated += 1;
+            } else {
+                assert_eq!(response.answers.len(), 1);
+                answered += 1;
+            }
+        }
+        assert_eq!((answered, truncated), (2, 2));
+        // And the listener goes on answering.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        socket.send_to(&query.to_bytes(), addr).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = DNSPacket::parse(&buf[..received]).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_daemon_secondary_zone() {
+        use crate::authority::Zones;
+        use crate::daemon::{Daemon, DaemonConfig};
+
+        let dir = std::env::temp_dir().join(format!("dnsr-secondary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("example.test.zone");
+        let zone_text = |serial: u32, address: &str| {
+            format!(
+                "$TTL 60\n@ SOA ns hostmaster {serial} 3600 600 86400 60\n\tNS ns\nns A 192.0.2.53\nwww A {address}\n"
+            )
+        };
+        std::fs::write(&file, zone_text(1, "192.0.2.10")).unwrap();
+        let primary_config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\n[[zones]]\norigin = \"example.test\"\nfile = {:?}\nallow_transfer = [\"127.0.0.1\"]",
+            file.display().to_string()
+        ))
+        .unwrap();
+        assert!(matches!(
+            DaemonConfig::from_toml(&format!(
+                "[[zones]]\norigin = \"example.test\"\nfile = {:?}\nprimaries = [\"192.0.2.53\"]",
+                file.display().to_string()
+            ))
+            .map(|config| Zones::load(&config.zones)),
+            Ok(Err(DnsError::InvalidConfig(_)))
+        ));
+
+        // Transfers are served to allowed clients only, SOA first and last.
+        let zones = Zones::load(&primary_config.zones).unwrap();
+        let axfr = MessageBuilder::query()
+            .question("example.test", RecordType::Axfr, Class::In)
+            .build()
+            .unwrap();
+        let refused = zones.transfer(&axfr, "192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(refused[0].header.flags.rcode, Rcode::Refused);
+        let transfer = zones.transfer(&axfr, "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(transfer.len(), 1);
+        let records = &transfer[0].answers;
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].type_, RecordType::Soa);
+        assert_eq!(records[4].type_, RecordType::Soa);
+        // A record that cannot be sent fails the whole transfer rather than
+        // leaving the secondary without the records sent alongside it.
+        let zone = zones.find("example.test").unwrap();
+        let oversize = DNSRecord::new(
+            "txt.example.test",
+            RecordType::Txt,
+            Class::In,
+            300,
+            DNSRecordData::Txt(vec!["x".repeat(256)]),
+        );
+        let broken =
+            zone::Zone::new("example.test", zone.records().cloned().chain([oversize])).unwrap();
+        zones.install("example.test", broken, "Zone replaced");
+        let failed = zones.transfer(&axfr, "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].header.flags.rcode, Rcode::ServFail);
+        assert!(failed[0].answers.is_empty());
+        zones.install("example.test", (*zone).clone(), "Zone restored");
+        assert_eq!(
+            zones
+                .answer(&axfr, "127.0.0.1".parse().unwrap())
+                .unwrap()
+                .header
+                .flags
+                .rcode,
+            Rcode::Refused
+        );
+
+        let primary = Daemon::bind(&primary_config).await.unwrap();
+        let primary_addr = primary.local_addr().unwrap();
+        let primary_task = tokio::spawn(primary.run());
+        let secondary_config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\ncache = false\n[[zones]]\norigin = \"example.test\"\nprimaries = [\"{primary_addr}\"]"
+        ))
+        .unwrap();
+        let secondary = Daemon::bind(&secondary_config).await.unwrap();
+        let secondary_addr = secondary.local_addr().unwrap();
+        let secondary_task = tokio::spawn(secondary.run());
+        let client = |port| {
+            DNSResolver::builder()
+                .upstreams([Ipv4Addr::LOCALHOST])
+                .upstream_port(Ipv4Addr::LOCALHOST, port)
+                .recursion_desired(true)
+                .cache(false)
+                .build()
+                .unwrap()
+        };
+        let (primary_client, secondary_client) =
+            (client(primary_addr.port()), client(secondary_addr.port()));
+        let resolve_eventually = async |client: &DNSResolver, expected: Ipv4Addr| {
+            let mut address = None;
+            for _ in 0..100 {
+                address = client.resolve("www.example.test").await.ok();
+                if address == Some(expected) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            address
+        };
+        let first = Ipv4Addr::new(192, 0, 2, 10);
+        assert_eq!(
+            resolve_eventually(&secondary_client, first).await,
+            Some(first)
+        );
+
+        // A NOTIFY from the primary has the new serial transferred at once,
+        // long before the SOA's refresh interval.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, zone_text(2, "192.0.2.11")).unwrap();
+        let second = Ipv4Addr::new(192, 0, 2, 11);
+        assert_eq!(
+            resolve_eventually(&primary_client, second).await,
+            Some(second)
+        );
+        let notify = MessageBuilder::query()
+            .flags(DNSFlags {
+                opcode: Opcode::Notify,
+                aa: true,
+                ..DNSFlags::default()
+            })
+            .question("example.test", RecordType::Soa, Class::In)
+            .build()
+            .unwrap();
+        let response = secondary_client
+            .exchange_raw(&notify.to_bytes(), &Ipv4Addr::LOCALHOST)
+            .await
+            .unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert_eq!(response.header.flags.opcode, Opcode::Notify);
+        assert_eq!(response.header.flags.rcode, Rcode::NoError);
+        assert_eq!(
+            resolve_eventually(&secondary_client, second).await,
+            Some(second)
+        );
+
+        // Only the zone's primaries are listened to.
+        let secondaries = Zones::load(&secondary_config.zones).unwrap();
+        let response = secondaries.notify(&notify, "192.0.2.1".parse().unwrap());
+        assert_eq!(response.header.flags.rcode, Rcode::Refused);
+        // Until its first transfer, a secondary zone answers SERVFAIL.
+        let query = MessageBuilder::query()
+            .question("www.example.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        assert_eq!(
+            secondaries
+                .answer(&query, "127.0.0.1".parse().unwrap())
+                .unwrap()
+                .header
+                .flags
+                .rcode,
+            Rcode::ServFail
+        );
+        primary_task.abort();
+        secondary_task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_daemon_client_subnet() {
+        use crate::config::{ClientSubnetPolicy, QueryOptions};
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+
+        for invalid in [
+            "forwarders = [\"192.0.2.53\"]\n[client_subnet]\n\"192.0.2.54\" = { mode = \"strip\" }",
+            "forwarders = [\"192.0.2.53\"]\n[client_subnet]\n\"192.0.2.53\" = { mode = \"inject\", ipv4_prefix = 33 }",
+        ] {
+            let config = DaemonConfig::from_toml(invalid).unwrap();
+            assert!(matches!(config.resolver(), Err(DnsError::InvalidConfig(_))));
+        }
+        let options = QueryOptions {
+            client_subnet: Some(ClientSubnet::new("2001:db8:1:2::1".parse().unwrap(), 128)),
+            ..QueryOptions::default()
+        };
+        let upstream = Ipv4Addr::new(192, 0, 2, 53);
+        let resolver = DNSResolver::builder()
+            .upstreams([upstream])
+            .upstream_client_subnet(upstream, ClientSubnetPolicy::INJECT)
+            .build()
+            .unwrap();
+        let injected = resolver.client_subnet_for(&upstream, &options).unwrap();
+        assert_eq!(injected.address, "2001:db8:1::".parse::<IpAddr>().unwrap());
+        assert_eq!(injected.source_prefix, 56);
+        assert_eq!(
+            resolver.client_subnet_for(&upstream, &QueryOptions::default()),
+            None
+        );
+
+        let address = Ipv4Addr::new(192, 0, 2, 82);
+        let forwarder = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.ecs.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let daemon = |client_subnet: &str| {
+            let config = DaemonConfig::from_toml(&format!(
+                "listen = \"127.0.0.1:0\"\nforwarders = [\"{}\"]\ncache = false\n{client_subnet}",
+                forwarder.addr()
+            ))
+            .unwrap();
+            async move {
+                let daemon = Daemon::bind(&config).await.unwrap();
+                let client = DNSResolver::builder()
+                    .upstreams([Ipv4Addr::LOCALHOST])
+                    .upstream_port(Ipv4Addr::LOCALHOST, daemon.local_addr().unwrap().port())
+                    .recursion_desired(true)
+                    .build()
+                    .unwrap();
+                (tokio::spawn(daemon.run()), client)
+            }
+        };
+        let query = |subnet: Option<ClientSubnet>| {
+            let mut builder = MessageBuilder::query()
+                .flags(DNSFlags {
+                    rd: true,
+                    ..DNSFlags::default()
+                })
+                .question("www.ecs.test", RecordType::A, Class::In);
+            if let Some(subnet) = subnet {
+                builder = builder.edns(Edns {
+                    options: vec![EdnsOption::ClientSubnet(subnet)],
+                    ..Edns::default()
+                });
+            }
+            builder.build().unwrap().to_bytes()
+        };
+
+        // Stripped unless configured otherwise, whatever the client sends.
+        let (task, client) = daemon("").await;
+        let subnet = ClientSubnet::new("198.51.100.77".parse().unwrap(), 32);
+        client
+            .exchange_raw(&query(Some(subnet.clone())), &Ipv4Addr::LOCALHOST)
+            .await
+            .unwrap();
+        assert_eq!(forwarder.client_subnets(), [None]);
+        task.abort();
+
+        // Injected: the client's address, or the subnet it sent, shortened.
+        let (task, client) =
+            daemon("[client_subnet]\n\"127.0.0.1\" = { mode = \"inject\", ipv4_prefix = 16 }")
+                .await;
+        assert_eq!(client.resolve("www.ecs.test").await.unwrap(), address);
+        for subnet in [
+            subnet,
+            ClientSubnet::new("198.51.100.77".parse().unwrap(), 0),
+        ] {
+            client
+                .exchange_raw(&query(Some(subnet)), &Ipv4Addr::LOCALHOST)
+                .await
+                .unwrap();
+        }
+        let sent: Vec<_> = forwarder.client_subnets()[1..]
+            .iter()
+            .map(|subnet| {
+                let subnet = subnet.clone().unwrap();
+                (subnet.address, subnet.source_prefix)
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (IpAddr::from([127, 0, 0, 0]), 16),
+                (IpAddr::from([198, 51, 0, 0]), 16),
+                (IpAddr::from([0, 0, 0, 0]), 0),
+            ]
+        );
+        task.abort();
+
+        // Answers tailored to a subnet are not cached, those scoped to
+        // every client are.
+        let queried = |source_prefix| {
+            let resolver = DNSResolver::builder()
+                .upstreams([Ipv4Addr::LOCALHOST])
+                .upstream_port(Ipv4Addr::LOCALHOST, forwarder.addr().port())
+                .client_subnet(ClientSubnet::new(
+                    "198.51.100.77".parse().unwrap(),
+                    source_prefix,
+                ))
+                .build()
+                .unwrap();
+            let forwarder = &forwarder;
+            async move {
+                let before = forwarder.queries().len();
+                for _ in 0..2 {
+                    resolver
+                        .resolve_record("www.ecs.test", RecordType::A)
+                        .await
+                        .unwrap();
+                }
+                forwarder.queries().len() - before
+            }
+        };
+        assert_eq!(queried(24).await, 2);
+        assert_eq!(queried(0).await, 1);
+    }
+
+    #[cfg(all(feature = "daemon", feature = "dot"))]
+    #[tokio::test]
+    async fn test_daemon_tls() {
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::TlsConnector;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let address = Ipv4Addr::new(192, 0, 2, 83);
+        let root = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.tls.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let generated = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("dnsr-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (certificate, private_key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&certificate, generated.cert.pem()).unwrap();
+        std::fs::write(&private_key, generated.signing_key.serialize_pem()).unwrap();
+        let config = |key: &std::path::Path| {
+            DaemonConfig::from_toml(&format!(
+                "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\ncache = false\n\
+                 [tls]\nlisten = \"127.0.0.1:0\"\ncertificate = {:?}\nprivate_key = {:?}",
+                root.addr(),
+                certificate,
+                key
+            ))
+            .unwrap()
+        };
+        assert!(matches!(
+            Daemon::bind(&config(&dir.join("missing.pem"))).await,
+            Err(DnsError::InvalidConfig(_))
+        ));
+        let daemon = Daemon::bind(&config(&private_key)).await.unwrap();
+        let tls_addr = daemon.tls_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(tls_addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let query = MessageBuilder::query()
+            .flags(DNSFlags {
+                rd: true,
+                ..DNSFlags::default()
+            })
+            .question("www.tls.test", RecordType::A, Class::In)
+            .build()
+            .unwrap()
+            .to_bytes();
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut response = vec![0; stream.read_u16().await.unwrap() as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert_eq!(response.answers[0].data, DNSRecordData::Ipv4Addr(address));
+        task.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "doq")]
+    #[tokio::test]
+    async fn test_daemon_quic() {
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+        use quinn::crypto::rustls::QuicClientConfig;
+        use quinn::{ClientConfig, Endpoint};
+        use std::sync::Arc;
+        use tokio_rustls::rustls::{self, RootCertStore};
+
+        let address = Ipv4Addr::new(192, 0, 2, 84);
+        let root = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.quic.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let generated = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("dnsr-quic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (certificate, private_key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&certificate, generated.cert.pem()).unwrap();
+        std::fs::write(&private_key, generated.signing_key.serialize_pem()).unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\ncache = false\n\
+             [quic]\nlisten = \"127.0.0.1:0\"\ncertificate = {certificate:?}\n\
+             private_key = {private_key:?}",
+            root.addr(),
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let quic_addr = daemon.quic_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"doq".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+        let connection = client
+            .connect(quic_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let exchange = |id: u16| {
+            let connection = connection.clone();
+            async move {
+                let query = MessageBuilder::query()
+                    .id(id)
+                    .question("www.quic.test", RecordType::A, Class::In)
+                    .build()
+                    .unwrap()
+                    .to_bytes();
+                let (mut send, mut recv) = connection.open_bi().await.unwrap();
+                send.write_all(&(query.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                send.write_all(&query).await.unwrap();
+                send.finish().unwrap();
+                recv.read_to_end(u16::MAX as usize + 2).await
+            }
+        };
+        let response = exchange(0).await.unwrap();
+        let response = DNSPacket::parse(&response[2..]).unwrap();
+        assert_eq!(response.header.id, 0);
+        assert_eq!(response.answers[0].data, DNSRecordData::Ipv4Addr(address));
+
+        // A query with an ID other than 0 is a protocol error.
+        assert!(exchange(1).await.is_err());
+
+        // So is a stream too short to hold a length prefix.
+        for short in [&[][..], &[0][..]] {
+            let connection = client
+                .connect(quic_addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            let (mut send, _recv) = connection.open_bi().await.unwrap();
+            send.write_all(short).await.unwrap();
+            send.finish().unwrap();
+            let closed = tokio::time::timeout(Duration::from_secs(1), connection.closed());
+            assert!(matches!(
+                closed.await.unwrap(),
+                quinn::ConnectionError::ApplicationClosed(_)
+            ));
+        }
+        task.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(all(feature = "blocklist", feature = "server"))]
+    #[tokio::test]
+    async fn test_blocklist() {
+        use crate::blocklist::{Blocklists, DomainSet};
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let set = DomainSet::parse(
+            "# hosts\n0.0.0.0 ads.example.test tracker.test # inline\n\
+             127.0.0.1 localhost\n0.0.0.0 0.0.0.0\n\
+             ! adblock\n||Malware.test^\n||path.test/x\nplain.test.\n",
+        );
+        assert_eq!(set.len(), 4);
+        assert!(set.contains("ads.example.test"));
+        assert!(set.contains("cdn.ADS.example.test."));
+        assert!(set.contains("malware.test"));
+        assert!(set.contains("plain.test"));
+        assert!(!set.contains("example.test"));
+        assert!(!set.contains("localhost"));
+        assert!(!set.contains("notplain.test"));
+
+        // One list over HTTP, one that cannot be read.
+        let http = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/hosts.txt", http.local_addr().unwrap());
+        let http_task = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = http.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let body = "0.0.0.0 served.test\n";
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let missing = std::env::temp_dir().join("dnsr-missing-blocklist.txt");
+        let blocklists = Blocklists::new([url.clone(), missing.display().to_string()]);
+        assert!(!blocklists.is_blocked("served.test"));
+        blocklists.refresh().await;
+        assert!(blocklists.is_blocked("www.served.test"));
+        let status = blocklists.status();
+        assert_eq!(status[0].source, url);
+        assert_eq!(status[0].domains, 1);
+        assert!(status[0].updated_at.is_some() && status[0].last_error.is_none());
+        assert!(status[1].updated_at.is_none() && status[1].last_error.is_some());
+        http_task.abort();
+
+        // The daemon answers NXDOMAIN for blocked names and reports its
+        // lists over the API.
+        let address = Ipv4Addr::new(192, 0, 2, 85);
+        let root = TestServer::builder()
+            .records(["allowed.block.test", "ads.block.test"].map(|name| {
+                DNSRecord::new(
+                    name,
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(address),
+                )
+            }))
+            .start()
+            .await
+            .unwrap();
+        let list = std::env::temp_dir().join(format!("dnsr-blocklist-{}.txt", std::process::id()));
+        std::fs::write(&list, "ads.block.test\n").unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\ncache = false\n\
+             api_listen = \"127.0.0.1:0\"\nblocklists = [{:?}]",
+            root.addr(),
+            list.display().to_string(),
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let api = daemon.api_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        let status_url = format!("http://{api}/api/blocklists");
+        let mut status = serde_json::Value::Null;
+        for _ in 0..50 {
+            let body = reqwest::get(&status_url)
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            status = serde_json::from_str(&body).unwrap();
+            if status[0]["updated_at"].is_u64() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status[0]["domains"], 1);
+        assert!(status[0]["last_error"].is_null());
+        assert!(matches!(
+            client.resolve("ads.block.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert_eq!(client.resolve("allowed.block.test").await.unwrap(), address);
+        assert!(root.queries().iter().all(|q| q.name != "ads.block.test"));
+        task.abort();
+        std::fs::remove_file(list).unwrap();
+    }
+
+    #[cfg(all(feature = "blocklist", feature = "server"))]
+    #[tokio::test]
+    async fn test_client_groups() {
+        use crate::blocklist::{Blocklists, ClientPolicies, ClientPolicy};
+        use crate::daemon::{ClientNetwork, Daemon, DaemonConfig};
+        use crate::testutil::TestServer;
+
+        let network: ClientNetwork = "192.168.1.77/26".parse().unwrap();
+        assert!(network.contains("192.168.1.64".parse().unwrap()));
+        assert!(network.contains("::ffff:192.168.1.127".parse().unwrap()));
+        assert!(!network.contains("192.168.1.128".parse().unwrap()));
+        assert!(
+            "fd00::/64"
+                .parse::<ClientNetwork>()
+                .unwrap()
+                .contains("fd00::1".parse().unwrap())
+        );
+        assert!("192.0.2.1/33".parse::<ClientNetwork>().is_err());
+        assert!("example.test/8".parse::<ClientNetwork>().is_err());
+
+        let list = std::env::temp_dir().join(format!("dnsr-groups-{}.txt", std::process::id()));
+        std::fs::write(&list, "ads.test\nhomework.ads.test\n").unwrap();
+        let config = DaemonConfig::from_toml(
+            "[[groups]]\nname = \"kids\"\nclients = [\"10.0.0.0/8\"]\n\
+             allow = [\"homework.ads.test\"]\ndeny = [\"video.test\"]\n\
+             [[groups]]\nname = \"admins\"\nclients = [\"10.0.0.1\", \"192.0.2.1\"]\n\
+             default_blocklists = false",
+        )
+        .unwrap();
+        assert!(matches!(
+            DaemonConfig::from_toml("[[groups]]\nname = \"x\"\nclients = [\"10.0.0.0/40\"]"),
+            Err(DnsError::InvalidConfig(_))
+        ));
+        let policies = ClientPolicies::new(
+            Blocklists::new([list.display().to_string()]),
+            config.groups.into_iter().map(ClientPolicy::new),
+        );
+        policies.refresh().await;
+        let (kid, admin, other) = (
+            "10.1.2.3".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        );
+        assert_eq!(policies.group(kid).map(ClientPolicy::name), Some("kids"));
+        // The first group listing a client wins.
+        assert_eq!(
+            policies
+                .group("10.0.0.1".parse().unwrap())
+                .map(ClientPolicy::name),
+            Some("kids")
+        );
+        assert!(policies.group(other).is_none());
+        assert!(policies.is_blocked(kid, "www.ads.test"));
+        assert!(!policies.is_blocked(kid, "homework.ads.test"));
+        assert!(policies.is_blocked(kid, "cdn.video.test"));
+        assert!(!policies.is_blocked(admin, "ads.test"));
+        assert!(policies.is_blocked(other, "homework.ads.test"));
+        assert!(!policies.is_blocked(other, "video.test"));
+        std::fs::remove_file(list).unwrap();
+
+        // The policy of the querying client applies over UDP and DoH alike.
+        let address = Ipv4Addr::new(192, 0, 2, 86);
+        let root = TestServer::builder()
+            .records(["video.group.test", "www.group.test"].map(|name| {
+                DNSRecord::new(
+                    name,
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(address),
+                )
+            }))
+            .start()
+            .await
+            .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\ncache = false\n\
+             api_listen = \"127.0.0.1:0\"\n\
+             [[groups]]\nname = \"local\"\nclients = [\"127.0.0.0/8\"]\n\
+             deny = [\"video.group.test\"]",
+            root.addr(),
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let api = daemon.api_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.resolve("video.group.test").await,
+            Err(DnsError::NxDomain(_))
+        ));
+        assert_eq!(client.resolve("www.group.test").await.unwrap(), address);
+        let doh_response = |name: &str| {
+            let query = MessageBuilder::query()
+                .id(0)
+                .flags(DNSFlags {
+                    rd: true,
+                    ..DNSFlags::default()
+                })
+                .question(name, RecordType::A, Class::In)
+                .build()
+                .unwrap();
+            let request = reqwest::Client::new()
+                .post(format!("http://{api}/dns-query"))
+                .header("content-type", "application/dns-message")
+                .body(query.to_bytes());
+            async move {
+                let body = request.send().await.unwrap().bytes().await.unwrap();
+                DNSPacket::parse(&body).unwrap()
+            }
+        };
+        let blocked = doh_response("video.group.test").await;
+        assert_eq!(blocked.header.flags.rcode, Rcode::NxDomain);
+        let allowed = doh_response("www.group.test").await;
+        assert_eq!(allowed.answers[0].data, DNSRecordData::Ipv4Addr(address));
+        // A body that is not a DNS message is a bad request.
+        let garbage = reqwest::Client::new()
+            .post(format!("http://{api}/dns-query"))
+            .header("content-type", "application/dns-message")
+            .body(vec![0x12, 0x34, 0x01])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(garbage.status(), reqwest::StatusCode::BAD_REQUEST);
+        task.abort();
+    }
+
+    #[cfg(all(feature = "querylog", feature = "server"))]
+    #[tokio::test]
+    async fn test_query_log() {
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::querylog::{QueryLog, QueryLogEntry, QueryLogField, QueryLogFilter};
+        use crate::testutil::TestServer;
+
+        let dir = std::env::temp_dir().join(format!("dnsr-querylog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = QueryLog::open(&dir.join("unit.db"), Duration::from_secs(3600)).unwrap();
+        let entry =
+            |timestamp_ms, client: &str, name: &str, rcode: &str, blocked: bool| QueryLogEntry {
+                timestamp_ms,
+                client: client.parse().unwrap(),
+                name: name.to_string(),
+                record_type: "A".to_string(),
+                rcode: rcode.to_string(),
+                latency_ms: 2.0,
+                cache_hit: !blocked,
+                blocked,
+            };
+        let now = crate::querylog::unix_ms();
+        // Older than the retention period, so pruned after the first write.
+        log.record(entry(
+            now - 7_200_000,
+            "192.0.2.3",
+            "old.test",
+            "NOERROR",
+            false,
+        ));
+        log.record(entry(
+            now - 3000,
+            "192.0.2.1",
+            "www.example.test",
+            "NOERROR",
+            false,
+        ));
+        log.record(entry(
+            now - 2000,
+            "192.0.2.2",
+            "ads.example.test",
+            "NXDOMAIN",
+            true,
+        ));
+        log.record(entry(
+            now - 1000,
+            "192.0.2.1",
+            "www.example.test",
+            "NOERROR",
+            false,
+        ));
+        let mut all = Vec::new();
+        for _ in 0..50 {
+            all = log.search(QueryLogFilter::default()).await.unwrap();
+            if all.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].timestamp_ms, now - 1000);
+        let blocked = QueryLogFilter {
+            blocked: Some(true),
+            ..QueryLogFilter::default()
+        };
+        let found = log.search(blocked).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].client, "192.0.2.2".parse::<IpAddr>().unwrap());
+        let filter = QueryLogFilter {
+            name: Some("WWW.Example".to_string()),
+            since_ms: Some(now - 1500),
+            ..QueryLogFilter::default()
+        };
+        assert_eq!(log.search(filter).await.unwrap().len(), 1);
+        let summary = log
+            .summarize(QueryLogFilter::default(), QueryLogField::Name)
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.queries, summary.blocked, summary.cache_hits),
+            (3, 1, 2)
+        );
+        assert_eq!(summary.average_latency_ms, Some(2.0));
+        assert_eq!(summary.top[0].value, "www.example.test");
+        assert_eq!(summary.top[0].queries, 2);
+
+        // The daemon logs what it answers and serves the history.
+        let address = Ipv4Addr::new(192, 0, 2, 87);
+        let root = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.log.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\n\
+             api_listen = \"127.0.0.1:0\"\n[query_log]\npath = {:?}",
+            root.addr(),
+            dir.join("daemon.db").display().to_string(),
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let api = daemon.api_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(client.resolve("www.log.test").await.unwrap(), address);
+        }
+        let _ = client.resolve("missing.log.test").await;
+
+        let get = |path: String| async move {
+            let body = reqwest::get(format!("http://{api}{path}"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        let mut queries = serde_json::Value::Null;
+        for _ in 0..50 {
+            queries = get("/api/queries?name=log.test".to_string()).await;
+            if queries.as_array().is_some_and(|rows| rows.len() == 3) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(queries[0]["name"], "missing.log.test");
+        assert_eq!(queries[0]["rcode"], "NXDOMAIN");
+        assert_eq!(queries[1]["cache_hit"], true);
+        assert_eq!(queries[2]["cache_hit"], false);
+        assert_eq!(queries[2]["client"], "127.0.0.1");
+        assert_eq!(queries[2]["record_type"], "A");
+        let summary = get("/api/queries/summary?by=rcode&limit=1".to_string()).await;
+        assert_eq!(summary["queries"], 3);
+        assert_eq!(summary["cache_hits"], 1);
+        assert_eq!(summary["top"][0]["value"], "NOERROR");
+        assert_eq!(summary["top"].as_array().unwrap().len(), 1);
+        task.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(all(feature = "daemon", feature = "server"))]
+    #[tokio::test]
+    async fn test_dashboard() {
+        use crate::daemon::{Daemon, DaemonConfig};
+        use crate::dashboard::{DashboardStats, MAX_TRACKED_CLIENTS, MAX_TRACKED_NAMES};
+        use crate::testutil::TestServer;
+
+        let stats = DashboardStats::default();
+        assert_eq!(stats.snapshot().cache_hit_ratio, None);
+        let minute = 60_000 * 29_000_000;
+        let laptop = IpAddr::from([192, 0, 2, 10]);
+        let phone = IpAddr::from([192, 0, 2, 11]);
+        for (offset, client, name, blocked, cache_hit) in [
+            (0, laptop, "www.example.test", false, false),
+            (1_000, laptop, "WWW.example.test.", false, true),
+            (2_000, phone, "ads.test", true, false),
+            (61_000, laptop, "www.example.test", false, true),
+            (62_000, phone, "ads.test", true, false),
+            (63_000, laptop, "other.test", false, false),
+        ] {
+            stats.record_at(minute + offset, client, name, blocked, cache_hit);
+        }
+        let snapshot = stats.snapshot_at(minute + 90_000);
+        assert_eq!(
+            (snapshot.queries, snapshot.blocked, snapshot.cache_hits),
+            (6, 2, 2)
+        );
+        assert_eq!(snapshot.cache_hit_ratio, Some(2.0 / 6.0));
+        assert_eq!(snapshot.top_domains[0].name, "www.example.test");
+        assert_eq!(snapshot.top_domains[0].queries, 3);
+        assert_eq!(snapshot.top_domains[1].name, "ads.test");
+        assert_eq!(snapshot.top_blocked.len(), 1);
+        assert_eq!(snapshot.qps.len(), 60);
+        let last = &snapshot.qps[59];
+        assert_eq!(last.timestamp_ms, minute + 60_000);
+        assert_eq!(last.qps, 3.0 / 30.0);
+        assert_eq!(snapshot.qps[58].qps, 3.0 / 60.0);
+        assert_eq!(snapshot.qps[0].qps, 0.0);
+        // Each client is counted apart, the busiest first.
+        let clients: Vec<_> = snapshot
+            .top_clients
+            .iter()
+            .map(|client| (client.client, client.queries, client.blocked))
+            .collect();
+        assert_eq!(clients, [(laptop, 4, 0), (phone, 2, 2)]);
+        assert_eq!(snapshot.top_clients[0].top_domains[0].queries, 3);
+        assert_eq!(snapshot.top_clients[1].top_domains[0].name, "ads.test");
+        // A v4-mapped address is the same client.
+        let mapped = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 11).to_ipv6_mapped());
+        assert_eq!(stats.client(mapped).unwrap().queries, 2);
+        assert!(stats.client(IpAddr::from([192, 0, 2, 12])).is_none());
+        assert_eq!(stats.clients().len(), 2);
+        // An hour on, the rates have gone but the totals remain.
+        let later = stats.snapshot_at(minute + 2 * 60 * 60_000);
+        assert!(later.qps.iter().all(|sample| sample.qps == 0.0));
+        assert_eq!(later.queries, 6);
+
+        // Past the limit, half the names are forgotten, not every name
+        // queried as little as most were.
+        let long_tail = DashboardStats::default();
+        for _ in 0..2 {
+            long_tail.record_at(minute, laptop, "popular.test", false, false);
+        }
+        for i in 0..MAX_TRACKED_NAMES {
+            let name = format!("{i}.tail.test");
+            long_tail.record_at(minute, laptop, &name, false, false);
+        }
+        let top_domains = long_tail.snapshot_at(minute).top_domains;
+        assert_eq!(top_domains.len(), 10);
+        assert_eq!(top_domains[0].name, "popular.test");
+        // Likewise for clients, however many asked as little.
+        let clients = DashboardStats::default();
+        for i in 0..=MAX_TRACKED_CLIENTS as u32 {
+            let client = IpAddr::from(Ipv4Addr::from(0x0a00_0000 + i));
+            clients.record_at(minute, client, "www.example.test", false, false);
+        }
+        assert_eq!(clients.clients().len(), MAX_TRACKED_CLIENTS / 2 + 1);
+
+        let address = Ipv4Addr::new(192, 0, 2, 88);
+        let root = TestServer::builder()
+            .record(DNSRecord::new(
+                "www.dashboard.test",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let web_root = std::env::temp_dir().join(format!("dnsr-web-{}", std::process::id()));
+        std::fs::create_dir_all(&web_root).unwrap();
+        std::fs::write(web_root.join("index.html"), "<h1>dashboard</h1>").unwrap();
+        let config = DaemonConfig::from_toml(&format!(
+            "listen = \"127.0.0.1:0\"\nroot_servers = [\"{}\"]\n\
+             api_listen = \"127.0.0.1:0\"\nweb_root = {:?}",
+            root.addr(),
+            web_root.display().to_string(),
+        ))
+        .unwrap();
+        let daemon = Daemon::bind(&config).await.unwrap();
+        let addr = daemon.local_addr().unwrap();
+        let api = daemon.api_addr().unwrap().unwrap();
+        let task = tokio::spawn(daemon.run());
+        let client = DNSResolver::builder()
+            .upstreams([Ipv4Addr::LOCALHOST])
+            .upstream_port(Ipv4Addr::LOCALHOST, addr.port())
+            .recursion_desired(true)
+            .cache(false)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(client.resolve("www.dashboard.test").await.unwrap(), address);
+        }
+
+        let get = |path: &str| reqwest::get(format!("http://{api}{path}"));
+        let body = get("/api/stats").await.unwrap().text().await.unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(stats["queries"], 2);
+        assert_eq!(stats["cache_hit_ratio"], 0.5);
+        assert_eq!(stats["top_domains"][0]["name"], "www.dashboard.test");
+        assert_eq!(stats["top_domains"][0]["queries"], 2);
+        assert_eq!(
+            stats["upstreams"][0]["server"],
+            root.addr().ip().to_string()
+        );
+        assert_eq!(stats["top_clients"][0]["client"], "127.0.0.1");
+        let body = get("/api/stats/clients")
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(clients[0]["queries"], 2);
+        assert_eq!(clients[0]["top_domains"][0]["name"], "www.dashboard.test");
+        let client = get("/api/stats/clients/127.0.0.1").await.unwrap();
+        assert_eq!(client.status(), 200);
+        let client = get("/api/stats/clients/192.0.2.1").await.unwrap();
+        assert_eq!(client.status(), 404);
+        // The web UI and its lookups are served alongside.
+        let page = get("/").await.unwrap().text().await.unwrap();
+        assert_eq!(page, "<h1>dashboard</h1>");
+        let lookup = get("/resolve?domain=www.dashboard.test").await.unwrap();
+        assert!(lookup.status().is_success());
+        task.abort();
+        std::fs::remove_dir_all(web_root).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking() {
+        let address = Ipv4Addr::new(192, 0, 2, 43);
+        let mock = MockTransport::new().answer(
+            "blocking.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "blocking.example",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(address),
+            )],
+        );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let resolver = crate::blocking::DNSResolver::from_async(resolver).unwrap();
+        assert_eq!(resolver.resolve("blocking.example").unwrap(), address);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_deadline_and_cancellation() {
+        /// Never answers.
+        struct Silent;
+
+        impl DnsTransport for Silent {
+            async fn exchange(&self, _query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                std::future::pending().await
+            }
+        }
+
+        let resolver = DNSResolver::builder()
+            .transport(Silent)
+            .cache(false)
+            .build()
+            .unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(10);
+        assert!(matches!(
+            resolver
+                .resolve_with_deadline("slow.example", deadline)
+                .await,
+            Err(DnsError::DeadlineExceeded(_))
+        ));
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.cancel();
+        });
+        assert!(matches!(
+            resolver
+                .resolve_with_cancellation("slow.example", &token)
+                .await,
+            Err(DnsError::Cancelled(_))
+        ));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_seeded_rng() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+        use std::sync::{Arc, Mutex};
+
+        /// Keeps the bytes of every query, and refuses it.
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<Vec<Vec<u8>>>>);
+
+        impl DnsTransport for Recording {
+            async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                self.0.lock().unwrap().push(query.to_bytes());
+                MessageBuilder::response_to(query)
+                    .rcode(Rcode::Refused)
+                    .build()
+            }
+        }
+
+        let queries = |seed| async move {
+            let recording = Recording::default();
+            let resolver = DNSResolver::builder()
+                .transport(recording.clone())
+                .randomize_case(true)
+                .rng(StdRng::seed_from_u64(seed))
+                .cache(false)
+                .build()
+                .unwrap();
+            for _ in 0..3 {
+                resolver.resolve("www.example.com").await.unwrap_err();
+            }
+            recording.0.lock().unwrap().clone()
+        };
+        let first = queries(7).await;
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, queries(7).await);
+        assert_ne!(first, queries(8).await);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_tracing_spans() {
+        use std::io;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mock = MockTransport::new().answer(
+            "traced.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "traced.example",
+                RecordType::A,
+                Class::In,
+                60,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 9)),
+            )],
+        );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        resolver.resolve("traced.example").await.unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|line| line.contains("Received response"))
+            .unwrap();
+        assert!(line.contains("resolve{domain_name=\"traced.example\" qtype=\"A\"}"));
+        assert!(line.contains("qname=\"traced.example\" qtype=A qclass=IN server=198.41.0.4"));
+        assert!(line.contains("rtt_ms="));
+        assert!(line.contains("rcode=NOERROR answers=1"));
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_observer() {
+        use crate::observer::QueryObserver;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Recording(Mutex<Vec<String>>);
+
+        impl QueryObserver for Recording {
+            fn on_query_sent(&self, server: IpAddr, query: &DNSPacket) {
+                let name = &query.questions[0].name;
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("sent {name} to {server}"));
+            }
+
+            fn on_response_received(&self, _server: IpAddr, response: &DNSPacket, _rtt: Duration) {
+                let rcode = response.header.flags.rcode;
+                self.0.lock().unwrap().push(format!("received {rcode}"));
+            }
+
+            fn on_cache_hit(&self, name: &str, record_type: RecordType) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("cached {name} {record_type}"));
+            }
+
+            fn on_error(&self, _server: IpAddr, _query: &DNSPacket, error: &DnsError) {
+                self.0.lock().unwrap().push(format!("failed: {error}"));
+            }
+        }
+
+        struct Failing;
+
+        impl DnsTransport for Failing {
+            async fn exchange(&self, _query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                Err(std::io::Error::other("unreachable").into())
+            }
+        }
+
+        let observer = Arc::new(Recording::default());
+        let mock = MockTransport::new().answer(
+            "observed.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "observed.example",
+                RecordType::A,
+                Class::In,
+                60,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 11)),
+            )],
+        );
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .observer(observer.clone())
+            .build()
+            .unwrap();
+        resolver.resolve("observed.example").await.unwrap();
+        resolver.resolve("observed.example").await.unwrap();
+        let mut expected = vec![
+            "sent observed.example to 198.41.0.4".to_string(),
+            "received NOERROR".to_string(),
+        ];
+        if cfg!(feature = "cache") {
+            expected.push("cached observed.example A".to_string());
+        } else {
+            expected.extend_from_within(..);
+        }
+        assert_eq!(*observer.0.lock().unwrap(), expected);
+
+        let observer = Arc::new(Recording::default());
+        let resolver = DNSResolver::builder()
+            .transport(Failing)
+            .observer(observer.clone())
+            .build()
+            .unwrap();
+        resolver.resolve("unreachable.example").await.unwrap_err();
+        assert_eq!(
+            *observer.0.lock().unwrap(),
+            [
+                "sent unreachable.example to 198.41.0.4",
+                "failed: unreachable"
+            ]
+        );
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_query_options() {
+        use crate::config::{CachePolicy, QueryOptions};
+        use std::sync::{Arc, Mutex};
+
+        /// Answers every query, keeping its RD flag and DO bit.
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<Vec<(bool, bool)>>>);
+
+        impl DnsTransport for Recording {
+            async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+                let dnssec_ok = query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((query.header.flags.rd, dnssec_ok));
+                MessageBuilder::response_to(query)
+                    .answer(DNSRecord::new(
+                        &query.questions[0].name,
+                        RecordType::A,
+                        Class::In,
+                        60,
+                        DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 12)),
+                    ))
+                    .build()
+            }
+        }
+
+        let recording = Recording::default();
+        let resolver = DNSResolver::builder()
+            .transport(recording.clone())
+            .build()
+            .unwrap();
+        let queries = || recording.0.lock().unwrap().len();
+        let options = QueryOptions {
+            recursion_desired: Some(true),
+            dnssec_ok: Some(true),
+            cache: CachePolicy::Bypass,
+            ..QueryOptions::default()
+        };
+        resolver
+            .resolve_record_with("options.example", RecordType::A, &options)
+            .await
+            .unwrap();
+        assert_eq!(*recording.0.lock().unwrap(), [(true, true)]);
+
+        resolver
+            .resolve_record("options.example", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(recording.0.lock().unwrap()[1], (false, false));
+        resolver
+            .resolve_record("options.example", RecordType::A)
+            .await
+            .unwrap();
+        let cached = if cfg!(feature = "cache") { 2 } else { 3 };
+        assert_eq!(queries(), cached);
+
+        let refresh = QueryOptions {
+            cache: CachePolicy::Refresh,
+            ..QueryOptions::default()
+        };
+        resolver
+            .resolve_record_with("options.example", RecordType::A, &refresh)
+            .await
+            .unwrap();
+        assert_eq!(queries(), cached + 1);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_json_request_log() {
+        use crate::server::{LogFormat, handle};
+        use std::io;
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(LogFormat::Json.layer(move || writer.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let span = tracing::info_span!(
+            "resolve_dns",
+            client = "192.0.2.7:5353",
+            domain = "missing.example",
+            qtype = "A"
+        );
+        let missing = async { Err::<(), _>(DnsError::NxDomain("missing.example".to_string())) };
+        assert!(handle(&span, missing).await.is_err());
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.lines().last().unwrap()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["fields"]["outcome"], "error");
+        assert_eq!(line["fields"]["status"], 404);
+        assert!(line["fields"]["duration_ms"].is_f64());
+        assert_eq!(line["span"]["client"], "192.0.2.7:5353");
+        assert_eq!(line["span"]["domain"], "missing.example");
+        assert_eq!(line["span"]["qtype"], "A");
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_doh_proxy() {
+        use crate::server::{DohProxy, doh_get, doh_post};
+        use crate::testutil::TestServer;
+        use axum::Router;
+        use axum::body::{Body, to_bytes};
+        use axum::extract::connect_info::MockConnectInfo;
+        use axum::http::{Request, StatusCode, header};
+        use axum::routing::get;
+        use std::net::{SocketAddr, SocketAddrV4};
+        use tower::ServiceExt;
+
+        let address = Ipv4Addr::new(192, 0, 2, 82);
+        let upstream = TestServer::builder()
+            .record(DNSRecord::new(
+                "doh.proxy.test",
+                RecordType::A,
+                Class::In,
+                120,
+                DNSRecordData::Ipv4Addr(address),
+            ))
+            .start()
+            .await
+            .unwrap();
+        let SocketAddr::V4(upstream_addr) = upstream.addr() else {
+            unreachable!("the test server listens on 127.0.0.1");
+        };
+        let proxy =
+            DohProxy::new(SocketAddrV4::new(*upstream_addr.ip(), upstream_addr.port())).unwrap();
+        let app = Router::new()
+            .route(
+                "/dns-query",
+                get(doh_get::<DohProxy>).post(doh_post::<DohProxy>),
+            )
+            .with_state(proxy)
+            .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 7], 5353))));
+        let query = MessageBuilder::query()
+            .question("doh.proxy.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+
+        let get_request = Request::get(format!("/dns-query?dns={}", doh::to_base64url(&query)))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(get_request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/dns-message"
+        );
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=120");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let answer = DNSPacket::parse(&body).unwrap();
+        assert_eq!(answer.header.id, query.header.id);
+        assert_eq!(answer.answers[0].data, DNSRecordData::Ipv4Addr(address));
+
+        let post = |content_type: &str, body: Vec<u8>| {
+            Request::post("/dns-query")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(post("application/dns-message", query.to_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(post("application/octet-stream", query.to_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let response = app
+            .oneshot(post("application/dns-message", answer.to_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(upstream.queries().len(), 2);
+
+        // Minimal responses keep what negative answers need.
+        let minimal = DohProxy::new(SocketAddrV4::new(*upstream_addr.ip(), upstream_addr.port()))
+            .unwrap()
+            .minimal_responses(true);
+        let missing = MessageBuilder::query()
+            .question("missing.proxy.test", RecordType::A, Class::In)
+            .build()
+            .unwrap();
+        let response = minimal.forward(&missing.to_bytes()).await.unwrap();
+        let response = DNSPacket::parse(&response).unwrap();
+        assert_eq!(response.header.flags.rcode, Rcode::NxDomain);
+        assert_eq!(response.authorities[0].type_, RecordType::Soa);
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_trace_propagation() {
+        use axum::http::HeaderMap;
+        use opentelemetry::trace::{SpanId, TraceId, TracerProvider};
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                    .parse()
+                    .unwrap(),
+            );
+            let request = tracing::info_span!("resolve_dns");
+            crate::server::otel::continue_trace(&request, &headers);
+            request.in_scope(|| tracing::info_span!("query").in_scope(|| ()));
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let [query, request] = &spans[..] else {
+            panic!("expected two spans, got {spans:?}");
+        };
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        assert_eq!(request.span_context.trace_id(), trace_id);
+        assert_eq!(
+            request.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+        assert_eq!(query.span_context.trace_id(), trace_id);
+        assert_eq!(query.parent_span_id, request.span_context.span_id());
+    }
+
+    #[cfg(all(feature = "metrics", feature = "cache"))]
+    #[test]
+    fn test_metrics() {
+        use crate::config::QueryOptions;
+        use crate::metrics::{CACHE_HITS, CACHE_MISSES, QUERIES, QUERY_DURATION, RESPONSES};
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let mock = MockTransport::new().answer(
+            "metrics.example",
+            RecordType::A,
+            vec![DNSRecord::new(
+                "metrics.example",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 14)),
+            )],
+        );
+        let resolver = DNSResolver::builder().transport(mock).build().unwrap();
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                for _ in 0..2 {
+                    resolver
+                        .resolve_detailed(
+                            "metrics.example",
+                            RecordType::A,
+                            &QueryOptions::default(),
+                        )
+                        .await
+                        .unwrap();
+                }
+            })
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                (key.name().to_string(), labels, value)
+            })
+            .collect();
+        let find = |name: &str, labels: &[&str]| {
+            metrics
+                .iter()
+                .find(|(n, l, _)| n == name && l == labels)
+                .map(|(_, _, value)| value)
+                .unwrap_or_else(|| panic!("no {name} {labels:?} in {metrics:?}"))
+        };
+        assert_eq!(
+            find(QUERIES, &["type=A", "transport=mock"]),
+            &DebugValue::Counter(1)
+        );
+        assert_eq!(
+            find(RESPONSES, &["type=A", "rcode=NOERROR"]),
+            &DebugValue::Counter(1)
+        );
+        assert_eq!(find(CACHE_MISSES, &[]), &DebugValue::Counter(1));
+        assert_eq!(find(CACHE_HITS, &[]), &DebugValue::Counter(1));
+        let DebugValue::Histogram(rtts) = find(QUERY_DURATION, &["transport=mock"]) else {
+            panic!("{QUERY_DURATION} is not a histogram");
+        };
+        assert_eq!(rtts.len(), 1);
+    }
+
+    #[cfg(feature = "resolver")]
+    #[tokio::test]
+    async fn test_lookup_result() {
+        use crate::config::QueryOptions;
+
+        let mock = MockTransport::new()
+            .answer(
+                "www.detailed.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "www.detailed.example",
+                    RecordType::Cname,
+                    Class::In,
+                    300,
+                    DNSRecordData::Name("detailed.example".to_string()),
+                )],
+            )
+            .answer(
+                "detailed.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "detailed.example",
+                    RecordType::A,
+                    Class::In,
+                    60,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 13)),
+                )],
+            );
+        let resolver = DNSResolver::builder().transport(mock).build().unwrap();
+        let options = QueryOptions::default();
+
+        let result = resolver
+            .resolve_detailed("www.detailed.example", RecordType::A, &options)
+            .await
+            .unwrap();
+        assert_eq!(result.records.len(), 1);
+        assert_eq!(result.min_ttl(), Some(60));
+        assert_eq!(result.server, Some(Ipv4Addr::new(198, 41, 0, 4)));
+        assert!(!result.from_cache);
+
+        let again = resolver
+            .resolve_detailed("detailed.example", RecordType::A, &options)
+            .await
+            .unwrap();
+        if cfg!(feature = "cache") {
+            assert!(again.from_cache);
+            assert_eq!(again.server, None);
+            assert!(again.min_ttl().unwrap() <= 60);
+        } else {
+            assert!(!again.from_cache);
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_cli_output() {
+        use crate::LookupResult;
+        use crate::cli::{Cli, OutputFormat, render};
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(["dnsr", "example.com", "aaaa", "-o", "short"]).unwrap();
+        assert_eq!(cli.record_type, RecordType::Aaaa);
+        assert_eq!(cli.output, OutputFormat::Short);
+        assert!(Cli::try_parse_from(["dnsr", "example.com", "BOGUS"]).is_err());
+        assert_eq!("type16".parse::<RecordType>().unwrap(), RecordType::Txt);
+
+        let result = LookupResult {
+            records: vec![DNSRecord::new(
+                "example.com",
+                RecordType::A,
+                Class::In,
+                300,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+            server: Some(Ipv4Addr::new(192, 0, 2, 53)),
+            from_cache: false,
+            elapsed: Duration::from_millis(12),
+            nsid: None,
+            signatures: Vec::new(),
+        };
+        let output = |format| render(format, "example.com", &RecordType::A, &result);
+        assert_eq!(output(OutputFormat::Short), "192.0.2.1\n");
+        assert_eq!(
+            output(OutputFormat::Dig),
+            ";; QUESTION SECTION:\n;example.com.\t\tIN\tA\n\n\
+             ;; ANSWER SECTION:\nexample.com.\t300\tIN\tA\t192.0.2.1\n\n\
+             ;; Query time: 12 msec\n;; SERVER: 192.0.2.53#53(192.0.2.53)\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&output(OutputFormat::Json)).unwrap();
+        assert_eq!(json["answers"][0]["data"], "192.0.2.1");
+        assert_eq!(json["elapsed_ms"], 12);
+        assert!(output(OutputFormat::Yaml).contains("answers:\n- name: example.com\n  type: A\n"));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_cli_trace() {
+        use crate::cli::{OutputFormat, render_trace};
+        use crate::{HopOutcome, Trace, TraceHop};
+
+        let (root, nameserver) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 53));
+        let ns = DNSRecord::new(
+            "example",
+            RecordType::Ns,
+            Class::In,
+            3600,
+            DNSRecordData::Name("ns.example".to_string()),
+        );
+        let glue = DNSRecord::new(
+            "ns.example",
+            RecordType::A,
+            Class::In,
+            3600,
+            DNSRecordData::Ipv4Addr(nameserver),
+        );
+        let answer = DNSRecord::new(
+            "www.example",
+            RecordType::A,
+            Class::In,
+            300,
+            DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 80)),
+        );
+        let response = |builder: MessageBuilder| {
+            builder
+                .question("www.example", RecordType::A, Class::In)
+                .build()
+                .unwrap()
+        };
+        let question = DNSQuestion::new("www.example".to_string(), RecordType::A, Class::In);
+        let hop = |server, outcome, rcode, rtt, response| TraceHop {
+            server,
+            question: question.clone(),
+            outcome,
+            rcode,
+            rtt: Duration::from_millis(rtt),
+            response,
+        };
+        let trace = Trace {
+            hops: vec![
+                hop(
+                    Ipv4Addr::new(192, 0, 2, 2),
+                    HopOutcome::Failed("Timed out".to_string()),
+                    None,
+                    800,
+                    None,
+                ),
+                hop(
+                    root,
+                    HopOutcome::Referral {
+                        zone: "example".to_string(),
+                        nameservers: vec!["ns.example".to_string()],
+                    },
+                    Some(Rcode::NoError),
+                    24,
+                    Some(response(
+                        MessageBuilder::query()
+                            .authority(ns)
+                            .additional(glue.clone()),
+                    )),
+                ),
+                hop(
+                    nameserver,
+                    HopOutcome::Answer,
+                    Some(Rcode::NoError),
+                    3,
+                    Some(response(MessageBuilder::query().answer(answer.clone()))),
+                ),
+            ],
+            result: Ok(vec![answer]),
+        };
+        let output = |format| render_trace(format, "www.example", &RecordType::A, &trace);
+
+        assert_eq!(
+            output(OutputFormat::Dig),
+            ";; No answer from 192.0.2.2 in 800 ms: Timed out\n\
+             example.\t3600\tIN\tNS\tns.example.\n\
+             ns.example.\t3600\tIN\tA\t192.0.2.53\n\
+             ;; Referral to example. from 192.0.2.1#53(192.0.2.1) in 24 ms\n\n\
+             www.example.\t300\tIN\tA\t192.0.2.80\n\
+             ;; Answer from 192.0.2.53#53(192.0.2.53) in 3 ms\n\n"
+        );
+        assert_eq!(
+            output(OutputFormat::Short),
+            "192.0.2.2 failed Timed out 800 ms\n\
+             192.0.2.1 referral example 24 ms\n\
+             192.0.2.53 answer 3 ms\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&output(OutputFormat::Json)).unwrap();
+        assert_eq!(json["hops"][1]["nameservers"][0], "ns.example");
+        assert_eq!(json["hops"][1]["glue"][0]["data"], "192.0.2.53");
+        assert_eq!(json["answers"][0]["data"], "192.0.2.80");
+        assert!(json.get("error").is_none());
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_cli_bulk() {
+        use crate::cli::{BulkSummary, OutputFormat, resolve_bulk};
+
+        let record = |name: &str, last| {
+            DNSRecord::new(
+                name,
+                RecordType::A,
+                Class::In,
+                60,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, last)),
+            )
+        };
+        let mut mock = MockTransport::new().nxdomain("missing.bulk.example", RecordType::A);
+        for (name, last) in [("a.bulk.example", 1), ("b.bulk.example", 2)] {
+            mock = mock.answer(name, RecordType::A, vec![record(name, last)]);
+        }
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+        let input = "# inventory\na.bulk.example\n\nmissing.bulk.example\n\
+                     b.bulk.example A\nc.bulk.example BOGUS\n";
+
+        let mut out = Vec::new();
+        let summary = resolve_bulk(&resolver, input.as_bytes(), 2, OutputFormat::Csv, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            BulkSummary {
+                resolved: 2,
+                failed: 2
+            }
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "name,type,status,ttl,data\n\
+             a.bulk.example,A,NOERROR,60,192.0.2.1\n\
+             missing.bulk.example,A,Domain name missing.bulk.example does not exist,,\n\
+             b.bulk.example,A,NOERROR,60,192.0.2.2\n\
+             c.bulk.example,A,Unknown record type BOGUS,,\n"
+        );
+
+        let mut out = Vec::new();
+        resolve_bulk(&resolver, input.as_bytes(), 1, OutputFormat::Json, &mut out)
+            .await
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2]["answers"][0]["data"], "192.0.2.2");
+        assert!(lines[1]["error"].is_string());
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_cli_watch() {
+        use crate::cli::{Cli, Command, parse_duration, render_watch, watch};
+        use clap::Parser;
+
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("5d").is_err());
+        let record = |last, ttl| {
+            DNSRecord::new(
+                "watch.example",
+                RecordType::A,
+                Class::In,
+                ttl,
+                DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, last)),
+            )
+        };
+        let before = Ok(vec![record(1, 300), record(2, 300)]);
+        let after = Ok(vec![record(1, 60), record(3, 300)]);
+        let at = Duration::from_secs(30);
+
+        assert_eq!(
+            render_watch(at, Some(&before), &after, false),
+            ";; +30s: changed\n\
+             ~ watch.example.\t60\tIN\tA\t192.0.2.1\t(TTL was 300)\n\
+             + watch.example.\t300\tIN\tA\t192.0.2.3\n\
+             - watch.example.\t300\tIN\tA\t192.0.2.2\n"
+        );
+        assert_eq!(
+            render_watch(at, Some(&after), &after, false),
+            ";; +30s: unchanged\n"
+        );
+        assert!(render_watch(at, Some(&after), &after, true).starts_with(";; +30s: unchanged"));
+        assert!(render_watch(at, Some(&before), &after, true).contains("\x1b[32m+ "));
+
+        let cli = Cli::try_parse_from([
+            "dnsr",
+            "watch",
+            "watch.example",
+            "--interval",
+            "1ms",
+            "--count",
+            "2",
+        ])
+        .unwrap();
+        let Some(Command::Watch(args)) = cli.command else {
+            panic!("watch was not parsed as a subcommand");
+        };
+        let mock =
+            MockTransport::new().answer("watch.example", RecordType::A, vec![record(1, 300)]);
+        let resolver = DNSResolver::builder().transport(mock).build().unwrap();
+        let mut out = Vec::new();
+        watch(&resolver, &args, &mut out, false).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(";; +0s: 1 record\n  watch.example.\t300\tIN\tA\t192.0.2.1\n"));
+        assert!(out.ends_with(": unchanged\n"));
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_cli_bench() {
+        use crate::cli::{Cli, Command, OutputFormat, bench, read_targets, render_bench};
+        use clap::Parser;
+
+        let targets =
+            read_targets("# names\nbench.example\n\nmissing.example AAAA\n".as_bytes()).unwrap();
+        assert_eq!(
+            targets,
+            [
+                ("bench.example".to_string(), RecordType::A),
+                ("missing.example".to_string(), RecordType::Aaaa),
+            ]
+        );
+        assert!(read_targets("bench.example BOGUS\n".as_bytes()).is_err());
+
+        let cli = Cli::try_parse_from([
+            "dnsr",
+            "bench",
+            "--targets",
+            "-",
+            "--qps",
+            "1000",
+            "--duration",
+            "20ms",
+        ])
+        .unwrap();
+        let Some(Command::Bench(args)) = cli.command else {
+            panic!("bench was not parsed as a subcommand");
+        };
+        let mock = MockTransport::new()
+            .answer(
+                "bench.example",
+                RecordType::A,
+                vec![DNSRecord::new(
+                    "bench.example",
+                    RecordType::A,
+                    Class::In,
+                    300,
+                    DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+                )],
+            )
+            .nxdomain("missing.example", RecordType::Aaaa);
+        let resolver = DNSResolver::builder()
+            .transport(mock.clone())
+            .build()
+            .unwrap();
+
+        let report = bench(&resolver, &[], &targets, &args).await;
+        assert_eq!(report.sent, 20);
+        assert_eq!(report.rcodes["NOERROR"], 10);
+        assert_eq!(report.rcodes["NXDOMAIN"], 10);
+        assert_eq!(report.latencies.len(), 20);
+        assert_eq!(report.error_rate(), 0.0);
+        assert!(report.percentile(0.5) <= report.percentile(0.99));
+        assert!(report.elapsed >= Duration::from_millis(20));
+
+        let server = Ipv4Addr::new(192, 0, 2, 53);
+        let refusing = [("refused.example".to_string(), RecordType::A)];
+        let report = bench(&resolver, &[server], &refusing, &args).await;
+        assert_eq!(report.rcodes["REFUSED"], 20);
+        assert_eq!(report.error_rate(), 1.0);
+        assert!(
+            mock.queries()
+                .iter()
+                .any(|(to, _)| *to == IpAddr::V4(server))
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_bench(OutputFormat::Json, &report)).unwrap();
+        assert_eq!(json["sent"], 20);
+        assert_eq!(json["error_rate"], 1.0);
+        assert!(json["latency_ms"]["p99"].is_number());
+        assert!(render_bench(OutputFormat::Dig, &report).contains(";; Responses: REFUSED 20\n"));
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_cli_ptr() {
+        use crate::cli::{Ipv4Network, OutputFormat, parse_network, sweep_ptr};
+
+        let network = parse_network("192.0.2.6/30").unwrap();
+        assert_eq!(
+            network,
+            Ipv4Network {
+                address: Ipv4Addr::new(192, 0, 2, 4),
+                prefix_len: 30,
+            }
+        );
+        let addresses: Vec<_> = network.addresses().collect();
+        assert_eq!(addresses.first(), Some(&Ipv4Addr::new(192, 0, 2, 4)));
+        assert_eq!(addresses.len(), 4);
+        assert_eq!(parse_network("192.0.2.1").unwrap().addresses().count(), 1);
+        assert!(parse_network("192.0.2.0/33").is_err());
+        assert!(parse_network("10.0.0.0/8").is_err());
+
+        let ptr = |address: &str, name: &str| {
+            vec![DNSRecord::new(
+                address,
+                RecordType::Ptr,
+                Class::In,
+                3600,
+                DNSRecordData::Name(name.to_string()),
+            )]
+        };
+        let mock = MockTransport::new()
+            .answer(
+                "5.2.0.192.in-addr.arpa",
+                RecordType::Ptr,
+                ptr("5.2.0.192.in-addr.arpa", "mail.example"),
+            )
+            .answer(
+                "6.2.0.192.in-addr.arpa",
+                RecordType::Ptr,
+                ptr("6.2.0.192.in-addr.arpa", "www.example"),
+            )
+            .nxdomain("4.2.0.192.in-addr.arpa", RecordType::Ptr)
+            .nxdomain("7.2.0.192.in-addr.arpa", RecordType::Ptr);
+        let resolver = DNSResolver::builder()
+            .transport(mock)
+            .cache(false)
+            .build()
+            .unwrap();
+
+        let mut out = Vec::new();
+        let summary = sweep_ptr(&resolver, network, 2, OutputFormat::Short, &mut out)
+            .await
+            .unwrap();
+        assert_eq!((summary.resolved, summary.failed), (2, 2));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "192.0.2.5\tmail.example.\n192.0.2.6\twww.example.\n"
+        );
+
+        let mut out = Vec::new();
+        sweep_ptr(&resolver, network, 3, OutputFormat::Dig, &mut out)
+            .await
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "ADDRESS          PTR");
+        assert!(lines[1].starts_with("192.0.2.4        -  ;; "));
+        assert_eq!(lines[2], "192.0.2.5        mail.example.");
+
+        let mut out = Vec::new();
+        sweep_ptr(&resolver, network, 4, OutputFormat::Json, &mut out)
+            .await
+            .unwrap();
+        let entries: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries[2]["name"], "www.example.");
+        assert!(entries[3]["name"].is_null());
+        assert!(entries[3]["error"].is_string());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_cli_completions() {
+        use crate::cli::{Cli, Command, completions, man_page};
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(["dnsr", "completions", "zsh"]).unwrap();
+        let Some(Command::Completions(args)) = cli.command else {
+            panic!("expected the completions subcommand");
+        };
+        let mut out = Vec::new();
+        completions(args.shell, &mut out).unwrap();
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.starts_with("#compdef dnsr"));
+        assert!(script.contains("propagation"));
+        assert!(script.contains("--concurrency"));
+
+        assert!(Cli::try_parse_from(["dnsr", "completions", "tcsh"]).is_err());
+
+        let mut out = Vec::new();
+        man_page(&mut out).unwrap();
+        let page = String::from_utf8(out).unwrap();
+        assert!(page.contains(".TH dnsr"));
+        assert!(page.contains(r"check\-delegation"));
+    }
 }
@@ -0,0 +1,320 @@
+//! Auditing what a domain publishes for mail: where it is delivered, and the
+//! SPF, DMARC and DKIM records receivers check senders against. DMARC and
+//! DKIM records are also parsed into the policy and key they publish.
+
+use crate::error::{DnsError, Result};
+use crate::transport::DnsTransport;
+use crate::{DNSRecordData, DNSResolver, Mx, RecordType};
+use serde::Serialize;
+use std::str::FromStr;
+
+/// The mail records of a domain, from [`DNSResolver::audit_email_domain`].
+/// Records that do not exist are empty or `None`; lookups that failed
+/// otherwise are listed in `errors`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EmailAudit {
+    /// The domain, without a trailing dot.
+    pub domain: String,
+    /// The mail exchangers, most preferred first. Empty for a null MX too.
+    pub mx: Vec<Mx>,
+    /// Every `v=spf1` TXT record of the domain. More than one is an error
+    /// that makes receivers ignore them all (RFC 7208 section 4.5).
+    pub spf: Vec<String>,
+    /// Every `v=DMARC1` TXT record at `_dmarc.<domain>`. Likewise, more than
+    /// one means no policy applies.
+    pub dmarc: Vec<String>,
+    /// Whether `<selector>._domainkey.<domain>` publishes a public key, or
+    /// `None` if no selector was given or the lookup failed. A key record
+    /// with an empty `p=` tag has been revoked and does not count.
+    pub dkim: Option<bool>,
+    /// The lookups that failed other than for want of records.
+    pub errors: Vec<String>,
+}
+
+impl EmailAudit {
+    /// Whether the domain has exactly one SPF and one DMARC record, and a DKIM
+    /// key if a selector was checked.
+    pub fn is_complete(&self) -> bool {
+        self.spf.len() == 1 && self.dmarc.len() == 1 && self.dkim != Some(false)
+    }
+}
+
+/// What receivers are asked to do with mail failing DMARC checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl FromStr for DmarcPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DmarcPolicy::None),
+            "quarantine" => Ok(DmarcPolicy::Quarantine),
+            "reject" => Ok(DmarcPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A domain's DMARC policy (RFC 7489), from [`DNSResolver::fetch_dmarc`].
+/// Tags other than `p`, `sp`, `pct` and `rua` are not kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Dmarc {
+    /// The domain the record was found for: the one asked about or, if it
+    /// has none, its nearest ancestor that does.
+    pub domain: String,
+    /// `p`, for `domain` itself.
+    pub policy: DmarcPolicy,
+    /// `sp`, for its subdomains, if it differs from `policy`.
+    pub subdomain_policy: Option<DmarcPolicy>,
+    /// `pct`, the percentage of failing mail the policy applies to.
+    pub percent: u8,
+    /// `rua`, the URIs aggregate reports are sent to.
+    pub aggregate_reports: Vec<String>,
+}
+
+impl Dmarc {
+    /// Parses the text of a `_dmarc` TXT record found for `domain`. `None`
+    /// if it does not start with `v=DMARC1` or has no valid `p` tag. An
+    /// out of range `pct` is taken as 100.
+    pub fn parse(domain: &str, text: &str) -> Option<Self> {
+        let mut tags = text
+            .split(';')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim()));
+        let (version, value) = tags.next()?;
+        if version != "v" || !value.eq_ignore_ascii_case("DMARC1") {
+            return None;
+        }
+        let mut dmarc = Dmarc {
+            domain: domain.trim_end_matches('.').to_string(),
+            policy: DmarcPolicy::None,
+            subdomain_policy: None,
+            percent: 100,
+            aggregate_reports: Vec::new(),
+        };
+        let mut policy = None;
+        for (name, value) in tags {
+            match name.to_ascii_lowercase().as_str() {
+                "p" => policy = value.parse().ok(),
+                "sp" => dmarc.subdomain_policy = value.parse().ok(),
+                "pct" => {
+                    dmarc.percent = value.parse().ok().filter(|&pct| pct <= 100).unwrap_or(100)
+                }
+                "rua" => {
+                    dmarc.aggregate_reports = value
+                        .split(',')
+                        .map(|uri| uri.trim().to_string())
+                        .filter(|uri| !uri.is_empty())
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        dmarc.policy = policy?;
+        Some(dmarc)
+    }
+
+    /// The policy for mail from `domain`: `policy` if the record is
+    /// `domain`'s own, and otherwise the subdomain policy.
+    pub fn policy_for(&self, domain: &str) -> DmarcPolicy {
+        if domain
+            .trim_end_matches('.')
+            .eq_ignore_ascii_case(&self.domain)
+        {
+            self.policy
+        } else {
+            self.subdomain_policy.unwrap_or(self.policy)
+        }
+    }
+}
+
+/// A DKIM public key record (RFC 6376 section 3.6.1), from
+/// [`DNSResolver::fetch_dkim`]. Tags other than `v`, `k` and `p` are not kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DkimKey {
+    /// `v`, which is `DKIM1` when given at all.
+    pub version: Option<String>,
+    /// `k`, the key algorithm, `rsa` unless given.
+    pub key_type: String,
+    /// `p`, the base64 public key with any whitespace removed. Empty when
+    /// the key has been revoked.
+    pub public_key: String,
+}
+
+impl DkimKey {
+    /// Parses the text of a key record, its character-strings joined. `None`
+    /// if it has no `p` tag, or a `v` tag other than a leading `v=DKIM1`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut key = DkimKey {
+            version: None,
+            key_type: "rsa".to_string(),
+            public_key: String::new(),
+        };
+        let mut public_key = None;
+        let tags = text.split(';').map(str::trim).filter(|tag| !tag.is_empty());
+        for (i, tag) in tags.enumerate() {
+            let (name, value) = tag.split_once('=')?;
+            let value: String = value.split_whitespace().collect();
+            match name.trim() {
+                "v" if i == 0 && value == "DKIM1" => key.version = Some(value),
+                "v" => return None,
+                "k" => key.key_type = value,
+                "p" => public_key = Some(value),
+                _ => {}
+            }
+        }
+        key.public_key = public_key?;
+        Some(key)
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.public_key.is_empty()
+    }
+}
+
+/// How many `_dmarc` names a DMARC lookup asks at most, in the DNS tree walk
+/// of DMARCbis, which stands in for the public suffix list.
+const MAX_DMARC_QUERIES: usize = 8;
+
+/// The texts of `records` that start with the tag `version`, ignoring ASCII
+/// case, followed by a space, a `;` or nothing.
+pub(crate) fn tagged(records: &[String], version: &str) -> Vec<String> {
+    records
+        .iter()
+        .filter(|text| {
+            text.get(..version.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(version))
+                && text[version.len()..]
+                    .chars()
+                    .next()
+                    .is_none_or(|c| c == ' ' || c == ';')
+        })
+        .cloned()
+        .collect()
+}
+
+/// The value of `result`, or the default if the name or its records do not
+/// exist. Any other error is added to `errors`.
+fn found<V: Default>(result: Result<V>, name: &str, errors: &mut Vec<String>) -> V {
+    match result {
+        Ok(value) => value,
+        Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => V::default(),
+        Err(e) => {
+            errors.push(format!("{name}: {e}"));
+            V::default()
+        }
+    }
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// Looks up the MX, SPF and DMARC records of `domain`, and the DKIM key
+    /// of `dkim_selector` if given, all at once.
+    pub async fn audit_email_domain(
+        &self,
+        domain: &str,
+        dkim_selector: Option<&str>,
+    ) -> EmailAudit {
+        let domain = domain.trim_end_matches('.').to_string();
+        let dmarc_name = format!("_dmarc.{domain}");
+        let dkim_name = dkim_selector.map(|selector| format!("{selector}._domainkey.{domain}"));
+        let (mx, txt, dmarc, dkim) = tokio::join!(
+            self.lookup_mx(&domain),
+            self.txt_records(&domain),
+            self.txt_records(&dmarc_name),
+            async {
+                match &dkim_name {
+                    Some(name) => Some(self.txt_records(name).await),
+                    None => None,
+                }
+            }
+        );
+
+        let mut errors = Vec::new();
+        let mx = found(mx, &domain, &mut errors);
+        let spf = tagged(&found(txt, &domain, &mut errors), "v=spf1");
+        let dmarc = tagged(&found(dmarc, &dmarc_name, &mut errors), "v=DMARC1");
+        let dkim = dkim_name.zip(dkim).and_then(|(name, result)| match result {
+            Ok(records) => Some(
+                records
+                    .iter()
+                    .filter_map(|text| DkimKey::parse(text))
+                    .any(|key| !key.is_revoked()),
+            ),
+            Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => Some(false),
+            Err(e) => {
+                errors.push(format!("{name}: {e}"));
+                None
+            }
+        });
+        EmailAudit {
+            domain,
+            mx,
+            spf,
+            dmarc,
+            dkim,
+            errors,
+        }
+    }
+
+    /// The DMARC policy governing `domain`: its own `_dmarc` record or, if it
+    /// has none, that of its nearest ancestor, the organizational domain.
+    /// Ancestors are asked one by one up to the top-level domain, skipping to
+    /// the last seven labels of long names so that at most eight names are
+    /// asked. Names with more than one DMARC record are passed over.
+    pub async fn fetch_dmarc(&self, domain: &str) -> Result<Option<Dmarc>> {
+        let labels: Vec<&str> = domain.trim_end_matches('.').split('.').collect();
+        let mut starts: Vec<usize> = (0..labels.len()).collect();
+        if labels.len() > MAX_DMARC_QUERIES {
+            starts = std::iter::once(0)
+                .chain(labels.len() + 1 - MAX_DMARC_QUERIES..labels.len())
+                .collect();
+        }
+        for start in starts {
+            let name = labels[start..].join(".");
+            let texts = match self.txt_records(&format!("_dmarc.{name}")).await {
+                Ok(texts) => texts,
+                Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if let [text] = tagged(&texts, "v=DMARC1").as_slice()
+                && let Some(dmarc) = Dmarc::parse(&name, text)
+            {
+                return Ok(Some(dmarc));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The DKIM key `selector` publishes for `domain`, from the TXT record of
+    /// `<selector>._domainkey.<domain>`. `None` if there is no such record
+    /// or none of its records parse as a key.
+    pub async fn fetch_dkim(&self, selector: &str, domain: &str) -> Result<Option<DkimKey>> {
+        let name = format!("{selector}._domainkey.{}", domain.trim_end_matches('.'));
+        match self.txt_records(&name).await {
+            Ok(texts) => Ok(texts.iter().find_map(|text| DkimKey::parse(text))),
+            Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The TXT records of `name`, each with its character-strings joined.
+    pub(crate) async fn txt_records(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .resolve_record(name, RecordType::Txt)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Txt(strings) => Some(strings.concat()),
+                _ => None,
+            })
+            .collect())
+    }
+}
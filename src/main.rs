@@ -1,32 +1,143 @@
 use axum::http::Method;
 use axum::routing::get;
 use axum::{Router, serve};
-use dns_resolver_rs::server::{resolve_dns, resolve_ip};
+use dns_resolver_rs::DNSResolver;
+use dns_resolver_rs::server::{
+    DohProxy, LogFormat, check_propagation, doh_get, doh_post, resolve_dns, resolve_ip, stats,
+};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::process::exit;
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+#[cfg(feature = "otel")]
+use {
+    opentelemetry::trace::TracerProvider,
+    opentelemetry_sdk::{Resource, trace::SdkTracerProvider},
+};
+
+const USAGE: &str = "Usage: dns-resolver-rs [--log-format=text|json] [--doh-upstream=IP[:PORT]] \
+                     [--minimal-responses]";
+
+#[derive(Default)]
+struct Options {
+    log_format: LogFormat,
+    /// Where `/dns-query` forwards DoH requests; DoH proxy mode is off
+    /// without it.
+    doh_upstream: Option<SocketAddrV4>,
+    /// Whether DoH responses are stripped of records clients do not need.
+    minimal_responses: bool,
+}
+
+/// Reads `--log-format=text|json` and `--doh-upstream=IP[:PORT]`, each also
+/// accepted with its value as the next argument, and `--minimal-responses`.
+fn options() -> Options {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if arg == "--minimal-responses" {
+            options.minimal_responses = true;
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, args.next()),
+        };
+        let Some(value) = value else {
+            eprintln!("{USAGE}");
+            exit(2);
+        };
+        let parsed = match name.as_str() {
+            "--log-format" => value.parse().map(|format| options.log_format = format),
+            "--doh-upstream" => {
+                parse_server(&value).map(|server| options.doh_upstream = Some(server))
+            }
+            _ => Err(USAGE.to_string()),
+        };
+        if let Err(e) = parsed {
+            eprintln!("{e}");
+            exit(2);
+        }
+    }
+    options
+}
+
+/// An upstream of `IP` or `IP:PORT`.
+fn parse_server(s: &str) -> Result<SocketAddrV4, String> {
+    s.parse()
+        .map(|ip| SocketAddrV4::new(ip, 53))
+        .or_else(|_| s.parse())
+        .map_err(|_| format!("Invalid upstream {s}, expected an IPv4 address and optional port"))
+}
+
+/// Exports spans over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`, by default
+/// a collector on `localhost:4318`.
+#[cfg(feature = "otel")]
+fn tracer_provider() -> SdkTracerProvider {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .expect("building the OTLP exporter failed");
+    SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name("dns-resolver-rs")
+                .build(),
+        )
+        .build()
+}
 
 #[tokio::main]
 async fn main() {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
-        .finish();
+    let options = options();
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::TRACE)
+        .with(options.log_format.layer(std::io::stdout));
+    #[cfg(feature = "otel")]
+    let provider = tracer_provider();
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("dns-resolver-rs")));
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
     let cors = CorsLayer::new()
         .allow_methods([Method::GET])
         .allow_origin(Any);
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/resolve", get(resolve_dns))
         .route("/reverse_resolve", get(resolve_ip))
+        .route("/stats", get(stats))
+        .route("/propagation", get(check_propagation))
         .fallback_service(ServeDir::new("static"))
-        .layer(ServiceBuilder::new().layer(cors));
+        .layer(ServiceBuilder::new().layer(cors))
+        .with_state(DNSResolver::default());
+    if let Some(upstream) = options.doh_upstream {
+        let proxy = DohProxy::new(upstream)
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(2);
+            })
+            .minimal_responses(options.minimal_responses);
+        app = app.merge(
+            Router::new()
+                .route(
+                    "/dns-query",
+                    get(doh_get::<DohProxy>).post(doh_post::<DohProxy>),
+                )
+                .with_state(proxy),
+        );
+        println!("Forwarding DoH requests on /dns-query to {upstream}");
+    }
 
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
     println!("DNS Resolver server running on http://localhost:3000");
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
     serve(listener, app).await.unwrap();
+    #[cfg(feature = "otel")]
+    provider.shutdown().expect("flushing spans failed");
 }
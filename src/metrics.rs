@@ -0,0 +1,85 @@
+//! Query, cache and latency metrics, recorded through the [`metrics`] crate's
+//! facade. Nothing is kept until the application installs a recorder, such as
+//! `metrics-exporter-prometheus`, so they reach whichever exporter it uses.
+//!
+//! | Name | Kind | Labels |
+//! |------|------|--------|
+//! | [`QUERIES`] | counter | `type`, `transport` |
+//! | [`RESPONSES`] | counter | `type`, `rcode` |
+//! | [`RETRANSMITS`] | counter | `transport` |
+//! | [`CACHE_HITS`], [`CACHE_MISSES`] | counter | |
+//! | [`QUERY_DURATION`] | histogram | `transport` |
+//!
+//! Call [`describe`] after installing the recorder to give it each metric's
+//! description and unit.
+
+use crate::DNSPacket;
+use crate::flags::Rcode;
+use ::metrics::{Unit, counter, describe_counter, describe_histogram, histogram};
+use std::time::Duration;
+
+/// Queries sent to a server, retransmissions included.
+pub const QUERIES: &str = "dns_resolver_queries_total";
+/// Responses received, by the type asked for and the RCODE answered.
+pub const RESPONSES: &str = "dns_resolver_responses_total";
+/// Queries sent again after a timeout.
+pub const RETRANSMITS: &str = "dns_resolver_retransmits_total";
+/// Lookups answered from the caches, positive or negative.
+pub const CACHE_HITS: &str = "dns_resolver_cache_hits_total";
+/// Lookups the caches could not answer, so queries were sent.
+pub const CACHE_MISSES: &str = "dns_resolver_cache_misses_total";
+/// Round-trip time of each exchange that brought a response.
+pub const QUERY_DURATION: &str = "dns_resolver_query_duration_seconds";
+
+/// Describes every metric to the installed recorder.
+pub fn describe() {
+    describe_counter!(
+        QUERIES,
+        "Queries sent to a server, retransmissions included"
+    );
+    describe_counter!(RESPONSES, "Responses received, by query type and RCODE");
+    describe_counter!(RETRANSMITS, "Queries sent again after a timeout");
+    describe_counter!(CACHE_HITS, "Lookups answered from the caches");
+    describe_counter!(CACHE_MISSES, "Lookups the caches could not answer");
+    describe_histogram!(
+        QUERY_DURATION,
+        Unit::Seconds,
+        "Round-trip time of exchanges that brought a response"
+    );
+}
+
+/// The type asked for, labelling query and response counts.
+fn query_type(query: &DNSPacket) -> String {
+    query
+        .questions
+        .first()
+        .map(|question| question.type_.to_string())
+        .unwrap_or_default()
+}
+
+pub(crate) fn query_sent(query: &DNSPacket, transport: &'static str, attempt: u32) {
+    counter!(QUERIES, "type" => query_type(query), "transport" => transport).increment(1);
+    if attempt > 0 {
+        counter!(RETRANSMITS, "transport" => transport).increment(1);
+    }
+}
+
+pub(crate) fn response_received(
+    query: &DNSPacket,
+    rcode: Rcode,
+    transport: &'static str,
+    rtt: Duration,
+) {
+    counter!(RESPONSES, "type" => query_type(query), "rcode" => rcode.to_string()).increment(1);
+    histogram!(QUERY_DURATION, "transport" => transport).record(rtt.as_secs_f64());
+}
+
+#[cfg(feature = "cache")]
+pub(crate) fn cache_hit() {
+    counter!(CACHE_HITS).increment(1);
+}
+
+#[cfg(feature = "cache")]
+pub(crate) fn cache_miss() {
+    counter!(CACHE_MISSES).increment(1);
+}
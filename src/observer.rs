@@ -0,0 +1,48 @@
+//! Hooks into what the resolver does, for custom logging, metrics or auditing.
+
+use crate::error::DnsError;
+use crate::{DNSPacket, RecordType};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Told about every query the resolver sends and every answer it takes from
+/// the caches. All methods do nothing unless overridden, and are called
+/// inline, so they should return quickly.
+pub trait QueryObserver: Send + Sync {
+    /// A query is about to be sent to `server`, once per attempt.
+    fn on_query_sent(&self, _server: IpAddr, _query: &DNSPacket) {}
+
+    /// `server` responded after `rtt`, before the response is checked
+    /// against the query.
+    fn on_response_received(&self, _server: IpAddr, _response: &DNSPacket, _rtt: Duration) {}
+
+    /// A positive or negative answer for `name` was found in the caches, so
+    /// no query was sent.
+    fn on_cache_hit(&self, _name: &str, _record_type: RecordType) {}
+
+    /// A query to `server` failed: the transport failed, no response came in
+    /// time, or the response did not match the query.
+    fn on_error(&self, _server: IpAddr, _query: &DNSPacket, _error: &DnsError) {}
+}
+
+/// The observers registered on a resolver.
+#[derive(Clone, Default)]
+pub(crate) struct Observers(pub(crate) Vec<Arc<dyn QueryObserver>>);
+
+impl Observers {
+    pub(crate) fn each(&self, f: impl Fn(&dyn QueryObserver)) {
+        for observer in &self.0 {
+            f(observer.as_ref());
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
@@ -0,0 +1,201 @@
+//! Offline analysis of captured DNS traffic. Reads classic libpcap files and
+//! parses the UDP and TCP payloads to or from port 53 into [`DNSPacket`]s.
+//!
+//! IP fragments are skipped and TCP streams are not reassembled, so only TCP
+//! segments holding whole length-prefixed messages are picked up.
+
+use crate::DNSPacket;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DNS_PORT: u16 = 53;
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Link-layer header types from the tcpdump.org registry.
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// A DNS message seen on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedPacket {
+    pub timestamp: SystemTime,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub transport: Transport,
+    pub packet: DNSPacket,
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads every DNS message from the capture file at `path`.
+pub fn read_file(path: impl AsRef<Path>) -> io::Result<Vec<CapturedPacket>> {
+    read(&std::fs::read(path)?)
+}
+
+/// Reads every DNS message from an in-memory capture. Frames that are not
+/// DNS, or do not parse as DNS, are skipped.
+pub fn read(capture: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    if capture.len() < GLOBAL_HEADER_LEN {
+        return Err(invalid(
+            "Capture is too short for a pcap header".to_string(),
+        ));
+    }
+    let magic = [capture[0], capture[1], capture[2], capture[3]];
+    let (big_endian, nanos) = match magic {
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        _ => return Err(invalid("Not a pcap file".to_string())),
+    };
+    // Record headers are only read while at least one fits in the capture.
+    let u32_at = |at: usize| {
+        let bytes = [
+            capture[at],
+            capture[at + 1],
+            capture[at + 2],
+            capture[at + 3],
+        ];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let link_type = u32_at(20) & 0xffff;
+
+    let mut packets = Vec::new();
+    let mut cursor = GLOBAL_HEADER_LEN;
+    while cursor + RECORD_HEADER_LEN <= capture.len() {
+        let seconds = u32_at(cursor);
+        let fraction = u32_at(cursor + 4);
+        let captured_len = u32_at(cursor + 8) as usize;
+        cursor += RECORD_HEADER_LEN;
+        let Some(frame) = capture.get(cursor..cursor + captured_len) else {
+            return Err(invalid(format!("Truncated pcap record at offset {cursor}")));
+        };
+        cursor += captured_len;
+
+        let subsec = if nanos {
+            Duration::from_nanos(fraction.into())
+        } else {
+            Duration::from_micros(fraction.into())
+        };
+        let timestamp = UNIX_EPOCH + Duration::from_secs(seconds.into()) + subsec;
+        if let Some(packet) = parse_frame(link_type, frame, timestamp) {
+            packets.push(packet);
+        }
+    }
+    Ok(packets)
+}
+
+fn parse_frame(link_type: u32, frame: &[u8], timestamp: SystemTime) -> Option<CapturedPacket> {
+    let (ethertype, ip) = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16_at(frame, 12)?;
+            let mut offset = 14;
+            while ethertype == ETHERTYPE_VLAN {
+                ethertype = u16_at(frame, offset + 2)?;
+                offset += 4;
+            }
+            (ethertype, frame.get(offset..)?)
+        }
+        LINKTYPE_LINUX_SLL => (u16_at(frame, 14)?, frame.get(16..)?),
+        // The family is in host byte order, so just look at the IP version.
+        LINKTYPE_NULL | LINKTYPE_RAW => {
+            let ip = if link_type == LINKTYPE_NULL {
+                frame.get(4..)?
+            } else {
+                frame
+            };
+            match ip.first()? >> 4 {
+                4 => (ETHERTYPE_IPV4, ip),
+                6 => (ETHERTYPE_IPV6, ip),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let (source, destination, protocol, segment) = match ethertype {
+        ETHERTYPE_IPV4 => parse_ipv4(ip)?,
+        ETHERTYPE_IPV6 => parse_ipv6(ip)?,
+        _ => return None,
+    };
+    let source_port = u16_at(segment, 0)?;
+    let destination_port = u16_at(segment, 2)?;
+    if source_port != DNS_PORT && destination_port != DNS_PORT {
+        return None;
+    }
+    let (transport, message) = match protocol {
+        PROTO_UDP => (Transport::Udp, segment.get(8..)?),
+        PROTO_TCP => {
+            let data_offset = (*segment.get(12)? >> 4) as usize * 4;
+            let payload = segment.get(data_offset..)?;
+            let len = u16_at(payload, 0)? as usize;
+            (Transport::Tcp, payload.get(2..2 + len)?)
+        }
+        _ => return None,
+    };
+    Some(CapturedPacket {
+        timestamp,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        transport,
+        packet: DNSPacket::parse(message).ok()?,
+    })
+}
+
+fn parse_ipv4(ip: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    let header_len = (*ip.first()? & 0x0f) as usize * 4;
+    let total_len = u16_at(ip, 2)? as usize;
+    let fragment = u16_at(ip, 6)?;
+    // More-fragments flag or a non-zero fragment offset.
+    if fragment & 0x3fff != 0 {
+        return None;
+    }
+    let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+    let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+    Some((
+        IpAddr::V4(Ipv4Addr::from(source)),
+        IpAddr::V4(Ipv4Addr::from(destination)),
+        *ip.get(9)?,
+        ip.get(header_len..total_len.min(ip.len()))?,
+    ))
+}
+
+/// Only handles UDP or TCP directly after the fixed header, without extension headers.
+fn parse_ipv6(ip: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    let payload_len = u16_at(ip, 4)? as usize;
+    let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+    let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+    Some((
+        IpAddr::V6(Ipv6Addr::from(source)),
+        IpAddr::V6(Ipv6Addr::from(destination)),
+        *ip.get(6)?,
+        ip.get(40..(40 + payload_len).min(ip.len()))?,
+    ))
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
@@ -0,0 +1,254 @@
+//! Asking the big public resolvers the same question at once, to see whether a
+//! change has reached their caches yet.
+
+use crate::config::QueryOptions;
+use crate::flags::Rcode;
+use crate::transport::DnsTransport;
+use crate::{Class, DNSResolver, RecordType};
+use serde::{Serialize, Serializer};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// An open recursive resolver run by a well-known operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicResolver {
+    pub name: &'static str,
+    pub address: Ipv4Addr,
+}
+
+impl PublicResolver {
+    const fn new(name: &'static str, address: [u8; 4]) -> Self {
+        let [a, b, c, d] = address;
+        PublicResolver {
+            name,
+            address: Ipv4Addr::new(a, b, c, d),
+        }
+    }
+}
+
+/// The resolvers [`DNSResolver::check_propagation`] is usually given: the
+/// largest unfiltered public services, each with its own caches.
+pub const PUBLIC_RESOLVERS: [PublicResolver; 8] = [
+    PublicResolver::new("Cloudflare", [1, 1, 1, 1]),
+    PublicResolver::new("Google", [8, 8, 8, 8]),
+    PublicResolver::new("Quad9", [9, 9, 9, 9]),
+    PublicResolver::new("OpenDNS", [208, 67, 222, 222]),
+    PublicResolver::new("AdGuard", [94, 140, 14, 140]),
+    PublicResolver::new("Level3", [4, 2, 2, 1]),
+    PublicResolver::new("UltraDNS", [64, 6, 64, 6]),
+    PublicResolver::new("Yandex", [77, 88, 8, 8]),
+];
+
+/// What a resolver answered, reduced to what should be the same everywhere
+/// once a change has propagated: TTLs and record order are left out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PropagatedAnswer {
+    /// The rdata of each record of the type asked for, sorted.
+    Records(Vec<String>),
+    NxDomain,
+    NoData,
+    /// No usable answer, such as a timeout or SERVFAIL. Never agrees or
+    /// disagrees with the other resolvers.
+    Failed(String),
+}
+
+/// One resolver's part in a [`Propagation`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolverAnswer {
+    pub resolver: PublicResolver,
+    pub answer: PropagatedAnswer,
+    /// The smallest TTL left on the records, for how long the resolver's
+    /// cache may keep serving them.
+    pub ttl: Option<u32>,
+    pub rtt: Duration,
+}
+
+/// How a set of resolvers answered the same question.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Propagation {
+    pub name: String,
+    pub record_type: RecordType,
+    /// In the order the resolvers were given.
+    pub answers: Vec<ResolverAnswer>,
+}
+
+impl Propagation {
+    /// Each distinct answer with the resolvers that gave it, most common
+    /// first. Failures are left out.
+    pub fn groups(&self) -> Vec<(&PropagatedAnswer, Vec<&ResolverAnswer>)> {
+        let mut groups: Vec<(&PropagatedAnswer, Vec<&ResolverAnswer>)> = Vec::new();
+        for answer in &self.answers {
+            if matches!(answer.answer, PropagatedAnswer::Failed(_)) {
+                continue;
+            }
+            match groups.iter_mut().find(|(seen, _)| **seen == answer.answer) {
+                Some((_, resolvers)) => resolvers.push(answer),
+                None => groups.push((&answer.answer, vec![answer])),
+            }
+        }
+        groups.sort_by_key(|(_, resolvers)| std::cmp::Reverse(resolvers.len()));
+        groups
+    }
+
+    /// Whether every resolver that answered gave the same answer.
+    pub fn agrees(&self) -> bool {
+        self.groups().len() <= 1
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &ResolverAnswer> {
+        self.answers
+            .iter()
+            .filter(|answer| matches!(answer.answer, PropagatedAnswer::Failed(_)))
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: &'a RecordType,
+    agrees: bool,
+    resolvers: Vec<Entry<'a>>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    name: &'static str,
+    address: Ipv4Addr,
+    /// One of records, nxdomain, nodata or failed.
+    status: &'static str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    records: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+    rtt_ms: u64,
+}
+
+/// A flat object with whether the resolvers agree and, for each, its status,
+/// records or error, TTL and round-trip time in milliseconds.
+impl Serialize for Propagation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let resolvers = self
+            .answers
+            .iter()
+            .map(|answer| {
+                let (status, records, error): (_, &[String], _) = match &answer.answer {
+                    PropagatedAnswer::Records(records) => ("records", records, None),
+                    PropagatedAnswer::NxDomain => ("nxdomain", &[], None),
+                    PropagatedAnswer::NoData => ("nodata", &[], None),
+                    PropagatedAnswer::Failed(error) => ("failed", &[], Some(error.as_str())),
+                };
+                Entry {
+                    name: answer.resolver.name,
+                    address: answer.resolver.address,
+                    status,
+                    records,
+                    error,
+                    ttl: answer.ttl,
+                    rtt_ms: answer.rtt.as_millis() as u64,
+                }
+            })
+            .collect();
+        Report {
+            name: &self.name,
+            record_type: &self.record_type,
+            agrees: self.agrees(),
+            resolvers,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<T: DnsTransport + Clone + 'static> DNSResolver<T> {
+    /// Asks each of `resolvers` for `name` at the same time, with recursion
+    /// desired, and compares what they answer. Nothing is read from or written
+    /// to this resolver's caches.
+    pub async fn check_propagation(
+        &self,
+        name: &str,
+        record_type: RecordType,
+        resolvers: &[PublicResolver],
+    ) -> Propagation {
+        let mut queries = JoinSet::new();
+        for (index, resolver) in resolvers.iter().copied().enumerate() {
+            let this = self.clone();
+            let name = name.to_string();
+            let record_type = record_type.clone();
+            queries.spawn(async move {
+                let answer = this.ask_public(&name, record_type, resolver).await;
+                (index, answer)
+            });
+        }
+        let mut answers: Vec<Option<ResolverAnswer>> = vec![None; resolvers.len()];
+        while let Some(joined) = queries.join_next().await {
+            let (index, answer) = joined.expect("propagation queries do not panic");
+            answers[index] = Some(answer);
+        }
+        Propagation {
+            name: name.to_string(),
+            record_type,
+            answers: answers.into_iter().flatten().collect(),
+        }
+    }
+
+    async fn ask_public(
+        &self,
+        name: &str,
+        record_type: RecordType,
+        resolver: PublicResolver,
+    ) -> ResolverAnswer {
+        let options = QueryOptions {
+            recursion_desired: Some(true),
+            ..QueryOptions::default()
+        };
+        let sent = Instant::now();
+        let response = self
+            .lookup_class(
+                name,
+                &resolver.address,
+                record_type.clone(),
+                Class::In,
+                &options,
+            )
+            .await;
+        let rtt = sent.elapsed();
+        let mut ttl = None;
+        let answer = match response {
+            Err(e) => PropagatedAnswer::Failed(e.to_string()),
+            Ok(response) if response.header.flags.rcode == Rcode::NxDomain => {
+                PropagatedAnswer::NxDomain
+            }
+            Ok(response) => match response.get_rcode_error(name, resolver.address) {
+                Some(e) => PropagatedAnswer::Failed(e.to_string()),
+                None => {
+                    let records: Vec<_> = response
+                        .answers
+                        .iter()
+                        .filter(|record| record.type_ == record_type)
+                        .collect();
+                    ttl = records.iter().map(|record| record.ttl).min();
+                    let mut data: Vec<String> = records
+                        .iter()
+                        .map(|record| record.data.to_string())
+                        .collect();
+                    data.sort();
+                    if data.is_empty() {
+                        PropagatedAnswer::NoData
+                    } else {
+                        PropagatedAnswer::Records(data)
+                    }
+                }
+            },
+        };
+        ResolverAnswer {
+            resolver,
+            answer,
+            ttl,
+            rtt,
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! The daemon's query history, kept as Pi-hole keeps it: a row per query
+//! answered, in an SQLite database, searched and aggregated through the
+//! daemon's HTTP API. Rows are written in batches on a thread of their own,
+//! so logging never holds up an answer, and deleted once older than the
+//! retention period.
+
+use crate::error::{DnsError, Result};
+use rusqlite::types::Value;
+use rusqlite::{Connection, Row, params, params_from_iter};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queries (
+        timestamp_ms INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        name TEXT NOT NULL,
+        type TEXT NOT NULL,
+        rcode TEXT NOT NULL,
+        latency_ms REAL NOT NULL,
+        cache_hit INTEGER NOT NULL,
+        blocked INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS queries_timestamp ON queries (timestamp_ms);
+";
+
+/// How often rows past the retention period are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How many rows a search returns unless asked for a number.
+const DEFAULT_SEARCH_LIMIT: u32 = 100;
+
+/// How many values a summary ranks unless asked for a number.
+const DEFAULT_TOP_LIMIT: u32 = 10;
+
+/// One query the daemon answered.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryLogEntry {
+    /// When the query arrived, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub client: IpAddr,
+    /// The queried name, in lowercase without a trailing dot.
+    pub name: String,
+    pub record_type: String,
+    pub rcode: String,
+    /// How long answering took.
+    pub latency_ms: f64,
+    /// Whether the answer came from the cache.
+    pub cache_hit: bool,
+    /// Whether a blocklist covered the name.
+    pub blocked: bool,
+}
+
+/// Which queries `/api/queries` returns and `/api/queries/summary` counts;
+/// every field left out matches them all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct QueryLogFilter {
+    pub client: Option<IpAddr>,
+    /// A part of the queried name, matched case-insensitively.
+    pub name: Option<String>,
+    pub record_type: Option<String>,
+    pub rcode: Option<String>,
+    pub blocked: Option<bool>,
+    pub cache_hit: Option<bool>,
+    /// Only queries from this time on, in milliseconds since the Unix epoch.
+    pub since_ms: Option<u64>,
+    /// Only queries before this time.
+    pub until_ms: Option<u64>,
+    /// How many rows a search returns, the newest first, or how many values
+    /// a summary ranks. 100 and 10 unless given.
+    pub limit: Option<u32>,
+}
+
+impl QueryLogFilter {
+    /// The filter's `WHERE` clause, with the values of its parameters.
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        let mut condition = |sql: &str, value: Value| {
+            values.push(value);
+            conditions.push(format!("{sql} ?{}", values.len()));
+        };
+        if let Some(client) = self.client {
+            condition("client =", Value::Text(client.to_string()));
+        }
+        if let Some(name) = &self.name {
+            let pattern = format!("%{}%", name.trim_end_matches('.').to_ascii_lowercase());
+            condition("name LIKE", Value::Text(pattern));
+        }
+        if let Some(record_type) = &self.record_type {
+            condition("type =", Value::Text(record_type.to_ascii_uppercase()));
+        }
+        if let Some(rcode) = &self.rcode {
+            condition("rcode =", Value::Text(rcode.to_ascii_uppercase()));
+        }
+        if let Some(blocked) = self.blocked {
+            condition("blocked =", Value::Integer(blocked.into()));
+        }
+        if let Some(cache_hit) = self.cache_hit {
+            condition("cache_hit =", Value::Integer(cache_hit.into()));
+        }
+        if let Some(since_ms) = self.since_ms {
+            condition("timestamp_ms >=", Value::Integer(since_ms as i64));
+        }
+        if let Some(until_ms) = self.until_ms {
+            condition("timestamp_ms <", Value::Integer(until_ms as i64));
+        }
+        if conditions.is_empty() {
+            return (String::new(), values);
+        }
+        (format!("WHERE {}", conditions.join(" AND ")), values)
+    }
+}
+
+/// What `/api/queries/summary` ranks the queries by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryLogField {
+    #[default]
+    Name,
+    Client,
+    RecordType,
+    Rcode,
+}
+
+impl QueryLogField {
+    fn column(self) -> &'static str {
+        match self {
+            QueryLogField::Name => "name",
+            QueryLogField::Client => "client",
+            QueryLogField::RecordType => "type",
+            QueryLogField::Rcode => "rcode",
+        }
+    }
+}
+
+/// Totals over the queries a filter matches, with the values of one field
+/// seen most often among them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryLogSummary {
+    pub queries: u64,
+    pub blocked: u64,
+    pub cache_hits: u64,
+    pub average_latency_ms: Option<f64>,
+    /// The most frequent values, the most frequent first.
+    pub top: Vec<QueryLogCount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueryLogCount {
+    pub value: String,
+    pub queries: u64,
+}
+
+/// A handle on the query history. Clones share the database.
+#[derive(Debug, Clone)]
+pub struct QueryLog {
+    connection: Arc<Mutex<Connection>>,
+    sender: Sender<QueryLogEntry>,
+}
+
+impl QueryLog {
+    /// Opens the database at `path`, creating it if needed, and starts the
+    /// thread writing to it. Rows older than `retention` are deleted now and
+    /// then every hour.
+    pub fn open(path: &Path, retention: Duration) -> Result<Self> {
+        let connection = Connection::open(path).map_err(sqlite_error)?;
+        connection.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        let connection = Arc::new(Mutex::new(connection));
+        let (sender, receiver) = mpsc::channel();
+        let writer = connection.clone();
+        std::thread::Builder::new()
+            .name("dnsr-query-log".to_string())
+            .spawn(move || write(&writer, &receiver, retention))?;
+        Ok(QueryLog { connection, sender })
+    }
+
+    /// Queues `entry` to be written. Entries are lost if writing fails.
+    pub fn record(&self, entry: QueryLogEntry) {
+        let _ = self.sender.send(entry);
+    }
+
+    /// The queries `filter` matches, the newest first.
+    pub async fn search(&self, filter: QueryLogFilter) -> Result<Vec<QueryLogEntry>> {
+        self.read(move |connection| {
+            let (conditions, mut values) = filter.to_sql();
+            values.push(Value::Integer(
+                filter.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).into(),
+            ));
+            let sql = format!(
+                "SELECT timestamp_ms, client, name, type, rcode, latency_ms, cache_hit, blocked \
+                 FROM queries {conditions} ORDER BY timestamp_ms DESC LIMIT ?{}",
+                values.len()
+            );
+            let mut statement = connection.prepare(&sql)?;
+            let rows = statement.query_map(params_from_iter(values), entry)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Totals over the queries `filter` matches, ranking the values of `by`.
+    pub async fn summarize(
+        &self,
+        filter: QueryLogFilter,
+        by: QueryLogField,
+    ) -> Result<QueryLogSummary> {
+        self.read(move |connection| {
+            let (conditions, mut values) = filter.to_sql();
+            let (queries, blocked, cache_hits, average_latency_ms) = connection.query_row(
+                &format!(
+                    "SELECT COUNT(*), TOTAL(blocked), TOTAL(cache_hit), AVG(latency_ms) \
+                     FROM queries {conditions}"
+                ),
+                params_from_iter(&values),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+            values.push(Value::Integer(
+                filter.limit.unwrap_or(DEFAULT_TOP_LIMIT).into(),
+            ));
+            let column = by.column();
+            let sql = format!(
+                "SELECT {column}, COUNT(*) AS queries FROM queries {conditions} \
+                 GROUP BY {column} ORDER BY queries DESC, {column} LIMIT ?{}",
+                values.len()
+            );
+            let mut statement = connection.prepare(&sql)?;
+            let top = statement
+                .query_map(params_from_iter(values), |row| {
+                    Ok(QueryLogCount {
+                        value: row.get(0)?,
+                        queries: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(QueryLogSummary {
+                queries,
+                blocked: f64::round(blocked) as u64,
+                cache_hits: f64::round(cache_hits) as u64,
+                average_latency_ms,
+                top,
+            })
+        })
+        .await
+    }
+
+    /// Runs `query` on a blocking thread, where waiting for the writer to
+    /// release the database holds up no task.
+    async fn read<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            query(&connection.lock().unwrap_or_else(|e| e.into_inner()))
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(sqlite_error)
+    }
+}
+
+/// Writes entries as they are queued, each batch in one transaction, until
+/// every [`QueryLog`] is dropped.
+fn write(connection: &Mutex<Connection>, receiver: &Receiver<QueryLogEntry>, retention: Duration) {
+    let mut pruned: Option<Instant> = None;
+    while let Ok(first) = receiver.recv() {
+        let batch: Vec<QueryLogEntry> = std::iter::once(first).chain(receiver.try_iter()).collect();
+        let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = insert(&mut connection, &batch) {
+            tracing::warn!(error = %e, "Writing the query log failed");
+        }
+        if pruned.is_none_or(|pruned| pruned.elapsed() >= PRUNE_INTERVAL) {
+            let cutoff = unix_ms().saturating_sub(retention.as_millis() as u64);
+            if let Err(e) = connection.execute(
+                "DELETE FROM queries WHERE timestamp_ms < ?1",
+                [cutoff as i64],
+            ) {
+                tracing::warn!(error = %e, "Pruning the query log failed");
+            }
+            pruned = Some(Instant::now());
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, batch: &[QueryLogEntry]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO queries \
+             (timestamp_ms, client, name, type, rcode, latency_ms, cache_hit, blocked) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for entry in batch {
+            statement.execute(params![
+                entry.timestamp_ms as i64,
+                entry.client.to_string(),
+                entry.name,
+                entry.record_type,
+                entry.rcode,
+                entry.latency_ms,
+                entry.cache_hit,
+                entry.blocked,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+fn entry(row: &Row<'_>) -> rusqlite::Result<QueryLogEntry> {
+    let client: String = row.get(1)?;
+    Ok(QueryLogEntry {
+        timestamp_ms: row.get::<_, i64>(0)? as u64,
+        client: client.parse().map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
+        })?,
+        name: row.get(2)?,
+        record_type: row.get(3)?,
+        rcode: row.get(4)?,
+        latency_ms: row.get(5)?,
+        cache_hit: row.get(6)?,
+        blocked: row.get(7)?,
+    })
+}
+
+fn sqlite_error(e: rusqlite::Error) -> DnsError {
+    DnsError::IoError(io::Error::other(e))
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub(crate) fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
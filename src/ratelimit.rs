@@ -0,0 +1,129 @@
+//! Response Rate Limiting for the daemon's UDP listener, after BIND's: each
+//! network of clients may be sent so many responses a second, so a flood of
+//! queries with a victim's spoofed address cannot be answered with a flood
+//! of responses to it. Past the limit, responses are dropped, except every
+//! so often one that slips through truncated, telling a real client behind
+//! the address to retry over TCP, where addresses cannot be spoofed.
+
+use crate::daemon::RateLimitConfig;
+use crate::edns::ClientSubnet;
+use crate::error::{DnsError, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// How many networks are counted before those idle long enough to have
+/// their whole allowance again are forgotten.
+pub(crate) const MAX_TRACKED_NETWORKS: usize = 100_000;
+
+/// What to do with the response to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Send it, the client being within its limit.
+    Respond,
+    /// Send an empty, truncated response in its place.
+    Slip,
+    /// Send nothing.
+    Drop,
+}
+
+/// The allowance each network has left. Clones share it.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    networks: Arc<Mutex<HashMap<IpAddr, Allowance>>>,
+}
+
+#[derive(Debug)]
+struct Allowance {
+    /// Responses that can be sent at once, refilled at the configured rate
+    /// up to one second's worth.
+    responses: f64,
+    updated: Instant,
+    /// Responses limited since the network was last within its limit.
+    limited: u32,
+}
+
+impl Allowance {
+    fn refill(&mut self, now: Instant, rate: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.responses = (self.responses + elapsed * rate).min(rate);
+        self.updated = self.updated.max(now);
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Result<Self> {
+        if config.responses_per_second == 0 {
+            return Err(DnsError::InvalidConfig(
+                "rate_limit.responses_per_second must be at least 1".to_string(),
+            ));
+        }
+        Ok(RateLimiter {
+            config: Arc::new(config.clone()),
+            networks: Arc::default(),
+        })
+    }
+
+    /// Counts a response to `client`, telling what to do with it.
+    pub fn check(&self, client: IpAddr) -> Verdict {
+        self.check_at(Instant::now(), client)
+    }
+
+    pub(crate) fn check_at(&self, now: Instant, client: IpAddr) -> Verdict {
+        let client = client.to_canonical();
+        if self
+            .config
+            .exempt
+            .iter()
+            .any(|network| network.contains(client))
+        {
+            return Verdict::Respond;
+        }
+        let prefix = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        let network = ClientSubnet::new(client, prefix).address;
+        let rate = f64::from(self.config.responses_per_second);
+        let mut networks = self.networks.lock().unwrap_or_else(|e| e.into_inner());
+        if !networks.contains_key(&network) && networks.len() >= MAX_TRACKED_NETWORKS {
+            networks.retain(|_, allowance| {
+                allowance.refill(now, rate);
+                allowance.responses < rate
+            });
+            // Every network being busy, the tenth with the most allowance
+            // left is forgotten, so a flood of new ones is not kept without
+            // bound yet cannot reset the allowance of those limited.
+            if networks.len() >= MAX_TRACKED_NETWORKS {
+                let mut idlest: Vec<(f64, IpAddr)> = networks
+                    .iter()
+                    .map(|(network, allowance)| (allowance.responses, *network))
+                    .collect();
+                let forgotten = idlest.len() / 10;
+                idlest.select_nth_unstable_by(forgotten, |a, b| b.0.total_cmp(&a.0));
+                for (_, network) in &idlest[..forgotten] {
+                    networks.remove(network);
+                }
+            }
+        }
+        let allowance = networks.entry(network).or_insert(Allowance {
+            responses: rate,
+            updated: now,
+            limited: 0,
+        });
+        allowance.refill(now, rate);
+        if allowance.responses >= 1.0 {
+            allowance.responses -= 1.0;
+            allowance.limited = 0;
+            return Verdict::Respond;
+        }
+        allowance.limited = allowance.limited.wrapping_add(1);
+        match self.config.slip {
+            0 => Verdict::Drop,
+            slip if allowance.limited.is_multiple_of(slip) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+}
@@ -0,0 +1,1291 @@
+//! The iterative resolver, sending queries over a [`DnsTransport`] and
+//! following referrals from the configured upstreams.
+
+#[cfg(feature = "cache")]
+use crate::cache::{
+    CachedRecords, DOMAIN_TO_IP_CACHE, IP_TO_DOMAIN_CACHE, NEGATIVE_CACHE, NegativeEntry,
+    RECORD_CACHE,
+};
+use crate::config::{
+    CachePolicy, ClientSubnetPolicy, DNSResolverBuilder, IpStrategy, QueryOptions,
+    TransportPreference, UpstreamPolicy,
+};
+#[cfg(feature = "dnstap")]
+use crate::dnstap;
+use crate::edns::{ClientSubnet, DEFAULT_UDP_PAYLOAD_SIZE, Edns, EdnsOption};
+use crate::error::{DnsError, Result};
+use crate::flags::Rcode;
+use crate::hosts::Hosts;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::observer::Observers;
+use crate::stats::{ServerHealth, ServerStats, ServerTable};
+use crate::transport::{DNS_PORT, DnsTransport, TcpTransport, UdpTransport};
+use crate::{Class, DNSPacket, DNSRecord, DNSRecordData, Mx, RecordType, Srv, build_query};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_util::sync::CancellationToken;
+use tracing::{Span, debug, field, info, instrument, warn};
+#[cfg(target_arch = "wasm32")]
+use wasmtimer::{
+    std::Instant,
+    tokio::{timeout, timeout_at},
+};
+
+/// How many CNAMEs a resolution follows, and how deep lookups of nameserver
+/// names nest, so a loop of aliases or of glueless delegations ends.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How many referrals a resolution follows, enough for any real delegation
+/// path, so a loop of referrals still ends.
+const MAX_REFERRALS: usize = 32;
+
+/// Long enough that a random label is never a real name.
+const WILDCARD_LABEL_LEN: usize = 16;
+const WILDCARD_LABEL_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Why a response holds no answer, as told by [`DNSPacket::get_negative_answer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NegativeKind {
+    /// The name does not exist at all.
+    NxDomain,
+    /// The name exists but holds no records of the queried type.
+    NoData,
+}
+
+/// How the resolver reads responses.
+impl DNSPacket {
+    pub(crate) fn get_answer_ip(&self) -> Option<Ipv4Addr> {
+        for answer in &self.answers {
+            if let DNSRecordData::Ipv4Addr(name) = answer.data {
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn get_answer_domain(&self) -> Option<&str> {
+        for answer in &self.answers {
+            if let DNSRecordData::Name(name) = &answer.data {
+                return Some(name.as_str());
+            }
+        }
+        None
+    }
+
+    /// The first TXT answer, its character-strings concatenated.
+    pub(crate) fn get_answer_txt(&self) -> Option<String> {
+        for answer in &self.answers {
+            if let DNSRecordData::Txt(strings) = &answer.data {
+                return Some(strings.concat());
+            }
+        }
+        None
+    }
+
+    pub(crate) fn get_nameserver_ips(&self) -> Vec<Ipv4Addr> {
+        self.additionals
+            .iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Ipv4Addr(ip) => Some(ip),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn get_nameserver_domain(&self) -> Option<&str> {
+        for record in &self.authorities {
+            if let DNSRecordData::Name(name) = &record.data {
+                return Some(name.as_str());
+            }
+        }
+        None
+    }
+
+    /// Whether this is a response to `query`: same ID and the same questions,
+    /// in order, so answers to a different or multi-question message are never
+    /// mistaken for ours.
+    pub(crate) fn answers_query(&self, query: &DNSPacket) -> bool {
+        self.header.flags.qr
+            && self.header.id == query.header.id
+            && self.questions.len() == query.questions.len()
+            && self.questions.iter().zip(&query.questions).all(|(a, b)| {
+                a.name.eq_ignore_ascii_case(&b.name) && a.type_ == b.type_ && a.class == b.class
+            })
+    }
+
+    /// Classifies an NXDOMAIN or NODATA response and computes how long it may be
+    /// cached: the lesser of the SOA TTL and SOA minimum (RFC 2308), capped by `ceiling`.
+    /// Responses without an SOA in the authority section are not cacheable.
+    pub(crate) fn get_negative_answer(
+        &self,
+        ceiling: Duration,
+    ) -> Option<(NegativeKind, Option<Duration>)> {
+        let kind = match self.header.flags.rcode {
+            Rcode::NxDomain => NegativeKind::NxDomain,
+            Rcode::NoError if self.answers.is_empty() => NegativeKind::NoData,
+            _ => return None,
+        };
+        let ttl = self
+            .authorities
+            .iter()
+            .find_map(|record| match &record.data {
+                DNSRecordData::Soa(soa) => Some(record.ttl.min(soa.minimum)),
+                _ => None,
+            });
+        if kind == NegativeKind::NoData && ttl.is_none() {
+            // A NOERROR response without answers or SOA is a referral.
+            return None;
+        }
+        Some((
+            kind,
+            ttl.map(|ttl| Duration::from_secs(ttl.into()).min(ceiling)),
+        ))
+    }
+
+    /// Errors for response codes that end the resolution outright. NXDOMAIN is
+    /// left to [`DNSPacket::get_negative_answer`] so it can be cached.
+    pub(crate) fn get_rcode_error(&self, domain_name: &str, server: Ipv4Addr) -> Option<DnsError> {
+        let domain = domain_name.to_string();
+        match self.header.flags.rcode {
+            Rcode::NoError | Rcode::NxDomain => None,
+            Rcode::ServFail => Some(DnsError::ServFail { domain, server }),
+            rcode => Some(DnsError::ErrorResponse {
+                domain,
+                server,
+                rcode,
+            }),
+        }
+    }
+}
+
+/// The RRSIG records of `section` covering records of `type_`.
+fn signatures_over(section: &[DNSRecord], type_: &RecordType) -> Vec<DNSRecord> {
+    let covered = (type_.clone() as u16).to_be_bytes();
+    section
+        .iter()
+        .filter(|record| {
+            record.type_ == RecordType::Rrsig
+                && matches!(&record.data, DNSRecordData::Data(rdata) if rdata.starts_with(&covered))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether `dns_packet` was tailored to the client subnet of its query,
+/// making it unfit to cache for other clients (RFC 7871 §7.3).
+#[cfg(feature = "cache")]
+fn tailored(dns_packet: &DNSPacket) -> bool {
+    dns_packet
+        .edns
+        .as_ref()
+        .and_then(Edns::client_subnet)
+        .is_some_and(|subnet| subnet.scope_prefix > 0)
+}
+
+fn negative_error(kind: NegativeKind, domain_name: &str) -> DnsError {
+    let domain = domain_name.to_string();
+    match kind {
+        NegativeKind::NxDomain => DnsError::NxDomain(domain),
+        NegativeKind::NoData => DnsError::NoRecordsFound(domain),
+    }
+}
+
+/// A mail exchanger with the addresses it resolved to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailExchanger {
+    pub mx: Mx,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// What a server discloses about itself over CHAOS-class TXT queries. Each
+/// is `None` when the server refused or did not answer that query.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ServerIdentity {
+    /// `version.bind`: usually the server software and its version.
+    pub version: Option<String>,
+    /// `hostname.bind`: the host name of the instance that answered.
+    pub hostname: Option<String>,
+    /// `id.server` (RFC 4892): the instance's identifier, the same for every
+    /// vendor.
+    pub id: Option<String>,
+}
+
+/// How a zone answered for a name that should not exist, from
+/// [`DNSResolver::detect_wildcard`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WildcardCheck {
+    /// The random name asked about.
+    pub probe: String,
+    /// Whether the zone answered for the name instead of denying it exists.
+    pub is_wildcard: bool,
+    /// The A records synthesized for the name. Empty if the wildcard only has
+    /// records of other types.
+    pub records: Vec<DNSRecord>,
+}
+
+/// The records a lookup found, and how it found them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupResult {
+    /// The records of the queried type, with their remaining TTLs.
+    pub records: Vec<DNSRecord>,
+    /// The server that answered, or `None` for answers from the cache.
+    pub server: Option<Ipv4Addr>,
+    pub from_cache: bool,
+    /// How long the whole resolution took, CNAMEs and referrals included.
+    pub elapsed: Duration,
+    /// The identifier the answering server gave when NSID was requested, which
+    /// tells the nodes behind an anycast address apart.
+    pub nsid: Option<Vec<u8>>,
+    /// The RRSIG records over `records` that came with them when the query set
+    /// the DO bit, unvalidated. None come from the cache.
+    pub signatures: Vec<DNSRecord>,
+}
+
+impl LookupResult {
+    /// The smallest TTL among the records, for how long the answer may be reused.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.records.iter().map(|record| record.ttl).min()
+    }
+}
+
+/// An iterative resolver, starting from the configured upstream servers and
+/// following referrals down to an authoritative answer. Queries are carried by
+/// `T`, plain UDP unless another [`DnsTransport`] is configured.
+///
+/// Every lookup can be abandoned by dropping its future: in-flight sockets are
+/// closed, and nothing is cached from a resolution that did not finish.
+#[derive(Debug, Clone)]
+pub struct DNSResolver<T = UdpTransport> {
+    pub(crate) transport: T,
+    /// Shared by clones, so that re-priming reaches all of them.
+    pub(crate) upstreams: Arc<RwLock<Vec<Ipv4Addr>>>,
+    /// Servers listening on a port other than 53.
+    pub(crate) ports: Arc<HashMap<Ipv4Addr, u16>>,
+    pub(crate) upstream_policy: UpstreamPolicy,
+    /// Where [`UpstreamPolicy::RoundRobin`] starts next, shared by clones.
+    pub(crate) next_upstream: Arc<AtomicUsize>,
+    pub(crate) timeout: Duration,
+    pub(crate) adaptive_timeout: bool,
+    pub(crate) retries: u32,
+    pub(crate) max_in_flight: usize,
+    /// Bounds the queries outstanding across every clone of the resolver.
+    pub(crate) in_flight: Arc<Semaphore>,
+    pub(crate) recursion_desired: bool,
+    pub(crate) use_cache: bool,
+    pub(crate) negative_ttl_ceiling: Duration,
+    pub(crate) udp_payload_size: Option<u16>,
+    pub(crate) request_nsid: bool,
+    pub(crate) client_subnet: Option<ClientSubnet>,
+    /// Upstreams told of clients otherwise than by `client_subnet`.
+    pub(crate) client_subnets: Arc<HashMap<Ipv4Addr, ClientSubnetPolicy>>,
+    pub(crate) ip_strategy: IpStrategy,
+    pub(crate) hosts: std::sync::Arc<Hosts>,
+    pub(crate) dnssec_ok: bool,
+    pub(crate) checking_disabled: bool,
+    pub(crate) randomize_case: bool,
+    pub(crate) rng: Option<SharedRng>,
+    pub(crate) observers: Observers,
+    pub(crate) servers: ServerTable,
+    /// Carries queries made with [`TransportPreference::Tcp`].
+    pub(crate) tcp: TcpTransport,
+    #[cfg(feature = "dnstap")]
+    pub(crate) dnstap: Option<std::sync::Arc<std::sync::Mutex<dnstap::DnstapWriter>>>,
+}
+
+/// A random number generator shared by every clone of a resolver.
+#[derive(Clone)]
+pub(crate) struct SharedRng(pub(crate) Arc<Mutex<dyn RngCore + Send>>);
+
+impl fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedRng").finish_non_exhaustive()
+    }
+}
+
+impl Default for DNSResolver {
+    fn default() -> Self {
+        DNSResolverBuilder::default()
+            .build()
+            .expect("the default configuration is valid")
+    }
+}
+
+impl DNSResolver {
+    pub fn builder() -> DNSResolverBuilder {
+        DNSResolverBuilder::default()
+    }
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// The transport picks the local address, so queries are logged as sent
+    /// from the unspecified address.
+    #[cfg(feature = "dnstap")]
+    fn log_dnstap(&self, type_: dnstap::MessageType, server: SocketAddr, message: &[u8]) {
+        let Some(writer) = &self.dnstap else {
+            return;
+        };
+        let local = (Ipv4Addr::UNSPECIFIED, 0).into();
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.log(type_, local, server, message, std::time::SystemTime::now()) {
+            warn!(error = %e, "Failed to write dnstap message");
+        }
+    }
+
+    /// Runs `f` with the configured generator, or the thread-local one.
+    fn with_rng<R>(&self, f: impl FnOnce(&mut dyn RngCore) -> R) -> R {
+        match &self.rng {
+            Some(SharedRng(rng)) => f(&mut *rng.lock().unwrap_or_else(|e| e.into_inner())),
+            None => f(&mut rand::rng()),
+        }
+    }
+
+    /// What a query made with `options` travels over, for metrics.
+    #[cfg(feature = "metrics")]
+    fn protocol(&self, options: &QueryOptions) -> &'static str {
+        match options.transport {
+            TransportPreference::Configured => self.transport.protocol(),
+            TransportPreference::Tcp => "tcp",
+        }
+    }
+
+    pub(crate) fn query_edns(
+        &self,
+        dnssec_ok: bool,
+        nsid: bool,
+        client_subnet: Option<ClientSubnet>,
+    ) -> Option<Edns> {
+        let mut options = Vec::new();
+        if nsid {
+            options.push(EdnsOption::Nsid(Vec::new()));
+        }
+        if let Some(subnet) = client_subnet {
+            options.push(EdnsOption::ClientSubnet(subnet));
+        }
+        let wanted = !options.is_empty() || dnssec_ok || self.udp_payload_size.is_some();
+        wanted.then(|| Edns {
+            udp_payload_size: self.udp_payload_size.unwrap_or(DEFAULT_UDP_PAYLOAD_SIZE),
+            dnssec_ok,
+            options,
+            ..Edns::default()
+        })
+    }
+
+    /// The EDNS Client Subnet option sent to `server`, if any, as its
+    /// [`ClientSubnetPolicy`] has it.
+    pub(crate) fn client_subnet_for(
+        &self,
+        server: &Ipv4Addr,
+        options: &QueryOptions,
+    ) -> Option<ClientSubnet> {
+        match self.client_subnets.get(server) {
+            None => self.client_subnet.clone(),
+            Some(ClientSubnetPolicy::Strip) => None,
+            Some(ClientSubnetPolicy::Inject {
+                ipv4_prefix,
+                ipv6_prefix,
+            }) => match &options.client_subnet {
+                Some(client) => {
+                    let max_prefix = if client.address.is_ipv4() {
+                        *ipv4_prefix
+                    } else {
+                        *ipv6_prefix
+                    };
+                    Some(ClientSubnet::new(
+                        client.address,
+                        client.source_prefix.min(max_prefix),
+                    ))
+                }
+                None => self.client_subnet.clone(),
+            },
+        }
+    }
+
+    #[cfg_attr(not(feature = "cache"), allow(unused_variables))]
+    pub(crate) async fn check_negative(
+        &self,
+        dns_packet: &DNSPacket,
+        domain_name: &str,
+        record_type: RecordType,
+        cache: CachePolicy,
+    ) -> Option<DnsError> {
+        let (kind, ttl) = dns_packet.get_negative_answer(self.negative_ttl_ceiling)?;
+        #[cfg(feature = "cache")]
+        if let Some(ttl) =
+            ttl.filter(|_| self.use_cache && cache != CachePolicy::Bypass && !tailored(dns_packet))
+        {
+            NEGATIVE_CACHE
+                .insert(
+                    (domain_name.to_string(), record_type),
+                    NegativeEntry { kind, ttl },
+                )
+                .await;
+        }
+        Some(negative_error(kind, domain_name))
+    }
+
+    /// Sends a single non-recursive query to `ip_addr` and returns its response.
+    pub async fn lookup(
+        &self,
+        domain_name: &str,
+        ip_addr: &Ipv4Addr,
+        record_type: RecordType,
+    ) -> Result<DNSPacket> {
+        self.lookup_class(
+            domain_name,
+            ip_addr,
+            record_type,
+            Class::In,
+            &QueryOptions::default(),
+        )
+        .await
+    }
+
+    #[instrument(
+        name = "query",
+        skip_all,
+        fields(
+            qname = domain_name,
+            qtype = %record_type,
+            qclass = %class,
+            server = %ip_addr,
+            rtt_ms = field::Empty,
+        )
+    )]
+    pub(crate) async fn lookup_class(
+        &self,
+        domain_name: &str,
+        ip_addr: &Ipv4Addr,
+        record_type: RecordType,
+        class: Class,
+        options: &QueryOptions,
+    ) -> Result<DNSPacket> {
+        let edns = self.query_edns(
+            options.dnssec_ok.unwrap_or(self.dnssec_ok),
+            options.nsid.unwrap_or(self.request_nsid),
+            self.client_subnet_for(ip_addr, options),
+        );
+        let mut query = build_query(domain_name, record_type, class, edns)?;
+        query.header.flags.rd = options.recursion_desired.unwrap_or(self.recursion_desired);
+        query.header.flags.cd = self.checking_disabled;
+        self.with_rng(|rng| {
+            query.header.id = rng.random();
+            if self.randomize_case {
+                for question in &mut query.questions {
+                    question.name = randomize_case(&question.name, rng);
+                }
+            }
+        });
+
+        let server = IpAddr::V4(*ip_addr);
+        let address = SocketAddr::new(server, self.port(ip_addr));
+        #[cfg(feature = "dnstap")]
+        self.log_dnstap(
+            dnstap::MessageType::ResolverQuery,
+            address,
+            &query.to_bytes(),
+        );
+        let mut attempt = 0;
+        let (response, rtt) = loop {
+            // Waiting here counts against neither the timeout nor the RTT. The
+            // semaphore is never closed, so this always yields a permit.
+            let _permit = self.in_flight.acquire().await;
+            debug!(attempt, "Sending query");
+            self.observers.each(|o| o.on_query_sent(server, &query));
+            #[cfg(feature = "metrics")]
+            metrics::query_sent(&query, self.protocol(options), attempt);
+            let sent = Instant::now();
+            let exchange = async {
+                match options.transport {
+                    TransportPreference::Configured => {
+                        self.transport.exchange_at(&query, address).await
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    TransportPreference::Tcp => self.tcp.exchange_at(&query, address).await,
+                    #[cfg(target_arch = "wasm32")]
+                    TransportPreference::Tcp => Err(DnsError::InvalidConfig(
+                        "TCP is not available on wasm32".to_string(),
+                    )),
+                }
+            };
+            let wait = match options.timeout {
+                Some(wait) => wait,
+                // The last attempt always waits the full timeout.
+                None if self.adaptive_timeout && attempt < self.retries => {
+                    self.servers.timeout(*ip_addr, self.timeout, attempt)
+                }
+                None => self.timeout,
+            };
+            let result = timeout(wait, exchange)
+                .await
+                .unwrap_or(Err(DnsError::Timeout(*ip_addr)));
+            match result {
+                Ok(response) => break (response, sent.elapsed()),
+                Err(e) => {
+                    if matches!(e, DnsError::Timeout(_) | DnsError::IoError(_)) {
+                        let timed_out = matches!(e, DnsError::Timeout(_));
+                        self.servers
+                            .record_failure(*ip_addr, sent.elapsed(), timed_out);
+                    }
+                    self.observers.each(|o| o.on_error(server, &query, &e));
+                    if !matches!(e, DnsError::Timeout(_)) || attempt >= self.retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+            }
+        };
+        self.servers
+            .record_response(*ip_addr, rtt, response.header.flags.rcode);
+        #[cfg(feature = "metrics")]
+        metrics::response_received(
+            &query,
+            response.header.flags.rcode,
+            self.protocol(options),
+            rtt,
+        );
+        self.observers
+            .each(|o| o.on_response_received(server, &response, rtt));
+        Span::current().record("rtt_ms", rtt.as_millis() as u64);
+        info!(
+            rcode = %response.header.flags.rcode,
+            answers = response.answers.len(),
+            authorities = response.authorities.len(),
+            "Received response"
+        );
+        #[cfg(feature = "dnstap")]
+        self.log_dnstap(
+            dnstap::MessageType::ResolverResponse,
+            address,
+            &response.to_bytes(),
+        );
+        let case_matches = !self.randomize_case
+            || response
+                .questions
+                .iter()
+                .zip(&query.questions)
+                .all(|(a, b)| a.name == b.name);
+        if !response.answers_query(&query) || !case_matches {
+            let error = DnsError::MalformedPacket(format!(
+                "Response from {ip_addr} does not match the query for {domain_name}"
+            ));
+            self.observers.each(|o| o.on_error(server, &query, &error));
+            return Err(error);
+        }
+        Ok(response)
+    }
+
+    /// Sends `query`, an already encoded message, to `server` as it is and
+    /// returns the response's bytes unchecked, for protocol experiments and
+    /// conformance tests. Nothing is retried, cached or validated, but the
+    /// timeout and the limit on queries in flight apply.
+    pub async fn exchange_raw(&self, query: &[u8], server: &Ipv4Addr) -> Result<Vec<u8>> {
+        let address = SocketAddr::new(IpAddr::V4(*server), self.port(server));
+        #[cfg(feature = "dnstap")]
+        self.log_dnstap(dnstap::MessageType::ResolverQuery, address, query);
+        let _permit = self.in_flight.acquire().await;
+        let response = timeout(self.timeout, self.transport.exchange_raw(query, address))
+            .await
+            .unwrap_or(Err(DnsError::Timeout(*server)))?;
+        #[cfg(feature = "dnstap")]
+        self.log_dnstap(dnstap::MessageType::ResolverResponse, address, &response);
+        Ok(response)
+    }
+
+    /// Sends the first query of a resolution, trying upstreams in the order
+    /// of the [`UpstreamPolicy`] and moving on when one does not respond.
+    /// Returns the server that answered.
+    async fn lookup_upstream(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<(Ipv4Addr, DNSPacket)> {
+        let mut upstreams = self.upstreams();
+        match self.upstream_policy {
+            UpstreamPolicy::Fastest => self.servers.rank(&mut upstreams),
+            UpstreamPolicy::Sequential => {}
+            UpstreamPolicy::Random => self.with_rng(|rng| upstreams.shuffle(rng)),
+            UpstreamPolicy::RoundRobin if !upstreams.is_empty() => {
+                let start = self.next_upstream.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+                upstreams.rotate_left(start);
+            }
+            UpstreamPolicy::RoundRobin => {}
+            UpstreamPolicy::Race => {
+                return self
+                    .race_upstreams(&upstreams, domain_name, record_type, options)
+                    .await;
+            }
+        }
+        let mut last_error = None;
+        for upstream in &upstreams {
+            match self
+                .lookup_class(
+                    domain_name,
+                    upstream,
+                    record_type.clone(),
+                    Class::In,
+                    options,
+                )
+                .await
+            {
+                Ok(packet) => return Ok((*upstream, packet)),
+                Err(e @ (DnsError::Timeout(_) | DnsError::IoError(_))) => {
+                    warn!(%upstream, error = %e, "Upstream failed");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| DnsError::Unresolvable(domain_name.to_string())))
+    }
+
+    /// Queries every upstream at once, returning the first response. Fails
+    /// only when all of them have, with the last error.
+    async fn race_upstreams(
+        &self,
+        upstreams: &[Ipv4Addr],
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<(Ipv4Addr, DNSPacket)> {
+        let mut pending: Vec<_> = upstreams
+            .iter()
+            .map(|upstream| {
+                let record_type = record_type.clone();
+                Box::pin(async move {
+                    let result = self
+                        .lookup_class(domain_name, upstream, record_type, Class::In, options)
+                        .await;
+                    (*upstream, result)
+                })
+            })
+            .collect();
+        let mut last_error = None;
+        std::future::poll_fn(|cx| {
+            let mut i = 0;
+            while i < pending.len() {
+                let Poll::Ready((upstream, result)) = pending[i].as_mut().poll(cx) else {
+                    i += 1;
+                    continue;
+                };
+                drop(pending.swap_remove(i));
+                match result {
+                    Ok(packet) => return Poll::Ready(Ok((upstream, packet))),
+                    Err(e) => {
+                        warn!(%upstream, error = %e, "Upstream failed");
+                        last_error = Some(e);
+                    }
+                }
+            }
+            if pending.is_empty() {
+                Poll::Ready(Err(last_error
+                    .take()
+                    .unwrap_or_else(|| DnsError::Unresolvable(domain_name.to_string()))))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// The servers resolution currently starts from.
+    pub fn upstreams(&self) -> Vec<Ipv4Addr> {
+        self.upstreams
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The port `server` is queried on.
+    pub fn port(&self, server: &Ipv4Addr) -> u16 {
+        self.ports.get(server).copied().unwrap_or(DNS_PORT)
+    }
+
+    /// The best of the nameserver addresses a referral carries.
+    fn pick_nameserver(&self, referral: &DNSPacket) -> Option<Ipv4Addr> {
+        let mut servers = referral.get_nameserver_ips();
+        self.servers.rank(&mut servers);
+        servers.first().copied()
+    }
+
+    /// The round-trip time and failures measured of each server queried so
+    /// far, shared with every clone of this resolver.
+    pub fn server_stats(&self) -> HashMap<Ipv4Addr, ServerStats> {
+        self.servers.snapshot()
+    }
+
+    /// Latency histograms and RCODE and error counts of each server queried in
+    /// the last few minutes, shared with every clone of this resolver.
+    pub fn server_health(&self) -> HashMap<Ipv4Addr, ServerHealth> {
+        self.servers.health()
+    }
+
+    /// Asks `server` for `version.bind` in class CH, which most server software
+    /// answers with its name and version unless configured to hide it.
+    pub async fn server_version(&self, server: &Ipv4Addr) -> Result<String> {
+        self.chaos_txt("version.bind", server).await
+    }
+
+    /// Asks `server` for `hostname.bind` in class CH, identifying the instance
+    /// behind an anycast address.
+    pub async fn server_hostname(&self, server: &Ipv4Addr) -> Result<String> {
+        self.chaos_txt("hostname.bind", server).await
+    }
+
+    /// Asks `server` for `version.bind`, `hostname.bind` and `id.server` at
+    /// once, for auditing a fleet. Fails only if the server answered none of
+    /// them; a refusal just leaves that field empty.
+    pub async fn probe_server(&self, server: &Ipv4Addr) -> Result<ServerIdentity> {
+        let (version, hostname, id) = tokio::join!(
+            self.chaos_txt("version.bind", server),
+            self.chaos_txt("hostname.bind", server),
+            self.chaos_txt("id.server", server),
+        );
+        let unreachable = |result: &Result<String>| {
+            matches!(result, Err(DnsError::Timeout(_) | DnsError::IoError(_)))
+        };
+        if unreachable(&version) && unreachable(&hostname) && unreachable(&id) {
+            return Err(version.unwrap_err());
+        }
+        Ok(ServerIdentity {
+            version: version.ok(),
+            hostname: hostname.ok(),
+            id: id.ok(),
+        })
+    }
+
+    /// Asks for the A records of a random label under `zone`, past the caches,
+    /// to tell whether the zone has a wildcard. In a zone with one every name
+    /// resolves, so bulk scans can discard answers matching those records.
+    pub async fn detect_wildcard(&self, zone: &str) -> Result<WildcardCheck> {
+        let label: String = self.with_rng(|rng| {
+            (0..WILDCARD_LABEL_LEN)
+                .map(|_| {
+                    WILDCARD_LABEL_CHARS[rng.random_range(0..WILDCARD_LABEL_CHARS.len())] as char
+                })
+                .collect()
+        });
+        let probe = format!("{label}.{}", zone.trim_end_matches('.'));
+        let options = QueryOptions {
+            cache: CachePolicy::Bypass,
+            ..QueryOptions::default()
+        };
+        let (is_wildcard, records) =
+            match self.resolve_detailed(&probe, RecordType::A, &options).await {
+                Ok(result) => (true, result.records),
+                Err(DnsError::NoRecordsFound(_)) => (true, Vec::new()),
+                Err(DnsError::NxDomain(_)) => (false, Vec::new()),
+                Err(e) => return Err(e),
+            };
+        Ok(WildcardCheck {
+            probe,
+            is_wildcard,
+            records,
+        })
+    }
+
+    async fn chaos_txt(&self, name: &str, server: &Ipv4Addr) -> Result<String> {
+        let dns_packet = self
+            .lookup_class(
+                name,
+                server,
+                RecordType::Txt,
+                Class::Ch,
+                &QueryOptions::default(),
+            )
+            .await?;
+        if let Some(err) = dns_packet.get_rcode_error(name, *server) {
+            return Err(err);
+        }
+        dns_packet
+            .get_answer_txt()
+            .ok_or_else(|| DnsError::NoRecordsFound(name.to_string()))
+    }
+
+    pub async fn resolve(&self, domain_name: &str) -> Result<Ipv4Addr> {
+        self.resolve_nested(domain_name, 0).await
+    }
+
+    /// [`DNSResolver::resolve`] `depth` CNAMEs or nameserver lookups into
+    /// another resolution.
+    #[instrument(name = "resolve", skip(self, depth), fields(qtype = "A"))]
+    async fn resolve_nested(&self, domain_name: &str, depth: usize) -> Result<Ipv4Addr> {
+        if depth > MAX_CNAME_CHAIN {
+            return Err(DnsError::Unresolvable(domain_name.to_string()));
+        }
+        #[cfg(feature = "cache")]
+        if self.use_cache {
+            if let Some(ip) = DOMAIN_TO_IP_CACHE.get(domain_name).await {
+                self.observers
+                    .each(|o| o.on_cache_hit(domain_name, RecordType::A));
+                #[cfg(feature = "metrics")]
+                metrics::cache_hit();
+                return Ok(ip);
+            }
+            let negative_key = (domain_name.to_string(), RecordType::A);
+            if let Some(entry) = NEGATIVE_CACHE.get(&negative_key).await {
+                self.observers
+                    .each(|o| o.on_cache_hit(domain_name, RecordType::A));
+                #[cfg(feature = "metrics")]
+                metrics::cache_hit();
+                return Err(negative_error(entry.kind, domain_name));
+            }
+            #[cfg(feature = "metrics")]
+            metrics::cache_miss();
+        }
+        let (mut ip_addr, mut dns_packet) = self
+            .lookup_upstream(domain_name, RecordType::A, &QueryOptions::default())
+            .await?;
+        for _ in 0..MAX_REFERRALS {
+            if let Some(err) = dns_packet.get_rcode_error(domain_name, ip_addr) {
+                return Err(err);
+            }
+            if let Some(ip) = dns_packet.get_answer_ip() {
+                #[cfg(feature = "cache")]
+                if self.use_cache && !tailored(&dns_packet) {
+                    DOMAIN_TO_IP_CACHE.insert(domain_name.to_string(), ip).await;
+                }
+                return Ok(ip);
+            } else if let Some(name) = dns_packet.get_answer_domain() {
+                return Box::pin(self.resolve_nested(name, depth + 1)).await;
+            } else if let Some(err) = self
+                .check_negative(&dns_packet, domain_name, RecordType::A, CachePolicy::Use)
+                .await
+            {
+                return Err(err);
+            } else if let Some(ns_ip) = self.pick_nameserver(&dns_packet) {
+                ip_addr = ns_ip;
+            } else if let Some(name) = dns_packet.get_nameserver_domain() {
+                ip_addr = Box::pin(self.resolve_nested(name, depth + 1)).await?;
+            } else {
+                return Err(DnsError::Unresolvable(domain_name.to_string()));
+            }
+            dns_packet = self.lookup(domain_name, &ip_addr, RecordType::A).await?;
+        }
+        Err(DnsError::Unresolvable(domain_name.to_string()))
+    }
+
+    /// Resolves `domain_name` to every record of `record_type`, following
+    /// CNAMEs. Records are cached for their smallest TTL.
+    pub async fn resolve_record(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+    ) -> Result<Vec<DNSRecord>> {
+        self.resolve_record_with(domain_name, record_type, &QueryOptions::default())
+            .await
+    }
+
+    /// Like [`DNSResolver::resolve_record`], with `options` overriding the
+    /// resolver configuration for the queries about `domain_name` and the
+    /// CNAMEs it leads to. Nameserver addresses are resolved as usual.
+    pub async fn resolve_record_with(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<Vec<DNSRecord>> {
+        let result = self
+            .resolve_detailed(domain_name, record_type, options)
+            .await?;
+        Ok(result.records)
+    }
+
+    /// Like [`DNSResolver::resolve_record_with`], also telling where the
+    /// records came from and how long resolving them took.
+    pub async fn resolve_detailed(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Result<LookupResult> {
+        self.resolve_detailed_nested(domain_name, record_type, options, 0)
+            .await
+    }
+
+    /// [`DNSResolver::resolve_detailed`] `depth` CNAMEs or nameserver
+    /// lookups into another resolution.
+    #[instrument(name = "resolve_detailed", skip(self, options, depth))]
+    async fn resolve_detailed_nested(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+        depth: usize,
+    ) -> Result<LookupResult> {
+        if depth > MAX_CNAME_CHAIN {
+            return Err(DnsError::Unresolvable(domain_name.to_string()));
+        }
+        let started = Instant::now();
+        #[cfg(feature = "cache")]
+        let key = (domain_name.to_string(), record_type.clone());
+        #[cfg(feature = "cache")]
+        if self.use_cache && options.cache == CachePolicy::Use {
+            if let Some(cached) = RECORD_CACHE.get(&key).await {
+                cached.mark_used();
+                self.observers
+                    .each(|o| o.on_cache_hit(domain_name, record_type.clone()));
+                #[cfg(feature = "metrics")]
+                metrics::cache_hit();
+                return Ok(LookupResult {
+                    records: cached.remaining(),
+                    server: None,
+                    from_cache: true,
+                    elapsed: started.elapsed(),
+                    nsid: None,
+                    signatures: Vec::new(),
+                });
+            }
+            if let Some(entry) = NEGATIVE_CACHE.get(&key).await {
+                self.observers
+                    .each(|o| o.on_cache_hit(domain_name, record_type.clone()));
+                #[cfg(feature = "metrics")]
+                metrics::cache_hit();
+                return Err(negative_error(entry.kind, domain_name));
+            }
+            #[cfg(feature = "metrics")]
+            metrics::cache_miss();
+        }
+        let (mut ip_addr, mut dns_packet) = self
+            .lookup_upstream(domain_name, record_type.clone(), options)
+            .await?;
+        for _ in 0..MAX_REFERRALS {
+            if let Some(err) = dns_packet.get_rcode_error(domain_name, ip_addr) {
+                return Err(err);
+            }
+            let records: Vec<DNSRecord> = dns_packet
+                .answers
+                .iter()
+                .filter(|record| record.type_ == record_type)
+                .cloned()
+                .collect();
+            if !records.is_empty() {
+                #[cfg(feature = "cache")]
+                if self.use_cache && options.cache != CachePolicy::Bypass && !tailored(&dns_packet)
+                {
+                    RECORD_CACHE
+                        .insert(key, CachedRecords::new(records.clone()))
+                        .await;
+                }
+                return Ok(LookupResult {
+                    records,
+                    server: Some(ip_addr),
+                    from_cache: false,
+                    elapsed: started.elapsed(),
+                    nsid: dns_packet
+                        .edns
+                        .as_ref()
+                        .and_then(Edns::nsid)
+                        .map(<[u8]>::to_vec),
+                    signatures: signatures_over(&dns_packet.answers, &record_type),
+                });
+            } else if let Some(name) = dns_packet.get_answer_domain() {
+                let target =
+                    Box::pin(self.resolve_detailed_nested(name, record_type, options, depth + 1))
+                        .await?;
+                return Ok(LookupResult {
+                    elapsed: started.elapsed(),
+                    ..target
+                });
+            } else if let Some(err) = self
+                .check_negative(&dns_packet, domain_name, record_type.clone(), options.cache)
+                .await
+            {
+                return Err(err);
+            } else if let Some(ns_ip) = self.pick_nameserver(&dns_packet) {
+                ip_addr = ns_ip;
+            } else if let Some(name) = dns_packet.get_nameserver_domain() {
+                ip_addr = Box::pin(self.resolve_nested(name, depth + 1)).await?;
+            } else {
+                return Err(DnsError::Unresolvable(domain_name.to_string()));
+            }
+            dns_packet = self
+                .lookup_class(
+                    domain_name,
+                    &ip_addr,
+                    record_type.clone(),
+                    Class::In,
+                    options,
+                )
+                .await?;
+        }
+        Err(DnsError::Unresolvable(domain_name.to_string()))
+    }
+
+    /// Every address of `host`, from the hosts file if listed there and
+    /// otherwise from its A and AAAA records, ordered by the configured
+    /// [`IpStrategy`]. Fails only if no family yields an address.
+    #[instrument(skip(self))]
+    pub async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let (first, second) = match self.ip_strategy {
+            IpStrategy::Ipv4Only => (RecordType::A, None),
+            IpStrategy::Ipv6Only => (RecordType::Aaaa, None),
+            IpStrategy::Ipv4ThenIpv6 => (RecordType::A, Some(RecordType::Aaaa)),
+            IpStrategy::Ipv6ThenIpv4 => (RecordType::Aaaa, Some(RecordType::A)),
+        };
+        let wanted = |ip: &IpAddr| match ip {
+            IpAddr::V4(_) => first == RecordType::A || second == Some(RecordType::A),
+            IpAddr::V6(_) => first == RecordType::Aaaa || second == Some(RecordType::Aaaa),
+        };
+        let mut listed = self.hosts.lookup(host).to_vec();
+        if !listed.is_empty() {
+            listed.retain(wanted);
+            listed.sort_by_key(|ip| ip.is_ipv4() != (first == RecordType::A));
+            return Ok(listed);
+        }
+
+        let (first, second) = tokio::join!(self.lookup_addresses(host, first), async {
+            match second {
+                Some(second) => Some(self.lookup_addresses(host, second).await),
+                None => None,
+            }
+        });
+        match (first, second) {
+            (Ok(mut first), Some(Ok(second))) => {
+                first.extend(second);
+                Ok(first)
+            }
+            (Ok(addresses), _) | (Err(_), Some(Ok(addresses))) => Ok(addresses),
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    async fn lookup_addresses(&self, host: &str, record_type: RecordType) -> Result<Vec<IpAddr>> {
+        let records = self.resolve_record(host, record_type).await?;
+        Ok(records
+            .iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Ipv4Addr(ip) => Some(IpAddr::V4(ip)),
+                DNSRecordData::Ipv6Addr(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The mail exchangers of `domain`, most preferred first. A null MX
+    /// (RFC 7505) yields an empty list.
+    pub async fn lookup_mx(&self, domain: &str) -> Result<Vec<Mx>> {
+        let mut exchangers: Vec<Mx> = self
+            .resolve_record(domain, RecordType::Mx)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Mx(mx) if !mx.exchange.is_empty() => Some(mx),
+                _ => None,
+            })
+            .collect();
+        exchangers.sort_by_key(|mx| mx.preference);
+        Ok(exchangers)
+    }
+
+    /// Like [`DNSResolver::lookup_mx`], also resolving each exchanger with
+    /// [`DNSResolver::lookup_ip`]. Exchangers that do not resolve are kept,
+    /// with no addresses.
+    pub async fn lookup_mx_addresses(&self, domain: &str) -> Result<Vec<MailExchanger>> {
+        let mut exchangers = Vec::new();
+        for mx in self.lookup_mx(domain).await? {
+            let addresses = self.lookup_ip(&mx.exchange).await.unwrap_or_default();
+            exchangers.push(MailExchanger { mx, addresses });
+        }
+        Ok(exchangers)
+    }
+
+    /// The hosts to deliver mail for `domain` to, in the order SMTP tries them
+    /// (RFC 5321 section 5.1): its mail exchangers by preference with their
+    /// addresses, or `domain` itself at preference 0 when it has no MX
+    /// records. Exchangers that do not resolve are left out, and a null MX
+    /// yields an empty list.
+    pub async fn resolve_mail_hosts(&self, domain: &str) -> Result<Vec<MailExchanger>> {
+        let exchangers = match self.lookup_mx(domain).await {
+            Err(DnsError::NoRecordsFound(_)) => {
+                let mx = Mx {
+                    preference: 0,
+                    exchange: domain.to_string(),
+                };
+                let addresses = self.lookup_ip(domain).await?;
+                return Ok(vec![MailExchanger { mx, addresses }]);
+            }
+            exchangers => exchangers?,
+        };
+        let mut hosts = Vec::new();
+        for mx in exchangers {
+            if let Ok(addresses) = self.lookup_ip(&mx.exchange).await
+                && !addresses.is_empty()
+            {
+                hosts.push(MailExchanger { mx, addresses });
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// The addresses to dial for `service` over `proto` at `domain`, from the
+    /// SRV records of `_service._proto.domain` in the order RFC 2782 tries
+    /// them: by priority, and at each priority in a random order weighted by
+    /// the servers' weights. Targets that do not resolve are left out, and a
+    /// target of `.` yields an empty list, as the service is not offered.
+    pub async fn resolve_service(
+        &self,
+        service: &str,
+        proto: &str,
+        domain: &str,
+    ) -> Result<Vec<SocketAddr>> {
+        let owner = format!(
+            "_{}._{}.{domain}",
+            service.trim_start_matches('_'),
+            proto.trim_start_matches('_')
+        );
+        let servers: Vec<Srv> = self
+            .resolve_record(&owner, RecordType::Srv)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Srv(srv) if !srv.target.is_empty() => Some(srv),
+                _ => None,
+            })
+            .collect();
+        let servers = self.with_rng(|rng| srv_order(servers, rng));
+        let mut addresses = Vec::new();
+        for srv in servers {
+            if let Ok(ips) = self.lookup_ip(&srv.target).await {
+                addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, srv.port)));
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Like [`DNSResolver::resolve`], giving up once `deadline` passes.
+    pub async fn resolve_with_deadline(
+        &self,
+        domain_name: &str,
+        deadline: Instant,
+    ) -> Result<Ipv4Addr> {
+        timeout_at(deadline, self.resolve(domain_name))
+            .await
+            .unwrap_or_else(|_| Err(DnsError::DeadlineExceeded(domain_name.to_string())))
+    }
+
+    /// Like [`DNSResolver::resolve`], giving up as soon as `token` is cancelled.
+    pub async fn resolve_with_cancellation(
+        &self,
+        domain_name: &str,
+        token: &CancellationToken,
+    ) -> Result<Ipv4Addr> {
+        tokio::select! {
+            result = self.resolve(domain_name) => result,
+            _ = token.cancelled() => Err(DnsError::Cancelled(domain_name.to_string())),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn reverse_resolve(&self, req_ip_addr: &Ipv4Addr) -> Result<String> {
+        let ip_addr = req_ip_addr.octets();
+        let ip_domain = format!(
+            "{}.{}.{}.{}.in-addr.arpa",
+            ip_addr[3], ip_addr[2], ip_addr[1], ip_addr[0]
+        );
+        #[cfg(feature = "cache")]
+        if self.use_cache
+            && let Some(domain) = IP_TO_DOMAIN_CACHE.get(req_ip_addr).await
+        {
+            self.observers
+                .each(|o| o.on_cache_hit(&ip_domain, RecordType::Ptr));
+            return Ok(domain);
+        }
+        #[cfg(feature = "cache")]
+        if self.use_cache
+            && let Some(entry) = NEGATIVE_CACHE
+                .get(&(ip_domain.clone(), RecordType::Ptr))
+                .await
+        {
+            self.observers
+                .each(|o| o.on_cache_hit(&ip_domain, RecordType::Ptr));
+            return Err(negative_error(entry.kind, &ip_domain));
+        }
+        let (mut ns_ip_addr, mut dns_packet) = self
+            .lookup_upstream(&ip_domain, RecordType::Ptr, &QueryOptions::default())
+            .await?;
+        for _ in 0..MAX_REFERRALS {
+            if let Some(err) = dns_packet.get_rcode_error(&ip_domain, ns_ip_addr) {
+                return Err(err);
+            }
+            if let Some(domain) = dns_packet.get_answer_domain() {
+                #[cfg(feature = "cache")]
+                if self.use_cache && !tailored(&dns_packet) {
+                    IP_TO_DOMAIN_CACHE
+                        .insert(*req_ip_addr, domain.to_string())
+                        .await;
+                }
+                return Ok(domain.to_string());
+            } else if let Some(err) = self
+                .check_negative(&dns_packet, &ip_domain, RecordType::Ptr, CachePolicy::Use)
+                .await
+            {
+                return Err(err);
+            } else if let Some(ns_ip) = self.pick_nameserver(&dns_packet) {
+                ns_ip_addr = ns_ip;
+            } else if let Some(name) = dns_packet.get_nameserver_domain() {
+                ns_ip_addr = self.resolve(name).await?;
+            } else {
+                return Err(DnsError::Unresolvable(ip_domain));
+            }
+            dns_packet = self
+                .lookup(&ip_domain, &ns_ip_addr, RecordType::Ptr)
+                .await?;
+        }
+        Err(DnsError::Unresolvable(ip_domain))
+    }
+}
+
+/// `servers` in the order RFC 2782 tries them: by priority, then by repeated
+/// weighted picks among those of the same priority. Servers of weight 0 are
+/// picked last, unless all of them have weight 0.
+fn srv_order(mut servers: Vec<Srv>, rng: &mut dyn RngCore) -> Vec<Srv> {
+    servers.sort_by_key(|srv| (srv.priority, srv.weight != 0));
+    let mut ordered = Vec::with_capacity(servers.len());
+    while !servers.is_empty() {
+        let priority = servers[0].priority;
+        let same = servers
+            .iter()
+            .take_while(|srv| srv.priority == priority)
+            .count();
+        let total: u32 = servers[..same]
+            .iter()
+            .map(|srv| u32::from(srv.weight))
+            .sum();
+        let pick = rng.random_range(0..=total);
+        let mut sum = 0;
+        let index = servers[..same]
+            .iter()
+            .position(|srv| {
+                sum += u32::from(srv.weight);
+                sum >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(servers.remove(index));
+    }
+    ordered
+}
+
+/// Flips each letter of `name` to a random case, for DNS 0x20.
+pub(crate) fn randomize_case(name: &str, rng: &mut dyn RngCore) -> String {
+    name.chars()
+        .map(|c| {
+            if rng.random::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
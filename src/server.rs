@@ -1,9 +1,65 @@
-use crate::DNSResolver;
+use crate::error::{DnsError, Result};
+use crate::propagation::{PUBLIC_RESOLVERS, Propagation};
+use crate::{DNSPacket, doh, idna};
+use crate::{DNSResolver, LATENCY_BUCKETS_MS, RecordType, ServerHealth, ServerStats};
 use axum::Json;
-use axum::extract::Query;
-use axum::http::StatusCode;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::{Instrument, Span, Subscriber, info, info_span, warn};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, fmt};
+
+/// How the server binary writes its logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for shipping to ELK, Loki and the like.
+    /// Each carries the fields of its event and of the request span around it.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format {other}, expected text or json")),
+        }
+    }
+}
+
+impl LogFormat {
+    /// A layer writing every event to `writer` in this format.
+    pub fn layer<S, W>(self, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let layer = fmt::layer().with_writer(writer);
+        match self {
+            LogFormat::Text => layer.boxed(),
+            LogFormat::Json => layer
+                .json()
+                .with_current_span(true)
+                .with_span_list(false)
+                .boxed(),
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Dns {
@@ -16,21 +72,428 @@ pub struct IpAddr {
 }
 
 pub async fn resolve_dns(
+    State(resolver): State<DNSResolver>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    #[cfg(feature = "otel")] headers: HeaderMap,
     Query(params): Query<Dns>,
-) -> Result<Json<IpAddr>, (StatusCode, Json<String>)> {
-    let ip = DNSResolver::default().resolve(params.domain.as_str()).await;
+) -> std::result::Result<Json<IpAddr>, (StatusCode, Json<String>)> {
+    let span = info_span!("resolve_dns", %client, domain = %params.domain, qtype = "A");
+    #[cfg(feature = "otel")]
+    otel::continue_trace(&span, &headers);
+    let ip = handle(&span, resolver.resolve(params.domain.as_str())).await;
     match ip {
         Ok(ip) => Ok(Json(IpAddr { ip })),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(e.to_string()))),
+        Err(e) => Err((status_for(&e), Json(e.to_string()))),
+    }
+}
+
+/// Runs a request's resolution in its span, then logs how long it took and
+/// how it went.
+pub(crate) async fn handle<T>(
+    span: &Span,
+    resolution: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let started = Instant::now();
+    let result = resolution.instrument(span.clone()).await;
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.in_scope(|| match &result {
+        Ok(_) => info!(duration_ms, outcome = "ok", "Handled request"),
+        Err(e) => warn!(
+            duration_ms,
+            outcome = "error",
+            status = status_for(e).as_u16(),
+            error = %e,
+            "Handled request"
+        ),
+    });
+    result
+}
+
+fn status_for(e: &DnsError) -> StatusCode {
+    match e {
+        DnsError::NxDomain(_) | DnsError::NoRecordsFound(_) => StatusCode::NOT_FOUND,
+        DnsError::InvalidName(_) | DnsError::InvalidNumber(_) | DnsError::InvalidMessage(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        DnsError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_GATEWAY,
     }
 }
 
 pub async fn resolve_ip(
+    State(resolver): State<DNSResolver>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    #[cfg(feature = "otel")] headers: HeaderMap,
     Query(params): Query<IpAddr>,
-) -> Result<Json<Dns>, (StatusCode, Json<String>)> {
-    let domain = DNSResolver::default().reverse_resolve(&params.ip).await;
+) -> std::result::Result<Json<Dns>, (StatusCode, Json<String>)> {
+    let span = info_span!("resolve_ip", %client, ip = %params.ip, qtype = "PTR");
+    #[cfg(feature = "otel")]
+    otel::continue_trace(&span, &headers);
+    let domain = handle(&span, resolver.reverse_resolve(&params.ip)).await;
     match domain {
-        Ok(domain) => Ok(Json(Dns { domain })),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(e.to_string()))),
+        Ok(domain) => Ok(Json(Dns {
+            domain: idna::to_unicode(&domain),
+        })),
+        Err(e) => Err((status_for(&e), Json(e.to_string()))),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PropagationQuery {
+    domain: String,
+    /// A record type such as `AAAA`, `A` if not given.
+    #[serde(rename = "type")]
+    record_type: Option<String>,
+}
+
+/// Asks the well-known public resolvers for a name at once and reports
+/// whether their answers agree.
+pub async fn check_propagation(
+    State(resolver): State<DNSResolver>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    #[cfg(feature = "otel")] headers: HeaderMap,
+    Query(params): Query<PropagationQuery>,
+) -> std::result::Result<Json<Propagation>, (StatusCode, Json<String>)> {
+    let record_type = match params.record_type.as_deref().map(RecordType::from_str) {
+        None => RecordType::A,
+        Some(Ok(record_type)) => record_type,
+        Some(Err(e)) => return Err((StatusCode::BAD_REQUEST, Json(e.to_string()))),
+    };
+    let span = info_span!(
+        "check_propagation",
+        %client,
+        domain = %params.domain,
+        qtype = %record_type
+    );
+    #[cfg(feature = "otel")]
+    otel::continue_trace(&span, &headers);
+    let propagation = resolver.check_propagation(&params.domain, record_type, &PUBLIC_RESOLVERS);
+    match handle(&span, async { Ok(propagation.await) }).await {
+        Ok(propagation) => Ok(Json(propagation)),
+        Err(e) => Err((status_for(&e), Json(e.to_string()))),
+    }
+}
+
+/// The media type of DNS messages in DoH requests and responses.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DoH proxy mode: `/dns-query` requests (RFC 8484) are passed to a classic
+/// DNS server over UDP, or over TCP when the response is truncated, and its
+/// responses passed back, so that a server without DoH support can be
+/// reached over HTTP.
+#[derive(Debug, Clone)]
+pub struct DohProxy {
+    resolver: DNSResolver,
+    upstream: Ipv4Addr,
+    minimal_responses: bool,
+}
+
+impl DohProxy {
+    pub fn new(upstream: SocketAddrV4) -> Result<Self> {
+        let resolver = DNSResolver::builder()
+            .upstreams([*upstream.ip()])
+            .upstream_port(*upstream.ip(), upstream.port())
+            .cache(false)
+            .build()?;
+        Ok(DohProxy {
+            resolver,
+            upstream: *upstream.ip(),
+            minimal_responses: false,
+        })
+    }
+
+    /// Strips the upstream's responses of the authority and additional
+    /// records clients do not need, with [`DNSPacket::minimize`]. Off by
+    /// default, passing responses on as they are.
+    pub fn minimal_responses(mut self, minimal: bool) -> Self {
+        self.minimal_responses = minimal;
+        self
+    }
+
+    /// Sends `query` to the upstream as it is and returns its response. Only
+    /// the header is checked, to refuse messages that are not queries.
+    pub async fn forward(&self, query: &[u8]) -> Result<Vec<u8>> {
+        if DNSPacket::parse(query).is_ok_and(|packet| packet.header.flags.qr) {
+            return Err(DnsError::InvalidMessage(
+                "Expected a query, not a response".to_string(),
+            ));
+        }
+        let response = self.resolver.exchange_raw(query, &self.upstream).await?;
+        if !self.minimal_responses {
+            return Ok(response);
+        }
+        // What cannot be parsed is passed on untouched rather than failed.
+        Ok(match DNSPacket::parse(&response) {
+            Ok(mut packet) => {
+                packet.minimize();
+                packet.to_bytes()
+            }
+            Err(_) => response,
+        })
+    }
+}
+
+/// What answers the queries of `/dns-query` requests: a [`DohProxy`]
+/// forwarding them, or the daemon resolving them itself.
+pub trait DohBackend: Clone + Send + Sync + 'static {
+    /// The response to `query` from `client`, both in wire format.
+    fn answer(
+        &self,
+        client: SocketAddr,
+        query: &[u8],
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+impl DohBackend for DohProxy {
+    fn answer(
+        &self,
+        _client: SocketAddr,
+        query: &[u8],
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        self.forward(query)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DohQuery {
+    /// The query in wire format, as unpadded base64url.
+    dns: String,
+}
+
+/// A DoH GET request, with the query in the `dns` parameter.
+pub async fn doh_get<B: DohBackend>(
+    State(backend): State<B>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<DohQuery>,
+) -> std::result::Result<Response, (StatusCode, Json<String>)> {
+    let query =
+        doh::decode(&params.dns).map_err(|e| (StatusCode::BAD_REQUEST, Json(e.to_string())))?;
+    answer_doh(&backend, client, "GET", &query).await
+}
+
+/// A DoH POST request, with the query as its body.
+pub async fn doh_post<B: DohBackend>(
+    State(backend): State<B>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<Response, (StatusCode, Json<String>)> {
+    if headers.get(CONTENT_TYPE) != Some(&HeaderValue::from_static(DNS_MESSAGE)) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(format!("Expected a body of type {DNS_MESSAGE}")),
+        ));
+    }
+    answer_doh(&backend, client, "POST", &body).await
+}
+
+/// Answers `query` and wraps the response, cacheable for as long as its
+/// shortest TTL as RFC 8484 asks.
+async fn answer_doh<B: DohBackend>(
+    backend: &B,
+    client: SocketAddr,
+    method: &'static str,
+    query: &[u8],
+) -> std::result::Result<Response, (StatusCode, Json<String>)> {
+    let span = info_span!("doh", %client, method);
+    let response = handle(&span, backend.answer(client, query))
+        .await
+        .map_err(|e| (status_for(&e), Json(e.to_string())))?;
+    let max_age = DNSPacket::parse(&response).ok().and_then(|packet| {
+        let ttl = packet
+            .answers
+            .iter()
+            .chain(&packet.authorities)
+            .map(|record| record.ttl)
+            .min()?;
+        HeaderValue::try_from(format!("max-age={ttl}")).ok()
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+    if let Some(max_age) = max_age {
+        headers.insert(CACHE_CONTROL, max_age);
+    }
+    Ok((headers, response).into_response())
+}
+
+/// What the resolver has measured of one upstream, for `/stats`.
+#[derive(Serialize, Debug, Clone)]
+pub struct UpstreamStats {
+    server: Ipv4Addr,
+    srtt_ms: Option<u64>,
+    failures: u32,
+    /// Upper bounds of the `latency` buckets but the last, in milliseconds.
+    latency_buckets_ms: [u64; LATENCY_BUCKETS_MS.len()],
+    latency: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    p50_ms: Option<u64>,
+    p99_ms: Option<u64>,
+    rcodes: BTreeMap<String, u64>,
+    timeouts: u64,
+    network_errors: u64,
+}
+
+impl UpstreamStats {
+    fn new(server: Ipv4Addr, stats: Option<&ServerStats>, health: ServerHealth) -> Self {
+        let percentile_ms = |fraction| {
+            health
+                .percentile(fraction)
+                .map(|bound| bound.as_millis() as u64)
+        };
+        UpstreamStats {
+            server,
+            srtt_ms: stats.map(|stats| stats.srtt.as_millis() as u64),
+            failures: stats.map_or(0, |stats| stats.failures),
+            latency_buckets_ms: LATENCY_BUCKETS_MS,
+            p50_ms: percentile_ms(0.5),
+            p99_ms: percentile_ms(0.99),
+            latency: health.latency,
+            rcodes: health
+                .rcodes
+                .iter()
+                .map(|(rcode, count)| (rcode.to_string(), *count))
+                .collect(),
+            timeouts: health.timeouts,
+            network_errors: health.network_errors,
+        }
+    }
+}
+
+/// Latency histograms and RCODE and error counts of each upstream, in the
+/// order they are configured.
+pub async fn stats(State(resolver): State<DNSResolver>) -> Json<Vec<UpstreamStats>> {
+    Json(upstream_stats(&resolver))
+}
+
+fn upstream_stats(resolver: &DNSResolver) -> Vec<UpstreamStats> {
+    let stats = resolver.server_stats();
+    let mut health = resolver.server_health();
+    resolver
+        .upstreams()
+        .into_iter()
+        .map(|server| {
+            let server_health = health.remove(&server).unwrap_or_default();
+            UpstreamStats::new(server, stats.get(&server), server_health)
+        })
+        .collect()
+}
+
+/// What the web UI's dashboard shows, for `/api/stats`.
+#[cfg(feature = "daemon")]
+#[derive(Serialize, Debug, Clone)]
+pub struct Dashboard {
+    #[serde(flatten)]
+    queries: crate::dashboard::DashboardSnapshot,
+    upstreams: Vec<UpstreamStats>,
+}
+
+/// The daemon's query totals and rankings, with the latency of each of its
+/// upstreams.
+#[cfg(feature = "daemon")]
+pub async fn dashboard(
+    State((stats, resolver)): State<(crate::dashboard::DashboardStats, DNSResolver)>,
+) -> Json<Dashboard> {
+    Json(Dashboard {
+        queries: stats.snapshot(),
+        upstreams: upstream_stats(&resolver),
+    })
+}
+
+/// How each of the daemon's blocklists last downloaded, for
+/// `/api/blocklists`.
+#[cfg(feature = "blocklist")]
+pub async fn blocklists(
+    State(policies): State<crate::blocklist::ClientPolicies>,
+) -> Json<Vec<crate::blocklist::BlocklistStatus>> {
+    Json(policies.status())
+}
+
+/// The `by` parameter of `/api/queries/summary`.
+#[cfg(feature = "querylog")]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SummaryQuery {
+    #[serde(default)]
+    by: crate::querylog::QueryLogField,
+}
+
+/// The daemon's logged queries matching the request's parameters, the
+/// newest first.
+#[cfg(feature = "querylog")]
+pub async fn queries(
+    State(query_log): State<crate::querylog::QueryLog>,
+    Query(filter): Query<crate::querylog::QueryLogFilter>,
+) -> std::result::Result<Json<Vec<crate::querylog::QueryLogEntry>>, (StatusCode, Json<String>)> {
+    query_log
+        .search(filter)
+        .await
+        .map(Json)
+        .map_err(|e| (status_for(&e), Json(e.to_string())))
+}
+
+/// Totals over the daemon's logged queries matching the request's
+/// parameters, with the most frequent names, clients, types or RCODEs.
+#[cfg(feature = "querylog")]
+pub async fn query_summary(
+    State(query_log): State<crate::querylog::QueryLog>,
+    Query(filter): Query<crate::querylog::QueryLogFilter>,
+    Query(params): Query<SummaryQuery>,
+) -> std::result::Result<Json<crate::querylog::QueryLogSummary>, (StatusCode, Json<String>)> {
+    query_log
+        .summarize(filter, params.by)
+        .await
+        .map(Json)
+        .map_err(|e| (status_for(&e), Json(e.to_string())))
+}
+
+/// Query and block counts and the top domains of every client the daemon
+/// has answered, the busiest first.
+#[cfg(feature = "daemon")]
+pub async fn client_stats(
+    State(stats): State<crate::dashboard::DashboardStats>,
+) -> Json<Vec<crate::dashboard::ClientSnapshot>> {
+    Json(stats.clients())
+}
+
+/// The counts of the client in the path, or 404 if it has sent nothing.
+#[cfg(feature = "daemon")]
+pub async fn one_client_stats(
+    State(stats): State<crate::dashboard::DashboardStats>,
+    axum::extract::Path(client): axum::extract::Path<std::net::IpAddr>,
+) -> std::result::Result<Json<crate::dashboard::ClientSnapshot>, (StatusCode, Json<String>)> {
+    stats.client(client).map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(format!("No queries from {client}")),
+        )
+    })
+}
+
+/// Distributed tracing: a request carrying a W3C `traceparent` header is
+/// handled in a span of the caller's trace, and the resolver's spans for each
+/// query it sends become children of that span.
+#[cfg(feature = "otel")]
+pub(crate) mod otel {
+    use axum::http::HeaderMap;
+    use opentelemetry::propagation::{Extractor, TextMapPropagator};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    /// Makes `span` a child of the span named in the `traceparent` header, if any.
+    pub(crate) fn continue_trace(span: &Span, headers: &HeaderMap) {
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        // Fails only without an OpenTelemetry layer, when there is nothing to export.
+        let _ = span.set_parent(parent);
     }
 }
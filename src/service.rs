@@ -0,0 +1,75 @@
+//! The resolver as a [`tower::Service`], and as the DNS backend of HTTP
+//! clients: reqwest with the `reqwest` feature, hyper-util's `HttpConnector`
+//! with the `hyper` feature.
+
+use crate::DNSResolver;
+use crate::error::{DnsError, Result};
+use crate::transport::DnsTransport;
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::Service;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// Resolves a domain name to its IPv4 address, like [`DNSResolver::resolve`].
+/// Always ready, since every call runs independently.
+impl<T> Service<String> for DNSResolver<T>
+where
+    T: DnsTransport + Clone + 'static,
+{
+    type Response = Ipv4Addr;
+    type Error = DnsError;
+    type Future = BoxFuture<Ipv4Addr>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, domain_name: String) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move { resolver.resolve(&domain_name).await })
+    }
+}
+
+/// Use with `reqwest::ClientBuilder::dns_resolver(Arc::new(resolver))`.
+#[cfg(all(feature = "reqwest", not(target_arch = "wasm32")))]
+impl<T> reqwest::dns::Resolve for DNSResolver<T>
+where
+    T: DnsTransport + Clone + 'static,
+{
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let ip = resolver.resolve(name.as_str()).await?;
+            // reqwest fills in the port of the URL.
+            let addrs: reqwest::dns::Addrs = Box::new(std::iter::once((ip, 0).into()));
+            Ok(addrs)
+        })
+    }
+}
+
+/// Use with `HttpConnector::new_with_resolver(resolver)`.
+#[cfg(feature = "hyper")]
+impl<T> Service<hyper_util::client::legacy::connect::dns::Name> for DNSResolver<T>
+where
+    T: DnsTransport + Clone + 'static,
+{
+    type Response = std::iter::Once<std::net::SocketAddr>;
+    type Error = DnsError;
+    type Future = BoxFuture<Self::Response>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: hyper_util::client::legacy::connect::dns::Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let ip = resolver.resolve(name.as_str()).await?;
+            // The connector fills in the port of the URI.
+            Ok(std::iter::once((ip, 0).into()))
+        })
+    }
+}
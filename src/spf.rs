@@ -0,0 +1,613 @@
+//! Sender Policy Framework (RFC 7208): parsing `v=spf1` records and checking
+//! whether an address may send mail for a domain, following `include` and
+//! `redirect` through the resolver.
+//!
+//! The HELO identity is not known here, so the `h` macro expands to the
+//! sender's domain, and uppercase macro letters are not URL-escaped.
+
+use crate::error::{DnsError, SpfError};
+use crate::mail::tagged;
+use crate::transport::DnsTransport;
+use crate::{DNSRecord, DNSRecordData, DNSResolver, RecordType};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// How many terms that query the DNS one check may evaluate, `include` and
+/// `redirect` included (RFC 7208 section 4.6.4).
+const MAX_LOOKUPS: usize = 10;
+
+/// How many of those lookups may find no records before the check fails.
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// How many exchangers an `mx` mechanism may look at.
+const MAX_MX: usize = 10;
+
+/// The outcome of an SPF check (RFC 7208 section 2.6).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpfResult {
+    /// The domain publishes no SPF record.
+    None,
+    /// The domain makes no assertion about the address.
+    Neutral,
+    Pass,
+    Fail,
+    /// The address is probably not authorized.
+    SoftFail,
+    /// A DNS error that may go away, with its message.
+    TempError(String),
+    /// A record that cannot be evaluated, with why.
+    PermError(String),
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError(_) => "temperror",
+            SpfResult::PermError(_) => "permerror",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The result a matching mechanism gives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl From<Qualifier> for SpfResult {
+    fn from(qualifier: Qualifier) -> Self {
+        match qualifier {
+            Qualifier::Pass => SpfResult::Pass,
+            Qualifier::Fail => SpfResult::Fail,
+            Qualifier::SoftFail => SpfResult::SoftFail,
+            Qualifier::Neutral => SpfResult::Neutral,
+        }
+    }
+}
+
+/// A test of the sending address. Domains are domain-specs, possibly holding
+/// macros; `None` stands for the domain being checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mechanism {
+    All,
+    Include(String),
+    A {
+        domain: Option<String>,
+        v4_prefix: u8,
+        v6_prefix: u8,
+    },
+    Mx {
+        domain: Option<String>,
+        v4_prefix: u8,
+        v6_prefix: u8,
+    },
+    Ptr(Option<String>),
+    Ip4(Ipv4Addr, u8),
+    Ip6(Ipv6Addr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub qualifier: Qualifier,
+    pub mechanism: Mechanism,
+}
+
+/// A parsed `v=spf1` record. Modifiers other than `redirect` and `exp` are
+/// ignored, as RFC 7208 requires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpfRecord {
+    /// The mechanisms, tried in order until one matches.
+    pub directives: Vec<Directive>,
+    /// The domain whose record applies if no mechanism matches.
+    pub redirect: Option<String>,
+    /// The domain of the explanation given to rejected senders.
+    pub explanation: Option<String>,
+}
+
+impl FromStr for SpfRecord {
+    type Err = SpfError;
+
+    fn from_str(text: &str) -> Result<Self, SpfError> {
+        let rest = text
+            .get(..6)
+            .filter(|version| version.eq_ignore_ascii_case("v=spf1"))
+            .map(|_| &text[6..])
+            .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+            .ok_or(SpfError::NotSpf)?;
+        let mut record = SpfRecord {
+            directives: Vec::new(),
+            redirect: None,
+            explanation: None,
+        };
+        for term in rest.split_ascii_whitespace() {
+            let Some((name, value)) = modifier(term) else {
+                record.directives.push(directive(term)?);
+                continue;
+            };
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "redirect" => &mut record.redirect,
+                "exp" => &mut record.explanation,
+                _ => continue,
+            };
+            if slot.replace(value.to_string()).is_some() {
+                return Err(SpfError::DuplicateModifier(name.to_string()));
+            }
+        }
+        Ok(record)
+    }
+}
+
+/// The name and value of `term` if it is a modifier, as in `redirect=...`.
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    valid.then_some((name, value))
+}
+
+fn directive(term: &str) -> Result<Directive, SpfError> {
+    let invalid = || SpfError::InvalidTerm(term.to_string());
+    let (qualifier, body) = match term.as_bytes()[0] {
+        b'+' => (Qualifier::Pass, &term[1..]),
+        b'-' => (Qualifier::Fail, &term[1..]),
+        b'~' => (Qualifier::SoftFail, &term[1..]),
+        b'?' => (Qualifier::Neutral, &term[1..]),
+        _ => (Qualifier::Pass, term),
+    };
+    let (name, arg) = body.split_at(body.find([':', '/']).unwrap_or(body.len()));
+    let domain = |arg: &str| match arg.strip_prefix(':') {
+        Some(domain) if !domain.is_empty() => Ok(Some(domain.to_string())),
+        Some(_) => Err(invalid()),
+        None if arg.is_empty() => Ok(None),
+        None => Err(invalid()),
+    };
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if arg.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(domain(arg)?.ok_or_else(invalid)?),
+        "exists" => Mechanism::Exists(domain(arg)?.ok_or_else(invalid)?),
+        "ptr" => Mechanism::Ptr(domain(arg)?),
+        kind @ ("a" | "mx") => {
+            let split = arg.find('/').unwrap_or(arg.len());
+            let (v4_prefix, v6_prefix) = dual_cidr(&arg[split..]).ok_or_else(invalid)?;
+            let domain = domain(&arg[..split])?;
+            if kind == "a" {
+                Mechanism::A {
+                    domain,
+                    v4_prefix,
+                    v6_prefix,
+                }
+            } else {
+                Mechanism::Mx {
+                    domain,
+                    v4_prefix,
+                    v6_prefix,
+                }
+            }
+        }
+        "ip4" => {
+            let (ip, prefix) = network(arg, 32).ok_or_else(invalid)?;
+            Mechanism::Ip4(ip, prefix)
+        }
+        "ip6" => {
+            let (ip, prefix) = network(arg, 128).ok_or_else(invalid)?;
+            Mechanism::Ip6(ip, prefix)
+        }
+        "all" => return Err(invalid()),
+        _ => return Err(SpfError::UnknownMechanism(name.to_string())),
+    };
+    Ok(Directive {
+        qualifier,
+        mechanism,
+    })
+}
+
+/// The prefix lengths of a `/24//64` suffix, either part optional.
+fn dual_cidr(cidr: &str) -> Option<(u8, u8)> {
+    let (v4, v6) = match cidr.split_once("//") {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (cidr, None),
+    };
+    let prefix = |digits: &str, max: u8| digits.parse().ok().filter(|&prefix| prefix <= max);
+    let v4 = match v4.strip_prefix('/') {
+        Some(digits) => prefix(digits, 32)?,
+        None if v4.is_empty() => 32,
+        None => return None,
+    };
+    let v6 = match v6 {
+        Some(digits) => prefix(digits, 128)?,
+        None => 128,
+    };
+    Some((v4, v6))
+}
+
+/// The address and prefix length of an `ip4:` or `ip6:` argument.
+fn network<A: FromStr>(arg: &str, max: u8) -> Option<(A, u8)> {
+    let arg = arg.strip_prefix(':')?;
+    let (ip, prefix) = match arg.split_once('/') {
+        Some((ip, prefix)) => (ip, prefix.parse().ok().filter(|&prefix| prefix <= max)?),
+        None => (arg, max),
+    };
+    Some((ip.parse().ok()?, prefix))
+}
+
+/// Whether `ip` is within `network`/`prefix`.
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            (u32::from(ip) ^ u32::from(network)) & mask == 0
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            (u128::from(ip) ^ u128::from(network)) & mask == 0
+        }
+        _ => false,
+    }
+}
+
+/// The state of one check: who is sending, and the lookups spent so far.
+struct Check<'a> {
+    ip: IpAddr,
+    sender: &'a str,
+    local: &'a str,
+    sender_domain: &'a str,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Check<'_> {
+    /// Counts a term that queries the DNS.
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(SpfResult::PermError(format!(
+                "More than {MAX_LOOKUPS} DNS lookups"
+            )));
+        }
+        Ok(())
+    }
+
+    /// `spec` with its macros expanded for `domain` (RFC 7208 section 7).
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, SpfResult> {
+        let invalid = || SpfResult::PermError(format!("Invalid macro in {spec}"));
+        let mut expanded = String::new();
+        let mut rest = spec;
+        while let Some(start) = rest.find('%') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let len = match after.chars().next() {
+                Some('%') => {
+                    expanded.push('%');
+                    1
+                }
+                Some('_') => {
+                    expanded.push(' ');
+                    1
+                }
+                Some('-') => {
+                    expanded.push_str("%20");
+                    1
+                }
+                Some('{') => {
+                    let end = after.find('}').ok_or_else(invalid)?;
+                    let value = self.macro_value(&after[1..end], domain);
+                    expanded.push_str(&value.ok_or_else(invalid)?);
+                    end + 1
+                }
+                _ => return Err(invalid()),
+            };
+            rest = &after[len..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// The value of a macro such as `ir` or `d2`, between the braces.
+    fn macro_value(&self, body: &str, domain: &str) -> Option<String> {
+        let letter = body.chars().next()?;
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.to_string(),
+            'l' => self.local.to_string(),
+            'o' | 'h' => self.sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{nibble:x}"))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_string(),
+            'v' if self.ip.is_ipv4() => "in-addr".to_string(),
+            'v' => "ip6".to_string(),
+            _ => return None,
+        };
+        let transformers = &body[1..];
+        let digits = transformers
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(transformers.len());
+        let keep = match &transformers[..digits] {
+            "" => None,
+            digits => Some(digits.parse::<usize>().ok().filter(|&keep| keep > 0)?),
+        };
+        let mut delimiters = &transformers[digits..];
+        let reverse = delimiters.starts_with(['r', 'R']);
+        if reverse {
+            delimiters = &delimiters[1..];
+        }
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return None;
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        Some(parts.join("."))
+    }
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// Checks whether `ip` may send mail from `sender`, an address or a bare
+    /// domain, against the SPF record of its domain, as `check_host()` of
+    /// RFC 7208 does.
+    pub async fn check_spf(&self, ip: IpAddr, sender: &str) -> SpfResult {
+        let (local, domain) = sender.rsplit_once('@').unwrap_or(("postmaster", sender));
+        let local = if local.is_empty() {
+            "postmaster"
+        } else {
+            local
+        };
+        let domain = domain.trim_end_matches('.');
+        let mut check = Check {
+            ip,
+            sender,
+            local,
+            sender_domain: domain,
+            lookups: 0,
+            void_lookups: 0,
+        };
+        self.check_host(domain, &mut check).await
+    }
+
+    async fn check_host(&self, domain: &str, check: &mut Check<'_>) -> SpfResult {
+        let record = match self.spf_record(domain).await {
+            Ok(Some(record)) => record,
+            Ok(None) => return SpfResult::None,
+            Err(result) => return result,
+        };
+        for directive in &record.directives {
+            match self
+                .mechanism_matches(&directive.mechanism, domain, check)
+                .await
+            {
+                Ok(true) => return directive.qualifier.into(),
+                Ok(false) => {}
+                Err(result) => return result,
+            }
+        }
+        let Some(redirect) = &record.redirect else {
+            return SpfResult::Neutral;
+        };
+        let target = match check.expand(redirect, domain) {
+            Ok(target) => target,
+            Err(result) => return result,
+        };
+        if let Err(result) = check.count_lookup() {
+            return result;
+        }
+        match Box::pin(self.check_host(&target, check)).await {
+            SpfResult::None => SpfResult::PermError(format!("{target} has no SPF record")),
+            result => result,
+        }
+    }
+
+    /// The SPF record of `domain`, or the result to give if there is more
+    /// than one, it does not parse or the lookup fails.
+    async fn spf_record(&self, domain: &str) -> Result<Option<SpfRecord>, SpfResult> {
+        let texts = match self.txt_records(domain).await {
+            Ok(texts) => texts,
+            Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => return Ok(None),
+            Err(e) => return Err(SpfResult::TempError(e.to_string())),
+        };
+        match tagged(&texts, "v=spf1").as_slice() {
+            [] => Ok(None),
+            [text] => text
+                .parse()
+                .map(Some)
+                .map_err(|e: SpfError| SpfResult::PermError(format!("{domain}: {e}"))),
+            _ => Err(SpfResult::PermError(format!(
+                "{domain} has more than one SPF record"
+            ))),
+        }
+    }
+
+    async fn mechanism_matches(
+        &self,
+        mechanism: &Mechanism,
+        domain: &str,
+        check: &mut Check<'_>,
+    ) -> Result<bool, SpfResult> {
+        let target = |spec: &Option<String>, check: &Check<'_>| match spec {
+            Some(spec) => check.expand(spec, domain),
+            None => Ok(domain.to_string()),
+        };
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip4(network, prefix) => {
+                Ok(in_network(check.ip, IpAddr::V4(*network), *prefix))
+            }
+            Mechanism::Ip6(network, prefix) => {
+                Ok(in_network(check.ip, IpAddr::V6(*network), *prefix))
+            }
+            Mechanism::A {
+                domain: spec,
+                v4_prefix,
+                v6_prefix,
+            } => {
+                check.count_lookup()?;
+                let target = target(spec, check)?;
+                let addresses = self.spf_addresses(&target, check).await?;
+                Ok(addresses
+                    .iter()
+                    .any(|&address| matches_prefix(check.ip, address, *v4_prefix, *v6_prefix)))
+            }
+            Mechanism::Mx {
+                domain: spec,
+                v4_prefix,
+                v6_prefix,
+            } => {
+                check.count_lookup()?;
+                let target = target(spec, check)?;
+                let exchangers: Vec<String> = self
+                    .spf_lookup(&target, RecordType::Mx, check)
+                    .await?
+                    .into_iter()
+                    .filter_map(|record| match record.data {
+                        DNSRecordData::Mx(mx) if !mx.exchange.is_empty() => Some(mx.exchange),
+                        _ => None,
+                    })
+                    .collect();
+                if exchangers.len() > MAX_MX {
+                    return Err(SpfResult::PermError(format!(
+                        "{target} has more than {MAX_MX} MX records"
+                    )));
+                }
+                for exchanger in exchangers {
+                    let addresses = self.spf_addresses(&exchanger, check).await?;
+                    if addresses
+                        .iter()
+                        .any(|&address| matches_prefix(check.ip, address, *v4_prefix, *v6_prefix))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                check.count_lookup()?;
+                let target = target(spec, check)?;
+                // Only IPv4 addresses can be reverse resolved, and failures
+                // are not errors here (RFC 7208 section 5.5).
+                let IpAddr::V4(ip) = check.ip else {
+                    return Ok(false);
+                };
+                let Ok(name) = self.reverse_resolve(&ip).await else {
+                    return Ok(false);
+                };
+                let validated = self
+                    .spf_addresses(&name, check)
+                    .await
+                    .is_ok_and(|addresses| addresses.contains(&check.ip));
+                let name = name.trim_end_matches('.');
+                let within = name.eq_ignore_ascii_case(&target)
+                    || name
+                        .len()
+                        .checked_sub(target.len() + 1)
+                        .is_some_and(|start| {
+                            name.as_bytes()[start] == b'.'
+                                && name[start + 1..].eq_ignore_ascii_case(&target)
+                        });
+                Ok(validated && within)
+            }
+            Mechanism::Include(spec) => {
+                check.count_lookup()?;
+                let target = check.expand(spec, domain)?;
+                match Box::pin(self.check_host(&target, check)).await {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError(e) => Err(SpfResult::TempError(e)),
+                    SpfResult::PermError(e) => Err(SpfResult::PermError(e)),
+                    SpfResult::None => Err(SpfResult::PermError(format!(
+                        "Included {target} has no SPF record"
+                    ))),
+                }
+            }
+            Mechanism::Exists(spec) => {
+                check.count_lookup()?;
+                let target = check.expand(spec, domain)?;
+                let records = self.spf_lookup(&target, RecordType::A, check).await?;
+                Ok(!records.is_empty())
+            }
+        }
+    }
+
+    /// The addresses of `name` in the family of the sending address.
+    async fn spf_addresses(
+        &self,
+        name: &str,
+        check: &mut Check<'_>,
+    ) -> Result<Vec<IpAddr>, SpfResult> {
+        let record_type = if check.ip.is_ipv4() {
+            RecordType::A
+        } else {
+            RecordType::Aaaa
+        };
+        Ok(self
+            .spf_lookup(name, record_type, check)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                DNSRecordData::Ipv4Addr(ip) => Some(IpAddr::V4(ip)),
+                DNSRecordData::Ipv6Addr(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The records of `name`, counting a lookup that finds none as void.
+    async fn spf_lookup(
+        &self,
+        name: &str,
+        record_type: RecordType,
+        check: &mut Check<'_>,
+    ) -> Result<Vec<DNSRecord>, SpfResult> {
+        match self.resolve_record(name, record_type).await {
+            Ok(records) => Ok(records),
+            Err(DnsError::NxDomain(_) | DnsError::NoRecordsFound(_)) => {
+                check.void_lookups += 1;
+                if check.void_lookups > MAX_VOID_LOOKUPS {
+                    return Err(SpfResult::PermError(format!(
+                        "More than {MAX_VOID_LOOKUPS} lookups found no records"
+                    )));
+                }
+                Ok(Vec::new())
+            }
+            Err(e) => Err(SpfResult::TempError(e.to_string())),
+        }
+    }
+}
+
+/// Whether `ip` is in the network of `address` with the prefix length of
+/// its family.
+fn matches_prefix(ip: IpAddr, address: IpAddr, v4_prefix: u8, v6_prefix: u8) -> bool {
+    let prefix = if address.is_ipv4() {
+        v4_prefix
+    } else {
+        v6_prefix
+    };
+    in_network(ip, address, prefix)
+}
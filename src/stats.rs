@@ -0,0 +1,233 @@
+//! Round-trip times and failures per server, so the resolver can prefer the
+//! fastest healthy one the way unbound and BIND select servers, and a rolling
+//! record of each server's latencies and errors for monitoring.
+
+use crate::flags::Rcode;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use wasmtimer::std::Instant;
+
+/// Assumed of a server never queried, as in unbound: slow enough that a known
+/// fast server wins, fast enough that it is still tried before a slow one.
+const UNKNOWN_RTT: Duration = Duration::from_millis(376);
+
+/// Consecutive failures after which a server is avoided while others answer.
+const MAX_FAILURES: u32 = 3;
+
+/// How long a failing server is avoided before it gets another chance.
+const HOLD_DOWN: Duration = Duration::from_secs(60);
+
+/// The shortest adaptive timeout, as in unbound, so that jitter on a very
+/// fast server does not cause needless retries.
+const MIN_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How far back [`ServerHealth`] looks. Counts are kept for two halves of it,
+/// the older dropped as each half ends, so it covers between one and two halves.
+const HEALTH_WINDOW: Duration = Duration::from_secs(300);
+
+/// Upper bounds of the latency histogram buckets of [`ServerHealth`], in
+/// milliseconds. A last bucket counts anything slower.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// What a resolver has measured of one server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Smoothed round-trip time, each sample weighted 1/8 (RFC 6298). A
+    /// timeout counts as a sample of the time waited.
+    pub srtt: Duration,
+    /// How much round-trip times vary around `srtt`.
+    pub rttvar: Duration,
+    /// Timeouts and network errors since the last response.
+    pub failures: u32,
+}
+
+/// Responses and errors of one server over the last few minutes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerHealth {
+    /// Responses by round-trip time, one count per bucket of
+    /// [`LATENCY_BUCKETS_MS`] and a last one for slower responses.
+    pub latency: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    /// Responses by the RCODE they carried.
+    pub rcodes: HashMap<Rcode, u64>,
+    pub timeouts: u64,
+    /// Failures to reach the server other than timeouts.
+    pub network_errors: u64,
+}
+
+impl ServerHealth {
+    /// Responses received, whatever their RCODE.
+    pub fn responses(&self) -> u64 {
+        self.latency.iter().sum()
+    }
+
+    /// The upper bound of the bucket holding the given fraction of responses,
+    /// such as 0.99 for the 99th percentile. `None` without responses or when
+    /// it falls in the last, unbounded bucket.
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let rank = (self.responses() as f64 * fraction).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MS
+                    .get(bucket)
+                    .map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+
+    fn record_rtt(&mut self, rtt: Duration) {
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|ms| rtt <= Duration::from_millis(*ms))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency[bucket] += 1;
+    }
+
+    fn merge(&mut self, other: &ServerHealth) {
+        for (count, more) in self.latency.iter_mut().zip(other.latency) {
+            *count += more;
+        }
+        for (rcode, more) in &other.rcodes {
+            *self.rcodes.entry(*rcode).or_default() += more;
+        }
+        self.timeouts += other.timeouts;
+        self.network_errors += other.network_errors;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    stats: ServerStats,
+    failed_at: Option<Instant>,
+    /// Counts of the current half of the health window and of the one before.
+    health: [ServerHealth; 2],
+    period_start: Instant,
+}
+
+impl Entry {
+    fn new(srtt: Duration) -> Self {
+        Entry {
+            stats: ServerStats {
+                srtt,
+                rttvar: srtt / 2,
+                failures: 0,
+            },
+            failed_at: None,
+            health: Default::default(),
+            period_start: Instant::now(),
+        }
+    }
+
+    /// The current health counts, after dropping those too old to keep.
+    fn health(&mut self) -> &mut ServerHealth {
+        let half = HEALTH_WINDOW / 2;
+        let elapsed = self.period_start.elapsed();
+        if elapsed >= half * 2 {
+            self.health = Default::default();
+            self.period_start = Instant::now();
+        } else if elapsed >= half {
+            self.health[1] = std::mem::take(&mut self.health[0]);
+            self.period_start += half;
+        }
+        &mut self.health[0]
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        let stats = &mut self.stats;
+        stats.rttvar = (stats.rttvar * 3 + stats.srtt.abs_diff(rtt)) / 4;
+        stats.srtt = (stats.srtt * 7 + rtt) / 8;
+    }
+
+    fn held_down(&self) -> bool {
+        self.stats.failures >= MAX_FAILURES
+            && self.failed_at.is_some_and(|at| at.elapsed() < HOLD_DOWN)
+    }
+}
+
+/// Statistics shared by every clone of a resolver.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServerTable(Arc<Mutex<HashMap<Ipv4Addr, Entry>>>);
+
+impl ServerTable {
+    fn lock(&self) -> MutexGuard<'_, HashMap<Ipv4Addr, Entry>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn record_response(&self, server: Ipv4Addr, rtt: Duration, rcode: Rcode) {
+        let mut servers = self.lock();
+        let entry = servers
+            .entry(server)
+            .and_modify(|entry| entry.sample(rtt))
+            .or_insert_with(|| Entry::new(rtt));
+        entry.stats.failures = 0;
+        entry.failed_at = None;
+        let health = entry.health();
+        health.record_rtt(rtt);
+        *health.rcodes.entry(rcode).or_default() += 1;
+    }
+
+    /// Records a timeout, or another network error if `timed_out` is false.
+    pub(crate) fn record_failure(&self, server: Ipv4Addr, waited: Duration, timed_out: bool) {
+        let mut servers = self.lock();
+        let entry = servers
+            .entry(server)
+            .or_insert_with(|| Entry::new(UNKNOWN_RTT));
+        entry.sample(waited);
+        entry.stats.failures += 1;
+        entry.failed_at = Some(Instant::now());
+        let health = entry.health();
+        if timed_out {
+            health.timeouts += 1;
+        } else {
+            health.network_errors += 1;
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> HashMap<Ipv4Addr, ServerStats> {
+        self.lock()
+            .iter()
+            .map(|(server, entry)| (*server, entry.stats))
+            .collect()
+    }
+
+    pub(crate) fn health(&self) -> HashMap<Ipv4Addr, ServerHealth> {
+        self.lock()
+            .iter_mut()
+            .map(|(server, entry)| {
+                let mut health = entry.health().clone();
+                health.merge(&entry.health[1]);
+                (*server, health)
+            })
+            .collect()
+    }
+
+    /// How long to wait for `server` on the given attempt: the retransmission
+    /// timeout of RFC 6298, doubled for each retry, kept between 50 ms and
+    /// `ceiling`. Servers not yet measured get the whole `ceiling`.
+    pub(crate) fn timeout(&self, server: Ipv4Addr, ceiling: Duration, attempt: u32) -> Duration {
+        let Some(stats) = self.lock().get(&server).map(|entry| entry.stats) else {
+            return ceiling;
+        };
+        let rto = stats.srtt + stats.rttvar * 4;
+        rto.saturating_mul(2u32.saturating_pow(attempt))
+            .max(MIN_TIMEOUT)
+            .min(ceiling)
+    }
+
+    /// Orders `servers` best first: those not held down after repeated
+    /// failures, then by smoothed RTT. Ties keep their order.
+    pub(crate) fn rank(&self, servers: &mut [Ipv4Addr]) {
+        let table = self.lock();
+        servers.sort_by_cached_key(|server| match table.get(server) {
+            Some(entry) => (entry.held_down(), entry.stats.srtt),
+            None => (false, UNKNOWN_RTT),
+        });
+    }
+}
@@ -0,0 +1,246 @@
+//! An in-process DNS server answering over UDP from a fixed set of records,
+//! for end-to-end tests that need a real server but no network access.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use dns_resolver_rs::testutil::TestServer;
+//! use dns_resolver_rs::{Class, DNSRecord, DNSRecordData, RecordType};
+//! use std::net::Ipv4Addr;
+//!
+//! let server = TestServer::builder()
+//!     .record(DNSRecord::new(
+//!         "www.example.test",
+//!         RecordType::A,
+//!         Class::In,
+//!         300,
+//!         DNSRecordData::Ipv4Addr(Ipv4Addr::new(192, 0, 2, 1)),
+//!     ))
+//!     .start()
+//!     .await?;
+//! let resolver = server.resolver().unwrap();
+//! assert_eq!(
+//!     resolver.resolve("www.example.test").await.unwrap(),
+//!     Ipv4Addr::new(192, 0, 2, 1)
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::builder::MessageBuilder;
+use crate::edns::{ClientSubnet, Edns, EdnsOption};
+use crate::error::Result;
+use crate::flags::{DNSFlags, Rcode};
+use crate::zone::owned_records;
+use crate::{
+    Class, DNSPacket, DNSQuestion, DNSRecord, DNSRecordData, DNSResolver, RecordType, Soa,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// How many CNAMEs an answer follows before giving up, so a loop in the
+/// records cannot hang the server.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The records a [`TestServer`] answers from, keyed by lowercase name.
+#[derive(Debug, Clone, Default)]
+pub struct TestServerBuilder {
+    records: HashMap<String, Vec<DNSRecord>>,
+}
+
+impl TestServerBuilder {
+    /// Adds `record` to those served under its owner name.
+    pub fn record(mut self, record: DNSRecord) -> Self {
+        let name = record.name.trim_end_matches('.').to_ascii_lowercase();
+        self.records.entry(name).or_default().push(record);
+        self
+    }
+
+    pub fn records(self, records: impl IntoIterator<Item = DNSRecord>) -> Self {
+        records.into_iter().fold(self, Self::record)
+    }
+
+    /// Binds a UDP socket on an ephemeral port of 127.0.0.1 and starts
+    /// answering on it in a background task, until the server is dropped.
+    pub async fn start(self) -> io::Result<TestServer> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(serve(socket, self.records, queries.clone()));
+        Ok(TestServer {
+            addr,
+            queries,
+            task,
+        })
+    }
+}
+
+/// A running DNS server answering authoritatively from its records: with the
+/// records of the queried name and type, following CNAMEs, with NODATA if
+/// the name only has other types or only names under it, and NXDOMAIN if it
+/// does not exist. Records owned by `*.example.test` answer for names under
+/// `example.test` that do not exist, as wildcards do (RFC 4592).
+/// Negative answers carry an SOA for the root so resolvers cache them.
+/// Queries carrying a client subnet are answered as if tailored to it, the
+/// subnet being echoed with its source prefix as the scope.
+/// Answers are never truncated, as the server does not listen on TCP.
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    queries: Arc<Mutex<Vec<DNSPacket>>>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A resolver using this server as its only upstream, without caching.
+    pub fn resolver(&self) -> Result<DNSResolver> {
+        let ip = Ipv4Addr::LOCALHOST;
+        DNSResolver::builder()
+            .upstreams([ip])
+            .upstream_port(ip, self.addr.port())
+            .cache(false)
+            .build()
+    }
+
+    /// Every question received so far, in order.
+    pub fn queries(&self) -> Vec<DNSQuestion> {
+        self.queries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .flat_map(|query| query.questions.iter().cloned())
+            .collect()
+    }
+
+    /// The EDNS Client Subnet option of every query received so far, in
+    /// order.
+    pub fn client_subnets(&self) -> Vec<Option<ClientSubnet>> {
+        self.queries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|query| query.edns.as_ref()?.client_subnet().cloned())
+            .collect()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(
+    socket: UdpSocket,
+    records: HashMap<String, Vec<DNSRecord>>,
+    queries: Arc<Mutex<Vec<DNSPacket>>>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        // Anything that is not a query is ignored, as a real server would.
+        let Ok(query) = DNSPacket::parse(&buf[..len]) else {
+            continue;
+        };
+        if query.header.flags.qr {
+            continue;
+        }
+        let response = respond(&records, &query);
+        queries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(query);
+        if let Ok(response) = response {
+            let _ = socket.send_to(&response.to_bytes(), peer).await;
+        }
+    }
+}
+
+fn respond(records: &HashMap<String, Vec<DNSRecord>>, query: &DNSPacket) -> Result<DNSPacket> {
+    let mut builder = MessageBuilder::response_to(query).flags(DNSFlags {
+        qr: true,
+        aa: true,
+        opcode: query.header.flags.opcode,
+        rd: query.header.flags.rd,
+        ..DNSFlags::default()
+    });
+    if let Some(subnet) = query.edns.as_ref().and_then(Edns::client_subnet) {
+        builder = builder.edns(Edns {
+            options: vec![EdnsOption::ClientSubnet(ClientSubnet {
+                scope_prefix: subnet.source_prefix,
+                ..subnet.clone()
+            })],
+            ..Edns::default()
+        });
+    }
+    let Some(question) = query.questions.first() else {
+        return builder.rcode(Rcode::FormErr).build();
+    };
+    let mut name = question.name.trim_end_matches('.').to_ascii_lowercase();
+    for _ in 0..MAX_CNAME_CHAIN {
+        let Some(owned) = owned_records(records, &name) else {
+            // Only the queried name itself is denied; a CNAME to a name
+            // served elsewhere is left for the resolver to follow.
+            if name.eq_ignore_ascii_case(&question.name) {
+                builder = builder.rcode(Rcode::NxDomain).authority(root_soa());
+            }
+            return builder.build();
+        };
+        let matching: Vec<&DNSRecord> = owned
+            .iter()
+            .filter(|record| record.type_ == question.type_)
+            .collect();
+        if !matching.is_empty() {
+            return matching
+                .into_iter()
+                .cloned()
+                .fold(builder, MessageBuilder::answer)
+                .build();
+        }
+        let cname = owned.iter().find_map(|record| match &record.data {
+            DNSRecordData::Name(target) if record.type_ == RecordType::Cname => {
+                Some((record, target))
+            }
+            _ => None,
+        });
+        let Some((record, target)) = cname else {
+            return builder.authority(root_soa()).build();
+        };
+        builder = builder.answer(record.clone());
+        name = target.trim_end_matches('.').to_ascii_lowercase();
+    }
+    builder.build()
+}
+
+/// The SOA negative answers carry, with a minimum TTL of a minute.
+fn root_soa() -> DNSRecord {
+    DNSRecord::new(
+        "",
+        RecordType::Soa,
+        Class::In,
+        60,
+        DNSRecordData::Soa(Soa {
+            mname: "ns.test".to_string(),
+            rname: "hostmaster.test".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        }),
+    )
+}
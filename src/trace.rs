@@ -0,0 +1,220 @@
+//! Resolution with every step recorded, for showing the delegation path the
+//! way `dig +trace` does.
+
+use crate::config::{CachePolicy, QueryOptions};
+use crate::error::{DnsError, Result};
+use crate::flags::Rcode;
+use crate::transport::DnsTransport;
+use crate::{Class, DNSPacket, DNSQuestion, DNSRecord, DNSRecordData, DNSResolver, RecordType};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use wasmtimer::std::Instant;
+
+/// Enough for any real delegation path and CNAME chain, so that a loop of
+/// referrals or aliases still ends.
+const MAX_HOPS: usize = 32;
+
+/// A resolution and every server asked along the way.
+#[derive(Debug)]
+pub struct Trace {
+    /// In the order the queries were sent, failed ones included.
+    pub hops: Vec<TraceHop>,
+    /// The records of the type asked for, or why there were none.
+    pub result: Result<Vec<DNSRecord>>,
+}
+
+/// One query of a traced resolution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceHop {
+    pub server: Ipv4Addr,
+    /// The name asked for changes as CNAMEs are followed.
+    pub question: DNSQuestion,
+    pub outcome: HopOutcome,
+    /// `None` if the server never answered.
+    pub rcode: Option<Rcode>,
+    /// How long the server took to answer, retries included.
+    pub rtt: Duration,
+    /// The response as received, for its glue and other records.
+    pub response: Option<DNSPacket>,
+}
+
+/// What a response meant for the resolution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HopOutcome {
+    /// Records of the type asked for, ending the resolution.
+    Answer,
+    /// An alias, whose target is resolved from the upstreams again.
+    Cname(String),
+    /// A delegation of `zone` to the named servers.
+    Referral {
+        zone: String,
+        nameservers: Vec<String>,
+    },
+    NxDomain,
+    NoData,
+    /// No usable response: the error it caused, as shown to users.
+    Failed(String),
+}
+
+impl<T: DnsTransport> DNSResolver<T> {
+    /// Resolves `domain_name` from the upstreams like
+    /// [`resolve_detailed`](DNSResolver::resolve_detailed), but never from the
+    /// cache, and records each query sent. A server that does not respond is
+    /// recorded and the next one of the same zone tried. Addresses of
+    /// nameservers without glue are looked up normally and not traced.
+    pub async fn resolve_trace(&self, domain_name: &str, record_type: RecordType) -> Trace {
+        let options = QueryOptions {
+            cache: CachePolicy::Bypass,
+            ..QueryOptions::default()
+        };
+        self.resolve_trace_with(domain_name, record_type, &options)
+            .await
+    }
+
+    /// Like [`resolve_trace`](DNSResolver::resolve_trace), with `options`
+    /// applying to every traced query.
+    pub(crate) async fn resolve_trace_with(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+    ) -> Trace {
+        let mut hops = Vec::new();
+        let result = self
+            .trace(domain_name, record_type, options, &mut hops)
+            .await;
+        Trace { hops, result }
+    }
+
+    async fn trace(
+        &self,
+        domain_name: &str,
+        record_type: RecordType,
+        options: &QueryOptions,
+        hops: &mut Vec<TraceHop>,
+    ) -> Result<Vec<DNSRecord>> {
+        let mut name = domain_name.to_string();
+        let mut servers = self.upstreams();
+        self.servers.rank(&mut servers);
+        while hops.len() < MAX_HOPS {
+            let (mut hop, response) = self
+                .trace_query(&name, &record_type, &servers, options, hops)
+                .await?;
+
+            if let Some(err) = response.get_rcode_error(&name, hop.server) {
+                hop.outcome = HopOutcome::Failed(err.to_string());
+                hops.push(hop);
+                return Err(err);
+            }
+            let records: Vec<DNSRecord> = response
+                .answers
+                .iter()
+                .filter(|record| record.type_ == record_type)
+                .cloned()
+                .collect();
+            if !records.is_empty() {
+                hops.push(hop);
+                return Ok(records);
+            }
+            if let Some(target) = response.get_answer_domain() {
+                name = target.to_string();
+                hop.outcome = HopOutcome::Cname(name.clone());
+                hops.push(hop);
+                servers = self.upstreams();
+                self.servers.rank(&mut servers);
+                continue;
+            }
+            if let Some(err) = self
+                .check_negative(&response, &name, record_type.clone(), options.cache)
+                .await
+            {
+                hop.outcome = match err {
+                    DnsError::NxDomain(_) => HopOutcome::NxDomain,
+                    _ => HopOutcome::NoData,
+                };
+                hops.push(hop);
+                return Err(err);
+            }
+
+            let Some(zone) = response
+                .authorities
+                .iter()
+                .find(|record| record.type_ == RecordType::Ns)
+                .map(|record| record.name.clone())
+            else {
+                let err = DnsError::Unresolvable(name.clone());
+                hop.outcome = HopOutcome::Failed(err.to_string());
+                hops.push(hop);
+                return Err(err);
+            };
+            let nameservers = response
+                .authorities
+                .iter()
+                .filter_map(|record| match &record.data {
+                    DNSRecordData::Name(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            hop.outcome = HopOutcome::Referral { zone, nameservers };
+            hops.push(hop);
+
+            servers = response.get_nameserver_ips();
+            if servers.is_empty() {
+                let nameserver = response
+                    .get_nameserver_domain()
+                    .ok_or_else(|| DnsError::Unresolvable(name.clone()))?;
+                servers.push(Box::pin(self.resolve(nameserver)).await?);
+            }
+            self.servers.rank(&mut servers);
+        }
+        Err(DnsError::Unresolvable(domain_name.to_string()))
+    }
+
+    /// Asks `servers` in turn until one responds, recording those that do not.
+    /// The hop for the response is left to the caller to record, as an answer
+    /// until it says otherwise.
+    async fn trace_query(
+        &self,
+        name: &str,
+        record_type: &RecordType,
+        servers: &[Ipv4Addr],
+        options: &QueryOptions,
+        hops: &mut Vec<TraceHop>,
+    ) -> Result<(TraceHop, DNSPacket)> {
+        let question = DNSQuestion::new(name.to_string(), record_type.clone(), Class::In);
+        let mut last_error = None;
+        for server in servers {
+            let sent = Instant::now();
+            let result = self
+                .lookup_class(name, server, record_type.clone(), Class::In, options)
+                .await;
+            let mut hop = TraceHop {
+                server: *server,
+                question: question.clone(),
+                outcome: HopOutcome::Answer,
+                rcode: None,
+                rtt: sent.elapsed(),
+                response: None,
+            };
+            match result {
+                Ok(response) => {
+                    hop.rcode = Some(response.header.flags.rcode);
+                    hop.response = Some(response.clone());
+                    return Ok((hop, response));
+                }
+                Err(e) => {
+                    hop.outcome = HopOutcome::Failed(e.to_string());
+                    hops.push(hop);
+                    if !matches!(e, DnsError::Timeout(_) | DnsError::IoError(_)) {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| DnsError::Unresolvable(name.to_string())))
+    }
+}
@@ -0,0 +1,1137 @@
+//! How queries reach a server. [`DNSResolver`](crate::DNSResolver) is generic
+//! over [`DnsTransport`], so any way of carrying DNS messages can be plugged in.
+//!
+//! UDP and TCP send each query to the server the resolver picked, on port 53
+//! unless [`upstream_port`](crate::config::DNSResolverBuilder::upstream_port)
+//! says otherwise. The DoT and
+//! DoH transports instead forward every query to the recursive resolver they
+//! were created for, and are meant to be used with
+//! [`recursion_desired`](crate::config::DNSResolverBuilder::recursion_desired).
+//!
+//! The UDP, TCP and DoT transports use tokio's non-blocking sockets, so a
+//! query never ties up a runtime worker while it waits; the resolver bounds
+//! each exchange with its timeout.
+//!
+//! Browsers offer no raw sockets, so on wasm32 only the DoH transport (over
+//! `fetch`) and [`MockTransport`] can carry queries.
+
+use crate::builder::MessageBuilder;
+use crate::error::{DnsError, Result};
+use crate::flags::{DNSFlags, Rcode};
+use crate::{DNSHeader, DNSPacket, DNSQuestion, DNSRecord, RecordType};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+pub const DNS_PORT: u16 = 53;
+
+/// Carries a query to a server and brings back its response. Implementations
+/// need not apply timeouts or check that the response matches the query; the
+/// resolver does both.
+pub trait DnsTransport: Send + Sync {
+    fn exchange(
+        &self,
+        query: &DNSPacket,
+        server: IpAddr,
+    ) -> impl Future<Output = Result<DNSPacket>> + Send;
+
+    /// Like [`DnsTransport::exchange`], to `server`'s port rather than 53. The
+    /// default ignores the port, which suits transports that pick their own
+    /// destination.
+    fn exchange_at(
+        &self,
+        query: &DNSPacket,
+        server: SocketAddr,
+    ) -> impl Future<Output = Result<DNSPacket>> + Send {
+        self.exchange(query, server.ip())
+    }
+
+    /// Sends `query`, an already encoded message, to `server` and returns the
+    /// response's bytes. The default parses the query and encodes the
+    /// response, so only messages [`DNSPacket`] can represent get through;
+    /// UDP and TCP send and return the bytes as they are.
+    fn exchange_raw(
+        &self,
+        query: &[u8],
+        server: SocketAddr,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        async move {
+            let query = DNSPacket::parse(query)?;
+            Ok(self.exchange_at(&query, server).await?.to_bytes())
+        }
+    }
+
+    /// A short name for how queries travel, labelling metrics.
+    fn protocol(&self) -> &'static str {
+        "custom"
+    }
+}
+
+/// Plain DNS over UDP, repeating the query over TCP when the response is
+/// truncated. Queries share a small pool of sockets, bound on first use and
+/// kept for the life of the transport and its clones. Not available on wasm32.
+#[derive(Debug, Clone, Default)]
+pub struct UdpTransport {
+    #[cfg(not(target_arch = "wasm32"))]
+    sockets: Arc<net::SocketPool>,
+    #[cfg(not(target_arch = "wasm32"))]
+    tcp: TcpTransport,
+}
+
+/// DNS over TCP. Each server gets one connection, opened on first use and
+/// shared by the transport and its clones, with queries pipelined on it
+/// rather than waiting for one another. Not available on wasm32.
+#[derive(Debug, Clone, Default)]
+pub struct TcpTransport {
+    #[cfg(not(target_arch = "wasm32"))]
+    connections: Arc<pipeline::Connections<tokio::net::TcpStream>>,
+}
+
+/// Answers queries from canned responses keyed by name and type, without any
+/// network traffic. Names are matched case-insensitively, and anything without
+/// a canned response is REFUSED.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    responses: HashMap<(Option<IpAddr>, String, RecordType), DNSPacket>,
+    queries: Arc<Mutex<Vec<(IpAddr, DNSQuestion)>>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers authoritatively with `records`.
+    pub fn answer(self, name: &str, type_: RecordType, records: Vec<DNSRecord>) -> Self {
+        self.response(name, type_, canned(Rcode::NoError, records))
+    }
+
+    pub fn nxdomain(self, name: &str, type_: RecordType) -> Self {
+        self.response(name, type_, canned(Rcode::NxDomain, Vec::new()))
+    }
+
+    /// Replies with the flags and sections of `response`, from any server.
+    /// Its ID and questions are replaced by those of each query.
+    pub fn response(self, name: &str, type_: RecordType, response: DNSPacket) -> Self {
+        self.insert(None, name, type_, response)
+    }
+
+    /// Like [`MockTransport::response`], but only for queries sent to
+    /// `server`, so referrals from one server to the next can be scripted.
+    /// Takes precedence over responses for any server.
+    pub fn response_from(
+        self,
+        server: IpAddr,
+        name: &str,
+        type_: RecordType,
+        response: DNSPacket,
+    ) -> Self {
+        self.insert(Some(server), name, type_, response)
+    }
+
+    fn insert(
+        mut self,
+        server: Option<IpAddr>,
+        name: &str,
+        type_: RecordType,
+        response: DNSPacket,
+    ) -> Self {
+        self.responses
+            .insert((server, name.to_ascii_lowercase(), type_), response);
+        self
+    }
+
+    /// Every query received so far, with the server it was sent to.
+    pub fn queries(&self) -> Vec<(IpAddr, DNSQuestion)> {
+        self.queries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// An authoritative response with `answers` and nothing else.
+fn canned(rcode: Rcode, answers: Vec<DNSRecord>) -> DNSPacket {
+    let flags = DNSFlags {
+        qr: true,
+        aa: true,
+        rcode,
+        ..DNSFlags::default()
+    };
+    DNSPacket {
+        header: DNSHeader::new(flags, 0),
+        questions: Vec::new(),
+        answers,
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        edns: None,
+    }
+}
+
+impl DnsTransport for MockTransport {
+    async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+        let Some(question) = query.questions.first() else {
+            return Err(DnsError::InvalidMessage(
+                "A query must carry at least one question".to_string(),
+            ));
+        };
+        self.queries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((server, question.clone()));
+
+        let name = question.name.to_ascii_lowercase();
+        let canned = self
+            .responses
+            .get(&(Some(server), name.clone(), question.type_.clone()))
+            .or_else(|| self.responses.get(&(None, name, question.type_.clone())));
+        let Some(canned) = canned else {
+            return MessageBuilder::response_to(query)
+                .rcode(Rcode::Refused)
+                .build();
+        };
+        let mut builder = MessageBuilder::response_to(query).flags(DNSFlags {
+            qr: true,
+            opcode: query.header.flags.opcode,
+            rd: query.header.flags.rd,
+            ..canned.header.flags
+        });
+        for record in &canned.answers {
+            builder = builder.answer(record.clone());
+        }
+        for record in &canned.authorities {
+            builder = builder.authority(record.clone());
+        }
+        for record in &canned.additionals {
+            builder = builder.additional(record.clone());
+        }
+        if let Some(edns) = &canned.edns {
+            builder = builder.edns(edns.clone());
+        }
+        builder.build()
+    }
+
+    fn protocol(&self) -> &'static str {
+        "mock"
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod pool {
+    use std::ops::{Deref, DerefMut};
+    use std::sync::Mutex;
+
+    /// Receive buffers shared by the UDP, TCP and DoT transports.
+    pub(crate) static RECEIVE_BUFFERS: BufferPool = BufferPool::new();
+
+    /// Buffers kept for reuse, so steady-state lookups do not allocate one per
+    /// response. A buffer goes back to its pool when dropped.
+    #[derive(Debug)]
+    pub(crate) struct BufferPool {
+        buffers: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl BufferPool {
+        /// Plenty for the queries a busy resolver has in flight; any more are freed.
+        const MAX_POOLED: usize = 64;
+
+        pub(crate) const fn new() -> Self {
+            Self {
+                buffers: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// A buffer of exactly `size` bytes, with unspecified contents.
+        pub(crate) fn take(&self, size: usize) -> PooledBuffer<'_> {
+            let mut buf = self
+                .buffers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop()
+                .unwrap_or_default();
+            buf.resize(size, 0);
+            PooledBuffer { buf, pool: self }
+        }
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct PooledBuffer<'a> {
+        buf: Vec<u8>,
+        pool: &'a BufferPool,
+    }
+
+    impl Deref for PooledBuffer<'_> {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.buf
+        }
+    }
+
+    impl DerefMut for PooledBuffer<'_> {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+    }
+
+    impl Drop for PooledBuffer<'_> {
+        fn drop(&mut self) {
+            let mut buffers = self.pool.buffers.lock().unwrap_or_else(|e| e.into_inner());
+            if buffers.len() < BufferPool::MAX_POOLED {
+                buffers.push(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod net {
+    use super::pool::{PooledBuffer, RECEIVE_BUFFERS};
+    use super::{DNS_PORT, DnsTransport, TcpTransport, UdpTransport};
+    use crate::edns::DEFAULT_UDP_PAYLOAD_SIZE;
+    use crate::error::{DnsError, Result};
+    use crate::flags::DNSFlags;
+    use crate::{DNSPacket, u16_at};
+    use std::collections::HashMap;
+    use std::collections::hash_map::Entry;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpStream, UdpSocket};
+    use tokio::sync::{Mutex as AsyncMutex, OnceCell, oneshot};
+    use tracing::{debug, info};
+
+    /// How many sockets each address family gets. Queries pick one at random,
+    /// so a spoofed response has to guess the port as well as the ID.
+    const SOCKETS_PER_FAMILY: usize = 4;
+
+    /// A received datagram and its length.
+    pub(crate) type Datagram = (PooledBuffer<'static>, usize);
+
+    /// The sockets of a [`UdpTransport`], bound on first use.
+    #[derive(Debug, Default)]
+    pub(crate) struct SocketPool {
+        v4: OnceCell<Vec<SharedSocket>>,
+        v6: OnceCell<Vec<SharedSocket>>,
+    }
+
+    /// A socket carrying many queries at once. Whichever waiting query holds
+    /// `reader` receives for all of them, handing each response to the query
+    /// with its server and ID.
+    #[derive(Debug)]
+    pub(crate) struct SharedSocket {
+        socket: UdpSocket,
+        reader: AsyncMutex<()>,
+        pending: Mutex<HashMap<(SocketAddr, u16), oneshot::Sender<Datagram>>>,
+        /// The largest payload any query on this socket has advertised.
+        payload_size: AtomicU16,
+    }
+
+    /// Forgets a query once its exchange finishes or is dropped.
+    struct Pending<'a> {
+        socket: &'a SharedSocket,
+        key: (SocketAddr, u16),
+    }
+
+    impl Drop for Pending<'_> {
+        fn drop(&mut self) {
+            self.socket.pending().remove(&self.key);
+        }
+    }
+
+    impl SharedSocket {
+        pub(crate) fn new(socket: UdpSocket) -> Self {
+            Self {
+                socket,
+                reader: AsyncMutex::new(()),
+                pending: Mutex::new(HashMap::new()),
+                payload_size: AtomicU16::new(DEFAULT_UDP_PAYLOAD_SIZE),
+            }
+        }
+
+        fn pending(
+            &self,
+        ) -> std::sync::MutexGuard<'_, HashMap<(SocketAddr, u16), oneshot::Sender<Datagram>>>
+        {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// Sends `query` to `server` and waits for the datagram answering it.
+        /// Returns `None` if a query with the same ID is already waiting on
+        /// `server` here, as the responses could not be told apart.
+        pub(crate) async fn exchange(
+            &self,
+            query: &[u8],
+            server: SocketAddr,
+            payload_size: u16,
+        ) -> Result<Option<Datagram>> {
+            let id = u16_at(query, 0)?;
+            let key = (server, id);
+            let (sender, mut receiver) = oneshot::channel();
+            match self.pending().entry(key) {
+                Entry::Occupied(_) => return Ok(None),
+                Entry::Vacant(entry) => entry.insert(sender),
+            };
+            let _pending = Pending { socket: self, key };
+            self.payload_size.fetch_max(payload_size, Ordering::Relaxed);
+            self.socket.send_to(query, server).await?;
+
+            tokio::select! {
+                biased;
+                datagram = &mut receiver => Ok(datagram.ok()),
+                _reader = self.reader.lock() => {
+                    // The previous reader may have received ours before giving up the socket.
+                    if let Ok(datagram) = receiver.try_recv() {
+                        return Ok(Some(datagram));
+                    }
+                    Ok(Some(self.read_until(key).await?))
+                }
+            }
+        }
+
+        /// Receives for every waiting query until the response for `key` arrives.
+        async fn read_until(&self, key: (SocketAddr, u16)) -> io::Result<Datagram> {
+            loop {
+                let size = self.payload_size.load(Ordering::Relaxed);
+                let mut buf = RECEIVE_BUFFERS.take(size as usize);
+                let (size, source) = self.socket.recv_from(&mut buf).await?;
+                if size < 2 {
+                    continue;
+                }
+                let received = (source, u16::from_be_bytes([buf[0], buf[1]]));
+                if received == key {
+                    return Ok((buf, size));
+                }
+                match self.pending().remove(&received) {
+                    Some(waiting) => {
+                        let _ = waiting.send((buf, size));
+                    }
+                    None => debug!(%source, id = received.1, "Dropping unexpected response"),
+                }
+            }
+        }
+    }
+
+    async fn bind(server: IpAddr) -> io::Result<UdpSocket> {
+        let local: SocketAddr = match server {
+            IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        UdpSocket::bind(local).await
+    }
+
+    async fn bind_shared(server: IpAddr) -> io::Result<Vec<SharedSocket>> {
+        let mut sockets = Vec::with_capacity(SOCKETS_PER_FAMILY);
+        for _ in 0..SOCKETS_PER_FAMILY {
+            sockets.push(SharedSocket::new(bind(server).await?));
+        }
+        Ok(sockets)
+    }
+
+    impl DnsTransport for UdpTransport {
+        async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+            self.exchange_at(query, (server, DNS_PORT).into()).await
+        }
+
+        async fn exchange_at(&self, query: &DNSPacket, address: SocketAddr) -> Result<DNSPacket> {
+            let server = address.ip();
+            // Room for whatever the query advertised, and never less than the default.
+            let payload_size = query
+                .edns
+                .as_ref()
+                .map_or(DEFAULT_UDP_PAYLOAD_SIZE, |edns| edns.udp_payload_size)
+                .max(DEFAULT_UDP_PAYLOAD_SIZE);
+            let sockets = match server {
+                IpAddr::V4(_) => &self.sockets.v4,
+                IpAddr::V6(_) => &self.sockets.v6,
+            };
+            let sockets = sockets.get_or_try_init(|| bind_shared(server)).await?;
+            let shared = &sockets[rand::random_range(0..sockets.len())];
+            let query_bytes = query.to_bytes();
+            let (buf, size) = match shared.exchange(&query_bytes, address, payload_size).await? {
+                Some(datagram) => datagram,
+                None => {
+                    // Rare enough that a socket of its own is simplest.
+                    let socket = bind(server).await?;
+                    socket.send_to(&query_bytes, address).await?;
+                    let mut buf = RECEIVE_BUFFERS.take(payload_size as usize);
+                    let (size, _src) = socket.recv_from(&mut buf).await?;
+                    (buf, size)
+                }
+            };
+            let response = DNSPacket::parse(&buf[..size])?;
+            if response.header.flags.tc {
+                info!(%server, "Response truncated, retrying over TCP");
+                return self.tcp.exchange_at(query, address).await;
+            }
+            Ok(response)
+        }
+
+        /// Uses a socket of its own, so a malformed query cannot disturb
+        /// others, and repeats the query over TCP when the response is truncated.
+        async fn exchange_raw(&self, query: &[u8], address: SocketAddr) -> Result<Vec<u8>> {
+            let socket = bind(address.ip()).await?;
+            socket.connect(address).await?;
+            socket.send(query).await?;
+            let mut buf = vec![0; u16::MAX as usize];
+            let size = socket.recv(&mut buf).await?;
+            buf.truncate(size);
+            if u16_at(&buf, 2).is_ok_and(|flags| DNSFlags::from(flags).tc) {
+                info!(%address, "Response truncated, retrying over TCP");
+                return self.tcp.exchange_raw(query, address).await;
+            }
+            Ok(buf)
+        }
+
+        fn protocol(&self) -> &'static str {
+            "udp"
+        }
+    }
+
+    impl DnsTransport for TcpTransport {
+        async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+            self.exchange_at(query, (server, DNS_PORT).into()).await
+        }
+
+        async fn exchange_at(&self, query: &DNSPacket, address: SocketAddr) -> Result<DNSPacket> {
+            let connect = || async move {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            };
+            self.connections.exchange(address, query, connect).await
+        }
+
+        /// Uses a connection of its own, which the server may close over a
+        /// malformed query, rather than one shared with other queries.
+        async fn exchange_raw(&self, query: &[u8], address: SocketAddr) -> Result<Vec<u8>> {
+            let len = u16::try_from(query.len()).map_err(|_| {
+                DnsError::InvalidMessage(format!("Message of {} bytes", query.len()))
+            })?;
+            let mut stream = TcpStream::connect(address).await?;
+            stream.write_u16(len).await?;
+            stream.write_all(query).await?;
+            let mut response = vec![0; stream.read_u16().await? as usize];
+            stream.read_exact(&mut response).await?;
+            Ok(response)
+        }
+
+        fn protocol(&self) -> &'static str {
+            "tcp"
+        }
+    }
+
+    /// Writes `query` to a stream and reads back one message, each prefixed with
+    /// its two-byte length (RFC 1035 section 4.2.2). For a connection used once.
+    pub(super) async fn exchange_stream<S>(stream: &mut S, query: &DNSPacket) -> Result<DNSPacket>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(&query.to_tcp_bytes()?).await?;
+
+        let len = stream.read_u16().await?;
+        let mut buf = RECEIVE_BUFFERS.take(len as usize);
+        stream.read_exact(&mut buf).await?;
+        DNSPacket::parse(&buf)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod pipeline {
+    use super::net::exchange_stream;
+    use super::pool::{PooledBuffer, RECEIVE_BUFFERS};
+    use crate::error::{DnsError, Result};
+    use crate::{DNSPacket, u16_at};
+    use std::collections::HashMap;
+    use std::collections::hash_map::Entry;
+    use std::fmt;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+    use tokio::sync::{Mutex as AsyncMutex, OnceCell, oneshot};
+    use tracing::debug;
+
+    /// How much is read from a connection at a time.
+    const READ_CHUNK: usize = 4096;
+
+    /// The open connections of a stream transport, one per server, shared by
+    /// its clones. Queries are pipelined on them (RFC 7766 section 6.2.1.1):
+    /// each is written as soon as it is made, and responses are matched to
+    /// queries by ID in whatever order they arrive.
+    pub(crate) struct Connections<S> {
+        open: Mutex<HashMap<SocketAddr, Slot<S>>>,
+    }
+
+    /// Where the connection to one server goes once made, so that queries
+    /// arriving together wait for a single connection rather than each
+    /// opening their own.
+    type Slot<S> = Arc<OnceCell<Arc<Pipeline<S>>>>;
+
+    impl<S> Default for Connections<S> {
+        fn default() -> Self {
+            Self {
+                open: Mutex::new(HashMap::new()),
+            }
+        }
+    }
+
+    impl<S> fmt::Debug for Connections<S> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let servers: Vec<_> = self.open().keys().copied().collect();
+            f.debug_struct("Connections")
+                .field("servers", &servers)
+                .finish()
+        }
+    }
+
+    impl<S> Connections<S> {
+        fn open(&self) -> MutexGuard<'_, HashMap<SocketAddr, Slot<S>>> {
+            self.open.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// The slot for `server`, emptied first if its connection has closed.
+        fn slot(&self, server: SocketAddr) -> Slot<S> {
+            let mut open = self.open();
+            let slot = open.entry(server).or_default();
+            if slot.get().is_some_and(|pipeline| pipeline.is_closed()) {
+                *slot = Slot::default();
+            }
+            slot.clone()
+        }
+    }
+
+    impl<S> Connections<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        /// Sends `query` over the open connection to `server`, calling
+        /// `connect` first if there is none. A reused connection that turns
+        /// out to be gone, as when the server closed it while idle, is
+        /// replaced and the query sent once more.
+        pub(crate) async fn exchange<C, F>(
+            &self,
+            server: SocketAddr,
+            query: &DNSPacket,
+            connect: C,
+        ) -> Result<DNSPacket>
+        where
+            C: Fn() -> F,
+            F: Future<Output = Result<S>>,
+        {
+            let message = query.to_tcp_bytes()?;
+            let mut reconnected = false;
+            loop {
+                let mut fresh = false;
+                let pipeline = self
+                    .slot(server)
+                    .get_or_try_init(|| async {
+                        fresh = true;
+                        Ok::<_, DnsError>(Arc::new(Pipeline::new(connect().await?)))
+                    })
+                    .await?
+                    .clone();
+                match pipeline.exchange(&message).await {
+                    Ok(Some(response)) => return DNSPacket::parse(&response),
+                    // Rare enough that a connection of its own is simplest.
+                    Ok(None) => return exchange_stream(&mut connect().await?, query).await,
+                    Err(DnsError::IoError(e)) if !fresh && !reconnected => {
+                        debug!(%server, error = %e, "Connection lost, reconnecting");
+                        reconnected = true;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// One connection carrying many queries at once. As with
+    /// [`SharedSocket`](super::net::SharedSocket), whichever waiting query
+    /// holds `reader` reads for all of them.
+    pub(crate) struct Pipeline<S> {
+        writer: AsyncMutex<WriteHalf<S>>,
+        reader: AsyncMutex<Reader<S>>,
+        pending: Mutex<HashMap<u16, oneshot::Sender<PooledBuffer<'static>>>>,
+        /// Set once the connection fails, or a write to it is cut short; it
+        /// then takes no more queries.
+        closed: AtomicBool,
+    }
+
+    struct Reader<S> {
+        half: ReadHalf<S>,
+        /// Bytes received but not yet a whole message. Kept here rather than
+        /// on the stack so that a reader giving up mid-message loses nothing.
+        received: Vec<u8>,
+    }
+
+    /// Forgets a query once its exchange finishes or is dropped.
+    struct Pending<'a, S> {
+        pipeline: &'a Pipeline<S>,
+        id: u16,
+    }
+
+    impl<S> Drop for Pending<'_, S> {
+        fn drop(&mut self) {
+            self.pipeline.pending().remove(&self.id);
+        }
+    }
+
+    /// Closes a connection if dropped before `done`, as a partly written
+    /// message would leave the server unable to find where the next starts.
+    struct Writing<'a> {
+        closed: &'a AtomicBool,
+        done: bool,
+    }
+
+    impl Drop for Writing<'_> {
+        fn drop(&mut self) {
+            if !self.done {
+                self.closed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn closed_error() -> DnsError {
+        io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into()
+    }
+
+    impl<S> Pipeline<S> {
+        fn pending(&self) -> MutexGuard<'_, HashMap<u16, oneshot::Sender<PooledBuffer<'static>>>> {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+    }
+
+    impl<S> Pipeline<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        pub(crate) fn new(stream: S) -> Self {
+            let (read, write) = tokio::io::split(stream);
+            Self {
+                writer: AsyncMutex::new(write),
+                reader: AsyncMutex::new(Reader {
+                    half: read,
+                    received: Vec::new(),
+                }),
+                pending: Mutex::new(HashMap::new()),
+                closed: AtomicBool::new(false),
+            }
+        }
+
+        /// Writes a length-prefixed query and waits for the message answering
+        /// it. Returns `None` if a query with the same ID is already waiting
+        /// here, as the responses could not be told apart.
+        pub(crate) async fn exchange(
+            &self,
+            message: &[u8],
+        ) -> Result<Option<PooledBuffer<'static>>> {
+            let id = u16_at(message, 2)?;
+            let (sender, mut receiver) = oneshot::channel();
+            match self.pending().entry(id) {
+                Entry::Occupied(_) => return Ok(None),
+                Entry::Vacant(entry) => entry.insert(sender),
+            };
+            let _pending = Pending { pipeline: self, id };
+
+            {
+                let mut writer = self.writer.lock().await;
+                if self.is_closed() {
+                    return Err(closed_error());
+                }
+                let mut writing = Writing {
+                    closed: &self.closed,
+                    done: false,
+                };
+                writer.write_all(message).await?;
+                writer.flush().await?;
+                writing.done = true;
+            }
+
+            tokio::select! {
+                biased;
+                response = &mut receiver => response.map(Some).map_err(|_| closed_error()),
+                mut reader = self.reader.lock() => {
+                    // The previous reader may have received ours before giving up the connection.
+                    if let Ok(response) = receiver.try_recv() {
+                        return Ok(Some(response));
+                    }
+                    if self.is_closed() {
+                        return Err(closed_error());
+                    }
+                    match self.read_until(&mut reader, id).await {
+                        Ok(response) => Ok(Some(response)),
+                        Err(e) => {
+                            // Everyone still waiting learns of it as their sender drops.
+                            self.closed.store(true, Ordering::Relaxed);
+                            self.pending().clear();
+                            Err(e.into())
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Reads messages for every waiting query until the one for `id` arrives.
+        async fn read_until(
+            &self,
+            reader: &mut Reader<S>,
+            id: u16,
+        ) -> io::Result<PooledBuffer<'static>> {
+            loop {
+                let message = reader.next_message().await?;
+                if message.len() < 2 {
+                    continue;
+                }
+                let received = u16::from_be_bytes([message[0], message[1]]);
+                if received == id {
+                    return Ok(message);
+                }
+                match self.pending().remove(&received) {
+                    Some(waiting) => {
+                        let _ = waiting.send(message);
+                    }
+                    None => debug!(id = received, "Dropping unexpected response"),
+                }
+            }
+        }
+    }
+
+    impl<S: AsyncRead> Reader<S> {
+        /// The next length-prefixed message. Safe to cancel: whatever was read
+        /// stays in `received` for the next reader.
+        async fn next_message(&mut self) -> io::Result<PooledBuffer<'static>> {
+            loop {
+                if let Ok(len) = u16_at(&self.received, 0)
+                    && self.received.len() >= 2 + len as usize
+                {
+                    let end = 2 + len as usize;
+                    let mut message = RECEIVE_BUFFERS.take(len as usize);
+                    message.copy_from_slice(&self.received[2..end]);
+                    self.received.drain(..end);
+                    return Ok(message);
+                }
+                let mut chunk = RECEIVE_BUFFERS.take(READ_CHUNK);
+                let read = self.half.read(&mut chunk).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.received.extend_from_slice(&chunk[..read]);
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "dot", not(target_arch = "wasm32")))]
+pub use self::tls::TlsTransport;
+
+#[cfg(all(feature = "dot", not(target_arch = "wasm32")))]
+mod tls {
+    use super::DnsTransport;
+    use super::pipeline::Connections;
+    use crate::DNSPacket;
+    use crate::error::{DnsError, Result};
+    use std::fmt;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    const DOT_PORT: u16 = 853;
+
+    /// DNS over TLS (RFC 7858) to a single recursive resolver, whose
+    /// certificate must be valid for `server_name`. Queries are pipelined on
+    /// one connection, shared by the transport and its clones.
+    #[derive(Clone)]
+    pub struct TlsTransport {
+        address: SocketAddr,
+        server_name: ServerName<'static>,
+        connector: TlsConnector,
+        connections: Arc<Connections<TlsStream<TcpStream>>>,
+    }
+
+    impl fmt::Debug for TlsTransport {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("TlsTransport")
+                .field("address", &self.address)
+                .field("server_name", &self.server_name)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl TlsTransport {
+        /// Connects to `address` on port 853, such as `1.1.1.1` with the
+        /// name `cloudflare-dns.com`.
+        pub fn new(address: IpAddr, server_name: &str) -> Result<Self> {
+            let server_name = ServerName::try_from(server_name.to_string())
+                .map_err(|_| DnsError::InvalidConfig(format!("Invalid TLS name {server_name}")))?;
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Ok(TlsTransport {
+                address: (address, DOT_PORT).into(),
+                server_name,
+                connector: TlsConnector::from(Arc::new(config)),
+                connections: Arc::default(),
+            })
+        }
+
+        async fn connect(&self) -> Result<TlsStream<TcpStream>> {
+            let stream = TcpStream::connect(self.address).await?;
+            stream.set_nodelay(true)?;
+            Ok(self
+                .connector
+                .connect(self.server_name.clone(), stream)
+                .await?)
+        }
+    }
+
+    impl DnsTransport for TlsTransport {
+        async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+            self.connections
+                .exchange(self.address, query, || self.connect())
+                .await
+        }
+
+        fn protocol(&self) -> &'static str {
+            "dot"
+        }
+    }
+}
+
+#[cfg(all(feature = "replay", not(target_arch = "wasm32")))]
+pub use self::replay::ReplayTransport;
+
+#[cfg(all(feature = "replay", not(target_arch = "wasm32")))]
+mod replay {
+    use super::{DNS_PORT, DnsTransport};
+    use crate::error::{DnsError, Result};
+    use crate::{Class, DNSPacket, DNSQuestion, RecordType};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    type Key = (IpAddr, String, RecordType, Class);
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Exchange {
+        server: IpAddr,
+        question: DNSQuestion,
+        response: DNSPacket,
+    }
+
+    impl Exchange {
+        fn key(&self) -> Key {
+            key(self.server, &self.question)
+        }
+    }
+
+    fn key(server: IpAddr, question: &DNSQuestion) -> Key {
+        (
+            server,
+            question.name.to_ascii_lowercase(),
+            question.type_.clone(),
+            question.class.clone(),
+        )
+    }
+
+    /// Records the exchanges of another transport to a fixture file, then
+    /// replays them, so that integration tests of iterative resolution run
+    /// the same offline and in CI. Without a file at the path, queries go
+    /// through `inner` and the file is rewritten as each response arrives;
+    /// once it exists, responses come from it alone. Delete it to record afresh.
+    ///
+    /// Responses are kept as RFC 8427 JSON, keyed by server, name regardless
+    /// of case, type and class, and take the ID and question of the query
+    /// they are replayed for. Failed exchanges are not recorded, and a query
+    /// with no recorded response fails as if the server were unreachable.
+    #[derive(Debug, Clone)]
+    pub struct ReplayTransport<T> {
+        inner: T,
+        path: PathBuf,
+        recording: bool,
+        exchanges: Arc<Mutex<HashMap<Key, Exchange>>>,
+    }
+
+    impl<T: DnsTransport> ReplayTransport<T> {
+        /// Replays from the fixture at `path` if there is one, and otherwise
+        /// records to it through `inner`.
+        pub fn new(path: impl Into<PathBuf>, inner: T) -> Result<Self> {
+            let path = path.into();
+            let (recording, exchanges) = match fs::read(&path) {
+                Ok(json) => {
+                    let exchanges: Vec<Exchange> = serde_json::from_slice(&json).map_err(|e| {
+                        DnsError::InvalidConfig(format!("Invalid fixture {}: {e}", path.display()))
+                    })?;
+                    let exchanges = exchanges.into_iter().map(|e| (e.key(), e)).collect();
+                    (false, exchanges)
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (true, HashMap::new()),
+                Err(e) => return Err(e.into()),
+            };
+            Ok(Self {
+                inner,
+                path,
+                recording,
+                exchanges: Arc::new(Mutex::new(exchanges)),
+            })
+        }
+
+        /// Whether queries go through the inner transport rather than the fixture.
+        pub fn is_recording(&self) -> bool {
+            self.recording
+        }
+
+        fn exchanges(&self) -> MutexGuard<'_, HashMap<Key, Exchange>> {
+            self.exchanges.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// Writes every exchange so far, sorted so that re-recording the same
+        /// queries gives the same file.
+        fn save(&self, exchanges: &HashMap<Key, Exchange>) -> Result<()> {
+            let mut sorted: Vec<_> = exchanges.iter().collect();
+            sorted.sort_by_key(|((server, name, type_, class), _)| {
+                (*server, name.clone(), type_.to_string(), class.to_string())
+            });
+            let sorted: Vec<_> = sorted.into_iter().map(|(_, exchange)| exchange).collect();
+            let json = serde_json::to_vec_pretty(&sorted)
+                .map_err(|e| DnsError::InvalidMessage(e.to_string()))?;
+            fs::write(&self.path, json)?;
+            Ok(())
+        }
+    }
+
+    impl<T: DnsTransport> DnsTransport for ReplayTransport<T> {
+        async fn exchange(&self, query: &DNSPacket, server: IpAddr) -> Result<DNSPacket> {
+            self.exchange_at(query, (server, DNS_PORT).into()).await
+        }
+
+        /// Exchanges are keyed by the server's address alone, whatever its port.
+        async fn exchange_at(&self, query: &DNSPacket, address: SocketAddr) -> Result<DNSPacket> {
+            let server = address.ip();
+            let Some(question) = query.questions.first() else {
+                return Err(DnsError::InvalidMessage(
+                    "A query must carry at least one question".to_string(),
+                ));
+            };
+            if !self.recording {
+                let recorded = self
+                    .exchanges()
+                    .get(&key(server, question))
+                    .map(|exchange| exchange.response.clone());
+                let Some(mut response) = recorded else {
+                    let message = format!(
+                        "No recorded response from {server} for {} {}",
+                        question.name, question.type_
+                    );
+                    return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+                };
+                response.header.id = query.header.id;
+                response.questions = query.questions.clone();
+                return Ok(response);
+            }
+
+            let response = self.inner.exchange_at(query, address).await?;
+            let exchange = Exchange {
+                server,
+                question: DNSQuestion {
+                    name: question.name.to_ascii_lowercase(),
+                    ..question.clone()
+                },
+                response: response.clone(),
+            };
+            let mut exchanges = self.exchanges();
+            exchanges.insert(exchange.key(), exchange);
+            self.save(&exchanges)?;
+            Ok(response)
+        }
+
+        fn protocol(&self) -> &'static str {
+            self.inner.protocol()
+        }
+    }
+}
+
+#[cfg(feature = "doh")]
+pub use self::https::HttpsTransport;
+
+#[cfg(feature = "doh")]
+mod https {
+    use super::DnsTransport;
+    use crate::DNSPacket;
+    use crate::error::{DnsError, Result};
+    use reqwest::header::{ACCEPT, CONTENT_TYPE};
+    use reqwest::{Client, Url};
+    use std::io;
+    use std::net::IpAddr;
+
+    const DNS_MESSAGE: &str = "application/dns-message";
+
+    /// DNS over HTTPS (RFC 8484) to a single recursive resolver, sending each
+    /// query in the body of a POST request.
+    #[derive(Debug, Clone)]
+    pub struct HttpsTransport {
+        client: Client,
+        url: Url,
+    }
+
+    impl HttpsTransport {
+        /// Queries the DoH endpoint at `url`, such as
+        /// `https://cloudflare-dns.com/dns-query`.
+        pub fn new(url: &str) -> Result<Self> {
+            let url = Url::parse(url)
+                .map_err(|e| DnsError::InvalidConfig(format!("Invalid DoH URL {url}: {e}")))?;
+            Ok(HttpsTransport {
+                client: Client::new(),
+                url,
+            })
+        }
+
+        async fn post(&self, body: Vec<u8>) -> Result<DNSPacket> {
+            let response = self
+                .client
+                .post(self.url.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .header(ACCEPT, DNS_MESSAGE)
+                .body(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(io::Error::other)?;
+            let body = response.bytes().await.map_err(io::Error::other)?;
+            DNSPacket::parse(&body)
+        }
+    }
+
+    impl DnsTransport for HttpsTransport {
+        async fn exchange(&self, query: &DNSPacket, _server: IpAddr) -> Result<DNSPacket> {
+            let fetch = self.post(query.to_bytes());
+            // Browser fetches are not Send, but wasm32 only has the one thread.
+            #[cfg(target_arch = "wasm32")]
+            let fetch = send_wrapper::SendWrapper::new(fetch);
+            fetch.await
+        }
+
+        fn protocol(&self) -> &'static str {
+            "doh"
+        }
+    }
+}
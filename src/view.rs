@@ -0,0 +1,309 @@
+//! Borrowed, zero-copy access to a wire-format message. [`PacketView::parse`]
+//! checks the framing of every name and record once without allocating;
+//! names and RDATA are then read straight out of the input buffer, and only
+//! the records actually needed are decoded into owned [`DNSRecord`]s.
+
+use crate::error::{DnsError, Result};
+use crate::{
+    Class, DNSHeader, DNSPacket, DNSRecord, RecordType, u16_at, u32_at, walk_name, wire_name_eq,
+};
+use std::fmt;
+
+const HEADER_LEN: usize = 12;
+
+/// A message whose sections are read in place from the buffer it was parsed from.
+#[derive(Debug, Clone)]
+pub struct PacketView<'a> {
+    buf: &'a [u8],
+    header: DNSHeader,
+    /// Where the answer, authority and additional sections start.
+    sections: [usize; 3],
+}
+
+impl<'a> PacketView<'a> {
+    /// Checks that the header, every name and every record fit in `buf`.
+    /// Record types and classes are not interpreted until asked for.
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        let header = DNSHeader::parse(buf)?;
+        let mut cursor = HEADER_LEN;
+        for _ in 0..header.num_questions {
+            cursor += walk_name(buf, cursor, |_| ())?;
+            if buf.len() < cursor + 4 {
+                return Err(DnsError::Truncated);
+            }
+            cursor += 4;
+        }
+        let mut sections = [0; 3];
+        let counts = [
+            header.num_answers,
+            header.num_authorities,
+            header.num_additionals,
+        ];
+        for (start, count) in sections.iter_mut().zip(counts) {
+            *start = cursor;
+            for _ in 0..count {
+                cursor += walk_name(buf, cursor, |_| ())?;
+                let data_len = u16_at(buf, cursor + 8)? as usize;
+                cursor += 10 + data_len;
+                if buf.len() < cursor {
+                    return Err(DnsError::Truncated);
+                }
+            }
+        }
+        Ok(Self {
+            buf,
+            header,
+            sections,
+        })
+    }
+
+    pub fn header(&self) -> &DNSHeader {
+        &self.header
+    }
+
+    /// The bytes the view was parsed from.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn questions(&self) -> impl Iterator<Item = QuestionRef<'a>> + 'a {
+        let buf = self.buf;
+        let mut cursor = HEADER_LEN;
+        (0..self.header.num_questions).map(move |_| {
+            let name = NameRef { buf, start: cursor };
+            cursor += name.wire_len();
+            let question = QuestionRef {
+                name,
+                rtype: read_u16(buf, cursor),
+                class: read_u16(buf, cursor + 2),
+            };
+            cursor += 4;
+            question
+        })
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        self.records(0, self.header.num_answers)
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        self.records(1, self.header.num_authorities)
+    }
+
+    /// Includes the OPT record, if any, as it appears on the wire.
+    pub fn additionals(&self) -> Records<'a> {
+        self.records(2, self.header.num_additionals)
+    }
+
+    /// Decodes the whole message, as [`DNSPacket::parse`] would.
+    pub fn to_packet(&self) -> Result<DNSPacket> {
+        DNSPacket::parse(self.buf)
+    }
+
+    fn records(&self, section: usize, remaining: u16) -> Records<'a> {
+        Records {
+            buf: self.buf,
+            cursor: self.sections[section],
+            remaining,
+        }
+    }
+}
+
+/// Reads a `u16` that [`PacketView::parse`] already checked is in bounds.
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+/// A name inside a validated message, compression pointers left unresolved
+/// until its labels are walked.
+#[derive(Debug, Clone, Copy)]
+pub struct NameRef<'a> {
+    buf: &'a [u8],
+    start: usize,
+}
+
+impl<'a> NameRef<'a> {
+    /// The raw labels from the leftmost one, the root excluded.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            buf: self.buf,
+            cursor: self.start,
+        }
+    }
+
+    /// Compares against a dotted name, ignoring ASCII case and a trailing dot.
+    pub fn eq_name(&self, name: &str) -> bool {
+        wire_name_eq(self.buf, self.start, name.strip_suffix('.').unwrap_or(name))
+    }
+
+    fn wire_len(&self) -> usize {
+        walk_name(self.buf, self.start, |_| ()).unwrap_or_default()
+    }
+}
+
+/// Dotted form without a trailing dot, like the owned types.
+impl fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(&String::from_utf8_lossy(label))?;
+        }
+        Ok(())
+    }
+}
+
+/// The labels of a [`NameRef`], following compression pointers as they come.
+#[derive(Debug, Clone)]
+pub struct Labels<'a> {
+    buf: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The name was checked when the view was parsed, so every length and
+        // pointer here is in bounds and points backwards.
+        loop {
+            let length = *self.buf.get(self.cursor)? as usize;
+            if length & 0b11000000 == 0b11000000 {
+                let low = *self.buf.get(self.cursor + 1)?;
+                self.cursor = u16::from_be_bytes([length as u8 & 0b00111111, low]) as usize;
+                continue;
+            }
+            if length == 0 {
+                return None;
+            }
+            let start = self.cursor + 1;
+            self.cursor = start + length;
+            return self.buf.get(start..self.cursor);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    name: NameRef<'a>,
+    rtype: u16,
+    class: u16,
+}
+
+impl<'a> QuestionRef<'a> {
+    pub fn name(&self) -> NameRef<'a> {
+        self.name
+    }
+
+    /// The type code as sent, which may be one this crate does not know.
+    pub fn rtype(&self) -> u16 {
+        self.rtype
+    }
+
+    pub fn record_type(&self) -> Option<RecordType> {
+        RecordType::try_from(self.rtype).ok()
+    }
+
+    pub fn class(&self) -> Option<Class> {
+        Class::try_from(self.class).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    name: NameRef<'a>,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata_start: usize,
+    rdata: &'a [u8],
+}
+
+impl<'a> RecordRef<'a> {
+    pub fn name(&self) -> NameRef<'a> {
+        self.name
+    }
+
+    /// The type code as sent, which may be one this crate does not know.
+    pub fn rtype(&self) -> u16 {
+        self.rtype
+    }
+
+    pub fn record_type(&self) -> Option<RecordType> {
+        RecordType::try_from(self.rtype).ok()
+    }
+
+    /// The raw class field; for OPT records this is the UDP payload size.
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// The RDATA exactly as sent. Any names inside may be compressed.
+    pub fn rdata(&self) -> &'a [u8] {
+        self.rdata
+    }
+
+    /// The target of an NS, CNAME or PTR record, read in place.
+    pub fn target(&self) -> Option<NameRef<'a>> {
+        let name = NameRef {
+            buf: self.buf,
+            start: self.rdata_start,
+        };
+        let is_name = matches!(
+            self.record_type(),
+            Some(RecordType::Ns | RecordType::Cname | RecordType::Ptr)
+        );
+        (is_name && walk_name(self.buf, self.rdata_start, |_| ()).is_ok()).then_some(name)
+    }
+
+    /// Decodes this record alone into its owned form.
+    pub fn to_record(&self) -> Result<DNSRecord> {
+        DNSRecord::parse(self.buf, self.offset).map(|(record, _)| record)
+    }
+}
+
+/// The records of one section, read lazily from the buffer.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    buf: &'a [u8],
+    cursor: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = RecordRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        let buf = self.buf;
+        let offset = self.cursor;
+        let name = NameRef { buf, start: offset };
+        let fixed = offset + name.wire_len();
+        let rdata_start = fixed + 10;
+        let data_len = read_u16(buf, fixed + 8) as usize;
+        self.cursor = rdata_start + data_len;
+        Some(RecordRef {
+            buf,
+            offset,
+            name,
+            rtype: read_u16(buf, fixed),
+            class: read_u16(buf, fixed + 2),
+            ttl: u32_at(buf, fixed + 4).unwrap_or_default(),
+            rdata_start,
+            rdata: &buf[rdata_start..self.cursor],
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for Records<'_> {}
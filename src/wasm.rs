@@ -0,0 +1,30 @@
+//! JavaScript bindings for resolving from a browser, such as from the static
+//! web UI, through a DNS over HTTPS endpoint. Build them with
+//! `wasm-pack build --target web -- --features doh`.
+
+use crate::DNSResolver;
+use crate::transport::HttpsTransport;
+use std::net::Ipv4Addr;
+use wasm_bindgen::prelude::*;
+
+/// Resolves `domain` to its IPv4 address through the DoH endpoint at `doh_url`,
+/// such as `https://cloudflare-dns.com/dns-query`.
+#[wasm_bindgen]
+pub async fn resolve(domain: String, doh_url: String) -> Result<String, JsError> {
+    let ip = resolver(&doh_url)?.resolve(&domain).await?;
+    Ok(ip.to_string())
+}
+
+/// Resolves `ip` back to its domain name through the DoH endpoint at `doh_url`.
+#[wasm_bindgen(js_name = reverseResolve)]
+pub async fn reverse_resolve(ip: String, doh_url: String) -> Result<String, JsError> {
+    let ip: Ipv4Addr = ip.parse()?;
+    Ok(resolver(&doh_url)?.reverse_resolve(&ip).await?)
+}
+
+fn resolver(doh_url: &str) -> crate::error::Result<DNSResolver<HttpsTransport>> {
+    DNSResolver::builder()
+        .transport(HttpsTransport::new(doh_url)?)
+        .recursion_desired(true)
+        .build()
+}
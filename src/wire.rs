@@ -0,0 +1,47 @@
+//! Parsing and encoding over [`bytes`] buffers, so a server can slice incoming
+//! datagrams and frame TCP messages without copying payloads between buffers.
+//!
+//! [`DNSPacket::parse`] and [`PacketView::parse`](crate::view::PacketView::parse)
+//! read a [`Bytes`] directly, since it dereferences to a byte slice.
+
+use crate::DNSPacket;
+use crate::error::Result;
+use crate::view::RecordRef;
+use bytes::{Buf, Bytes, BytesMut};
+
+impl DNSPacket {
+    /// The wire format, handed over without a copy.
+    pub fn encode(&self) -> Bytes {
+        Bytes::from(self.to_bytes())
+    }
+
+    /// The wire format preceded by its two-byte length, ready to write to a
+    /// TCP stream. Fails if the message is too long to frame.
+    pub fn encode_tcp(&self) -> Result<Bytes> {
+        self.to_tcp_bytes().map(Bytes::from)
+    }
+}
+
+/// Splits the next complete length-prefixed message off the front of `buf`,
+/// without copying it. Returns `None` until all of it has been read.
+pub fn next_tcp_message(buf: &mut BytesMut) -> Option<Bytes> {
+    let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+    if buf.len() < 2 + len {
+        // Saves reallocating as the rest of the message arrives.
+        buf.reserve(2 + len - buf.len());
+        return None;
+    }
+    buf.advance(2);
+    Some(buf.split_to(len).freeze())
+}
+
+impl RecordRef<'_> {
+    /// The RDATA as a slice of `message`, sharing its memory.
+    ///
+    /// # Panics
+    ///
+    /// If the record was not read from a view of `message`.
+    pub fn rdata_bytes(&self, message: &Bytes) -> Bytes {
+        message.slice_ref(self.rdata())
+    }
+}
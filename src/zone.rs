@@ -0,0 +1,672 @@
+//! Master files (RFC 1035 section 5): zones read from them and answered
+//! from as their authoritative server would, and written back out so they
+//! can be exported, diffed and kept under version control.
+
+use crate::builder::MessageBuilder;
+use crate::edns::Edns;
+use crate::error::{DnsError, Result};
+use crate::flags::{DNSFlags, Rcode};
+use crate::{Class, DNSPacket, DNSRecord, DNSRecordData, Mx, Naptr, RecordType, Soa, Srv, fqdn};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// How many CNAMEs an answer follows within a zone, so a loop in the
+/// records cannot go on forever.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Renders `records` as a master file for `origin`. Records are written in
+/// canonical order (RFC 4034 section 6.1) with the SOA leading its owner's
+/// RRsets, and exact duplicates, such as the closing SOA of a zone transfer,
+/// are dropped. All names are written fully qualified.
+pub fn to_zone_file(origin: &str, records: &[DNSRecord]) -> String {
+    let mut sorted: Vec<&DNSRecord> = records.iter().collect();
+    sorted.sort_by(|a, b| {
+        canonical_name_cmp(&a.name, &b.name)
+            .then_with(|| (a.type_ != RecordType::Soa).cmp(&(b.type_ != RecordType::Soa)))
+            .then_with(|| (a.type_.clone() as u16).cmp(&(b.type_.clone() as u16)))
+            .then_with(|| a.data.to_string().cmp(&b.data.to_string()))
+    });
+    sorted.dedup();
+
+    let mut zone = format!("$ORIGIN {}\n", fqdn(origin));
+    for record in sorted {
+        // Writing to a String cannot fail.
+        let _ = writeln!(zone, "{record}");
+    }
+    zone
+}
+
+/// Orders names by their labels from the root down, ignoring ASCII case.
+fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| {
+        name.trim_end_matches('.')
+            .rsplit('.')
+            .filter(|label| !label.is_empty())
+            .map(|label| label.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+/// Reads the records of a master file for `origin`, which relative names
+/// are completed with until a `$ORIGIN` line changes it. Owner names may be
+/// `@`, relative, or left out to repeat the previous one; TTLs may use
+/// BIND's `1h30m` units, defaulting to `$TTL` or else the last TTL given;
+/// parentheses continue a record over several lines and `;` starts a
+/// comment. Rdata this crate has no type for is read in the generic
+/// `\# 2 dead` form (RFC 3597).
+pub fn parse_zone_file(origin: &str, text: &str) -> Result<Vec<DNSRecord>> {
+    let mut origin = origin.trim_end_matches('.').to_string();
+    let mut default_ttl = None;
+    let mut last_ttl = None;
+    let mut owner: Option<String> = None;
+    let mut records = Vec::new();
+    for entry in entries(text)? {
+        let invalid = |message: String| invalid(entry.line, message);
+        let mut tokens = entry.tokens.as_slice();
+        if !entry.indented {
+            let (first, rest) = tokens.split_first().expect("entries are not empty");
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let [name] = rest else {
+                        return Err(invalid("$ORIGIN takes one name".to_string()));
+                    };
+                    origin = absolute(&name.text, &origin);
+                    continue;
+                }
+                "$TTL" => {
+                    let ttl = rest.first().and_then(|ttl| parse_ttl(&ttl.text));
+                    default_ttl = Some(ttl.ok_or_else(|| invalid("Invalid $TTL".to_string()))?);
+                    continue;
+                }
+                directive if directive.starts_with('$') => {
+                    return Err(invalid(format!("Unsupported directive {}", first.text)));
+                }
+                _ => {}
+            }
+            owner = Some(absolute(&first.text, &origin));
+            tokens = rest;
+        }
+        let Some(name) = owner.clone() else {
+            return Err(invalid("The first record has no owner name".to_string()));
+        };
+        let mut ttl = None;
+        let mut class = Class::In;
+        let type_ = loop {
+            let Some((token, rest)) = tokens.split_first() else {
+                return Err(invalid("Missing record type".to_string()));
+            };
+            tokens = rest;
+            match token.text.to_ascii_uppercase().as_str() {
+                "IN" => class = Class::In,
+                "CH" => class = Class::Ch,
+                "HS" => class = Class::Hs,
+                text if ttl.is_none() && text.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(
+                        parse_ttl(text).ok_or_else(|| invalid(format!("Invalid TTL {text}")))?,
+                    );
+                }
+                _ => {
+                    break RecordType::from_str(&token.text).map_err(|e| invalid(e.to_string()))?;
+                }
+            }
+        };
+        let data = rdata(&type_, tokens, &origin).map_err(invalid)?;
+        if ttl.is_some() {
+            last_ttl = ttl;
+        }
+        let ttl = ttl
+            .or(default_ttl)
+            .or(last_ttl)
+            .or(match &data {
+                DNSRecordData::Soa(soa) => Some(soa.minimum),
+                _ => None,
+            })
+            .ok_or_else(|| invalid("No TTL given, nor a $TTL".to_string()))?;
+        records.push(DNSRecord::new(&name, type_, class, ttl, data));
+    }
+    Ok(records)
+}
+
+fn invalid(line: usize, message: String) -> DnsError {
+    DnsError::InvalidZone(format!("line {line}: {message}"))
+}
+
+/// A record's fields, which may span lines within parentheses.
+struct Entry {
+    line: usize,
+    /// Whether the record leaves out its owner name.
+    indented: bool,
+    tokens: Vec<Token>,
+}
+
+struct Token {
+    /// With escapes resolved and quotes removed.
+    text: String,
+    /// As written, to tell `\#` from a quoted `#`.
+    raw: String,
+}
+
+/// Splits a master file into records of whitespace-separated tokens.
+fn entries(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        if depth == 0 {
+            entries.extend(current.take().filter(|entry| !entry.tokens.is_empty()));
+            current = Some(Entry {
+                line: number,
+                indented: line.starts_with([' ', '\t']),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().expect("an entry is always open");
+        let mut chars = line.chars().peekable();
+        let mut token: Option<(Vec<u8>, String)> = None;
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    let (_, raw) = token.get_or_insert_default();
+                    raw.push(c);
+                }
+                '\\' => {
+                    let (text, raw) = token.get_or_insert_default();
+                    raw.push(c);
+                    let Some(escaped) = chars.next() else {
+                        return Err(invalid(number, "Escape at the end of a line".to_string()));
+                    };
+                    raw.push(escaped);
+                    if escaped.is_ascii_digit() {
+                        let mut digits = escaped.to_string();
+                        for _ in 0..2 {
+                            let digit = chars.next_if(char::is_ascii_digit).ok_or_else(|| {
+                                invalid(number, "Escapes take three digits".to_string())
+                            })?;
+                            digits.push(digit);
+                            raw.push(digit);
+                        }
+                        let byte = digits
+                            .parse()
+                            .map_err(|_| invalid(number, format!("Invalid escape \\{digits}")))?;
+                        text.push(byte);
+                    } else {
+                        text.extend(escaped.to_string().bytes());
+                    }
+                }
+                _ if quoted => {
+                    let (text, raw) = token.get_or_insert_default();
+                    text.extend(c.to_string().bytes());
+                    raw.push(c);
+                }
+                ';' => break,
+                '(' | ')' | ' ' | '\t' => {
+                    entry.tokens.extend(token.take().map(|(text, raw)| Token {
+                        text: String::from_utf8_lossy(&text).into_owned(),
+                        raw,
+                    }));
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        depth -= 1;
+                        if depth < 0 {
+                            return Err(invalid(number, "Unbalanced )".to_string()));
+                        }
+                    }
+                }
+                _ => {
+                    let (text, raw) = token.get_or_insert_default();
+                    text.extend(c.to_string().bytes());
+                    raw.push(c);
+                }
+            }
+        }
+        if quoted {
+            return Err(invalid(number, "Unterminated quote".to_string()));
+        }
+        entry.tokens.extend(token.map(|(text, raw)| Token {
+            text: String::from_utf8_lossy(&text).into_owned(),
+            raw,
+        }));
+    }
+    if depth > 0 {
+        let line = current.as_ref().map_or(0, |entry| entry.line);
+        return Err(invalid(line, "Unbalanced (".to_string()));
+    }
+    entries.extend(current.filter(|entry| !entry.tokens.is_empty()));
+    Ok(entries)
+}
+
+/// `name` fully qualified, without its trailing dot: `@` is `origin`, and
+/// a name without a trailing dot is relative to it.
+pub(crate) fn absolute(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{name}.{origin}")
+    }
+}
+
+/// Seconds, bare or in BIND's units: `3600`, `1h`, `1d12h`, `2w`.
+fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(seconds) = text.parse() {
+        return Some(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        let value: u32 = std::mem::take(&mut number).parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+    }
+    number.is_empty().then_some(total)
+}
+
+/// The rdata of a `type_` record from its presentation form.
+fn rdata(
+    type_: &RecordType,
+    tokens: &[Token],
+    origin: &str,
+) -> std::result::Result<DNSRecordData, String> {
+    if tokens.first().is_some_and(|token| token.raw == "\\#") {
+        return generic_rdata(&tokens[1..]);
+    }
+    let fields: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+    let wrong_count = || format!("Wrong number of fields for {type_}: {}", fields.len());
+    let number = |field: &str| {
+        field
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid number {field}"))
+    };
+    let name = |field: &str| absolute(field, origin);
+    let data = match (type_, fields.as_slice()) {
+        (RecordType::A, [ip]) => DNSRecordData::Ipv4Addr(
+            ip.parse()
+                .map_err(|_| format!("Invalid IPv4 address {ip}"))?,
+        ),
+        (RecordType::Aaaa, [ip]) => DNSRecordData::Ipv6Addr(
+            ip.parse()
+                .map_err(|_| format!("Invalid IPv6 address {ip}"))?,
+        ),
+        (RecordType::Ns | RecordType::Cname | RecordType::Ptr, [target]) => {
+            DNSRecordData::Name(name(target))
+        }
+        (RecordType::Mx, [preference, exchange]) => DNSRecordData::Mx(Mx {
+            preference: number(preference)?,
+            exchange: name(exchange),
+        }),
+        (RecordType::Soa, [mname, rname, serial, times @ ..]) if times.len() == 4 => {
+            let time =
+                |field: &str| parse_ttl(field).ok_or_else(|| format!("Invalid time {field}"));
+            DNSRecordData::Soa(Soa {
+                mname: name(mname),
+                rname: name(rname),
+                serial: serial
+                    .parse()
+                    .map_err(|_| format!("Invalid serial {serial}"))?,
+                refresh: time(times[0])?,
+                retry: time(times[1])?,
+                expire: time(times[2])?,
+                minimum: time(times[3])?,
+            })
+        }
+        (RecordType::Srv, [priority, weight, port, target]) => DNSRecordData::Srv(Srv {
+            priority: number(priority)?,
+            weight: number(weight)?,
+            port: number(port)?,
+            target: name(target),
+        }),
+        (RecordType::Naptr, [order, preference, flags, services, regexp, replacement]) => {
+            DNSRecordData::Naptr(Naptr {
+                order: number(order)?,
+                preference: number(preference)?,
+                flags: character_string(flags)?,
+                services: character_string(services)?,
+                regexp: character_string(regexp)?,
+                replacement: name(replacement),
+            })
+        }
+        (RecordType::Txt, strings) if !strings.is_empty() => DNSRecordData::Txt(
+            strings
+                .iter()
+                .map(|string| character_string(string))
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        (
+            RecordType::A
+            | RecordType::Aaaa
+            | RecordType::Ns
+            | RecordType::Cname
+            | RecordType::Ptr
+            | RecordType::Mx
+            | RecordType::Soa
+            | RecordType::Srv
+            | RecordType::Naptr
+            | RecordType::Txt,
+            _,
+        ) => return Err(wrong_count()),
+        _ => return Err(format!("{type_} rdata must be written in the \\# form")),
+    };
+    Ok(data)
+}
+
+/// A string as a character-string holds it, in at most 255 bytes.
+fn character_string(string: &str) -> std::result::Result<String, String> {
+    if string.len() > 255 {
+        return Err(format!("String of {} bytes, longer than 255", string.len()));
+    }
+    Ok(string.to_string())
+}
+
+/// `\# <length> <hex>...`, the hex possibly split across fields.
+fn generic_rdata(tokens: &[Token]) -> std::result::Result<DNSRecordData, String> {
+    let Some((length, hex)) = tokens.split_first() else {
+        return Err("\\# needs a length".to_string());
+    };
+    let length: usize = length
+        .text
+        .parse()
+        .map_err(|_| format!("Invalid length {}", length.text))?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("Invalid hex {hex}"))?;
+    if data.len() != length {
+        return Err(format!("\\# length {length} but {} bytes", data.len()));
+    }
+    Ok(DNSRecordData::Data(data))
+}
+
+/// A zone's records, answered from as its authoritative server would:
+/// following CNAMEs within the zone, synthesizing answers from wildcards
+/// (RFC 4592), referring queries under a delegation to the subzone's name
+/// servers with their glue, and with the SOA in negative answers. Nothing
+/// else goes in the authority and additional sections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    origin: String,
+    /// By lowercase owner name, without the trailing dot.
+    records: HashMap<String, Vec<DNSRecord>>,
+}
+
+impl Zone {
+    /// Checks that every record is at or under `origin`, which has the one
+    /// SOA. Repeated records, such as the closing SOA of a zone transfer,
+    /// are kept once.
+    pub fn new(origin: &str, records: impl IntoIterator<Item = DNSRecord>) -> Result<Self> {
+        let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+        let mut zone = Zone {
+            origin,
+            records: HashMap::new(),
+        };
+        for record in records {
+            let owner = record.name.trim_end_matches('.').to_ascii_lowercase();
+            if !zone.contains(&owner) {
+                return Err(DnsError::InvalidZone(format!(
+                    "{} is outside {}",
+                    record.name,
+                    fqdn(&zone.origin)
+                )));
+            }
+            let owned = zone.records.entry(owner).or_default();
+            if !owned.contains(&record) {
+                owned.push(record);
+            }
+        }
+        let soas = zone.records.get(&zone.origin).map_or(0, |apex| {
+            apex.iter()
+                .filter(|record| record.type_ == RecordType::Soa)
+                .count()
+        });
+        if soas != 1 {
+            return Err(DnsError::InvalidZone(format!(
+                "{} must have one SOA at its apex, not {soas}",
+                fqdn(&zone.origin)
+            )));
+        }
+        Ok(zone)
+    }
+
+    /// Reads a master file, as [`parse_zone_file`] does.
+    pub fn parse(origin: &str, text: &str) -> Result<Self> {
+        Self::new(origin, parse_zone_file(origin, text)?)
+    }
+
+    /// The zone's apex, lowercase and without the trailing dot.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// The SOA record at the apex.
+    pub fn soa(&self) -> &DNSRecord {
+        self.records[&self.origin]
+            .iter()
+            .find(|record| record.type_ == RecordType::Soa)
+            .expect("Zone::new checks the SOA is there")
+    }
+
+    /// Every record, in no particular order.
+    pub fn records(&self) -> impl Iterator<Item = &DNSRecord> {
+        self.records.values().flatten()
+    }
+
+    /// Whether `name` is at or under the zone's apex.
+    pub fn contains(&self, name: &str) -> bool {
+        is_subdomain(name, &self.origin)
+    }
+
+    /// The records as a zone transfer sends them (RFC 5936): the SOA first,
+    /// the rest in canonical order and the SOA again to close.
+    pub fn transfer(&self) -> Vec<DNSRecord> {
+        let mut records: Vec<DNSRecord> = self
+            .records()
+            .filter(|record| record.type_ != RecordType::Soa)
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| {
+            canonical_name_cmp(&a.name, &b.name)
+                .then_with(|| (a.type_.clone() as u16).cmp(&(b.type_.clone() as u16)))
+        });
+        records.insert(0, self.soa().clone());
+        records.push(self.soa().clone());
+        records
+    }
+
+    /// The response to `query`, whose question must be in the zone.
+    pub fn answer(&self, query: &DNSPacket) -> Result<DNSPacket> {
+        let mut flags = DNSFlags {
+            qr: true,
+            aa: true,
+            opcode: query.header.flags.opcode,
+            rd: query.header.flags.rd,
+            ..DNSFlags::default()
+        };
+        let mut builder = MessageBuilder::response_to(query);
+        if query.edns.is_some() {
+            builder = builder.edns(Edns::default());
+        }
+        let [question] = query.questions.as_slice() else {
+            return builder.flags(flags).rcode(Rcode::FormErr).build();
+        };
+        let mut name = question.name.trim_end_matches('.').to_ascii_lowercase();
+        for followed in 0..MAX_CNAME_CHAIN {
+            if !self.contains(&name) {
+                break;
+            }
+            if let Some(cut) = self.delegation(&name, &question.type_) {
+                // A CNAME into a subzone is left for the resolver to follow.
+                if followed == 0 {
+                    flags.aa = false;
+                    builder = self.referral(builder, cut);
+                }
+                break;
+            }
+            let Some(owned) = owned_records(&self.records, &name) else {
+                return builder
+                    .flags(flags)
+                    .rcode(Rcode::NxDomain)
+                    .authority(self.negative_soa())
+                    .build();
+            };
+            let matching: Vec<DNSRecord> = owned
+                .iter()
+                .filter(|record| record.type_ == question.type_)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                return matching
+                    .into_iter()
+                    .fold(builder.flags(flags), MessageBuilder::answer)
+                    .build();
+            }
+            let cname = owned.iter().find_map(|record| match &record.data {
+                DNSRecordData::Name(target) if record.type_ == RecordType::Cname => {
+                    Some((record, target))
+                }
+                _ => None,
+            });
+            let Some((record, target)) = cname else {
+                return builder.flags(flags).authority(self.negative_soa()).build();
+            };
+            builder = builder.answer(record.clone());
+            name = target.trim_end_matches('.').to_ascii_lowercase();
+        }
+        builder.flags(flags).build()
+    }
+
+    /// The topmost delegation point at or above `name`, below the apex. The
+    /// DS records of a delegation are the parent's to answer for.
+    fn delegation<'a>(&self, name: &'a str, type_: &RecordType) -> Option<&'a str> {
+        let mut cut = None;
+        let mut ancestor = name;
+        while ancestor.len() > self.origin.len() {
+            let has_ns = self
+                .records
+                .get(ancestor)
+                .is_some_and(|owned| owned.iter().any(|record| record.type_ == RecordType::Ns));
+            if has_ns && !(ancestor == name && *type_ == RecordType::Ds) {
+                cut = Some(ancestor);
+            }
+            match ancestor.split_once('.') {
+                Some((_, parent)) => ancestor = parent,
+                None => break,
+            }
+        }
+        cut
+    }
+
+    /// Adds the NS records at `cut` and the addresses the zone has for them.
+    fn referral(&self, mut builder: MessageBuilder, cut: &str) -> MessageBuilder {
+        let servers: Vec<&DNSRecord> = self.records[cut]
+            .iter()
+            .filter(|record| record.type_ == RecordType::Ns)
+            .collect();
+        for server in &servers {
+            builder = builder.authority((*server).clone());
+        }
+        for server in servers {
+            let DNSRecordData::Name(target) = &server.data else {
+                continue;
+            };
+            let target = target.trim_end_matches('.').to_ascii_lowercase();
+            for glue in self.records.get(&target).into_iter().flatten() {
+                if matches!(glue.type_, RecordType::A | RecordType::Aaaa) {
+                    builder = builder.additional(glue.clone());
+                }
+            }
+        }
+        builder
+    }
+
+    /// The SOA as negative answers carry it, with the lesser of its TTL and
+    /// minimum as TTL (RFC 2308).
+    fn negative_soa(&self) -> DNSRecord {
+        let soa = self.soa();
+        let ttl = match &soa.data {
+            DNSRecordData::Soa(data) => soa.ttl.min(data.minimum),
+            _ => soa.ttl,
+        };
+        DNSRecord { ttl, ..soa.clone() }
+    }
+}
+
+/// Whether `name` is `domain` or under it, ignoring ASCII case and any
+/// trailing dots.
+pub(crate) fn is_subdomain(name: &str, domain: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let domain = domain.trim_end_matches('.');
+    domain.is_empty()
+        || name.eq_ignore_ascii_case(domain)
+        || name.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+            name.as_bytes()[dot] == b'.' && name[dot + 1..].eq_ignore_ascii_case(domain)
+        })
+}
+
+/// The records of `name` among `records`, keyed by lowercase owner name, or
+/// `None` if it does not exist. A name that does not exist is answered for
+/// by the wildcard of its closest encloser, the nearest ancestor that
+/// exists, if it has one (RFC 4592); a more specific name that exists, even
+/// without records, stops the wildcard matching under it.
+pub(crate) fn owned_records(
+    records: &HashMap<String, Vec<DNSRecord>>,
+    name: &str,
+) -> Option<Vec<DNSRecord>> {
+    if let Some(owned) = records.get(name) {
+        return Some(owned.clone());
+    }
+    if exists(records, name) {
+        return Some(Vec::new());
+    }
+    let mut encloser = name;
+    while let Some((_, parent)) = encloser.split_once('.') {
+        encloser = parent;
+        if exists(records, encloser) {
+            let wildcard = records.get(&format!("*.{encloser}"))?;
+            let synthesized = wildcard
+                .iter()
+                .map(|record| DNSRecord {
+                    name: name.to_string(),
+                    ..record.clone()
+                })
+                .collect();
+            return Some(synthesized);
+        }
+    }
+    None
+}
+
+/// Whether `name` owns records or is an empty non-terminal above names that
+/// do.
+fn exists(records: &HashMap<String, Vec<DNSRecord>>, name: &str) -> bool {
+    records.contains_key(name)
+        || records.keys().any(|owner| {
+            owner
+                .strip_suffix(name)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+}